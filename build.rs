@@ -7,8 +7,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .out_dir("src/protobuf")
         .compile(&["proto/transforms.proto"], &["proto"])?;
+    // converted blocks are served as JSON for debugging with the debug-json feature
     tonic_build::configure()
         .out_dir("src/protobuf")
+        .type_attribute(
+            ".sf.ethereum.type.v2",
+            "#[cfg_attr(feature = \"debug-json\", derive(serde::Serialize))]",
+        )
+        .field_attribute(
+            ".sf.ethereum.type.v2.BlockHeader.timestamp",
+            "#[cfg_attr(feature = \"debug-json\", serde(serialize_with = \"crate::debug_json::timestamp\"))]",
+        )
         .compile(&["proto/codec.proto"], &["proto"])?;
     Ok(())
 }
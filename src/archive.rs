@@ -1,4 +1,4 @@
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
 use tracing::debug;
@@ -8,6 +8,12 @@ use tracing::debug;
 pub struct LogRequest {
     pub address: Vec<String>,
     pub topic0: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub topic1: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub topic2: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub topic3: Vec<String>,
     pub transaction: bool,
     pub transaction_traces: bool,
 }
@@ -15,6 +21,8 @@ pub struct LogRequest {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct TxRequest {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub from: Vec<String>,
     pub to: Vec<String>,
     pub sighash: Vec<String>,
     pub traces: bool,
@@ -42,6 +50,12 @@ pub struct BlockFieldSelection {
     pub mix_hash: bool,
     pub base_fee_per_gas: bool,
     pub nonce: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub l1_block_number: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_root: Option<bool>,
+    pub blob_gas_used: bool,
+    pub excess_blob_gas: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -72,23 +86,28 @@ pub struct TxFieldSelection {
     pub r: bool,
     pub s: bool,
     pub y_parity: bool,
+    pub chain_id: bool,
     pub gas_used: bool,
     pub cumulative_gas_used: bool,
     pub effective_gas_price: bool,
     pub r#type: bool,
     pub status: bool,
+    pub max_fee_per_blob_gas: bool,
+    pub blob_versioned_hashes: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct TraceFieldSelection {
     pub transaction_index: bool,
+    pub trace_address: bool,
     pub r#type: bool,
     pub error: bool,
     pub create_from: bool,
     pub create_value: bool,
     pub create_gas: bool,
     pub create_result_gas_used: bool,
+    pub create_result_code: bool,
     pub create_result_address: bool,
     pub call_from: bool,
     pub call_to: bool,
@@ -98,6 +117,9 @@ pub struct TraceFieldSelection {
     pub call_type: bool,
     pub call_result_gas_used: bool,
     pub call_result_output: bool,
+    pub suicide_address: bool,
+    pub suicide_refund_address: bool,
+    pub suicide_balance: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -124,6 +146,16 @@ pub struct BatchRequest {
     pub logs: Option<Vec<LogRequest>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub transactions: Option<Vec<TxRequest>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_all_blocks: Option<bool>,
+}
+
+/// Integer some archives send as a JSON number and others as a hex string.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Quantity {
+    Number(u64),
+    Hex(String),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -132,7 +164,8 @@ pub struct BlockHeader {
     pub number: u64,
     pub hash: String,
     pub parent_hash: String,
-    pub size: u64,
+    #[serde(default)]
+    pub size: Option<Quantity>,
     pub sha3_uncles: String,
     pub miner: String,
     pub state_root: String,
@@ -148,6 +181,14 @@ pub struct BlockHeader {
     pub mix_hash: String,
     pub nonce: String,
     pub base_fee_per_gas: Option<String>,
+    #[serde(default)]
+    pub l1_block_number: Option<Number>,
+    #[serde(default)]
+    pub send_root: Option<String>,
+    #[serde(default)]
+    pub blob_gas_used: Option<String>,
+    #[serde(default)]
+    pub excess_blob_gas: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -178,11 +219,28 @@ pub struct Transaction {
     pub r: String,
     pub s: String,
     pub y_parity: Option<u8>,
+    #[serde(default)]
+    pub chain_id: Option<u64>,
     pub gas_used: String,
     pub cumulative_gas_used: String,
     pub effective_gas_price: String,
+    /// Legacy transactions may come without a type
+    #[serde(default)]
     pub r#type: i32,
     pub status: i32,
+    #[serde(default)]
+    pub access_list: Option<Vec<AccessListItem>>,
+    #[serde(default)]
+    pub max_fee_per_blob_gas: Option<String>,
+    #[serde(default)]
+    pub blob_versioned_hashes: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessListItem {
+    pub address: String,
+    pub storage_keys: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -212,6 +270,12 @@ pub struct TraceAction {
     pub gas: Option<String>,
     pub input: Option<String>,
     pub r#type: Option<CallType>,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub refund_address: Option<String>,
+    #[serde(default)]
+    pub balance: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -220,12 +284,16 @@ pub struct TraceResult {
     pub gas_used: Option<String>,
     pub address: Option<String>,
     pub output: Option<String>,
+    #[serde(default)]
+    pub code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Trace {
     pub transaction_index: u32,
+    #[serde(default)]
+    pub trace_address: Vec<u32>,
     pub r#type: TraceType,
     pub error: Option<String>,
     #[serde(default)]
@@ -242,6 +310,21 @@ pub struct Block {
     pub traces: Option<Vec<Trace>>,
 }
 
+/// Non-successful http response of the archive.
+#[derive(Debug)]
+pub struct ResponseError {
+    pub status: StatusCode,
+    pub text: String,
+}
+
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed response from archive - {}", self.text)
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
 #[derive(Debug)]
 pub struct Archive {
     client: Client,
@@ -261,9 +344,10 @@ impl Archive {
             .send()
             .await?;
 
-        if let Err(_) = response.error_for_status_ref() {
+        if response.error_for_status_ref().is_err() {
+            let status = response.status();
             let text = response.text().await?;
-            anyhow::bail!("failed response from archive - {}", text);
+            return Err(ResponseError { status, text }.into());
         }
 
         let text = response.text().await?;
@@ -276,9 +360,10 @@ impl Archive {
         let worker_url = self.worker(request.from_block).await?;
         let response = self.client.post(worker_url).json(&request).send().await?;
 
-        if let Err(_) = response.error_for_status_ref() {
+        if response.error_for_status_ref().is_err() {
+            let status = response.status();
             let text = response.text().await?;
-            anyhow::bail!("failed response from archive - {}", text);
+            return Err(ResponseError { status, text }.into());
         }
 
         let text = response.text().await?;
@@ -293,9 +378,10 @@ impl Archive {
             .send()
             .await?;
 
-        if let Err(_) = response.error_for_status_ref() {
+        if response.error_for_status_ref().is_err() {
+            let status = response.status();
             let text = response.text().await?;
-            anyhow::bail!("failed response from archive - {}", text);
+            return Err(ResponseError { status, text }.into());
         }
 
         let worker_url = response.text().await?;
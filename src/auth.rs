@@ -0,0 +1,199 @@
+use anyhow::Context;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Lets through requests carrying one of the configured tokens,
+/// either as `authorization: Bearer <token>` or as `x-api-key: <token>`.
+/// Without tokens every request is let through.
+#[derive(Clone)]
+pub struct Auth {
+    tokens: Option<Arc<HashSet<String>>>,
+}
+
+impl Auth {
+    pub fn new(tokens: impl IntoIterator<Item = String>) -> Auth {
+        let tokens: HashSet<String> = tokens.into_iter().collect();
+        Auth {
+            tokens: (!tokens.is_empty()).then(|| Arc::new(tokens)),
+        }
+    }
+
+    /// Tokens listed one per line in `path`, blank lines are skipped.
+    pub fn read_tokens(path: &Path) -> anyhow::Result<Vec<String>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.tokens.is_some()
+    }
+}
+
+fn token<T>(request: &Request<T>) -> Option<&str> {
+    let metadata = request.metadata();
+    if let Some(value) = metadata.get("authorization") {
+        let value = value.to_str().ok()?;
+        return value
+            .strip_prefix("Bearer ")
+            .or_else(|| value.strip_prefix("bearer "));
+    }
+    metadata.get("x-api-key")?.to_str().ok()
+}
+
+impl Interceptor for Auth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let tokens = match &self.tokens {
+            Some(tokens) => tokens,
+            None => return Ok(request),
+        };
+        match token(&request) {
+            Some(token) if tokens.contains(token.trim()) => Ok(request),
+            Some(_) => Err(Status::unauthenticated("invalid credentials")),
+            None => Err(Status::unauthenticated(
+                "missing credentials, send an authorization bearer token or an x-api-key header",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::Auth;
+    use crate::datasource::{BlockStream, DataRequest, DataSource};
+    use crate::firehose::Firehose;
+    use crate::pbfirehose::fetch_client::FetchClient;
+    use crate::pbfirehose::stream_client::StreamClient;
+    use crate::pbfirehose::{single_block_request, Request, SingleBlockRequest};
+    use crate::{fetch::fetch_service, stream::stream_service};
+    use std::sync::Arc;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::service::interceptor::InterceptedService;
+    use tonic::service::Interceptor;
+    use tonic::transport::Channel;
+    use tonic::Code;
+
+    fn request(header: &'static str, value: &str) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        request
+            .metadata_mut()
+            .insert(header, value.parse().unwrap());
+        request
+    }
+
+    fn code(auth: &mut Auth, request: tonic::Request<()>) -> Code {
+        match auth.call(request) {
+            Ok(_) => Code::Ok,
+            Err(status) => status.code(),
+        }
+    }
+
+    #[test]
+    fn credentials() {
+        let mut auth = Auth::new(vec!["secret".to_string(), "other".to_string()]);
+
+        assert_eq!(
+            code(&mut auth, request("authorization", "Bearer secret")),
+            Code::Ok
+        );
+        assert_eq!(code(&mut auth, request("x-api-key", "other")), Code::Ok);
+
+        assert_eq!(
+            code(&mut auth, request("authorization", "Bearer wrong")),
+            Code::Unauthenticated
+        );
+        assert_eq!(
+            code(&mut auth, request("authorization", "Basic secret")),
+            Code::Unauthenticated
+        );
+        assert_eq!(
+            code(&mut auth, request("x-api-key", "wrong")),
+            Code::Unauthenticated
+        );
+        assert_eq!(
+            code(&mut auth, tonic::Request::new(())),
+            Code::Unauthenticated
+        );
+
+        let mut disabled = Auth::new(vec![]);
+        assert!(!disabled.is_enabled());
+        assert_eq!(code(&mut disabled, tonic::Request::new(())), Code::Ok);
+    }
+
+    struct EmptyDataSource;
+
+    #[async_trait::async_trait]
+    impl DataSource for EmptyDataSource {
+        fn get_finalized_blocks(
+            &self,
+            _request: DataRequest,
+            _stop_on_head: bool,
+        ) -> anyhow::Result<BlockStream> {
+            Ok(Box::new(futures_util::stream::empty()))
+        }
+
+        async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+            Ok(0)
+        }
+
+        async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+            Ok(format!("0x{:064x}", height))
+        }
+    }
+
+    #[tokio::test]
+    async fn both_services_are_guarded() {
+        let firehose = Arc::new(Firehose::new(Arc::new(EmptyDataSource), None));
+        let auth = Auth::new(vec!["secret".to_string()]);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(InterceptedService::new(
+                    stream_service(firehose.clone(), None, None, Default::default()),
+                    auth.clone(),
+                ))
+                .add_service(InterceptedService::new(fetch_service(firehose, None), auth))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let channel = Channel::from_shared(url).unwrap().connect().await.unwrap();
+
+        let blocks = |key: Option<&str>| {
+            let mut request = tonic::Request::new(Request::default());
+            if let Some(key) = key {
+                request
+                    .metadata_mut()
+                    .insert("x-api-key", key.parse().unwrap());
+            }
+            let mut client = StreamClient::new(channel.clone());
+            async move { client.blocks(request).await.map(|_| ()) }
+        };
+        assert!(blocks(Some("secret")).await.is_ok());
+        assert_eq!(
+            blocks(Some("wrong")).await.unwrap_err().code(),
+            Code::Unauthenticated
+        );
+        assert_eq!(
+            blocks(None).await.unwrap_err().code(),
+            Code::Unauthenticated
+        );
+
+        let block = tonic::Request::new(SingleBlockRequest {
+            reference: Some(single_block_request::Reference::BlockNumber(
+                single_block_request::BlockNumber { num: 0 },
+            )),
+            transforms: vec![],
+        });
+        let status = FetchClient::new(channel).block(block).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+}
@@ -1,14 +1,196 @@
+use crate::datasource::HeaderVariant;
+use crate::firehose::{ETH_BLOCK_TYPE_URL, ETH_BLOCK_VERSION};
+use std::path::PathBuf;
+use tonic::codec::CompressionEncoding;
+
 #[derive(clap::Parser)]
 pub struct Cli {
-    /// Subsquid archive endpoint URL
+    /// Subsquid archive endpoint URL, repeat it for redundant archives tried in order
+    #[clap(long, required_unless_present = "merged_blocks")]
+    pub archive: Vec<String>,
+
+    /// Send archive calls other than block streams to every archive at once, taking the first answer
     #[clap(long)]
-    pub archive: String,
+    pub race_archives: bool,
+
+    /// Directory of decompressed merged blocks files served in place of the archive
+    #[clap(long, conflicts_with = "archive")]
+    pub merged_blocks: Option<PathBuf>,
 
     /// Rpc api URL of an ethereum node
     #[clap(long)]
     pub rpc: Option<String>,
 
+    /// WebSocket URL of the same node, used to subscribe to new heads instead of polling
+    #[clap(long)]
+    pub rpc_ws: Option<String>,
+
+    /// Interval between head polls of the rpc while waiting for new blocks in milliseconds
+    #[clap(long, default_value_t = 1_000)]
+    pub rpc_poll_interval_ms: u64,
+
+    /// Upper bound the poll interval slows down to while the chain doesn't advance (defaults to the poll interval)
+    #[clap(long)]
+    pub rpc_max_poll_interval_ms: Option<u64>,
+
     /// Number of blocks after which data is considered final
     #[clap(long)]
     pub finality_confirmation: Option<u64>,
+
+    /// Directory to cache finalized archive blocks in to serve repeated requests, no cache without it
+    #[clap(long)]
+    pub block_cache_dir: Option<PathBuf>,
+
+    /// Number of blocks kept in the block cache directory
+    #[clap(long, default_value_t = 100_000)]
+    pub block_cache_size: usize,
+
+    /// Number of cached blocks also kept in memory
+    #[clap(long, default_value_t = 1000)]
+    pub block_cache_memory_size: usize,
+
+    /// Milliseconds the archive finalized height is reused across streams, 0 disables the cache
+    #[clap(long, default_value_t = 1000)]
+    pub height_cache_ttl_ms: u64,
+
+    /// Port to serve prometheus metrics at /metrics
+    #[clap(long)]
+    pub metrics_port: Option<u16>,
+
+    /// Port to serve converted blocks as JSON at /blocks/<number>, for debugging
+    #[cfg(feature = "debug-json")]
+    #[clap(long)]
+    pub debug_json_port: Option<u16>,
+
+    /// Number of blocks the finalized archive height may lag behind the chain head
+    /// while the server reports itself healthy
+    #[clap(long, default_value_t = 200)]
+    pub health_max_lag: u64,
+
+    /// Number of times a transient data source failure is retried before a stream fails
+    #[clap(long, default_value_t = 5)]
+    pub max_retries: u32,
+
+    /// Delay before the first retry of a failed data source in milliseconds, doubled on every next one
+    #[clap(long, default_value_t = 500)]
+    pub retry_backoff_ms: u64,
+
+    /// Maximum time to wait for a data source call or the next block of a data source stream in milliseconds
+    #[clap(long, default_value_t = 60_000)]
+    pub call_timeout_ms: u64,
+
+    /// Number of finalized blocks converted concurrently (defaults to the number of cpus)
+    #[clap(long)]
+    pub conversion_parallelism: Option<usize>,
+
+    /// Compression of Stream/Blocks responses for clients accepting it
+    #[clap(long, value_enum, default_value_t = Compression::Gzip)]
+    pub stream_compression: Compression,
+
+    /// Number of Stream/Blocks requests served at once, further ones are rejected as resource exhausted
+    #[clap(long)]
+    pub max_streams: Option<usize>,
+
+    /// Number of blocks a single stream may send per second, faster streams are paused
+    #[clap(long)]
+    pub max_blocks_per_second: Option<u32>,
+
+    /// Number of blocks a single stream may request, unbounded streams may start
+    /// at most as many blocks below the finalized head
+    #[clap(long)]
+    pub max_block_range: Option<u64>,
+
+    /// Number of blocks the archive may lag behind the end of a range and still serve it,
+    /// the rpc takes over finalized blocks past that gap
+    #[clap(long, default_value_t = 0)]
+    pub archive_crossover_gap: u64,
+
+    /// Fail blocks whose transactions don't add up to the gas used of their header
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Fail blocks whose header doesn't hash to the block hash
+    #[clap(long)]
+    pub verify_block_hashes: bool,
+
+    /// Trace the transactions matched by log filters too, they only come with calls when
+    /// a call filter matches them otherwise
+    #[clap(long)]
+    pub full_traces: bool,
+
+    /// Attach the block JSON returned by the rpc to the blocks it serves, for auditing the conversion
+    #[clap(long)]
+    pub raw_blocks: bool,
+
+    /// Attach the gas changes of every call, only merged block files record them
+    #[clap(long)]
+    pub gas_changes: bool,
+
+    /// Number of transient failures in a row after which calls to a data source fail fast
+    /// as unavailable for the cooldown, the circuit breaker is off by default
+    #[clap(long)]
+    pub circuit_breaker_failures: Option<u32>,
+
+    /// Milliseconds a tripped circuit breaker fails calls before letting one probe the data source
+    #[clap(long, default_value_t = 30_000)]
+    pub circuit_breaker_cooldown_ms: u64,
+
+    /// Milliseconds running streams may take to finish on shutdown before they're closed
+    #[clap(long, default_value_t = 20000)]
+    pub shutdown_grace_period_ms: u64,
+
+    /// Compression of Fetch/Block responses for clients accepting it
+    #[clap(long, value_enum, default_value_t = Compression::Gzip)]
+    pub fetch_compression: Compression,
+
+    /// PEM encoded server certificate chain, serves over TLS together with --tls-key
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the server certificate
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM encoded CA certificates, clients must present a certificate signed by one of them
+    #[clap(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Token clients must send as an authorization bearer token or x-api-key header, may be repeated
+    #[clap(long)]
+    pub api_key: Vec<String>,
+
+    /// File with accepted client tokens, one per line
+    #[clap(long)]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// Block header fields of the chain, `arbitrum` adds the L2 fields to the headers
+    #[clap(long, value_enum, default_value_t = HeaderVariant::L1)]
+    pub header_variant: HeaderVariant,
+
+    /// Chain id of the blocks served by the archive or the merged blocks, checked against the chain id of the rpc
+    #[clap(long)]
+    pub archive_chain_id: Option<u64>,
+
+    /// Type url of the emitted blocks
+    #[clap(long, default_value = ETH_BLOCK_TYPE_URL)]
+    pub block_type_url: String,
+
+    /// Schema version of the emitted blocks
+    #[clap(long, default_value_t = ETH_BLOCK_VERSION)]
+    pub block_version: i32,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    pub fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(CompressionEncoding::Gzip),
+        }
+    }
 }
@@ -1,51 +1,126 @@
 use crate::datasource::HashAndHeight;
+use crate::pbfirehose::ForkStep;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use std::fmt;
 
+/// Position of a stream, handed to clients as an opaque base64 string.
 #[derive(PartialEq, Debug)]
 pub struct Cursor {
+    pub step: ForkStep,
     pub block: HashAndHeight,
     pub finalized: HashAndHeight,
+    /// Parent of an undone block, the last block the client keeps after the undo
+    pub parent: Option<HashAndHeight>,
 }
 
 impl Cursor {
     pub fn new(block: HashAndHeight, finalized: HashAndHeight) -> Cursor {
-        Cursor { block, finalized }
+        Cursor {
+            step: ForkStep::StepNew,
+            block,
+            finalized,
+            parent: None,
+        }
+    }
+
+    pub fn with_step(mut self, step: ForkStep) -> Cursor {
+        self.step = step;
+        self
+    }
+
+    pub fn undo(block: HashAndHeight, parent: HashAndHeight, finalized: HashAndHeight) -> Cursor {
+        Cursor {
+            step: ForkStep::StepUndo,
+            block,
+            finalized,
+            parent: Some(parent),
+        }
+    }
+
+    /// The last block the client holds, streams resumed from the cursor continue after it.
+    pub fn head(&self) -> &HashAndHeight {
+        self.parent.as_ref().unwrap_or(&self.block)
+    }
+}
+
+fn parse_step(value: &str) -> Option<ForkStep> {
+    match value {
+        "new" => Some(ForkStep::StepNew),
+        "undo" => Some(ForkStep::StepUndo),
+        "final" => Some(ForkStep::StepFinal),
+        _ => None,
+    }
+}
+
+fn step_name(step: ForkStep) -> &'static str {
+    match step {
+        ForkStep::StepUndo => "undo",
+        ForkStep::StepFinal => "final",
+        _ => "new",
     }
 }
 
+fn parse_head(height: &str, hash: &str, name: &str) -> Result<HashAndHeight, String> {
+    Ok(HashAndHeight {
+        hash: hash.to_string(),
+        height: height
+            .parse()
+            .map_err(|_| format!("invalid {} height", name))?,
+    })
+}
+
 impl TryFrom<&String> for Cursor {
     type Error = String;
 
     fn try_from(value: &String) -> Result<Self, Self::Error> {
-        let split: Vec<_> = value.split(':').collect();
+        let decoded = URL_SAFE_NO_PAD
+            .decode(value)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or("invalid cursor")?;
+        let split: Vec<_> = decoded.split(':').collect();
 
-        if split.len() != 4 {
+        let step = split
+            .first()
+            .and_then(|step| parse_step(step))
+            .ok_or("invalid cursor")?;
+        let expected = if step == ForkStep::StepUndo { 7 } else { 5 };
+        if split.len() != expected {
             return Err("invalid cursor".to_string());
         }
 
-        let block = HashAndHeight {
-            hash: split[1].to_string(),
-            height: split[0].parse().map_err(|_| "invalid block height")?,
-        };
-
-        let finalized = HashAndHeight {
-            hash: split[3].to_string(),
-            height: split[2]
-                .parse()
-                .map_err(|_| "invalid finalized block height")?,
+        let block = parse_head(split[1], split[2], "block")?;
+        let finalized = parse_head(split[3], split[4], "finalized block")?;
+        let parent = if step == ForkStep::StepUndo {
+            Some(parse_head(split[5], split[6], "parent block")?)
+        } else {
+            None
         };
 
-        Ok(Cursor { block, finalized })
+        Ok(Cursor {
+            step,
+            block,
+            finalized,
+            parent,
+        })
     }
 }
 
 impl fmt::Display for Cursor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}:{}:{}:{}",
-            self.block.height, self.block.hash, self.finalized.height, self.finalized.hash
-        )
+        let mut value = format!(
+            "{}:{}:{}:{}:{}",
+            step_name(self.step),
+            self.block.height,
+            self.block.hash,
+            self.finalized.height,
+            self.finalized.hash
+        );
+        if let Some(parent) = &self.parent {
+            value.push_str(&format!(":{}:{}", parent.height, parent.hash));
+        }
+        write!(f, "{}", URL_SAFE_NO_PAD.encode(value))
     }
 }
 
@@ -53,39 +128,86 @@ impl fmt::Display for Cursor {
 mod tests {
     use crate::cursor::Cursor;
     use crate::datasource::HashAndHeight;
+    use crate::pbfirehose::ForkStep;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    fn head(hash: &str, height: u64) -> HashAndHeight {
+        HashAndHeight {
+            hash: hash.to_string(),
+            height,
+        }
+    }
 
     #[test]
     fn display_cursor() {
-        let cursor = Cursor {
-            block: HashAndHeight {
-                hash: "hash0".to_string(),
-                height: 0,
-            },
-            finalized: HashAndHeight {
-                hash: "hash1".to_string(),
-                height: 1,
-            },
-        };
+        let cursor = Cursor::new(head("hash0", 0), head("hash1", 1));
 
-        let expected = "0:hash0:1:hash1".to_string();
+        let expected = URL_SAFE_NO_PAD.encode("new:0:hash0:1:hash1");
         assert_eq!(cursor.to_string(), expected);
+        assert!(!cursor.to_string().contains("hash0"));
     }
 
     #[test]
     fn try_cursor_from_string() {
-        let value = "0:hash0:1:hash1".to_string();
+        let value = URL_SAFE_NO_PAD.encode("final:0:hash0:1:hash1");
         let cursor = Cursor::try_from(&value).unwrap();
 
-        let expected = Cursor {
-            block: HashAndHeight {
-                hash: "hash0".to_string(),
-                height: 0,
-            },
-            finalized: HashAndHeight {
-                hash: "hash1".to_string(),
-                height: 1,
-            },
-        };
+        let expected =
+            Cursor::new(head("hash0", 0), head("hash1", 1)).with_step(ForkStep::StepFinal);
         assert_eq!(cursor, expected);
     }
+
+    #[test]
+    fn try_cursor_from_malformed_string() {
+        let values = [
+            "",
+            "new:0:hash0",
+            "new:0:hash0:1:hash1:extra",
+            "new:zero:hash0:1:hash1",
+            "new:0:hash0:one:hash1",
+            "new:-1:hash0:1:hash1",
+            "0:hash0:1:hash1",
+            "old:0:hash0:1:hash1",
+            "undo:1:hash1:1:hash1",
+            "undo:1:hash1:0:hash0:zero:hash0",
+        ];
+        for value in values {
+            assert!(
+                Cursor::try_from(&URL_SAFE_NO_PAD.encode(value)).is_err(),
+                "{} should be rejected",
+                value
+            );
+        }
+        // plaintext cursors aren't accepted
+        assert!(Cursor::try_from(&"0:hash0:1:hash1".to_string()).is_err());
+        assert!(Cursor::try_from(&"not base64!".to_string()).is_err());
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursors = [
+            Cursor::new(head("0xaa", 17_000_000), head("0xbb", 16_999_990)),
+            Cursor::new(head("0xaa", 17_000_000), head("0xbb", 16_999_990))
+                .with_step(ForkStep::StepFinal),
+            Cursor::undo(
+                head("0xaa", 17_000_000),
+                head("0xcc", 16_999_999),
+                head("0xbb", 16_999_990),
+            ),
+        ];
+        for cursor in cursors {
+            let parsed = Cursor::try_from(&cursor.to_string()).unwrap();
+            assert_eq!(parsed, cursor);
+        }
+    }
+
+    #[test]
+    fn undo_cursor_resumes_from_parent() {
+        let cursor = Cursor::undo(head("0xaa", 10), head("0xcc", 9), head("0xbb", 5));
+        assert_eq!(cursor.head(), &head("0xcc", 9));
+
+        let cursor = Cursor::new(head("0xaa", 10), head("0xbb", 5));
+        assert_eq!(cursor.head(), &head("0xaa", 10));
+    }
 }
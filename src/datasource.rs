@@ -1,15 +1,32 @@
 use futures_core::stream::Stream;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::pin::Pin;
 
 #[derive(Debug, Clone)]
 pub struct LogRequest {
     pub address: Vec<String>,
     pub topic0: Vec<String>,
+    pub topic1: Vec<String>,
+    pub topic2: Vec<String>,
+    pub topic3: Vec<String>,
+}
+
+impl LogRequest {
+    /// Accepted values of each topic position, an empty list accepts any value.
+    pub fn topics(&self) -> [&Vec<String>; 4] {
+        [&self.topic0, &self.topic1, &self.topic2, &self.topic3]
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TransactionRequest {
-    pub address: Vec<String>,
+    /// Recipients of the transaction
+    pub to: Vec<String>,
     pub sighash: Vec<String>,
+    /// Senders of the transaction
+    pub from: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -18,14 +35,50 @@ pub struct DataRequest {
     pub to: Option<u64>,
     pub logs: Vec<LogRequest>,
     pub transactions: Vec<TransactionRequest>,
+    pub balance_changes: bool,
+    pub storage_changes: bool,
+    /// Calls come with the gas changes of their execution, when the source records them
+    pub gas_changes: bool,
+    /// Transactions come with their call traces, they are left out otherwise
+    pub traces: bool,
+    /// Only block headers are requested, block bodies aren't fetched at all
+    pub headers_only: bool,
+    /// Transactions come with their receipts as returned by the rpc
+    pub raw_receipts: bool,
+    /// Blocks come with their JSON as returned by the rpc
+    pub raw_blocks: bool,
+    /// Blocks without any requested data are delivered too, instead of being skipped
+    pub all_blocks: bool,
+}
+
+impl DataRequest {
+    /// Identifies the data requested for each block regardless of the block range.
+    pub fn filter_key(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                &self.logs,
+                &self.transactions,
+                self.balance_changes,
+                self.storage_changes,
+                self.gas_changes,
+                self.traces,
+                self.headers_only,
+                self.raw_receipts,
+                self.raw_blocks,
+                self.all_blocks,
+            )
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub number: u64,
     pub hash: String,
     pub parent_hash: String,
-    pub size: u64,
+    /// Hex quantity, absent when the source doesn't report it
+    pub size: Option<String>,
     pub sha3_uncles: String,
     pub miner: String,
     pub state_root: String,
@@ -41,9 +94,37 @@ pub struct BlockHeader {
     pub mix_hash: String,
     pub nonce: String,
     pub base_fee_per_gas: Option<String>,
+    pub l2_extra: Option<L2Extra>,
+    pub blob_gas_used: Option<String>,
+    pub excess_blob_gas: Option<String>,
+    pub withdrawals_root: Option<String>,
+    pub parent_beacon_root: Option<String>,
+    pub requests_hash: Option<String>,
+}
+
+/// Header fields of Arbitrum-style L2 blocks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct L2Extra {
+    pub l1_block_number: u64,
+    pub send_root: String,
+}
+
+/// Selects the header fields data sources provide
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HeaderVariant {
+    L1,
+    Arbitrum,
+}
+
+/// Named blocks of the chain, as understood by `eth_getBlockByNumber`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockTag {
+    Latest,
+    Safe,
+    Finalized,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub transaction_index: u32,
     pub hash: String,
@@ -60,14 +141,27 @@ pub struct Transaction {
     pub r: String,
     pub s: String,
     pub y_parity: Option<u8>,
+    pub chain_id: Option<u64>,
     pub gas_used: String,
     pub cumulative_gas_used: String,
+    /// Empty when the source doesn't report it
     pub effective_gas_price: String,
     pub r#type: i32,
     pub status: i32,
+    pub access_list: Vec<AccessTuple>,
+    pub max_fee_per_blob_gas: Option<String>,
+    pub blob_versioned_hashes: Vec<String>,
+    /// Receipt JSON exactly as the rpc returned it, only kept when requested
+    pub raw_receipt: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessTuple {
+    pub address: String,
+    pub storage_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Log {
     pub address: String,
     pub data: String,
@@ -76,7 +170,7 @@ pub struct Log {
     pub transaction_index: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TraceType {
     Create,
     Call,
@@ -84,7 +178,7 @@ pub enum TraceType {
     Reward,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CallType {
     Call,
     Callcode,
@@ -92,7 +186,7 @@ pub enum CallType {
     Staticcall,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TraceAction {
     pub from: Option<String>,
     pub to: Option<String>,
@@ -102,29 +196,116 @@ pub struct TraceAction {
     pub r#type: Option<CallType>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TraceResult {
     pub gas_used: Option<String>,
     pub address: Option<String>,
     pub output: Option<String>,
+    /// deployed bytecode of created contracts
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BalanceChangeReason {
+    RewardMineUncle,
+    RewardMineBlock,
+    DaoRefundContract,
+    DaoAdjustBalance,
+    Transfer,
+    GasBuy,
+    RewardTransactionFee,
+    GasRefund,
+    SuicideRefund,
+    SuicideWithdraw,
+    Withdrawal,
+}
+
+impl BalanceChangeReason {
+    /// Block-level changes applied before the first transaction runs, the DAO fork
+    /// moves its balances at the start of its block.
+    pub fn is_pre_transaction(&self) -> bool {
+        matches!(
+            self,
+            BalanceChangeReason::DaoRefundContract | BalanceChangeReason::DaoAdjustBalance
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceChange {
+    pub address: String,
+    pub old_value: String,
+    pub new_value: String,
+    pub reason: BalanceChangeReason,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageChange {
+    pub address: String,
+    pub key: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// Why the gas left to a call changed, as recorded by the tracer
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GasChangeReason {
+    Call,
+    CallCode,
+    CallDataCopy,
+    CodeCopy,
+    CodeStorage,
+    ContractCreation,
+    ContractCreation2,
+    DelegateCall,
+    EventLog,
+    ExtCodeCopy,
+    FailedExecution,
+    IntrinsicGas,
+    PrecompiledContract,
+    RefundAfterExecution,
+    Return,
+    ReturnDataCopy,
+    Revert,
+    SelfDestruct,
+    StaticCall,
+    StateColdAccess,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GasChange {
+    pub old_value: u64,
+    pub new_value: u64,
+    pub reason: GasChangeReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trace {
     pub transaction_index: u32,
+    pub trace_address: Vec<u32>,
     pub r#type: TraceType,
     pub error: Option<String>,
     pub revert_reason: Option<String>,
     pub action: Option<TraceAction>,
     pub result: Option<TraceResult>,
+    pub balance_changes: Vec<BalanceChange>,
+    pub storage_changes: Vec<StorageChange>,
+    /// Only recorded by sources with step level tracing
+    pub gas_changes: Vec<GasChange>,
+    /// keccak256 hash -> preimage, both hex encoded
+    pub keccak_preimages: HashMap<String, String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
     pub logs: Vec<Log>,
     pub transactions: Vec<Transaction>,
     pub traces: Vec<Trace>,
+    pub uncles: Vec<BlockHeader>,
+    pub balance_changes: Vec<BalanceChange>,
+    /// Block JSON exactly as the rpc returned it, only kept when requested
+    pub raw: Option<String>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -161,6 +342,48 @@ pub trait DataSource {
     ) -> anyhow::Result<BlockStream>;
     async fn get_finalized_height(&self) -> anyhow::Result<u64>;
     async fn get_block_hash(&self, height: u64) -> anyhow::Result<String>;
+
+    /// The lowest block the source can serve.
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+
+    /// Height of the latest known block, including the ones that aren't final yet.
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.get_finalized_height().await
+    }
+
+    /// EIP-155 chain id of the served blocks, `None` when the source can't tell.
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Finalized blocks at `heights` in the same order, `None` for the missing ones.
+    /// `from` and `to` of the request are ignored.
+    async fn get_blocks_by_refs(
+        &self,
+        heights: &[u64],
+        request: DataRequest,
+    ) -> anyhow::Result<Vec<Option<Block>>>
+    where
+        Self: Sync,
+    {
+        let mut blocks = Vec::with_capacity(heights.len());
+        for height in heights {
+            let req = DataRequest {
+                from: *height,
+                to: Some(*height),
+                ..request.clone()
+            };
+            let mut stream = Pin::from(self.get_finalized_blocks(req, true)?);
+            let block = match stream.next().await {
+                Some(result) => result?.into_iter().next(),
+                None => None,
+            };
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
 }
 
 #[async_trait::async_trait]
@@ -171,6 +394,30 @@ pub trait HotSource: DataSource {
         state: HashAndHeight,
     ) -> anyhow::Result<HotBlockStream>;
     fn as_ds(&self) -> &(dyn DataSource + Send + Sync);
+
+    /// Hashes of the blocks `from` to `to` as the source currently sees them, so the blocks
+    /// of a forked chain can be told apart down to the common ancestor.
+    async fn get_block_hashes(&self, from: u64, to: u64) -> anyhow::Result<Vec<String>>
+    where
+        Self: Sync,
+    {
+        let mut hashes = Vec::new();
+        for height in from..=to {
+            hashes.push(self.get_block_hash(height).await?);
+        }
+        Ok(hashes)
+    }
+
+    /// Height of the block the source reports under `tag`.
+    async fn get_tagged_height(&self, tag: BlockTag) -> anyhow::Result<u64>
+    where
+        Self: Sync,
+    {
+        match tag {
+            BlockTag::Latest => self.get_head_height().await,
+            BlockTag::Safe | BlockTag::Finalized => self.get_finalized_height().await,
+        }
+    }
 }
 
 pub trait HotDataSource: DataSource + HotSource {}
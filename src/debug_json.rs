@@ -0,0 +1,166 @@
+use crate::firehose::{FetchError, Firehose};
+use crate::pbcodec;
+use crate::pbfirehose::single_block_request::{BlockNumber, Reference};
+use crate::pbfirehose::SingleBlockRequest;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prost::Message;
+use serde::Serializer;
+use serde_json::Value;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::error;
+
+/// Serializes the header timestamp as unix seconds.
+pub fn timestamp<S: Serializer>(
+    value: &Option<prost_types::Timestamp>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(timestamp) => serializer.serialize_some(&timestamp.seconds),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Byte fields are the only integer arrays of a block, they are shown as hex strings.
+fn hex_bytes(value: Value) -> Value {
+    match value {
+        Value::Array(items) => {
+            let bytes: Option<Vec<u8>> = items
+                .iter()
+                .map(|item| item.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                .collect();
+            match bytes {
+                Some(bytes) if !bytes.is_empty() => Value::String(prefix_hex::encode(bytes)),
+                _ => Value::Array(items.into_iter().map(hex_bytes).collect()),
+            }
+        }
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, hex_bytes(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+}
+
+/// The block `number` as the fetch service would send it.
+async fn block(firehose: &Firehose, number: u64) -> anyhow::Result<Value> {
+    let request = SingleBlockRequest {
+        reference: Some(Reference::BlockNumber(BlockNumber { num: number })),
+        transforms: vec![],
+    };
+    let any = firehose
+        .block(request)
+        .await?
+        .block
+        .ok_or_else(|| anyhow::format_err!("no block in the response"))?;
+    let block = pbcodec::Block::decode(&any.value[..])?;
+    Ok(hex_bytes(serde_json::to_value(block)?))
+}
+
+async fn handle(firehose: &Firehose, request: Request<Body>) -> Response<Body> {
+    let number = match request.uri().path().strip_prefix("/blocks/") {
+        Some(number) if request.method() == Method::GET => number,
+        _ => return respond(StatusCode::NOT_FOUND, Body::empty()),
+    };
+    let number = match number.parse() {
+        Ok(number) => number,
+        Err(_) => {
+            let message = format!("invalid block number: {}", number);
+            return respond(StatusCode::BAD_REQUEST, message);
+        }
+    };
+
+    match block(firehose, number).await {
+        Ok(block) => {
+            let mut response = Response::new(Body::from(block.to_string()));
+            response
+                .headers_mut()
+                .insert("content-type", "application/json".parse().unwrap());
+            response
+        }
+        Err(e) => match e.downcast_ref::<FetchError>() {
+            Some(FetchError::InvalidArgument(message)) => {
+                respond(StatusCode::BAD_REQUEST, message.clone())
+            }
+            Some(FetchError::NotFound(message)) => respond(StatusCode::NOT_FOUND, message.clone()),
+            Some(FetchError::Unavailable(message)) => {
+                respond(StatusCode::SERVICE_UNAVAILABLE, message.clone())
+            }
+            Some(FetchError::DataLoss(message)) => {
+                respond(StatusCode::BAD_GATEWAY, message.clone())
+            }
+            None => {
+                error!("failed to fetch block №{} as json: {}", number, e);
+                respond(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            }
+        },
+    }
+}
+
+/// Binds the server to `addr`, the returned address is the one actually bound.
+fn bind(
+    firehose: Arc<Firehose>,
+    addr: &SocketAddr,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = hyper::Result<()>>)> {
+    let make_service = make_service_fn(move |_| {
+        let firehose = firehose.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let firehose = firehose.clone();
+                async move { Ok::<_, Infallible>(handle(&firehose, request).await) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(addr)?.serve(make_service);
+    Ok((server.local_addr(), server))
+}
+
+/// Serves converted blocks as JSON at `/blocks/<number>`, bytes are hex encoded.
+pub async fn serve(firehose: Arc<Firehose>, addr: SocketAddr) -> anyhow::Result<()> {
+    let (_, server) = bind(firehose, &addr)?;
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug_json::bind;
+    use crate::firehose::Firehose;
+    use crate::testing::{hash, MockDataSource};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn block_as_json() {
+        let firehose = Arc::new(Firehose::new(Arc::new(MockDataSource::new(10)), None));
+        let (addr, server) = bind(firehose, &([127, 0, 0, 1], 0).into()).unwrap();
+        tokio::spawn(server);
+        let url = |path: &str| format!("http://{}{}", addr, path);
+
+        let response = reqwest::get(url("/blocks/7")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let block: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(block["number"], 7);
+        assert_eq!(block["hash"], hash(7));
+        assert_eq!(block["header"]["parent_hash"], hash(6));
+        assert_eq!(block["header"]["timestamp"], 0);
+        let trace = &block["transaction_traces"][0];
+        assert_eq!(trace["to"], "0x3535353535353535353535353535353535353535");
+        assert_eq!(trace["gas_price"]["bytes"], "0x04a817c800");
+
+        let response = reqwest::get(url("/blocks/11")).await.unwrap();
+        assert_eq!(response.status(), 404);
+        let response = reqwest::get(url("/blocks/latest")).await.unwrap();
+        assert_eq!(response.status(), 400);
+    }
+}
@@ -1,27 +1,49 @@
 use crate::datasource::{
-    Block, BlockHeader, BlockStream, CallType, DataRequest, DataSource, Log, Trace, TraceAction,
-    TraceResult, TraceType, Transaction,
+    AccessTuple, Block, BlockHeader, BlockStream, CallType, DataRequest, DataSource, HeaderVariant,
+    L2Extra, Log, Trace, TraceAction, TraceResult, TraceType, Transaction,
 };
+use crate::firehose::FetchError;
 use crate::{
     archive,
     archive::{
         Archive, BatchRequest, BlockFieldSelection, FieldSelection, LogFieldSelection, LogRequest,
-        TraceFieldSelection, TxFieldSelection, TxRequest,
+        Quantity, TraceFieldSelection, TxFieldSelection, TxRequest,
     },
 };
 use async_stream::try_stream;
+use futures_util::StreamExt;
 use serde_json::Number;
+use std::collections::{BTreeSet, HashMap};
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Debug)]
 pub struct ArchiveDataSource {
     archive: Arc<Archive>,
+    header_variant: HeaderVariant,
+    chain_id: Option<u64>,
 }
 
 impl ArchiveDataSource {
     pub fn new(archive: Arc<Archive>) -> ArchiveDataSource {
-        ArchiveDataSource { archive }
+        ArchiveDataSource {
+            archive,
+            header_variant: HeaderVariant::L1,
+            chain_id: None,
+        }
+    }
+
+    /// L1 archives reject the L2 header fields, so they are requested only for L2 variants.
+    pub fn with_header_variant(mut self, header_variant: HeaderVariant) -> ArchiveDataSource {
+        self.header_variant = header_variant;
+        self
+    }
+
+    /// Archives don't report the chain they serve, so it's configured instead.
+    pub fn with_chain_id(mut self, chain_id: Option<u64>) -> ArchiveDataSource {
+        self.chain_id = chain_id;
+        self
     }
 }
 
@@ -32,6 +54,15 @@ impl DataSource for ArchiveDataSource {
         request: DataRequest,
         stop_on_head: bool,
     ) -> anyhow::Result<BlockStream> {
+        // archives don't trace the state, there are no diffs to take the changes from
+        if request.balance_changes || request.storage_changes {
+            return Err(FetchError::InvalidArgument(
+                "the archive serves no balance or storage changes".to_string(),
+            )
+            .into());
+        }
+        let l2 = (self.header_variant == HeaderVariant::Arbitrum).then_some(true);
+        let traces = request.traces;
         let mut fields = FieldSelection {
             block: Some(BlockFieldSelection {
                 base_fee_per_gas: true,
@@ -53,13 +84,17 @@ impl DataSource for ArchiveDataSource {
                 state_root: true,
                 timestamp: true,
                 transactions_root: true,
+                l1_block_number: l2,
+                send_root: l2,
+                blob_gas_used: true,
+                excess_blob_gas: true,
             }),
             log: None,
             transaction: None,
             trace: None,
         };
 
-        let logs = if request.logs.is_empty() {
+        let logs = if request.logs.is_empty() || request.headers_only {
             None
         } else {
             fields.log = Some(LogFieldSelection {
@@ -84,21 +119,26 @@ impl DataSource for ArchiveDataSource {
                 s: true,
                 hash: true,
                 status: true,
+                max_fee_per_blob_gas: true,
+                blob_versioned_hashes: true,
                 to: true,
                 transaction_index: true,
                 r#type: true,
                 v: true,
                 value: true,
                 y_parity: true,
+                chain_id: true,
             });
             fields.trace = Some(TraceFieldSelection {
                 transaction_index: true,
+                trace_address: true,
                 r#type: true,
                 error: true,
                 create_from: true,
                 create_value: true,
                 create_gas: true,
                 create_result_gas_used: true,
+                create_result_code: true,
                 create_result_address: true,
                 call_from: true,
                 call_to: true,
@@ -108,6 +148,9 @@ impl DataSource for ArchiveDataSource {
                 call_type: true,
                 call_result_gas_used: true,
                 call_result_output: true,
+                suicide_address: true,
+                suicide_refund_address: true,
+                suicide_balance: true,
             });
             let logs = request
                 .logs
@@ -115,14 +158,17 @@ impl DataSource for ArchiveDataSource {
                 .map(|r| LogRequest {
                     address: r.address,
                     topic0: r.topic0,
+                    topic1: r.topic1,
+                    topic2: r.topic2,
+                    topic3: r.topic3,
                     transaction: true,
-                    transaction_traces: true,
+                    transaction_traces: traces,
                 })
                 .collect();
             Some(logs)
         };
 
-        let transactions = if request.transactions.is_empty() {
+        let transactions = if request.transactions.is_empty() || request.headers_only {
             None
         } else {
             fields.transaction = Some(TxFieldSelection {
@@ -140,21 +186,26 @@ impl DataSource for ArchiveDataSource {
                 s: true,
                 hash: true,
                 status: true,
+                max_fee_per_blob_gas: true,
+                blob_versioned_hashes: true,
                 to: true,
                 transaction_index: true,
                 r#type: true,
                 v: true,
                 value: true,
                 y_parity: true,
+                chain_id: true,
             });
             fields.trace = Some(TraceFieldSelection {
                 transaction_index: true,
+                trace_address: true,
                 r#type: true,
                 error: true,
                 create_from: true,
                 create_value: true,
                 create_gas: true,
                 create_result_gas_used: true,
+                create_result_code: true,
                 create_result_address: true,
                 call_from: true,
                 call_to: true,
@@ -164,14 +215,18 @@ impl DataSource for ArchiveDataSource {
                 call_type: true,
                 call_result_gas_used: true,
                 call_result_output: true,
+                suicide_address: true,
+                suicide_refund_address: true,
+                suicide_balance: true,
             });
             let transactions = request
                 .transactions
                 .into_iter()
                 .map(|r| TxRequest {
-                    to: r.address,
+                    from: r.from,
+                    to: r.to,
                     sighash: r.sighash,
-                    traces: true,
+                    traces,
                 })
                 .collect();
             Some(transactions)
@@ -183,6 +238,7 @@ impl DataSource for ArchiveDataSource {
             fields: Some(fields),
             logs,
             transactions,
+            include_all_blocks: request.all_blocks.then_some(true),
         };
 
         let archive = self.archive.clone();
@@ -201,7 +257,7 @@ impl DataSource for ArchiveDataSource {
 
                 let blocks = archive.query(&req).await?;
                 let last_block_num = blocks[blocks.len() - 1].header.number;
-                let blocks = blocks.into_iter().map(|b| Block::from(b)).collect();
+                let blocks = blocks.into_iter().map(Block::from).collect();
 
                 yield blocks;
 
@@ -220,9 +276,80 @@ impl DataSource for ArchiveDataSource {
         self.archive.height().await
     }
 
-    async fn get_block_hash(&self, _height: u64) -> anyhow::Result<String> {
-        todo!()
+    /// Queries the header of the block, archives have no lookup of a single hash.
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        let request = DataRequest {
+            from: height,
+            to: Some(height),
+            logs: vec![],
+            transactions: vec![],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: false,
+            headers_only: true,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: true,
+        };
+        let mut stream = Pin::from(self.get_finalized_blocks(request, true)?);
+        while let Some(blocks) = stream.next().await {
+            if let Some(block) = blocks?.into_iter().find(|b| b.header.number == height) {
+                return Ok(block.header.hash);
+            }
+        }
+        anyhow::bail!("block №{} not found", height)
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        Ok(self.chain_id)
     }
+
+    /// Queries every cluster of nearby heights as one range and keeps the referenced blocks,
+    /// so far apart references don't stream the blocks between them.
+    async fn get_blocks_by_refs(
+        &self,
+        heights: &[u64],
+        request: DataRequest,
+    ) -> anyhow::Result<Vec<Option<Block>>> {
+        let referenced: BTreeSet<u64> = heights.iter().copied().collect();
+        let mut by_height = HashMap::new();
+        for (from, to) in ref_clusters(&referenced) {
+            let request = DataRequest {
+                from,
+                to: Some(to),
+                all_blocks: true,
+                ..request.clone()
+            };
+            let mut stream = Pin::from(self.get_finalized_blocks(request, true)?);
+            while let Some(blocks) = stream.next().await {
+                for block in blocks? {
+                    if referenced.contains(&block.header.number) {
+                        by_height.insert(block.header.number, block);
+                    }
+                }
+            }
+        }
+        Ok(heights
+            .iter()
+            .map(|height| by_height.get(height).cloned())
+            .collect())
+    }
+}
+
+/// Heights closer than this to the previous reference are queried along with it.
+const REF_CLUSTER_GAP: u64 = 10;
+
+/// Ranges covering the ascending `heights`, split wherever two of them are too far apart.
+fn ref_clusters(heights: &BTreeSet<u64>) -> Vec<(u64, u64)> {
+    let mut clusters: Vec<(u64, u64)> = vec![];
+    for &height in heights {
+        match clusters.last_mut() {
+            Some((_, to)) if height - *to <= REF_CLUSTER_GAP => *to = height,
+            _ => clusters.push((height, height)),
+        }
+    }
+    clusters
 }
 
 fn number_to_u64(value: Number) -> u64 {
@@ -237,11 +364,21 @@ fn number_to_u64(value: Number) -> u64 {
 
 impl From<archive::BlockHeader> for BlockHeader {
     fn from(value: archive::BlockHeader) -> Self {
+        let l2_extra = match (value.l1_block_number, value.send_root) {
+            (Some(l1_block_number), Some(send_root)) => Some(L2Extra {
+                l1_block_number: number_to_u64(l1_block_number),
+                send_root,
+            }),
+            _ => None,
+        };
         BlockHeader {
             number: value.number,
             hash: value.hash,
             parent_hash: value.parent_hash,
-            size: value.size,
+            size: value.size.map(|size| match size {
+                Quantity::Number(size) => format!("{:#x}", size),
+                Quantity::Hex(size) => size,
+            }),
             sha3_uncles: value.sha3_uncles,
             miner: value.miner,
             state_root: value.state_root,
@@ -257,6 +394,12 @@ impl From<archive::BlockHeader> for BlockHeader {
             mix_hash: value.mix_hash,
             nonce: value.nonce,
             base_fee_per_gas: value.base_fee_per_gas,
+            l2_extra,
+            blob_gas_used: value.blob_gas_used,
+            excess_blob_gas: value.excess_blob_gas,
+            withdrawals_root: None,
+            parent_beacon_root: None,
+            requests_hash: None,
         }
     }
 }
@@ -291,11 +434,30 @@ impl From<archive::Transaction> for Transaction {
             r: value.r,
             s: value.s,
             y_parity: value.y_parity,
+            chain_id: value.chain_id,
             gas_used: value.gas_used,
             cumulative_gas_used: value.cumulative_gas_used,
             effective_gas_price: value.effective_gas_price,
             r#type: value.r#type,
             status: value.status,
+            access_list: value
+                .access_list
+                .unwrap_or_default()
+                .into_iter()
+                .map(AccessTuple::from)
+                .collect(),
+            max_fee_per_blob_gas: value.max_fee_per_blob_gas,
+            blob_versioned_hashes: value.blob_versioned_hashes.unwrap_or_default(),
+            raw_receipt: None,
+        }
+    }
+}
+
+impl From<archive::AccessListItem> for AccessTuple {
+    fn from(value: archive::AccessListItem) -> Self {
+        AccessTuple {
+            address: value.address,
+            storage_keys: value.storage_keys,
         }
     }
 }
@@ -324,13 +486,14 @@ impl From<archive::CallType> for CallType {
 
 impl From<archive::TraceAction> for TraceAction {
     fn from(value: archive::TraceAction) -> Self {
+        // suicide traces name their fields differently
         TraceAction {
-            from: value.from,
-            to: value.to,
-            value: value.value,
+            from: value.from.or(value.address),
+            to: value.to.or(value.refund_address),
+            value: value.value.or(value.balance),
             gas: value.gas,
             input: value.input,
-            r#type: value.r#type.and_then(|r#type| Some(CallType::from(r#type))),
+            r#type: value.r#type.map(CallType::from),
         }
     }
 }
@@ -341,6 +504,7 @@ impl From<archive::TraceResult> for TraceResult {
             gas_used: value.gas_used,
             address: value.address,
             output: value.output,
+            code: value.code,
         }
     }
 }
@@ -349,15 +513,16 @@ impl From<archive::Trace> for Trace {
     fn from(value: archive::Trace) -> Self {
         Trace {
             transaction_index: value.transaction_index,
+            trace_address: value.trace_address,
             r#type: TraceType::from(value.r#type),
             error: value.error,
             revert_reason: value.revert_reason,
-            action: value
-                .action
-                .and_then(|action| Some(TraceAction::from(action))),
-            result: value
-                .result
-                .and_then(|result| Some(TraceResult::from(result))),
+            action: value.action.map(TraceAction::from),
+            result: value.result.map(TraceResult::from),
+            balance_changes: vec![],
+            storage_changes: vec![],
+            gas_changes: vec![],
+            keccak_preimages: HashMap::new(),
         }
     }
 }
@@ -370,20 +535,169 @@ impl From<archive::Block> for Block {
                 .logs
                 .unwrap_or_default()
                 .into_iter()
-                .map(|l| Log::from(l))
+                .map(Log::from)
                 .collect(),
             transactions: value
                 .transactions
                 .unwrap_or_default()
                 .into_iter()
-                .map(|t| Transaction::from(t))
+                .map(Transaction::from)
                 .collect(),
             traces: value
                 .traces
                 .unwrap_or_default()
                 .into_iter()
-                .map(|t| Trace::from(t))
+                .map(Trace::from)
                 .collect(),
+            uncles: vec![],
+            balance_changes: vec![],
+            raw: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::Archive;
+    use crate::datasource::{DataRequest, DataSource, LogRequest, TransactionRequest};
+    use crate::ds_archive::ArchiveDataSource;
+    use crate::firehose::FetchError;
+    use crate::testing::{hash, MockArchive};
+    use futures_util::StreamExt;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    fn request() -> DataRequest {
+        DataRequest {
+            from: 0,
+            to: None,
+            logs: vec![],
+            transactions: vec![TransactionRequest {
+                to: vec![],
+                sighash: vec![],
+                from: vec![],
+            }],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn block_hash() {
+        let archive = MockArchive::start(5);
+        let ds = ArchiveDataSource::new(Arc::new(Archive::new(archive.url())));
+
+        assert_eq!(ds.get_block_hash(3).await.unwrap(), hash(3));
+        assert!(ds.get_block_hash(6).await.is_err());
+        // only the header of the block is queried
+        let query = &archive.queries()[0];
+        assert_eq!(query["fromBlock"], 3);
+        assert_eq!(query["toBlock"], 3);
+        assert_eq!(query["includeAllBlocks"], true);
+        assert!(query.get("transactions").is_none() && query.get("logs").is_none());
+    }
+
+    #[tokio::test]
+    async fn blocks_by_refs() {
+        let archive = MockArchive::start(8);
+        let ds = ArchiveDataSource::new(Arc::new(Archive::new(archive.url())));
+
+        let blocks = ds
+            .get_blocks_by_refs(&[7, 2, 5, 9, 2], request())
+            .await
+            .unwrap();
+        let numbers: Vec<_> = blocks
+            .iter()
+            .map(|block| block.as_ref().map(|block| block.header.number))
+            .collect();
+        assert_eq!(numbers, vec![Some(7), Some(2), Some(5), None, Some(2)]);
+        // resolved by a single query of the whole range
+        let queries = archive.queries();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0]["fromBlock"], 2);
+        assert_eq!(queries[0]["toBlock"], 9);
+        assert_eq!(queries[0]["includeAllBlocks"], true);
+    }
+
+    #[tokio::test]
+    async fn distant_refs_are_queried_apart() {
+        let archive = MockArchive::start(20_000);
+        let ds = ArchiveDataSource::new(Arc::new(Archive::new(archive.url())));
+
+        let blocks = ds
+            .get_blocks_by_refs(&[18_000, 0, 18_003], request())
+            .await
+            .unwrap();
+        assert!(blocks.iter().all(Option::is_some));
+        // only the clusters of references are asked for, not the blocks between them
+        let ranges: Vec<_> = archive
+            .queries()
+            .iter()
+            .map(|query| (query["fromBlock"].as_u64(), query["toBlock"].as_u64()))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![(Some(0), Some(0)), (Some(18_000), Some(18_003))]
+        );
+        let asked: u64 = ranges
+            .iter()
+            .map(|(from, to)| to.unwrap() - from.unwrap() + 1)
+            .sum();
+        assert_eq!(asked, 5);
+    }
+
+    #[tokio::test]
+    async fn state_changes_are_rejected() {
+        let archive = MockArchive::start(8);
+        let ds = ArchiveDataSource::new(Arc::new(Archive::new(archive.url())));
+
+        for (balance_changes, storage_changes) in [(true, false), (false, true)] {
+            let request = DataRequest {
+                balance_changes,
+                storage_changes,
+                ..request()
+            };
+            let err = ds.get_finalized_blocks(request, true).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<FetchError>(),
+                Some(FetchError::InvalidArgument(_))
+            ));
         }
+        assert!(archive.queries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn headers_only_selects_no_bodies() {
+        let archive = MockArchive::start(8);
+        let ds = ArchiveDataSource::new(Arc::new(Archive::new(archive.url())));
+
+        let request = DataRequest {
+            from: 2,
+            to: Some(4),
+            logs: vec![LogRequest {
+                address: vec![],
+                topic0: vec![],
+                topic1: vec![],
+                topic2: vec![],
+                topic3: vec![],
+            }],
+            headers_only: true,
+            ..request()
+        };
+        let stream = Pin::from(ds.get_finalized_blocks(request, true).unwrap());
+        let blocks: Vec<_> = stream.map(|batch| batch.unwrap()).concat().await;
+        let numbers: Vec<_> = blocks.iter().map(|block| block.header.number).collect();
+        assert_eq!(numbers, vec![2, 3, 4]);
+
+        let query = &archive.queries()[0];
+        assert!(query.get("transactions").is_none() && query.get("logs").is_none());
+        let fields = query["fields"].as_object().unwrap();
+        assert_eq!(fields.keys().collect::<Vec<_>>(), vec!["block"]);
     }
 }
@@ -0,0 +1,321 @@
+use crate::datasource::{
+    Block, BlockStream, BlockTag, DataRequest, DataSource, HashAndHeight, HotBlockStream,
+    HotDataSource, HotSource,
+};
+use crate::firehose::FetchError;
+use crate::metrics::Metrics;
+use crate::retry::is_transient;
+use futures_util::StreamExt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The cooldown is over, a single call probes whether the upstream recovered
+    /// while the others fail fast. Another probe goes through if it didn't finish by `until`.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+impl BreakerState {
+    /// Exported as a gauge, 0 when closed, 1 when half-open and 2 when open.
+    fn level(&self) -> i64 {
+        match self {
+            BreakerState::Closed { .. } => 0,
+            BreakerState::HalfOpen { .. } => 1,
+            BreakerState::Open { .. } => 2,
+        }
+    }
+}
+
+/// Stops calling an upstream after `max_failures` transient failures in a row,
+/// calls fail fast as unavailable until `cooldown` is over.
+/// Errors the upstream answered with, like missing blocks, don't count as failures.
+pub struct CircuitBreaker {
+    source: &'static str,
+    max_failures: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    metrics: Arc<Metrics>,
+}
+
+impl CircuitBreaker {
+    /// `source` names the upstream in errors and metrics.
+    pub fn new(
+        source: &'static str,
+        max_failures: u32,
+        cooldown: Duration,
+        metrics: Arc<Metrics>,
+    ) -> CircuitBreaker {
+        let state = BreakerState::Closed { failures: 0 };
+        metrics.set_breaker_state(source, state.level());
+        CircuitBreaker {
+            source,
+            max_failures: max_failures.max(1),
+            cooldown,
+            state: Mutex::new(state),
+            metrics,
+        }
+    }
+
+    fn set(&self, state: &mut BreakerState, next: BreakerState) {
+        if state.level() != next.level() {
+            self.metrics.set_breaker_state(self.source, next.level());
+        }
+        *state = next;
+    }
+
+    /// Fails while the breaker is open, the first call after the cooldown goes through as a probe
+    /// and the others fail until its result is recorded.
+    fn check(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let (BreakerState::Open { until } | BreakerState::HalfOpen { until }) = *state else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < until {
+            self.metrics.short_circuited(self.source);
+            let message = match *state {
+                BreakerState::Open { .. } => format!(
+                    "{} is failing, calls to it resume in {:?}",
+                    self.source,
+                    until - now
+                ),
+                _ => format!(
+                    "{} is failing, a call probes whether it recovered",
+                    self.source
+                ),
+            };
+            return Err(FetchError::Unavailable(message).into());
+        }
+        let probe = BreakerState::HalfOpen {
+            until: now + self.cooldown,
+        };
+        self.set(&mut state, probe);
+        Ok(())
+    }
+
+    fn record<T>(&self, result: &anyhow::Result<T>) {
+        let mut state = self.state.lock().unwrap();
+        let failed = result.as_ref().err().is_some_and(is_transient);
+        let next = match *state {
+            _ if !failed => {
+                if matches!(*state, BreakerState::HalfOpen { .. }) {
+                    info!("{} recovered, closing its circuit breaker", self.source);
+                }
+                BreakerState::Closed { failures: 0 }
+            }
+            BreakerState::Closed { failures } if failures + 1 < self.max_failures => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            // calls started before the breaker opened don't extend the cooldown
+            BreakerState::Open { until } => BreakerState::Open { until },
+            BreakerState::Closed { .. } | BreakerState::HalfOpen { .. } => {
+                warn!(
+                    "{} keeps failing, short-circuiting calls to it for {:?}",
+                    self.source, self.cooldown
+                );
+                BreakerState::Open {
+                    until: Instant::now() + self.cooldown,
+                }
+            }
+        };
+        self.set(&mut state, next);
+    }
+
+    async fn call<T>(
+        &self,
+        future: impl std::future::Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        self.check()?;
+        let result = future.await;
+        self.record(&result);
+        result
+    }
+}
+
+/// Guards every call to `inner` and every item of its streams with the circuit breaker.
+pub struct BreakerDataSource<S: ?Sized> {
+    inner: Arc<S>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<S: ?Sized> BreakerDataSource<S> {
+    pub fn new(inner: Arc<S>, breaker: CircuitBreaker) -> BreakerDataSource<S> {
+        BreakerDataSource {
+            inner,
+            breaker: Arc::new(breaker),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: DataSource + Send + Sync + ?Sized> DataSource for BreakerDataSource<S> {
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        self.breaker.check()?;
+        let breaker = self.breaker.clone();
+        let stream = self.inner.get_finalized_blocks(request, stop_on_head)?;
+        Ok(Box::new(
+            Pin::from(stream).inspect(move |result| breaker.record(result)),
+        ))
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        self.breaker.call(self.inner.get_finalized_height()).await
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        self.breaker.call(self.inner.get_block_hash(height)).await
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        self.breaker.call(self.inner.get_first_block()).await
+    }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.breaker.call(self.inner.get_head_height()).await
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        self.breaker.call(self.inner.get_chain_id()).await
+    }
+
+    async fn get_blocks_by_refs(
+        &self,
+        heights: &[u64],
+        request: DataRequest,
+    ) -> anyhow::Result<Vec<Option<Block>>> {
+        self.breaker
+            .call(self.inner.get_blocks_by_refs(heights, request))
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: HotDataSource + Send + Sync + ?Sized> HotSource for BreakerDataSource<S> {
+    fn get_hot_blocks(
+        &self,
+        request: DataRequest,
+        state: HashAndHeight,
+    ) -> anyhow::Result<HotBlockStream> {
+        self.breaker.check()?;
+        let breaker = self.breaker.clone();
+        let stream = self.inner.get_hot_blocks(request, state)?;
+        Ok(Box::new(
+            Pin::from(stream).inspect(move |result| breaker.record(result)),
+        ))
+    }
+
+    fn as_ds(&self) -> &(dyn DataSource + Send + Sync) {
+        self
+    }
+
+    async fn get_block_hashes(&self, from: u64, to: u64) -> anyhow::Result<Vec<String>> {
+        self.breaker
+            .call(self.inner.get_block_hashes(from, to))
+            .await
+    }
+
+    async fn get_tagged_height(&self, tag: BlockTag) -> anyhow::Result<u64> {
+        self.breaker.call(self.inner.get_tagged_height(tag)).await
+    }
+}
+
+impl<S: HotDataSource + Send + Sync + ?Sized> HotDataSource for BreakerDataSource<S> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::DataSource;
+    use crate::ds_breaker::{BreakerDataSource, BreakerState, CircuitBreaker};
+    use crate::firehose::FetchError;
+    use crate::metrics::Metrics;
+    use crate::testing::MockDataSource;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn trips_and_resets() {
+        let metrics = Arc::new(Metrics::new());
+        let breaker = CircuitBreaker::new("rpc", 2, Duration::from_millis(50), metrics.clone());
+        let reset = || std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let upstream = Arc::new(MockDataSource::new(9));
+        let ds = BreakerDataSource::new(upstream, breaker);
+
+        let failure = ds.breaker.call(async { Err::<(), _>(reset().into()) });
+        assert!(failure.await.is_err());
+        assert_eq!(
+            *ds.breaker.state.lock().unwrap(),
+            BreakerState::Closed { failures: 1 }
+        );
+        // an answered request isn't a failure of the upstream
+        let not_found = ds.get_block_hash(10).await.unwrap_err();
+        assert!(not_found.downcast_ref::<FetchError>().is_none());
+        assert_eq!(
+            *ds.breaker.state.lock().unwrap(),
+            BreakerState::Closed { failures: 0 }
+        );
+
+        for _ in 0..2 {
+            let failure = ds.breaker.call(async { Err::<(), _>(reset().into()) });
+            assert!(failure.await.is_err());
+        }
+        assert!(matches!(
+            *ds.breaker.state.lock().unwrap(),
+            BreakerState::Open { .. }
+        ));
+        let err = ds.get_finalized_height().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FetchError>(),
+            Some(FetchError::Unavailable(_))
+        ));
+        assert!(ds.get_block_hash(1).await.is_err());
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("firehose_circuit_breaker_state{source=\"rpc\"} 2"));
+        assert!(text.contains("firehose_short_circuited_calls_total{source=\"rpc\"} 2"));
+
+        // a failed probe opens the breaker again, a successful one closes it
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let failure = ds.breaker.call(async { Err::<(), _>(reset().into()) });
+        assert!(failure.await.is_err());
+        assert!(ds.get_finalized_height().await.is_err());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (probe_tx, probe_rx) = tokio::sync::oneshot::channel::<()>();
+        let probe = ds.breaker.call(async {
+            probe_rx.await.unwrap();
+            ds.inner.get_finalized_height().await
+        });
+        let others = async {
+            // calls during the probe fail fast
+            let err = ds.get_block_hash(1).await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<FetchError>(),
+                Some(FetchError::Unavailable(_))
+            ));
+            probe_tx.send(()).unwrap();
+        };
+        let (probed, ()) = tokio::join!(probe, others);
+        assert_eq!(probed.unwrap(), 9);
+        assert_eq!(ds.get_block_hash(1).await.unwrap(), crate::testing::hash(1));
+        assert_eq!(
+            *ds.breaker.state.lock().unwrap(),
+            BreakerState::Closed { failures: 0 }
+        );
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("firehose_circuit_breaker_state{source=\"rpc\"} 0"));
+    }
+}
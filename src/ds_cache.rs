@@ -0,0 +1,695 @@
+use crate::datasource::{Block, BlockStream, DataRequest, DataSource};
+use anyhow::Context;
+use async_stream::try_stream;
+use futures_util::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Request filters the block was fetched with and its height.
+type BlockKey = (usize, u64);
+
+/// Drops the least recently used entries beyond `capacity`.
+struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: BTreeMap<K, (u64, V)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Ord + Clone, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (tick, value) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+        *tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    fn keys(&self, range: impl RangeBounds<K>) -> impl Iterator<Item = &K> {
+        self.entries.range(range).map(|(key, _)| key)
+    }
+
+    /// Returns the dropped entries, including the one replaced by `value`.
+    fn insert(&mut self, key: K, value: V) -> Vec<(K, V)> {
+        let mut dropped: Vec<_> = self
+            .remove(&key)
+            .map(|replaced| (key.clone(), replaced))
+            .into_iter()
+            .collect();
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, value));
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("every entry is ordered");
+            let (_, value) = self.entries.remove(&oldest).unwrap();
+            dropped.push((oldest, value));
+        }
+        dropped
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (tick, value) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+}
+
+#[derive(Clone)]
+struct Stored {
+    file: u64,
+    hash: String,
+}
+
+enum Cached {
+    Loaded(Arc<Block>),
+    Stored(u64),
+}
+
+struct Index {
+    filters: HashMap<String, usize>,
+    /// Start -> end of the ranges whose blocks are all cached,
+    /// heights without a stored block have no block matching the filters.
+    covered: BTreeMap<BlockKey, u64>,
+    stored: Lru<BlockKey, Stored>,
+    /// Blocks read back from disk
+    loaded: Lru<BlockKey, Arc<Block>>,
+}
+
+impl Index {
+    fn filter(&mut self, request: &DataRequest) -> usize {
+        let next = self.filters.len();
+        *self.filters.entry(request.filter_key()).or_insert(next)
+    }
+
+    fn covering(&self, filter: usize, height: u64) -> Option<(u64, u64)> {
+        let (&(range_filter, start), &end) = self.covered.range(..=(filter, height)).next_back()?;
+        (range_filter == filter && end >= height).then_some((start, end))
+    }
+
+    /// Start of the first covered range after `height`.
+    fn next_covered(&self, filter: usize, height: u64) -> Option<u64> {
+        self.covered
+            .range((filter, height)..=(filter, u64::MAX))
+            .next()
+            .map(|(&(_, start), _)| start)
+    }
+
+    fn cover(&mut self, filter: usize, mut from: u64, mut to: u64) {
+        let touching: Vec<_> = self
+            .covered
+            .range((filter, 0)..=(filter, to.saturating_add(1)))
+            .rev()
+            .take_while(|(_, end)| end.saturating_add(1) >= from)
+            .map(|(&(_, start), &end)| (start, end))
+            .collect();
+        for (start, end) in touching {
+            self.covered.remove(&(filter, start));
+            from = from.min(start);
+            to = to.max(end);
+        }
+        self.covered.insert((filter, from), to);
+    }
+
+    fn uncover(&mut self, filter: usize, from: u64, to: u64) {
+        let overlapping: Vec<_> = self
+            .covered
+            .range((filter, 0)..=(filter, to))
+            .rev()
+            .take_while(|(_, &end)| end >= from)
+            .map(|(&(_, start), &end)| (start, end))
+            .collect();
+        for (start, end) in overlapping {
+            self.covered.remove(&(filter, start));
+            if start < from {
+                self.covered.insert((filter, start), from - 1);
+            }
+            if end > to {
+                self.covered.insert((filter, to + 1), end);
+            }
+        }
+    }
+
+    fn get(&mut self, key: BlockKey) -> Option<Cached> {
+        let stored = self.stored.get(&key)?;
+        Some(match self.loaded.get(&key) {
+            Some(block) => Cached::Loaded(block),
+            None => Cached::Stored(stored.file),
+        })
+    }
+
+    /// Cached blocks from `from` up to `to` and the end of the range if `from` is covered.
+    fn lookup(&mut self, filter: usize, from: u64, to: u64) -> Option<(u64, Vec<(u64, Cached)>)> {
+        let (_, end) = self.covering(filter, from)?;
+        let end = end.min(to);
+        let heights: Vec<_> = self
+            .stored
+            .keys((filter, from)..=(filter, end))
+            .map(|&(_, height)| height)
+            .collect();
+        let blocks = heights
+            .into_iter()
+            .filter_map(|height| Some((height, self.get((filter, height))?)))
+            .collect();
+        Some((end, blocks))
+    }
+
+    /// Covers `from..=last` with `blocks`, returns the files of the dropped blocks.
+    fn store(
+        &mut self,
+        filter: usize,
+        from: u64,
+        last: u64,
+        blocks: Vec<(u64, Stored)>,
+    ) -> Vec<u64> {
+        self.cover(filter, from, last);
+        let mut files = vec![];
+        for (height, stored) in blocks {
+            let key = (filter, height);
+            for (dropped, stored) in self.stored.insert(key, stored) {
+                if dropped != key {
+                    self.uncover(dropped.0, dropped.1, dropped.1);
+                }
+                self.loaded.remove(&dropped);
+                files.push(stored.file);
+            }
+        }
+        files
+    }
+
+    /// Drops the blocks from `from` up to `to`, returns their files.
+    fn forget(&mut self, filter: usize, from: u64, to: u64) -> Vec<u64> {
+        let keys: Vec<_> = self
+            .stored
+            .keys((filter, from)..=(filter, to))
+            .copied()
+            .collect();
+        self.uncover(filter, from, to);
+        keys.into_iter()
+            .filter_map(|key| {
+                self.loaded.remove(&key);
+                Some(self.stored.remove(&key)?.file)
+            })
+            .collect()
+    }
+}
+
+struct BlockCache {
+    dir: PathBuf,
+    next_file: AtomicU64,
+    index: Mutex<Index>,
+}
+
+impl BlockCache {
+    fn path(&self, file: u64) -> PathBuf {
+        self.dir.join(file.to_string())
+    }
+
+    async fn load(&self, key: BlockKey, cached: Cached) -> anyhow::Result<Block> {
+        let file = match cached {
+            Cached::Loaded(block) => return Ok(Block::clone(&block)),
+            Cached::Stored(file) => file,
+        };
+        let bytes = tokio::fs::read(self.path(file)).await?;
+        let block: Block = serde_json::from_slice(&bytes)?;
+        let mut index = self.index.lock().unwrap();
+        if index.stored.peek(&key).is_some() {
+            index.loaded.insert(key, Arc::new(block.clone()));
+        }
+        Ok(block)
+    }
+
+    async fn store(&self, filter: usize, from: u64, blocks: &[Block]) -> anyhow::Result<()> {
+        let mut stored = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            let file = self.next_file.fetch_add(1, Ordering::Relaxed);
+            tokio::fs::write(self.path(file), serde_json::to_vec(block)?).await?;
+            stored.push((
+                block.header.number,
+                Stored {
+                    file,
+                    hash: block.header.hash.clone(),
+                },
+            ));
+        }
+        let Some(&(last, _)) = stored.last() else {
+            return Ok(());
+        };
+        let dropped = self.index.lock().unwrap().store(filter, from, last, stored);
+        self.remove(dropped).await;
+        Ok(())
+    }
+
+    async fn remove(&self, files: Vec<u64>) {
+        for file in files {
+            tokio::fs::remove_file(self.path(file)).await.ok();
+        }
+    }
+
+    /// Loads blocks up to the first unreadable one, whose height is returned along.
+    async fn load_all(
+        &self,
+        filter: usize,
+        cached: Vec<(u64, Cached)>,
+    ) -> (Vec<Block>, Option<u64>) {
+        let mut blocks = Vec::with_capacity(cached.len());
+        for (height, cached) in cached {
+            match self.load((filter, height), cached).await {
+                Ok(block) => blocks.push(block),
+                Err(e) => {
+                    warn!(
+                        "cached block №{} is unreadable, fetching it again: {:#}",
+                        height, e
+                    );
+                    return (blocks, Some(height));
+                }
+            }
+        }
+        (blocks, None)
+    }
+
+    async fn forget(&self, filter: usize, from: u64, to: u64) {
+        let files = self.index.lock().unwrap().forget(filter, from, to);
+        self.remove(files).await;
+    }
+}
+
+/// Keeps finalized blocks in files of a directory, bounded by a number of blocks
+/// and evicting the least recently used ones, so repeated ranges and single-block requests
+/// don't hit the wrapped source. Blocks read back from disk are also kept in memory.
+/// Only finalized blocks pass through a `DataSource`, hot blocks are never cached.
+pub struct CachingDataSource {
+    inner: Arc<dyn DataSource + Send + Sync>,
+    cache: Arc<BlockCache>,
+}
+
+impl CachingDataSource {
+    /// Keeps up to `capacity` blocks in the `blocks` directory of `dir`, left over blocks are removed.
+    pub fn new(
+        inner: Arc<dyn DataSource + Send + Sync>,
+        dir: PathBuf,
+        capacity: usize,
+    ) -> anyhow::Result<CachingDataSource> {
+        let dir = dir.join("blocks");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("failed to clear {}", dir.display()))?;
+        }
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let index = Index {
+            filters: HashMap::new(),
+            covered: BTreeMap::new(),
+            stored: Lru::new(capacity),
+            loaded: Lru::new(0),
+        };
+        Ok(CachingDataSource {
+            inner,
+            cache: Arc::new(BlockCache {
+                dir,
+                next_file: AtomicU64::new(0),
+                index: Mutex::new(index),
+            }),
+        })
+    }
+
+    /// Number of blocks read back from disk that are kept in memory.
+    pub fn with_memory_capacity(self, capacity: usize) -> CachingDataSource {
+        self.cache.index.lock().unwrap().loaded = Lru::new(capacity);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl DataSource for CachingDataSource {
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        let inner = self.inner.clone();
+        let cache = self.cache.clone();
+        let filter = cache.index.lock().unwrap().filter(&request);
+
+        Ok(Box::new(try_stream! {
+            let to = request.to.unwrap_or(u64::MAX);
+            let mut from = request.from;
+            while from <= to {
+                let cached = cache.index.lock().unwrap().lookup(filter, from, to);
+                if let Some((end, cached)) = cached {
+                    let (blocks, unreadable) = cache.load_all(filter, cached).await;
+                    if !blocks.is_empty() {
+                        yield blocks;
+                    }
+                    from = end + 1;
+                    // the rest of the range is fetched again
+                    if let Some(height) = unreadable {
+                        cache.forget(filter, height, end).await;
+                        from = height;
+                    }
+                    continue
+                }
+
+                // stream from the wrapped source until the next cached range
+                let next = cache.index.lock().unwrap().next_covered(filter, from);
+                let req = DataRequest {
+                    from,
+                    to: next.map_or(request.to, |next| Some(to.min(next - 1))),
+                    ..request.clone()
+                };
+                let mut stream = Pin::from(inner.get_finalized_blocks(req, stop_on_head)?);
+                while let Some(result) = stream.next().await {
+                    let blocks = result?;
+                    let Some(last) = blocks.last().map(|block| block.header.number) else {
+                        continue
+                    };
+                    if let Err(e) = cache.store(filter, from, &blocks).await {
+                        warn!("failed to cache blocks №{}-{}: {:#}", from, last, e);
+                    }
+                    yield blocks;
+                    from = last + 1;
+                }
+
+                match next {
+                    Some(next) => from = next,
+                    None => break,
+                }
+            }
+        }))
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        self.inner.get_finalized_height().await
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        let hash = {
+            let index = self.cache.index.lock().unwrap();
+            index.filters.values().find_map(|&filter| {
+                let stored = index.stored.peek(&(filter, height))?;
+                Some(stored.hash.clone())
+            })
+        };
+        match hash {
+            Some(hash) => Ok(hash),
+            None => self.inner.get_block_hash(height).await,
+        }
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        self.inner.get_first_block().await
+    }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.inner.get_head_height().await
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        self.inner.get_chain_id().await
+    }
+
+    /// Served from the cache when every block is cached, by the wrapped source otherwise.
+    async fn get_blocks_by_refs(
+        &self,
+        heights: &[u64],
+        request: DataRequest,
+    ) -> anyhow::Result<Vec<Option<Block>>> {
+        let (filter, cached) = {
+            let mut index = self.cache.index.lock().unwrap();
+            let filter = index.filter(&request);
+            let cached: Option<Vec<_>> = heights
+                .iter()
+                .map(|&height| Some((height, index.get((filter, height))?)))
+                .collect();
+            (filter, cached)
+        };
+        if let Some(cached) = cached {
+            match self.cache.load_all(filter, cached).await {
+                (blocks, None) => return Ok(blocks.into_iter().map(Some).collect()),
+                (_, Some(height)) => self.cache.forget(filter, height, height).await,
+            }
+        }
+        self.inner.get_blocks_by_refs(heights, request).await
+    }
+}
+
+/// Shares the finalized height of the wrapped source between all streams for `ttl`,
+/// concurrent callers wait for a single upstream call instead of issuing their own.
+pub struct HeightCachingDataSource {
+    inner: Arc<dyn DataSource + Send + Sync>,
+    ttl: Duration,
+    height: tokio::sync::Mutex<Option<(Instant, u64)>>,
+}
+
+impl HeightCachingDataSource {
+    pub fn new(inner: Arc<dyn DataSource + Send + Sync>, ttl: Duration) -> HeightCachingDataSource {
+        HeightCachingDataSource {
+            inner,
+            ttl,
+            height: tokio::sync::Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DataSource for HeightCachingDataSource {
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        self.inner.get_finalized_blocks(request, stop_on_head)
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        // held across the upstream call so concurrent callers share its result
+        let mut cached = self.height.lock().await;
+        if let Some((fetched_at, height)) = *cached {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(height);
+            }
+        }
+        let mut height = self.inner.get_finalized_height().await?;
+        // a lagging upstream replica must not move the finalized head backwards
+        if let Some((_, previous)) = *cached {
+            height = height.max(previous);
+        }
+        *cached = Some((Instant::now(), height));
+        Ok(height)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        self.inner.get_block_hash(height).await
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        self.inner.get_first_block().await
+    }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.inner.get_head_height().await
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        self.inner.get_chain_id().await
+    }
+
+    async fn get_blocks_by_refs(
+        &self,
+        heights: &[u64],
+        request: DataRequest,
+    ) -> anyhow::Result<Vec<Option<Block>>> {
+        self.inner.get_blocks_by_refs(heights, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::{Block, DataRequest, DataSource};
+    use crate::ds_cache::{CachingDataSource, HeightCachingDataSource};
+    use crate::testing::MockDataSource;
+    use futures_util::StreamExt;
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn request(from: u64, to: u64) -> DataRequest {
+        DataRequest {
+            from,
+            to: Some(to),
+            logs: vec![],
+            transactions: vec![],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        }
+    }
+
+    async fn heights(ds: &CachingDataSource, request: DataRequest) -> Vec<u64> {
+        let stream = Pin::from(ds.get_finalized_blocks(request, true).unwrap());
+        let batches: Vec<Vec<Block>> = stream.map(|result| result.unwrap()).collect().await;
+        batches
+            .into_iter()
+            .flatten()
+            .map(|block| block.header.number)
+            .collect()
+    }
+
+    fn cache_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("block-cache-{}-{}", std::process::id(), name))
+    }
+
+    fn setup(
+        name: &str,
+        batch_size: usize,
+        capacity: usize,
+    ) -> (Arc<MockDataSource>, CachingDataSource) {
+        let upstream = Arc::new(MockDataSource::new(100).with_batch_size(batch_size));
+        let ds = CachingDataSource::new(upstream.clone(), cache_dir(name), capacity).unwrap();
+        (upstream, ds)
+    }
+
+    #[tokio::test]
+    async fn repeated_range_hits_cache() {
+        let (upstream, ds) = setup("repeated", 3, 100);
+        let ds = ds.with_memory_capacity(5);
+        let expected: Vec<u64> = (0..=9).collect();
+
+        for _ in 0..5 {
+            assert_eq!(heights(&ds, request(0, 9)).await, expected);
+        }
+        assert_eq!(upstream.requests().len(), 1);
+
+        // single blocks are served from the range covering them
+        assert_eq!(heights(&ds, request(4, 4)).await, vec![4]);
+        let blocks = ds.get_blocks_by_refs(&[7, 2], request(0, 0)).await.unwrap();
+        let numbers: Vec<_> = blocks
+            .iter()
+            .map(|b| b.as_ref().unwrap().header.number)
+            .collect();
+        assert_eq!(numbers, vec![7, 2]);
+        assert_eq!(ds.get_block_hash(5).await.unwrap(), crate::testing::hash(5));
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_miss_falls_back_to_upstream() {
+        let (upstream, ds) = setup("miss", 3, 100);
+
+        assert_eq!(
+            heights(&ds, request(0, 5)).await,
+            (0..=5).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            heights(&ds, request(0, 9)).await,
+            (0..=9).collect::<Vec<_>>()
+        );
+        // only the blocks after the cached ones are fetched
+        assert_eq!(upstream.requests(), vec![(0, Some(5)), (6, Some(9))]);
+
+        // a gap between cached ranges is fetched up to the next cached block
+        heights(&ds, request(20, 29)).await;
+        assert_eq!(
+            heights(&ds, request(5, 24)).await,
+            (5..=24).collect::<Vec<_>>()
+        );
+        assert_eq!(upstream.requests()[3], (10, Some(19)));
+        assert_eq!(upstream.requests().len(), 4);
+
+        // different filters are cached separately
+        let mut with_balances = request(0, 9);
+        with_balances.balance_changes = true;
+        heights(&ds, with_balances).await;
+        assert_eq!(upstream.requests().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_block_is_evicted() {
+        let (upstream, ds) = setup("evicted", 2, 4);
+        let calls = || upstream.requests().len();
+
+        heights(&ds, request(0, 3)).await;
+        heights(&ds, request(10, 11)).await;
+        assert_eq!(calls(), 2);
+
+        // 0..=1 got evicted, 2..=3 is still cached
+        heights(&ds, request(2, 3)).await;
+        assert_eq!(calls(), 2);
+        heights(&ds, request(0, 1)).await;
+        assert_eq!(calls(), 3);
+
+        // refetching 0..=1 evicted 10..=11
+        heights(&ds, request(10, 11)).await;
+        assert_eq!(calls(), 4);
+
+        // the files of evicted blocks are removed
+        let files = std::fs::read_dir(cache_dir("evicted").join("blocks")).unwrap();
+        assert_eq!(files.count(), 4);
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_fetched_again() {
+        let (upstream, ds) = setup("missing", 10, 100);
+        heights(&ds, request(0, 9)).await;
+
+        let dir = cache_dir("missing").join("blocks");
+        for file in std::fs::read_dir(&dir).unwrap() {
+            std::fs::remove_file(file.unwrap().path()).unwrap();
+        }
+        assert_eq!(
+            heights(&ds, request(0, 9)).await,
+            (0..=9).collect::<Vec<_>>()
+        );
+        assert_eq!(upstream.requests(), vec![(0, Some(9)), (0, Some(9))]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_height_requests_share_one_call() {
+        let upstream = Arc::new(MockDataSource::new(100));
+        let ds = HeightCachingDataSource::new(upstream.clone(), Duration::from_millis(200));
+
+        let heights =
+            futures_util::future::join_all((0..10).map(|_| ds.get_finalized_height())).await;
+        assert!(heights.into_iter().all(|height| height.unwrap() == 100));
+        assert_eq!(upstream.height_calls(), 1);
+
+        upstream.set_height(90);
+        assert_eq!(ds.get_finalized_height().await.unwrap(), 100);
+        assert_eq!(upstream.height_calls(), 1);
+
+        // once expired the upstream is asked again, but the height never goes backwards
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(ds.get_finalized_height().await.unwrap(), 100);
+        assert_eq!(upstream.height_calls(), 2);
+
+        upstream.set_height(105);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(ds.get_finalized_height().await.unwrap(), 105);
+        assert_eq!(upstream.height_calls(), 3);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::datasource::{Block, BlockStream, DataRequest, DataSource};
+use async_stream::try_stream;
+use futures_util::StreamExt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Serves blocks of the first source that works out of an ordered list of redundant sources.
+/// A stream failing mid-range resumes on the next source after the last delivered block.
+pub struct FailoverDataSource {
+    sources: Vec<Arc<dyn DataSource + Send + Sync>>,
+    race: bool,
+}
+
+impl FailoverDataSource {
+    pub fn new(sources: Vec<Arc<dyn DataSource + Send + Sync>>) -> FailoverDataSource {
+        assert!(!sources.is_empty(), "failover requires at least one source");
+        FailoverDataSource {
+            sources,
+            race: false,
+        }
+    }
+
+    /// Sends calls other than block streams to every source at once and takes the first answer,
+    /// trading upstream load for latency.
+    pub fn with_racing(mut self, race: bool) -> FailoverDataSource {
+        self.race = race;
+        self
+    }
+
+    async fn call<T, F, Fut>(&self, call: F) -> anyhow::Result<T>
+    where
+        F: Fn(Arc<dyn DataSource + Send + Sync>) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+    {
+        if self.race {
+            let calls = self.sources.iter().map(|ds| Box::pin(call(ds.clone())));
+            let (result, _) = futures_util::future::select_ok(calls).await?;
+            return Ok(result);
+        }
+        let mut failure = None;
+        for (index, ds) in self.sources.iter().enumerate() {
+            match call(ds.clone()).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    warn!(
+                        "archive source #{} failed, trying the next one: {}",
+                        index, e
+                    );
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap())
+    }
+}
+
+#[async_trait::async_trait]
+impl DataSource for FailoverDataSource {
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        let sources = self.sources.clone();
+
+        Ok(Box::new(try_stream! {
+            let mut from = request.from;
+            for (index, ds) in sources.iter().enumerate() {
+                let req = DataRequest {
+                    from,
+                    ..request.clone()
+                };
+                let mut failure = None;
+                match ds.get_finalized_blocks(req, stop_on_head) {
+                    Ok(stream) => {
+                        let mut stream = Pin::from(stream);
+                        while let Some(result) = stream.next().await {
+                            match result {
+                                Ok(blocks) => {
+                                    if let Some(last) = blocks.last() {
+                                        from = last.header.number + 1;
+                                    }
+                                    yield blocks;
+                                }
+                                Err(e) => {
+                                    failure = Some(e);
+                                    break
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => failure = Some(e),
+                }
+
+                let Some(e) = failure else {
+                    break
+                };
+                if request.to.is_some_and(|to| from > to) {
+                    break
+                } else if index + 1 == sources.len() {
+                    Err(e)?;
+                } else {
+                    warn!(
+                        "archive source #{} failed at block №{}, resuming on the next one: {}",
+                        index, from, e
+                    );
+                }
+            }
+        }))
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        self.call(|ds| async move { ds.get_finalized_height().await })
+            .await
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        self.call(|ds| async move { ds.get_block_hash(height).await })
+            .await
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        self.call(|ds| async move { ds.get_first_block().await })
+            .await
+    }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.call(|ds| async move { ds.get_head_height().await })
+            .await
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        self.call(|ds| async move { ds.get_chain_id().await }).await
+    }
+
+    async fn get_blocks_by_refs(
+        &self,
+        heights: &[u64],
+        request: DataRequest,
+    ) -> anyhow::Result<Vec<Option<Block>>> {
+        self.call(|ds| {
+            let request = request.clone();
+            async move { ds.get_blocks_by_refs(heights, request).await }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::{DataRequest, DataSource};
+    use crate::ds_failover::FailoverDataSource;
+    use crate::testing::MockDataSource;
+    use futures_util::StreamExt;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    fn request(from: u64, to: u64) -> DataRequest {
+        DataRequest {
+            from,
+            to: Some(to),
+            logs: vec![],
+            transactions: vec![],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: false,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn secondary_serves_after_primary_fails() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let primary = Arc::new(MockDataSource::new(5).with_failure(4, reset.into()));
+        let secondary = Arc::new(MockDataSource::new(9));
+        let ds = FailoverDataSource::new(vec![primary.clone(), secondary]);
+
+        let stream = Pin::from(ds.get_finalized_blocks(request(2, 8), true).unwrap());
+        let batches: Vec<_> = stream.map(|batch| batch.unwrap()).collect().await;
+        assert!(primary.failure_delivered());
+        let numbers: Vec<u64> = batches
+            .iter()
+            .flatten()
+            .map(|block| block.header.number)
+            .collect();
+        assert_eq!(numbers, vec![2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(batches.len(), 2);
+
+        // the primary lacks block 8, the secondary knows it
+        assert!(ds.get_block_hash(8).await.is_ok());
+        assert_eq!(ds.get_finalized_height().await.unwrap(), 5);
+        let ds = ds.with_racing(true);
+        assert!(ds.get_block_hash(8).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failure_after_last_block_ends_stream() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let primary = Arc::new(MockDataSource::new(9).with_failure(6, reset.into()));
+        let secondary = Arc::new(MockDataSource::new(9));
+        let ds = FailoverDataSource::new(vec![primary.clone(), secondary.clone()]);
+
+        let stream = Pin::from(ds.get_finalized_blocks(request(2, 5), true).unwrap());
+        let results: Vec<_> = stream.collect().await;
+        assert!(primary.failure_delivered());
+        assert_eq!(results.len(), 1);
+        let numbers: Vec<u64> = results[0]
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|block| block.header.number)
+            .collect();
+        assert_eq!(numbers, vec![2, 3, 4, 5]);
+        assert!(secondary.requests().is_empty());
+    }
+}
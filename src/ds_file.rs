@@ -0,0 +1,665 @@
+use crate::datasource::{
+    AccessTuple, BalanceChange, BalanceChangeReason, Block, BlockHeader, BlockStream, CallType,
+    DataRequest, DataSource, GasChange, GasChangeReason, L2Extra, Log, StorageChange, Trace,
+    TraceAction, TraceResult, TraceType, Transaction,
+};
+use crate::firehose::{log_matches, transaction_matches};
+use crate::pbcodec;
+use anyhow::Context;
+use async_stream::try_stream;
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// `sf.bstream.v1.Block`, the envelope of every block of a merged blocks file.
+/// Only the fields needed to get to the payload are decoded.
+#[derive(Clone, PartialEq, prost::Message)]
+struct BstreamBlock {
+    #[prost(uint64, tag = "1")]
+    number: u64,
+    #[prost(bytes = "vec", tag = "8")]
+    payload_buffer: Vec<u8>,
+    #[prost(message, optional, tag = "11")]
+    payload: Option<prost_types::Any>,
+}
+
+/// Decodes a dbin file holding `sf.bstream.v1.Block` messages with `sf.ethereum.type.v2.Block` payloads.
+///
+/// The file starts with the `dbin` magic and its version, followed by the content type,
+/// three bytes and a two bytes version in version 0 or a length prefixed string in version 1.
+/// Every message is prefixed with its length as a big endian u32.
+fn decode_merged_blocks(data: &[u8]) -> anyhow::Result<Vec<pbcodec::Block>> {
+    let mut data = data
+        .strip_prefix(b"dbin")
+        .context("not a dbin file, compressed files have to be decompressed first")?;
+    let (version, rest) = data.split_first().context("no dbin version")?;
+    data = match version {
+        0 => rest.get(5..).context("no dbin content type")?,
+        1 => {
+            let length = rest.get(..2).context("no dbin content type")?;
+            let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+            rest.get(2 + length..).context("no dbin content type")?
+        }
+        version => anyhow::bail!("unsupported dbin version {}", version),
+    };
+
+    let mut blocks = vec![];
+    while !data.is_empty() {
+        let length = data.get(..4).context("truncated message length")?;
+        let length = usize::try_from(u32::from_be_bytes(length.try_into()?))?;
+        let message = data.get(4..4 + length).context("truncated message")?;
+        let envelope = BstreamBlock::decode(message)?;
+        let payload = match &envelope.payload {
+            Some(any) => &any.value[..],
+            None => &envelope.payload_buffer[..],
+        };
+        let block = pbcodec::Block::decode(payload)
+            .with_context(|| format!("invalid payload of block №{}", envelope.number))?;
+        blocks.push(block);
+        data = &data[4 + length..];
+    }
+    Ok(blocks)
+}
+
+fn data(value: &[u8]) -> String {
+    prefix_hex::encode(value)
+}
+
+fn quantity(value: &[u8]) -> String {
+    let digits = prefix_hex::encode(value);
+    let digits = digits[2..].trim_start_matches('0');
+    if digits.is_empty() {
+        "0x0".to_string()
+    } else {
+        format!("0x{}", digits)
+    }
+}
+
+fn big_int(value: &Option<pbcodec::BigInt>) -> String {
+    quantity(value.as_ref().map_or(&[][..], |value| &value.bytes[..]))
+}
+
+impl From<pbcodec::BlockHeader> for BlockHeader {
+    fn from(value: pbcodec::BlockHeader) -> Self {
+        BlockHeader {
+            number: value.number,
+            hash: data(&value.hash),
+            parent_hash: data(&value.parent_hash),
+            // the size is only known for the block itself
+            size: None,
+            sha3_uncles: data(&value.uncle_hash),
+            miner: data(&value.coinbase),
+            state_root: data(&value.state_root),
+            transactions_root: data(&value.transactions_root),
+            receipts_root: data(&value.receipt_root),
+            logs_bloom: data(&value.logs_bloom),
+            difficulty: big_int(&value.difficulty),
+            total_difficulty: big_int(&value.total_difficulty),
+            gas_limit: format!("{:#x}", value.gas_limit),
+            gas_used: format!("{:#x}", value.gas_used),
+            timestamp: value.timestamp.map_or(0, |timestamp| {
+                u64::try_from(timestamp.seconds).unwrap_or_default()
+            }),
+            extra_data: data(&value.extra_data),
+            mix_hash: data(&value.mix_hash),
+            nonce: format!("{:#x}", value.nonce),
+            base_fee_per_gas: value
+                .base_fee_per_gas
+                .map(|base_fee| big_int(&Some(base_fee))),
+            l2_extra: value.l2_extra.map(|l2_extra| L2Extra {
+                l1_block_number: l2_extra.l1_block_number,
+                send_root: data(&l2_extra.send_root),
+            }),
+            blob_gas_used: value.blob_gas_used.map(|gas| format!("{:#x}", gas)),
+            excess_blob_gas: value.excess_blob_gas.map(|gas| format!("{:#x}", gas)),
+            withdrawals_root: (!value.withdrawals_root.is_empty())
+                .then(|| data(&value.withdrawals_root)),
+            parent_beacon_root: (!value.parent_beacon_root.is_empty())
+                .then(|| data(&value.parent_beacon_root)),
+            requests_hash: (!value.requests_hash.is_empty()).then(|| data(&value.requests_hash)),
+        }
+    }
+}
+
+impl From<pbcodec::AccessTuple> for AccessTuple {
+    fn from(value: pbcodec::AccessTuple) -> Self {
+        AccessTuple {
+            address: data(&value.address),
+            storage_keys: value.storage_keys.iter().map(|key| data(key)).collect(),
+        }
+    }
+}
+
+impl From<pbcodec::StorageChange> for StorageChange {
+    fn from(value: pbcodec::StorageChange) -> Self {
+        StorageChange {
+            address: data(&value.address),
+            key: data(&value.key),
+            old_value: data(&value.old_value),
+            new_value: data(&value.new_value),
+        }
+    }
+}
+
+/// None for the reasons the data sources never report.
+fn balance_change(value: pbcodec::BalanceChange) -> Option<BalanceChange> {
+    use pbcodec::balance_change::Reason;
+    let reason = match value.reason() {
+        Reason::RewardMineUncle => BalanceChangeReason::RewardMineUncle,
+        Reason::RewardMineBlock => BalanceChangeReason::RewardMineBlock,
+        Reason::DaoRefundContract => BalanceChangeReason::DaoRefundContract,
+        Reason::DaoAdjustBalance => BalanceChangeReason::DaoAdjustBalance,
+        Reason::Transfer => BalanceChangeReason::Transfer,
+        Reason::GasBuy => BalanceChangeReason::GasBuy,
+        Reason::RewardTransactionFee => BalanceChangeReason::RewardTransactionFee,
+        Reason::GasRefund => BalanceChangeReason::GasRefund,
+        Reason::SuicideRefund => BalanceChangeReason::SuicideRefund,
+        Reason::SuicideWithdraw => BalanceChangeReason::SuicideWithdraw,
+        Reason::Withdrawal => BalanceChangeReason::Withdrawal,
+        _ => return None,
+    };
+    Some(BalanceChange {
+        address: data(&value.address),
+        old_value: big_int(&value.old_value),
+        new_value: big_int(&value.new_value),
+        reason,
+    })
+}
+
+fn gas_change(value: pbcodec::GasChange) -> Option<GasChange> {
+    use pbcodec::gas_change::Reason;
+    let reason = match value.reason() {
+        Reason::Call => GasChangeReason::Call,
+        Reason::CallCode => GasChangeReason::CallCode,
+        Reason::CallDataCopy => GasChangeReason::CallDataCopy,
+        Reason::CodeCopy => GasChangeReason::CodeCopy,
+        Reason::CodeStorage => GasChangeReason::CodeStorage,
+        Reason::ContractCreation => GasChangeReason::ContractCreation,
+        Reason::ContractCreation2 => GasChangeReason::ContractCreation2,
+        Reason::DelegateCall => GasChangeReason::DelegateCall,
+        Reason::EventLog => GasChangeReason::EventLog,
+        Reason::ExtCodeCopy => GasChangeReason::ExtCodeCopy,
+        Reason::FailedExecution => GasChangeReason::FailedExecution,
+        Reason::IntrinsicGas => GasChangeReason::IntrinsicGas,
+        Reason::PrecompiledContract => GasChangeReason::PrecompiledContract,
+        Reason::RefundAfterExecution => GasChangeReason::RefundAfterExecution,
+        Reason::Return => GasChangeReason::Return,
+        Reason::ReturnDataCopy => GasChangeReason::ReturnDataCopy,
+        Reason::Revert => GasChangeReason::Revert,
+        Reason::SelfDestruct => GasChangeReason::SelfDestruct,
+        Reason::StaticCall => GasChangeReason::StaticCall,
+        Reason::StateColdAccess => GasChangeReason::StateColdAccess,
+        Reason::Unknown => return None,
+    };
+    Some(GasChange {
+        old_value: value.old_value,
+        new_value: value.new_value,
+        reason,
+    })
+}
+
+/// Traces of the calls of a transaction, their trace addresses follow from the parent of each call.
+fn call_traces(calls: Vec<pbcodec::Call>, transaction_index: u32) -> Vec<Trace> {
+    let mut trace_addresses: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut children: HashMap<u32, u32> = HashMap::new();
+    let mut traces = vec![];
+    for call in calls {
+        let trace_address = match trace_addresses.get(&call.parent_index) {
+            Some(parent) => {
+                let child = children.entry(call.parent_index).or_default();
+                let mut trace_address = parent.clone();
+                trace_address.push(*child);
+                *child += 1;
+                trace_address
+            }
+            None => vec![],
+        };
+        trace_addresses.insert(call.index, trace_address.clone());
+
+        let (error, revert_reason) = if call.status_reverted {
+            let reason = call
+                .failure_reason
+                .strip_prefix("execution reverted: ")
+                .unwrap_or_default();
+            (None, Some(reason.to_string()))
+        } else if call.status_failed {
+            (Some(call.failure_reason.clone()), None)
+        } else {
+            (None, None)
+        };
+        let call_type = call.call_type();
+        let value = call.value.is_some().then(|| big_int(&call.value));
+        let (r#type, action, result) = if call.suicide {
+            let action = TraceAction {
+                from: Some(data(&call.caller)),
+                to: Some(data(&call.address)),
+                value,
+                gas: None,
+                input: None,
+                r#type: None,
+            };
+            (TraceType::Suicide, action, None)
+        } else {
+            let creation = call_type == pbcodec::CallType::Create;
+            let r#type = match call_type {
+                pbcodec::CallType::Call => Some(CallType::Call),
+                pbcodec::CallType::Callcode => Some(CallType::Callcode),
+                pbcodec::CallType::Delegate => Some(CallType::Delegatecall),
+                pbcodec::CallType::Static => Some(CallType::Staticcall),
+                _ => None,
+            };
+            if !creation && r#type.is_none() {
+                continue;
+            }
+            let code = call
+                .code_changes
+                .iter()
+                .find(|change| change.address == call.address)
+                .map(|change| data(&change.new_code));
+            let action = TraceAction {
+                from: Some(data(&call.caller)),
+                to: r#type.is_some().then(|| data(&call.address)),
+                value,
+                gas: Some(format!("{:#x}", call.gas_limit)),
+                input: r#type.is_some().then(|| data(&call.input)),
+                r#type,
+            };
+            let result = TraceResult {
+                gas_used: Some(format!("{:#x}", call.gas_consumed)),
+                address: creation.then(|| data(&call.address)),
+                output: Some(data(&call.return_data)),
+                code,
+            };
+            let r#type = if creation {
+                TraceType::Create
+            } else {
+                TraceType::Call
+            };
+            (r#type, action, Some(result))
+        };
+
+        traces.push(Trace {
+            transaction_index,
+            trace_address,
+            r#type,
+            error,
+            revert_reason,
+            action: Some(action),
+            result,
+            balance_changes: call
+                .balance_changes
+                .into_iter()
+                .filter_map(balance_change)
+                .collect(),
+            storage_changes: call
+                .storage_changes
+                .into_iter()
+                .map(StorageChange::from)
+                .collect(),
+            gas_changes: call
+                .gas_changes
+                .into_iter()
+                .filter_map(gas_change)
+                .collect(),
+            keccak_preimages: call
+                .keccak_preimages
+                .into_iter()
+                .map(|(hash, preimage)| (format!("0x{}", hash), format!("0x{}", preimage)))
+                .collect(),
+        });
+    }
+    traces
+}
+
+impl TryFrom<pbcodec::Block> for Block {
+    type Error = anyhow::Error;
+
+    fn try_from(value: pbcodec::Block) -> Result<Self, Self::Error> {
+        let mut header = BlockHeader::from(value.header.context("no header")?);
+        header.size = Some(format!("{:#x}", value.size));
+
+        let mut logs = vec![];
+        let mut transactions = vec![];
+        let mut traces = vec![];
+        for mut tx in value.transaction_traces {
+            let receipt = tx.receipt.take().unwrap_or_default();
+            for log in &receipt.logs {
+                logs.push(Log {
+                    address: data(&log.address),
+                    data: data(&log.data),
+                    topics: log.topics.iter().map(|topic| data(topic)).collect(),
+                    log_index: log.block_index,
+                    transaction_index: tx.index,
+                });
+            }
+            let creation = tx
+                .calls
+                .first()
+                .is_some_and(|call| call.call_type() == pbcodec::CallType::Create);
+            // typed transactions carry the signature parity in place of v
+            let y_parity = (tx.r#type != 0)
+                .then(|| match tx.v[..] {
+                    [] => Some(0),
+                    [parity] => Some(parity),
+                    _ => None,
+                })
+                .flatten();
+            // the gas price of the trace is the price paid
+            let gas_price = big_int(&tx.gas_price);
+            transactions.push(Transaction {
+                transaction_index: tx.index,
+                hash: data(&tx.hash),
+                nonce: tx.nonce,
+                from: data(&tx.from),
+                to: (!creation && !tx.to.is_empty()).then(|| data(&tx.to)),
+                input: data(&tx.input),
+                value: big_int(&tx.value),
+                gas: format!("{:#x}", tx.gas_limit),
+                gas_price: gas_price.clone(),
+                max_fee_per_gas: tx
+                    .max_fee_per_gas
+                    .as_ref()
+                    .map(|fee| big_int(&Some(fee.clone()))),
+                max_priority_fee_per_gas: tx
+                    .max_priority_fee_per_gas
+                    .as_ref()
+                    .map(|fee| big_int(&Some(fee.clone()))),
+                v: quantity(&tx.v),
+                r: data(&tx.r),
+                s: data(&tx.s),
+                y_parity,
+                chain_id: None,
+                gas_used: format!("{:#x}", tx.gas_used),
+                cumulative_gas_used: format!("{:#x}", receipt.cumulative_gas_used),
+                effective_gas_price: gas_price,
+                r#type: tx.r#type,
+                status: i32::from(tx.status() == pbcodec::TransactionTraceStatus::Succeeded),
+                access_list: tx.access_list.into_iter().map(AccessTuple::from).collect(),
+                max_fee_per_blob_gas: tx
+                    .blob_gas_fee_cap
+                    .as_ref()
+                    .map(|fee| big_int(&Some(fee.clone()))),
+                blob_versioned_hashes: tx.blob_hashes.iter().map(|hash| data(hash)).collect(),
+                raw_receipt: String::from_utf8(receipt.raw)
+                    .ok()
+                    .filter(|raw| !raw.is_empty()),
+            });
+            traces.extend(call_traces(tx.calls, tx.index));
+        }
+
+        let mut balance_changes = vec![];
+        for change in value.balance_changes {
+            balance_changes.extend(balance_change(change));
+        }
+
+        Ok(Block {
+            header,
+            logs,
+            transactions,
+            traces,
+            uncles: value.uncles.into_iter().map(BlockHeader::from).collect(),
+            balance_changes,
+            raw: None,
+        })
+    }
+}
+
+/// Keeps the requested logs and transactions along with the transactions emitting those logs,
+/// the way the rpc source selects them.
+fn select(mut block: Block, request: &DataRequest) -> Block {
+    if request.headers_only {
+        block.logs.clear();
+        block.transactions.clear();
+        block.traces.clear();
+        block.uncles.clear();
+        block.balance_changes.clear();
+        return block;
+    }
+
+    block.logs.retain(|log| log_matches(log, &request.logs));
+    let kept: HashSet<u32> = block
+        .transactions
+        .iter()
+        .filter(|tx| transaction_matches(tx, &request.transactions))
+        .map(|tx| tx.transaction_index)
+        .chain(block.logs.iter().map(|log| log.transaction_index))
+        .collect();
+    block
+        .transactions
+        .retain(|tx| kept.contains(&tx.transaction_index));
+    block
+        .traces
+        .retain(|trace| request.traces && kept.contains(&trace.transaction_index));
+    for trace in &mut block.traces {
+        if !request.balance_changes {
+            trace.balance_changes.clear();
+        }
+        if !request.storage_changes {
+            trace.storage_changes.clear();
+        }
+        if !request.gas_changes {
+            trace.gas_changes.clear();
+        }
+    }
+    if !request.balance_changes {
+        block.balance_changes.clear();
+    }
+    block
+}
+
+/// First blocks of the bundles in `dir`, ascending.
+async fn bundles(dir: &Path) -> anyhow::Result<Vec<u64>> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+    let mut bundles = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let start = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".dbin"))
+            .and_then(|start| start.parse().ok());
+        if let Some(start) = start {
+            bundles.push(start);
+        }
+    }
+    bundles.sort_unstable();
+    Ok(bundles)
+}
+
+async fn read_bundle(dir: &Path, start: u64) -> anyhow::Result<Vec<pbcodec::Block>> {
+    let path = dir.join(format!("{:010}.dbin", start));
+    let bytes = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    decode_merged_blocks(&bytes).with_context(|| format!("invalid {}", path.display()))
+}
+
+/// The bundle that may hold block `height`.
+fn bundle_of(bundles: &[u64], height: u64) -> Option<u64> {
+    bundles
+        .iter()
+        .rev()
+        .find(|start| **start <= height)
+        .copied()
+}
+
+/// Serves finalized blocks from a directory of merged blocks files, the bundles of blocks
+/// written by the StreamingFast tooling and named after their first block, e.g. `0000000100.dbin`.
+///
+/// Bundles have to be decompressed, remote stores like S3 buckets can be served once mounted.
+pub struct FileDataSource {
+    dir: PathBuf,
+    chain_id: Option<u64>,
+}
+
+impl FileDataSource {
+    pub fn new(dir: PathBuf) -> FileDataSource {
+        FileDataSource {
+            dir,
+            chain_id: None,
+        }
+    }
+
+    /// Merged blocks don't tell the chain they belong to, so it's configured instead.
+    pub fn with_chain_id(mut self, chain_id: Option<u64>) -> FileDataSource {
+        self.chain_id = chain_id;
+        self
+    }
+
+    async fn block(&self, height: u64) -> anyhow::Result<Option<pbcodec::Block>> {
+        let bundles = bundles(&self.dir).await?;
+        let Some(start) = bundle_of(&bundles, height) else {
+            return Ok(None);
+        };
+        let blocks = read_bundle(&self.dir, start).await?;
+        Ok(blocks.into_iter().find(|block| block.number == height))
+    }
+}
+
+#[async_trait::async_trait]
+impl DataSource for FileDataSource {
+    /// Bundles are read one at a time, every bundle is a batch.
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        _stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        let dir = self.dir.clone();
+        Ok(Box::new(try_stream! {
+            let bundles = bundles(&dir).await?;
+            let first = bundle_of(&bundles, request.from).or(bundles.first().copied());
+            for start in bundles.into_iter().filter(|start| Some(*start) >= first) {
+                if request.to.is_some_and(|to| start > to) {
+                    break;
+                }
+                let mut batch = vec![];
+                for block in read_bundle(&dir, start).await? {
+                    let number = block.number;
+                    if number < request.from || request.to.is_some_and(|to| number > to) {
+                        continue;
+                    }
+                    let block = Block::try_from(block)
+                        .with_context(|| format!("invalid block №{}", number))?;
+                    batch.push(select(block, &request));
+                }
+                if !batch.is_empty() {
+                    yield batch;
+                }
+            }
+        }))
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        let bundles = bundles(&self.dir).await?;
+        let last = bundles.last().context("no merged blocks files")?;
+        let blocks = read_bundle(&self.dir, *last).await?;
+        let block = blocks.last().context("empty merged blocks file")?;
+        Ok(block.number)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        let block = self
+            .block(height)
+            .await?
+            .with_context(|| format!("block №{} not found", height))?;
+        Ok(data(&block.hash))
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        let bundles = bundles(&self.dir).await?;
+        let first = bundles.first().context("no merged blocks files")?;
+        let blocks = read_bundle(&self.dir, *first).await?;
+        let block = blocks.first().context("empty merged blocks file")?;
+        Ok(block.number)
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        Ok(self.chain_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::{DataRequest, DataSource, LogRequest, TransactionRequest};
+    use crate::ds_file::{decode_merged_blocks, FileDataSource};
+    use crate::pbcodec;
+    use crate::testing::hash;
+    use futures_util::StreamExt;
+    use std::path::{Path, PathBuf};
+    use std::pin::Pin;
+
+    fn testdata() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/merged-blocks")
+    }
+
+    fn request(from: u64, to: Option<u64>, headers_only: bool) -> DataRequest {
+        DataRequest {
+            from,
+            to,
+            logs: vec![LogRequest {
+                address: vec![],
+                topic0: vec![],
+                topic1: vec![],
+                topic2: vec![],
+                topic3: vec![],
+            }],
+            transactions: vec![TransactionRequest {
+                to: vec![],
+                sighash: vec![],
+                from: vec![],
+            }],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn merged_blocks_file() {
+        let ds = FileDataSource::new(testdata());
+        assert_eq!(ds.get_first_block().await.unwrap(), 0);
+        assert_eq!(ds.get_finalized_height().await.unwrap(), 2);
+        assert_eq!(ds.get_block_hash(1).await.unwrap(), hash(1));
+        assert!(ds.get_block_hash(3).await.is_err());
+
+        let with_balances = DataRequest {
+            balance_changes: true,
+            ..request(0, None, false)
+        };
+        let stream = ds.get_finalized_blocks(with_balances, true).unwrap();
+        let batches: Vec<_> = Pin::from(stream).collect().await;
+        let blocks = batches.into_iter().next().unwrap().unwrap();
+        let numbers: Vec<u64> = blocks.iter().map(|block| block.header.number).collect();
+        assert_eq!(numbers, vec![0, 1, 2]);
+        assert_eq!(blocks[1].transactions.len(), 1);
+        assert_eq!(blocks[1].logs.len(), 1);
+        assert_eq!(blocks[1].traces.len(), 1);
+        // the block reward
+        assert_eq!(blocks[1].balance_changes.len(), 1);
+
+        // converting the blocks again gives back the blocks of the file
+        let file = std::fs::read(testdata().join("0000000000.dbin")).unwrap();
+        let expected = decode_merged_blocks(&file).unwrap();
+        let converted = blocks
+            .into_iter()
+            .map(pbcodec::Block::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(converted, expected);
+
+        let stream = ds
+            .get_finalized_blocks(request(1, Some(1), true), true)
+            .unwrap();
+        let batches: Vec<_> = Pin::from(stream).collect().await;
+        let blocks = batches.into_iter().next().unwrap().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].header.hash, hash(1));
+        assert!(blocks[0].transactions.is_empty());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::datasource::{
+    Block, BlockStream, BlockTag, DataRequest, DataSource, HashAndHeight, HotBlockStream,
+    HotDataSource, HotSource,
+};
+use async_stream::try_stream;
+use futures_util::StreamExt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Serves finalized blocks from the archive while it has them and from the rpc past its head,
+/// hot blocks always come from the rpc.
+pub struct MergedDataSource {
+    archive: Arc<dyn DataSource + Send + Sync>,
+    rpc: Arc<dyn HotDataSource + Send + Sync>,
+    crossover_gap: u64,
+    poll_interval: Duration,
+}
+
+impl MergedDataSource {
+    pub fn new(
+        archive: Arc<dyn DataSource + Send + Sync>,
+        rpc: Arc<dyn HotDataSource + Send + Sync>,
+    ) -> MergedDataSource {
+        MergedDataSource {
+            archive,
+            rpc,
+            crossover_gap: 0,
+            poll_interval: Duration::ZERO,
+        }
+    }
+
+    /// Keeps waiting for the archive, polling its height every `poll_interval`, while it's at most
+    /// `gap` blocks behind the end of the range, the rpc only takes over past that gap.
+    pub fn with_crossover_gap(mut self, gap: u64, poll_interval: Duration) -> MergedDataSource {
+        self.crossover_gap = gap;
+        self.poll_interval = poll_interval;
+        self
+    }
+}
+
+/// Blocks that were already streamed would be delivered twice,
+/// returns the height the next batch should start from.
+fn check_continuity(source: &str, next: u64, blocks: &[Block]) -> anyhow::Result<u64> {
+    match (blocks.first(), blocks.last()) {
+        (Some(first), Some(last)) => {
+            anyhow::ensure!(
+                first.header.number >= next,
+                "{} returned block №{} again, expected №{} onwards",
+                source,
+                first.header.number,
+                next
+            );
+            Ok(last.header.number + 1)
+        }
+        _ => Ok(next),
+    }
+}
+
+#[async_trait::async_trait]
+impl DataSource for MergedDataSource {
+    /// With `stop_on_head` the stream ends at the finalized head of the rpc,
+    /// otherwise the rpc keeps streaming blocks as they become final.
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        let archive = self.archive.clone();
+        let rpc = self.rpc.clone();
+        let crossover_gap = self.crossover_gap;
+        let poll_interval = self.poll_interval;
+
+        Ok(Box::new(try_stream! {
+            let mut from = request.from;
+
+            loop {
+                if request.to.is_some_and(|to| from > to) {
+                    return
+                }
+                let archive_height = archive.get_finalized_height().await?;
+                if from <= archive_height {
+                    let to = request.to.map_or(archive_height, |to| to.min(archive_height));
+                    let req = DataRequest {
+                        from,
+                        to: Some(to),
+                        ..request.clone()
+                    };
+                    let mut stream = Pin::from(archive.get_finalized_blocks(req, true)?);
+                    // filtered batches are sparse, but every batch ends with the last block of its range
+                    let mut next = from;
+                    while let Some(result) = stream.next().await {
+                        let blocks = result?;
+                        next = check_continuity("archive", next, &blocks)?;
+                        yield blocks;
+                    }
+                    from = next;
+                    if next <= to {
+                        warn!(
+                            "archive stream ended at block №{} short of №{}, backfilling from the rpc",
+                            next - 1, to
+                        );
+                        break
+                    }
+                    continue
+                }
+
+                // blocks past the archive head are left to the rpc unless the archive is close
+                // enough to the end of the range, so the source doesn't flip at every new block
+                let rpc_height = rpc.get_finalized_height().await?;
+                let end = request.to.map_or(rpc_height, |to| to.min(rpc_height));
+                if from > end || end - archive_height > crossover_gap {
+                    break
+                }
+                tokio::time::sleep(poll_interval).await;
+            }
+
+            let mut to = request.to;
+            if stop_on_head {
+                let rpc_height = rpc.get_finalized_height().await?;
+                to = Some(to.map_or(rpc_height, |to| to.min(rpc_height)));
+            }
+            if to.is_some_and(|to| from > to) {
+                return
+            }
+
+            let req = DataRequest {
+                from,
+                to,
+                ..request
+            };
+            let mut stream = Pin::from(rpc.get_finalized_blocks(req, stop_on_head)?);
+            let mut next = from;
+            while let Some(result) = stream.next().await {
+                let blocks = result?;
+                next = check_continuity("rpc", next, &blocks)?;
+                yield blocks;
+            }
+        }))
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        self.rpc.get_finalized_height().await
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        self.rpc.get_block_hash(height).await
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        self.archive.get_first_block().await
+    }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.rpc.get_head_height().await
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        self.rpc.get_chain_id().await
+    }
+}
+
+#[async_trait::async_trait]
+impl HotSource for MergedDataSource {
+    fn get_hot_blocks(
+        &self,
+        request: DataRequest,
+        state: HashAndHeight,
+    ) -> anyhow::Result<HotBlockStream> {
+        self.rpc.get_hot_blocks(request, state)
+    }
+
+    fn as_ds(&self) -> &(dyn DataSource + Send + Sync) {
+        self
+    }
+
+    async fn get_block_hashes(&self, from: u64, to: u64) -> anyhow::Result<Vec<String>> {
+        self.rpc.get_block_hashes(from, to).await
+    }
+
+    async fn get_tagged_height(&self, tag: BlockTag) -> anyhow::Result<u64> {
+        self.rpc.get_tagged_height(tag).await
+    }
+}
+
+impl HotDataSource for MergedDataSource {}
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::{Block, DataRequest, DataSource, HotSource};
+    use crate::ds_merged::{check_continuity, MergedDataSource};
+    use crate::testing::{block, chain, fork_hash, hash, MockDataSource};
+    use futures_util::StreamExt;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn recording(height: u64) -> Arc<MockDataSource> {
+        Arc::new(MockDataSource::new(height))
+    }
+
+    fn advancing(heights: Vec<u64>) -> Arc<MockDataSource> {
+        Arc::new(MockDataSource::new(heights[0]).with_heights(heights))
+    }
+
+    fn short(height: u64, served: u64) -> Arc<MockDataSource> {
+        Arc::new(MockDataSource::new(height).with_served(served))
+    }
+
+    fn request(from: u64, to: Option<u64>) -> DataRequest {
+        DataRequest {
+            from,
+            to,
+            logs: vec![],
+            transactions: vec![],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        }
+    }
+
+    async fn heights(ds: &MergedDataSource, request: DataRequest) -> Vec<u64> {
+        let stream = Pin::from(ds.get_finalized_blocks(request, true).unwrap());
+        let batches: Vec<Vec<Block>> = stream.map(|result| result.unwrap()).collect().await;
+        batches
+            .into_iter()
+            .flatten()
+            .map(|block| block.header.number)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn archive_hands_off_to_rpc() {
+        let archive = recording(5);
+        let rpc = recording(8);
+        let ds = MergedDataSource::new(archive.clone(), rpc.clone());
+
+        assert_eq!(
+            heights(&ds, request(0, None)).await,
+            (0..=8).collect::<Vec<_>>()
+        );
+        assert_eq!(archive.requests(), vec![(0, Some(5))]);
+        assert_eq!(rpc.requests(), vec![(6, Some(8))]);
+    }
+
+    #[tokio::test]
+    async fn archive_is_followed_within_crossover_gap() {
+        let gap = |gap: u64| {
+            // the archive lags two blocks behind the rpc for a while before catching up
+            let archive = advancing(vec![5, 8, 8, 10]);
+            let rpc = recording(10);
+            let ds = MergedDataSource::new(archive.clone(), rpc.clone())
+                .with_crossover_gap(gap, Duration::from_millis(1));
+            (ds, archive, rpc)
+        };
+
+        let (ds, archive, rpc) = gap(3);
+        assert_eq!(
+            heights(&ds, request(0, None)).await,
+            (0..=10).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            archive.requests(),
+            vec![(0, Some(5)), (6, Some(8)), (9, Some(10))]
+        );
+        assert!(rpc.requests().is_empty());
+
+        let (ds, archive, rpc) = gap(1);
+        assert_eq!(
+            heights(&ds, request(0, None)).await,
+            (0..=10).collect::<Vec<_>>()
+        );
+        assert_eq!(archive.requests(), vec![(0, Some(5)), (6, Some(8))]);
+        assert_eq!(rpc.requests(), vec![(9, Some(10))]);
+    }
+
+    #[tokio::test]
+    async fn block_hashes_of_fork() {
+        let rpc = chain(6..=8, fork_hash, hash(5))
+            .into_iter()
+            .fold(MockDataSource::new(8), MockDataSource::with_block);
+        let ds = MergedDataSource::new(recording(5), Arc::new(rpc));
+
+        let hashes = ds.get_block_hashes(3, 8).await.unwrap();
+        let expected: Vec<String> = (3..=5).map(hash).chain((6..=8).map(fork_hash)).collect();
+        assert_eq!(hashes, expected);
+
+        // the delivered chain forked off right after its last block with the same hash
+        let delivered: Vec<String> = (3..=8).map(hash).collect();
+        let ancestor = delivered
+            .iter()
+            .zip(&hashes)
+            .zip(3..)
+            .take_while(|((delivered, current), _)| delivered == current)
+            .last()
+            .map(|(_, height)| height);
+        assert_eq!(ancestor, Some(5));
+
+        assert!(ds.get_block_hashes(7, 9).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handoff_at_archive_head() {
+        let archive = recording(5);
+        let rpc = recording(8);
+        let ds = MergedDataSource::new(archive.clone(), rpc.clone());
+
+        assert_eq!(heights(&ds, request(5, Some(6))).await, vec![5, 6]);
+        assert_eq!(archive.requests(), vec![(5, Some(5))]);
+        assert_eq!(rpc.requests(), vec![(6, Some(6))]);
+    }
+
+    #[tokio::test]
+    async fn short_archive_range_is_backfilled() {
+        let archive = short(5, 3);
+        let rpc = recording(8);
+        let ds = MergedDataSource::new(archive.clone(), rpc.clone());
+
+        assert_eq!(
+            heights(&ds, request(0, None)).await,
+            (0..=8).collect::<Vec<_>>()
+        );
+        assert_eq!(archive.requests(), vec![(0, Some(5))]);
+        assert_eq!(rpc.requests(), vec![(4, Some(8))]);
+    }
+
+    #[tokio::test]
+    async fn range_within_one_source() {
+        let archive = recording(5);
+        let rpc = recording(8);
+        let ds = MergedDataSource::new(archive.clone(), rpc.clone());
+
+        assert_eq!(heights(&ds, request(2, Some(5))).await, vec![2, 3, 4, 5]);
+        assert_eq!(archive.requests(), vec![(2, Some(5))]);
+        assert!(rpc.requests().is_empty());
+
+        assert_eq!(heights(&ds, request(7, None)).await, vec![7, 8]);
+        assert_eq!(archive.requests(), vec![(2, Some(5))]);
+        assert_eq!(rpc.requests(), vec![(7, Some(8))]);
+
+        // nothing is final beyond the rpc head
+        assert!(heights(&ds, request(9, None)).await.is_empty());
+        assert_eq!(rpc.requests(), vec![(7, Some(8))]);
+    }
+
+    #[test]
+    fn repeated_blocks_are_rejected() {
+        assert_eq!(
+            check_continuity(
+                "archive",
+                3,
+                &[block(5, hash(5), hash(4)), block(7, hash(7), hash(6))]
+            )
+            .unwrap(),
+            8
+        );
+        assert_eq!(check_continuity("archive", 3, &[]).unwrap(), 3);
+        assert!(check_continuity(
+            "rpc",
+            3,
+            &[block(2, hash(2), hash(1)), block(3, hash(3), hash(2))]
+        )
+        .is_err());
+    }
+}
@@ -1,21 +1,26 @@
 use crate::datasource::{
-    Block, BlockHeader, BlockStream, CallType, DataRequest, DataSource, HashAndHeight,
-    HotBlockStream, HotDataSource, HotSource, HotUpdate, Log, LogRequest, Trace, TraceAction,
-    TraceResult, TraceType, Transaction, TransactionRequest,
+    AccessTuple, BalanceChange, BalanceChangeReason, Block, BlockHeader, BlockStream, BlockTag,
+    CallType, DataRequest, DataSource, HashAndHeight, HeaderVariant, HotBlockStream, HotDataSource,
+    HotSource, HotUpdate, L2Extra, Log, LogRequest, StorageChange, Trace, TraceAction, TraceResult,
+    TraceType, Transaction, TransactionRequest,
 };
 use anyhow::Context;
 use async_stream::try_stream;
 use ethers_core::types as evm;
-use ethers_providers::{Http, Middleware, Provider};
+use ethers_providers::{Http, Middleware, Provider, RpcError, StreamExt, Ws};
 use futures_core::Stream;
 use futures_util::future::join_all;
 use prefix_hex::ToHexPrefixed;
+use serde_json::value::RawValue;
 use std::cmp::min;
 use std::collections::{HashMap, HashSet};
 use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
 
 type Range = (u64, u64);
 
@@ -24,7 +29,28 @@ async fn get_finalized_height(
     finality_confirmation: u64,
 ) -> anyhow::Result<u64> {
     let height = height_tracker.height().await?;
-    Ok(height.saturating_sub(finality_confirmation))
+    Ok(finalized_height(height, finality_confirmation))
+}
+
+/// The node tells which blocks are safe and finalized, unlike the finalized height
+/// which trails the head by the finality confirmation.
+async fn get_tagged_height(client: &Provider<Http>, tag: BlockTag) -> anyhow::Result<u64> {
+    let number = match tag {
+        BlockTag::Latest => evm::BlockNumber::Latest,
+        BlockTag::Safe => evm::BlockNumber::Safe,
+        BlockTag::Finalized => evm::BlockNumber::Finalized,
+    };
+    let block = client
+        .get_block(number)
+        .await?
+        .with_context(|| format!("no {} block", number))?;
+    Ok(block.number.context("no number")?.as_u64())
+}
+
+/// A block is final once it is `finality_confirmation` blocks deep,
+/// anything shallower can still be reorganized.
+fn finalized_height(head: u64, finality_confirmation: u64) -> u64 {
+    head.saturating_sub(finality_confirmation)
 }
 
 async fn get_logs(
@@ -46,13 +72,20 @@ async fn get_logs(
             filter = filter.address(address);
         }
 
-        if !request.topic0.is_empty() {
-            let topic = request
-                .topic0
+        for (position, values) in request.topics().into_iter().enumerate() {
+            if values.is_empty() {
+                continue;
+            }
+            let topic = values
                 .iter()
                 .map(|topic| topic.parse::<evm::H256>())
                 .collect::<Result<Vec<_>, _>>()?;
-            filter = filter.topic0(topic);
+            filter = match position {
+                0 => filter.topic0(topic),
+                1 => filter.topic1(topic),
+                2 => filter.topic2(topic),
+                _ => filter.topic3(topic),
+            };
         }
 
         let mut request_logs = client.get_logs(&filter).await?;
@@ -62,18 +95,31 @@ async fn get_logs(
     Ok(logs)
 }
 
-fn traverse_trace(trace: evm::GethTrace) -> anyhow::Result<Vec<Trace>> {
+fn traverse_call(
+    mut call: evm::CallFrame,
+    transaction_index: u32,
+    trace_address: Vec<u32>,
+    traces: &mut Vec<Trace>,
+) -> anyhow::Result<()> {
+    let calls = call.calls.take().unwrap_or_default();
+    let mut trace = Trace::try_from(call)?;
+    trace.transaction_index = transaction_index;
+    trace.trace_address = trace_address.clone();
+    traces.push(trace);
+    for (index, call) in calls.into_iter().enumerate() {
+        let mut trace_address = trace_address.clone();
+        trace_address.push(u32::try_from(index)?);
+        traverse_call(call, transaction_index, trace_address, traces)?;
+    }
+    Ok(())
+}
+
+fn traverse_trace(trace: evm::GethTrace, transaction_index: u32) -> anyhow::Result<Vec<Trace>> {
     let mut traces = vec![];
     match trace {
         evm::GethTrace::Known(trace) => match trace {
-            evm::GethTraceFrame::CallTracer(mut call) => {
-                let calls = call.calls.take().unwrap_or_default();
-                let trace = Trace::try_from(call)?;
-                traces.push(trace);
-                for call in calls {
-                    let trace = Trace::try_from(call)?;
-                    traces.push(trace);
-                }
+            evm::GethTraceFrame::CallTracer(call) => {
+                traverse_call(call, transaction_index, vec![], &mut traces)?;
             }
             _ => unimplemented!(),
         },
@@ -91,19 +137,24 @@ fn to_sighash(input: &str) -> Option<&str> {
 }
 
 fn is_tx_requested(tx: &evm::Transaction, request: &DataRequest) -> bool {
-    let tx_address = tx.to.and_then(|val| Some(format!("{:?}", val)));
+    let tx_to = tx.to.map(|val| format!("{:?}", val));
+    let tx_from = format!("{:?}", tx.from);
     let input = tx.input.to_hex_prefixed();
     let tx_sighash = to_sighash(&input);
 
     request.transactions.iter().any(|request| {
-        let TransactionRequest { address, sighash } = request;
+        let TransactionRequest { to, sighash, from } = request;
 
-        if let Some(tx_address) = &tx_address {
-            if !address.is_empty() && !address.iter().any(|address| tx_address == address) {
+        if !from.is_empty() && !from.iter().any(|from| from.eq_ignore_ascii_case(&tx_from)) {
+            return false;
+        }
+
+        if let Some(tx_to) = &tx_to {
+            if !to.is_empty() && !to.iter().any(|to| tx_to == to) {
                 return false;
             }
         } else {
-            if !address.is_empty() {
+            if !to.is_empty() {
                 return false;
             }
         }
@@ -126,18 +177,59 @@ async fn get_stride(
     client: &Provider<Http>,
     range: &Range,
     request: &DataRequest,
+    block_receipts: &AtomicBool,
+    header_variant: HeaderVariant,
 ) -> anyhow::Result<Vec<Block>> {
-    let rpc_blocks = get_blocks(client, range).await?;
-    let blocks = get_requested_data(client, rpc_blocks, request).await?;
+    if request.headers_only {
+        return get_headers(client, range, request.raw_blocks, header_variant).await;
+    }
+    let (rpc_blocks, raw): (Vec<_>, Vec<_>) = get_blocks(client, range, request.raw_blocks)
+        .await?
+        .into_iter()
+        .unzip();
+    let mut blocks =
+        get_requested_data(client, rpc_blocks, request, block_receipts, header_variant).await?;
+    for (block, raw) in blocks.iter_mut().zip(raw) {
+        block.raw = raw;
+    }
     Ok(blocks)
 }
 
+type RawBlock<TX> = (evm::Block<TX>, Option<String>);
+
+/// Block `id` along with its JSON exactly as the rpc returned it when `keep_raw`,
+/// it comes with full transactions when `full` and with their hashes otherwise.
+async fn get_raw_block<TX>(
+    client: &Provider<Http>,
+    id: evm::BlockId,
+    full: bool,
+    keep_raw: bool,
+) -> anyhow::Result<Option<RawBlock<TX>>>
+where
+    evm::Block<TX>: serde::de::DeserializeOwned,
+{
+    let raw: Option<Box<RawValue>> = match id {
+        evm::BlockId::Hash(hash) => client.request("eth_getBlockByHash", (hash, full)).await?,
+        evm::BlockId::Number(number) => {
+            client
+                .request("eth_getBlockByNumber", (number, full))
+                .await?
+        }
+    };
+    raw.map(|raw| {
+        let block = serde_json::from_str(raw.get()).context("invalid block")?;
+        Ok((block, keep_raw.then(|| raw.get().to_string())))
+    })
+    .transpose()
+}
+
 async fn get_blocks(
     client: &Provider<Http>,
     range: &Range,
-) -> anyhow::Result<Vec<evm::Block<evm::Transaction>>> {
+    keep_raw: bool,
+) -> anyhow::Result<Vec<RawBlock<evm::Transaction>>> {
     let futures: Vec<_> = (range.0..=range.1)
-        .map(|num| client.get_block_with_txs(num))
+        .map(|num| get_raw_block(client, num.into(), true, keep_raw))
         .collect();
     join_all(futures)
         .await
@@ -146,15 +238,465 @@ async fn get_blocks(
         .collect()
 }
 
+async fn get_headers(
+    client: &Provider<Http>,
+    range: &Range,
+    keep_raw: bool,
+    header_variant: HeaderVariant,
+) -> anyhow::Result<Vec<Block>> {
+    let futures: Vec<_> = (range.0..=range.1)
+        .map(|num| get_raw_block::<evm::H256>(client, num.into(), false, keep_raw))
+        .collect();
+    join_all(futures)
+        .await
+        .into_iter()
+        .map(|res| {
+            let (block, raw) = res?.expect("unfinalized block was requested");
+            let mut block = convert_block(block, header_variant)?;
+            block.raw = raw;
+            Ok(block)
+        })
+        .collect()
+}
+
+async fn get_uncles(
+    client: &Provider<Http>,
+    block: &evm::Block<evm::Transaction>,
+) -> anyhow::Result<Vec<BlockHeader>> {
+    let hash = block.hash.context("no hash")?;
+    let futures: Vec<_> = (0..block.uncles.len())
+        .map(|idx| client.get_uncle(hash, evm::U64::from(idx)))
+        .collect();
+    join_all(futures)
+        .await
+        .into_iter()
+        .map(|result| {
+            let mut uncle = result?.context("uncle not found")?;
+            // uncles are returned without total difficulty
+            uncle.total_difficulty.get_or_insert_with(evm::U256::zero);
+            BlockHeader::try_from(&uncle)
+        })
+        .collect()
+}
+
+/// Balances of all accounts touched by the transaction right before its execution.
+async fn get_balances(
+    client: &Provider<Http>,
+    hash: evm::H256,
+) -> anyhow::Result<HashMap<evm::Address, evm::U256>> {
+    let options = evm::GethDebugTracingOptions {
+        tracer: Some(evm::GethDebugTracerType::BuiltInTracer(
+            evm::GethDebugBuiltInTracerType::PreStateTracer,
+        )),
+        tracer_config: Some(evm::GethDebugTracerConfig::BuiltInTracer(
+            evm::GethDebugBuiltInTracerConfig::PreStateTracer(evm::PreStateConfig {
+                diff_mode: Some(false),
+            }),
+        )),
+        ..Default::default()
+    };
+    let frame: evm::PreStateFrame = client
+        .request("debug_traceTransaction", (hash, options))
+        .await?;
+    match frame {
+        evm::PreStateFrame::Default(state) => Ok(state
+            .0
+            .into_iter()
+            .map(|(address, account)| (address, account.balance.unwrap_or_default()))
+            .collect()),
+        evm::PreStateFrame::Diff(_) => anyhow::bail!("unexpected prestate diff"),
+    }
+}
+
+async fn get_storage_changes(
+    client: &Provider<Http>,
+    hash: evm::H256,
+) -> anyhow::Result<Vec<StorageChange>> {
+    let options = evm::GethDebugTracingOptions {
+        tracer: Some(evm::GethDebugTracerType::BuiltInTracer(
+            evm::GethDebugBuiltInTracerType::PreStateTracer,
+        )),
+        tracer_config: Some(evm::GethDebugTracerConfig::BuiltInTracer(
+            evm::GethDebugBuiltInTracerConfig::PreStateTracer(evm::PreStateConfig {
+                diff_mode: Some(true),
+            }),
+        )),
+        ..Default::default()
+    };
+    let frame: evm::PreStateFrame = client
+        .request("debug_traceTransaction", (hash, options))
+        .await?;
+    match frame {
+        evm::PreStateFrame::Diff(diff) => Ok(storage_changes(diff)),
+        evm::PreStateFrame::Default(_) => anyhow::bail!("expected prestate diff"),
+    }
+}
+
+fn storage_changes(diff: evm::DiffMode) -> Vec<StorageChange> {
+    let mut slots: HashMap<(evm::Address, evm::H256), (evm::H256, evm::H256)> = HashMap::new();
+    for (address, account) in diff.pre {
+        for (key, value) in account.storage.unwrap_or_default() {
+            slots.entry((address, key)).or_default().0 = value;
+        }
+    }
+    // slots cleared by the transaction are missing in the post state
+    for (address, account) in diff.post {
+        for (key, value) in account.storage.unwrap_or_default() {
+            slots.entry((address, key)).or_default().1 = value;
+        }
+    }
+
+    let mut slots: Vec<_> = slots.into_iter().collect();
+    slots.sort();
+    slots
+        .into_iter()
+        .filter(|(_, (old_value, new_value))| old_value != new_value)
+        .map(|((address, key), (old_value, new_value))| StorageChange {
+            address: format!("{:?}", address),
+            key: format!("{:?}", key),
+            old_value: format!("{:?}", old_value),
+            new_value: format!("{:?}", new_value),
+        })
+        .collect()
+}
+
+/// Number of calls in the tree of `call`, itself included.
+fn call_count(call: &evm::CallFrame) -> usize {
+    1 + call.calls.iter().flatten().map(call_count).sum::<usize>()
+}
+
+/// Accounts whose storage the calls write to, in trace order, `None` for calls that can't write
+/// or were reverted. Delegate calls run in the storage of their caller.
+fn storage_accounts(
+    call: &evm::CallFrame,
+    reverted: bool,
+    accounts: &mut Vec<Option<evm::Address>>,
+) {
+    let reverted = reverted || call.error.is_some();
+    let account = match (call.typ.as_str(), &call.to) {
+        _ if reverted => None,
+        ("DELEGATECALL" | "CALLCODE", _) => Some(call.from),
+        ("STATICCALL", _) => None,
+        (_, Some(evm::NameOrAddress::Address(address))) => Some(*address),
+        _ => None,
+    };
+    accounts.push(account);
+    for call in call.calls.iter().flatten() {
+        storage_accounts(call, reverted, accounts);
+    }
+}
+
+/// Hands the state changes of a transaction to the calls making them, `accounts` are
+/// the [`storage_accounts`] of the calls. A storage change belongs to the last call writing
+/// to the storage of its account, the prestate diff only tells the net change of the transaction.
+fn assign_state_changes(
+    traces: &mut [Trace],
+    accounts: &[Option<evm::Address>],
+    balance_changes: Vec<(usize, BalanceChange)>,
+    storage_changes: Vec<StorageChange>,
+) {
+    if traces.is_empty() {
+        return;
+    }
+    for (position, change) in balance_changes {
+        let position = position.min(traces.len() - 1);
+        traces[position].balance_changes.push(change);
+    }
+
+    for change in storage_changes {
+        let position = accounts
+            .iter()
+            .rposition(|account| {
+                account.is_some_and(|account| format!("{:?}", account) == change.address)
+            })
+            .filter(|position| *position < traces.len())
+            .unwrap_or(0);
+        traces[position].storage_changes.push(change);
+    }
+}
+
+/// Synthesizes balance changes of a transaction by replaying
+/// its gas payments and value transfers on top of the pre-state balances.
+/// Every change is recorded with the position of its call in trace order,
+/// gas payments belong to the root call.
+struct BalanceTracker {
+    balances: HashMap<evm::Address, evm::U256>,
+    call: usize,
+    changes: Vec<(usize, BalanceChange)>,
+}
+
+impl BalanceTracker {
+    fn new(balances: HashMap<evm::Address, evm::U256>) -> BalanceTracker {
+        BalanceTracker {
+            balances,
+            call: 0,
+            changes: vec![],
+        }
+    }
+
+    fn apply_transaction(
+        &mut self,
+        tx: &evm::Transaction,
+        receipt: &evm::TransactionReceipt,
+        trace: &evm::GethTrace,
+        coinbase: evm::Address,
+        base_fee: Option<evm::U256>,
+    ) -> anyhow::Result<()> {
+        let call = match trace {
+            evm::GethTrace::Known(evm::GethTraceFrame::CallTracer(call)) => call,
+            _ => anyhow::bail!("unexpected trace frame"),
+        };
+        let gas_price = receipt
+            .effective_gas_price
+            .or(tx.gas_price)
+            .context("no gas price")?;
+        let gas_used = receipt.gas_used.context("no gas used")?;
+
+        self.call = 0;
+        self.debit(tx.from, tx.gas * gas_price, BalanceChangeReason::GasBuy)?;
+        self.apply_call(call, &mut 0)?;
+        self.call = 0;
+        self.credit(
+            tx.from,
+            tx.gas.saturating_sub(gas_used) * gas_price,
+            BalanceChangeReason::GasRefund,
+        )?;
+        let tip = gas_price.saturating_sub(base_fee.unwrap_or_default());
+        self.credit(
+            coinbase,
+            gas_used * tip,
+            BalanceChangeReason::RewardTransactionFee,
+        )?;
+        Ok(())
+    }
+
+    /// `position` is the position of `call` among the calls of its transaction.
+    fn apply_call(&mut self, call: &evm::CallFrame, position: &mut usize) -> anyhow::Result<()> {
+        self.call = *position;
+        // value transfers of a failed call and all its subcalls are reverted
+        if call.error.is_some() {
+            *position += call_count(call);
+            return Ok(());
+        }
+        *position += 1;
+
+        let value = call.value.unwrap_or_default();
+        let to = match &call.to {
+            Some(evm::NameOrAddress::Address(address)) => Some(*address),
+            _ => None,
+        };
+        match (call.typ.as_str(), to) {
+            ("CALL" | "CREATE" | "CREATE2", Some(to)) => {
+                self.debit(call.from, value, BalanceChangeReason::Transfer)?;
+                self.credit(to, value, BalanceChangeReason::Transfer)?;
+            }
+            ("SELFDESTRUCT", Some(to)) => {
+                self.debit(call.from, value, BalanceChangeReason::SuicideWithdraw)?;
+                self.credit(to, value, BalanceChangeReason::SuicideRefund)?;
+            }
+            _ => {}
+        }
+
+        for call in call.calls.iter().flatten() {
+            self.apply_call(call, position)?;
+        }
+        Ok(())
+    }
+
+    fn credit(
+        &mut self,
+        address: evm::Address,
+        amount: evm::U256,
+        reason: BalanceChangeReason,
+    ) -> anyhow::Result<()> {
+        let old_value = self.balances.get(&address).copied().unwrap_or_default();
+        let new_value = old_value
+            .checked_add(amount)
+            .context(format!("balance overflow of {:?}", address))?;
+        self.record(address, old_value, new_value, reason);
+        Ok(())
+    }
+
+    fn debit(
+        &mut self,
+        address: evm::Address,
+        amount: evm::U256,
+        reason: BalanceChangeReason,
+    ) -> anyhow::Result<()> {
+        let old_value = self.balances.get(&address).copied().unwrap_or_default();
+        let new_value = old_value
+            .checked_sub(amount)
+            .context(format!("insufficient balance of {:?}", address))?;
+        self.record(address, old_value, new_value, reason);
+        Ok(())
+    }
+
+    fn record(
+        &mut self,
+        address: evm::Address,
+        old_value: evm::U256,
+        new_value: evm::U256,
+        reason: BalanceChangeReason,
+    ) {
+        if old_value == new_value {
+            return;
+        }
+        self.balances.insert(address, new_value);
+        let change = BalanceChange {
+            address: format!("{:?}", address),
+            old_value: format!("{:#x}", old_value),
+            new_value: format!("{:#x}", new_value),
+            reason,
+        };
+        self.changes.push((self.call, change));
+    }
+}
+
+/// Withdrawn amounts are given in gwei
+const GWEI: u64 = 1_000_000_000;
+
+/// Balance changes of the validator withdrawals credited at the end of a block,
+/// replayed on top of the balances the withdrawn accounts end the block with minus their withdrawals.
+fn withdrawal_changes(
+    withdrawals: &[evm::Withdrawal],
+    mut balances: HashMap<evm::Address, evm::U256>,
+) -> anyhow::Result<Vec<BalanceChange>> {
+    for withdrawal in withdrawals {
+        let balance = balances.entry(withdrawal.address).or_default();
+        *balance = balance
+            .checked_sub(withdrawal.amount * GWEI)
+            .context(format!(
+                "balance of {:?} below its withdrawals",
+                withdrawal.address
+            ))?;
+    }
+    let mut tracker = BalanceTracker::new(balances);
+    for withdrawal in withdrawals {
+        tracker.credit(
+            withdrawal.address,
+            withdrawal.amount * GWEI,
+            BalanceChangeReason::Withdrawal,
+        )?;
+    }
+    Ok(tracker
+        .changes
+        .into_iter()
+        .map(|(_, change)| change)
+        .collect())
+}
+
+async fn get_withdrawal_changes<T>(
+    client: &Provider<Http>,
+    block: &evm::Block<T>,
+) -> anyhow::Result<Vec<BalanceChange>> {
+    let withdrawals = block.withdrawals.as_deref().unwrap_or_default();
+    if withdrawals.is_empty() {
+        return Ok(vec![]);
+    }
+    let number = block.number.context("no block number")?;
+    let addresses: HashSet<_> = withdrawals
+        .iter()
+        .map(|withdrawal| withdrawal.address)
+        .collect();
+    let futures: Vec<_> = addresses
+        .into_iter()
+        .map(|address| async move {
+            let balance = client.get_balance(address, Some(number.into())).await?;
+            Ok::<_, anyhow::Error>((address, balance))
+        })
+        .collect();
+    let balances = join_all(futures)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+    withdrawal_changes(withdrawals, balances)
+}
+
+/// A receipt along with its JSON as the node returned it, when it's kept.
+type Receipt = (evm::TransactionReceipt, Option<String>);
+
+fn parse_receipt(raw: &RawValue, keep_raw: bool) -> anyhow::Result<Receipt> {
+    let receipt = serde_json::from_str(raw.get()).context("invalid receipt")?;
+    Ok((receipt, keep_raw.then(|| raw.get().to_string())))
+}
+
+/// Receipts of the transactions by hash, fetched a block at a time with `eth_getBlockReceipts`.
+/// Nodes without the method are asked for every receipt separately from then on.
+async fn get_receipts(
+    client: &Provider<Http>,
+    tx_by_block: &HashMap<u64, Vec<evm::Transaction>>,
+    block_receipts: &AtomicBool,
+    keep_raw: bool,
+) -> anyhow::Result<HashMap<evm::H256, Receipt>> {
+    let hashes: HashSet<evm::H256> = tx_by_block.values().flatten().map(|tx| tx.hash).collect();
+    if block_receipts.load(Ordering::Relaxed) {
+        let futures: Vec<_> = tx_by_block
+            .iter()
+            .filter(|(_, transactions)| !transactions.is_empty())
+            .map(|(block_num, _)| {
+                let block = evm::BlockNumber::from(*block_num);
+                client.request::<_, Vec<Box<RawValue>>>("eth_getBlockReceipts", [block])
+            })
+            .collect();
+        let results: Result<Vec<_>, _> = join_all(futures).await.into_iter().collect();
+        match results {
+            Ok(receipts) => {
+                let mut receipt_by_hash = HashMap::new();
+                for raw in receipts.into_iter().flatten() {
+                    let receipt = parse_receipt(&raw, keep_raw)?;
+                    if hashes.contains(&receipt.0.transaction_hash) {
+                        receipt_by_hash.insert(receipt.0.transaction_hash, receipt);
+                    }
+                }
+                anyhow::ensure!(
+                    receipt_by_hash.len() == hashes.len(),
+                    "{} block receipts were returned for {} transactions",
+                    receipt_by_hash.len(),
+                    hashes.len()
+                );
+                return Ok(receipt_by_hash);
+            }
+            Err(e) if e.as_error_response().is_some_and(|e| e.code == -32601) => {
+                warn!("eth_getBlockReceipts isn't supported, receipts are fetched one by one");
+                block_receipts.store(false, Ordering::Relaxed);
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+
+    let futures: Vec<_> = hashes
+        .iter()
+        .map(|hash| {
+            client.request::<_, Option<Box<RawValue>>>("eth_getTransactionReceipt", [*hash])
+        })
+        .collect();
+    let mut receipt_by_hash = HashMap::new();
+    for (hash, result) in hashes.iter().zip(join_all(futures).await) {
+        let raw = result?.with_context(|| format!("no receipt of transaction {:?}", hash))?;
+        let receipt = parse_receipt(&raw, keep_raw)?;
+        receipt_by_hash.insert(receipt.0.transaction_hash, receipt);
+    }
+    Ok(receipt_by_hash)
+}
+
 async fn get_requested_data(
     client: &Provider<Http>,
     mut blocks: Vec<evm::Block<evm::Transaction>>,
     request: &DataRequest,
+    block_receipts: &AtomicBool,
+    header_variant: HeaderVariant,
 ) -> anyhow::Result<Vec<Block>> {
     if blocks.is_empty() {
         return Ok(vec![]);
     }
 
+    if request.headers_only {
+        return blocks
+            .into_iter()
+            .map(|block| convert_block(block, header_variant))
+            .collect();
+    }
+
     let range = (
         blocks.first().unwrap().number.unwrap().as_u64(),
         blocks.last().unwrap().number.unwrap().as_u64(),
@@ -162,21 +704,27 @@ async fn get_requested_data(
 
     let logs = get_logs(client, &range, &request.logs).await?;
 
+    let futures: Vec<_> = blocks
+        .iter()
+        .map(|block| get_uncles(client, block))
+        .collect();
+    let mut uncles_by_block: HashMap<u64, Vec<BlockHeader>> = HashMap::new();
+    for (block, result) in blocks.iter().zip(join_all(futures).await) {
+        uncles_by_block.insert(block.number.unwrap().as_u64(), result?);
+    }
+
     let mut logs_transactions = HashSet::new();
     let mut logs_by_block: HashMap<u64, Vec<evm::Log>> = HashMap::new();
     for log in logs {
-        let tx_hash = log.transaction_hash.unwrap().clone();
+        let tx_hash = log.transaction_hash.unwrap();
         logs_transactions.insert(tx_hash);
 
         let block_num = log.block_number.unwrap().as_u64();
-        if logs_by_block.contains_key(&block_num) {
-            logs_by_block.get_mut(&block_num).unwrap().push(log);
-        } else {
-            logs_by_block.insert(block_num, vec![log]);
-        }
+        logs_by_block.entry(block_num).or_default().push(log);
     }
 
     let mut tx_by_block = HashMap::new();
+    // hashes of the transactions to trace, none unless calls are requested
     let mut tx_hashes = vec![];
     for block in &mut blocks {
         let block_num = block.number.unwrap().as_u64();
@@ -184,7 +732,9 @@ async fn get_requested_data(
 
         for tx in block.transactions.drain(..) {
             if logs_transactions.contains(&tx.hash) || is_tx_requested(&tx, request) {
-                tx_hashes.push(tx.hash.clone());
+                if request.traces {
+                    tx_hashes.push(tx.hash);
+                }
                 transactions.push(tx);
             }
         }
@@ -192,16 +742,8 @@ async fn get_requested_data(
         tx_by_block.insert(block_num, transactions);
     }
 
-    let futures: Vec<_> = tx_hashes
-        .iter()
-        .map(|hash| client.get_transaction_receipt(*hash))
-        .collect();
-    let results = join_all(futures).await;
-    let mut receipt_by_hash: HashMap<evm::H256, evm::TransactionReceipt> = HashMap::new();
-    for result in results {
-        let receipt = result?.unwrap();
-        receipt_by_hash.insert(receipt.transaction_hash, receipt);
-    }
+    let mut receipt_by_hash =
+        get_receipts(client, &tx_by_block, block_receipts, request.raw_receipts).await?;
 
     let futures: Vec<_> = tx_hashes
         .into_iter()
@@ -219,21 +761,60 @@ async fn get_requested_data(
                 ..Default::default()
             };
             async move {
-                let result = client.debug_trace_transaction(hash, options).await;
-                (hash, result)
+                let trace = client.debug_trace_transaction(hash, options).await?;
+                let balances = if request.balance_changes {
+                    Some(get_balances(client, hash).await?)
+                } else {
+                    None
+                };
+                let storage_changes = if request.storage_changes {
+                    get_storage_changes(client, hash).await?
+                } else {
+                    vec![]
+                };
+                Ok::<_, anyhow::Error>((hash, trace, balances, storage_changes))
             }
         })
         .collect();
     let results = join_all(futures).await;
+
+    let mut miner_by_block = HashMap::new();
+    for block in &blocks {
+        let block_num = block.number.unwrap().as_u64();
+        let coinbase = block.author.context("no author")?;
+        miner_by_block.insert(block_num, (coinbase, block.base_fee_per_gas));
+    }
+    let tx_by_hash: HashMap<_, _> = tx_by_block
+        .values()
+        .flatten()
+        .map(|tx| (tx.hash, tx))
+        .collect();
+
     let mut traces_by_block: HashMap<u64, Vec<Trace>> = HashMap::new();
-    for (hash, result) in results {
-        let trace = result?;
-        let receipt = receipt_by_hash
+    for result in results {
+        let (hash, trace, balances, storage_changes) = result?;
+        let (receipt, _) = receipt_by_hash
             .get(&hash)
             .expect("receipt is expected to be loaded");
         let block_num = receipt.block_number.unwrap().as_u64();
+        let transaction_index = receipt.transaction_index.as_u32();
+
+        let balance_changes = if let Some(balances) = balances {
+            let tx = tx_by_hash[&hash];
+            let (coinbase, base_fee) = miner_by_block[&block_num];
+            let mut tracker = BalanceTracker::new(balances);
+            tracker.apply_transaction(tx, receipt, &trace, coinbase, base_fee)?;
+            tracker.changes
+        } else {
+            vec![]
+        };
 
-        let mut traces = traverse_trace(trace)?;
+        let mut accounts = vec![];
+        if let evm::GethTrace::Known(evm::GethTraceFrame::CallTracer(call)) = &trace {
+            storage_accounts(call, false, &mut accounts);
+        }
+        let mut traces = traverse_trace(trace, transaction_index)?;
+        assign_state_changes(&mut traces, &accounts, balance_changes, storage_changes);
 
         traces_by_block
             .entry(block_num)
@@ -241,16 +822,27 @@ async fn get_requested_data(
             .or_insert(traces);
     }
 
+    let mut withdrawals_by_block = HashMap::new();
+    if request.balance_changes {
+        let futures: Vec<_> = blocks
+            .iter()
+            .map(|block| get_withdrawal_changes(client, block))
+            .collect();
+        for (block, result) in blocks.iter().zip(join_all(futures).await) {
+            withdrawals_by_block.insert(block.number.unwrap().as_u64(), result?);
+        }
+    }
+
     let blocks = blocks
         .into_iter()
         .map(|block| {
-            let mut block = Block::try_from(block)?;
+            let mut block = convert_block(block, header_variant)?;
 
             let mut logs = logs_by_block
                 .remove(&block.header.number)
                 .unwrap_or_default()
                 .into_iter()
-                .map(|log| Log::try_from(log))
+                .map(Log::try_from)
                 .collect::<Result<Vec<_>, _>>()?;
             logs.sort_by_key(|log| log.log_index);
 
@@ -259,10 +851,12 @@ async fn get_requested_data(
                 .unwrap_or_default()
                 .into_iter()
                 .map(|tx| {
-                    let receipt = receipt_by_hash.remove(&tx.hash).unwrap();
-                    Transaction::try_from((tx, receipt))
+                    let (receipt, raw_receipt) = receipt_by_hash.remove(&tx.hash).unwrap();
+                    let mut tx = Transaction::try_from((tx, receipt))?;
+                    tx.raw_receipt = raw_receipt;
+                    Ok(tx)
                 })
-                .collect::<Result<Vec<_>, _>>()?;
+                .collect::<anyhow::Result<Vec<_>>>()?;
             transactions.sort_by_key(|tx| tx.transaction_index);
 
             let traces = traces_by_block
@@ -272,6 +866,12 @@ async fn get_requested_data(
             block.logs = logs;
             block.transactions = transactions;
             block.traces = traces;
+            block.uncles = uncles_by_block
+                .remove(&block.header.number)
+                .unwrap_or_default();
+            block.balance_changes = withdrawals_by_block
+                .remove(&block.header.number)
+                .unwrap_or_default();
 
             Ok(block)
         })
@@ -312,92 +912,174 @@ impl TryFrom<evm::CallFrame> for Trace {
 
     fn try_from(value: evm::CallFrame) -> Result<Self, Self::Error> {
         let r#type = TraceType::try_from(&value.typ)?;
+        let to = match &value.to {
+            Some(evm::NameOrAddress::Name(_)) => anyhow::bail!("names aren't supported"),
+            Some(evm::NameOrAddress::Address(address)) => Some(format!("{:?}", address)),
+            None => None,
+        };
         let action = match r#type {
-            TraceType::Call => {
-                let to = match value.to.as_ref().context("no to")? {
-                    evm::NameOrAddress::Name(_) => anyhow::bail!("names aren't supported"),
-                    evm::NameOrAddress::Address(address) => address,
-                };
-                Some(TraceAction {
-                    from: Some(format!("{:?}", value.from)),
-                    gas: Some(format!("{:#x}", value.gas)),
-                    input: Some(value.input.to_hex_prefixed()),
-                    to: Some(format!("{:?}", to)),
-                    r#type: Some(CallType::try_from(&value.typ)?),
-                    value: value.value.and_then(|val| Some(format!("{:#x}", val))),
-                })
-            }
+            TraceType::Call => Some(TraceAction {
+                from: Some(format!("{:?}", value.from)),
+                gas: Some(format!("{:#x}", value.gas)),
+                input: Some(value.input.to_hex_prefixed()),
+                to: Some(to.clone().context("no to")?),
+                r#type: Some(CallType::try_from(&value.typ)?),
+                value: value.value.map(|val| format!("{:#x}", val)),
+            }),
             TraceType::Create => Some(TraceAction {
                 from: Some(format!("{:?}", value.from)),
                 gas: Some(format!("{:#x}", value.gas)),
                 input: Some(value.input.to_hex_prefixed()),
                 to: None,
                 r#type: None,
-                value: value.value.and_then(|val| Some(format!("{:#x}", val))),
+                value: value.value.map(|val| format!("{:#x}", val)),
+            }),
+            TraceType::Suicide => Some(TraceAction {
+                from: Some(format!("{:?}", value.from)),
+                gas: None,
+                input: None,
+                to: to.clone(),
+                r#type: None,
+                value: value.value.map(|val| format!("{:#x}", val)),
             }),
-            TraceType::Suicide => None,
             TraceType::Reward => unreachable!(),
         };
         let result = match r#type {
             TraceType::Call => Some(TraceResult {
                 address: None,
                 gas_used: Some(format!("{:#x}", value.gas_used)),
-                output: value.output.and_then(|val| Some(val.to_hex_prefixed())),
+                output: value.output.map(|val| val.to_hex_prefixed()),
+                code: None,
             }),
             TraceType::Create => Some(TraceResult {
-                address: value.to.and_then(|val| Some(format!("{:?}", val))),
+                address: to,
                 gas_used: Some(format!("{:#x}", value.gas_used)),
-                output: value.output.and_then(|val| Some(val.to_hex_prefixed())),
+                // the output of a successful creation is the deployed code
+                code: value
+                    .output
+                    .as_ref()
+                    .filter(|_| value.error.is_none())
+                    .map(|val| val.to_hex_prefixed()),
+                output: value.output.map(|val| val.to_hex_prefixed()),
             }),
             TraceType::Suicide => None,
             TraceType::Reward => unreachable!(),
         };
 
         Ok(Trace {
-            transaction_index: 0, // call_frame has no info about its tx
+            transaction_index: 0,  // call_frame has no info about its tx
+            trace_address: vec![], // neither about its position in the call tree
             r#type,
             action,
             result,
+            balance_changes: vec![],
+            storage_changes: vec![],
+            gas_changes: vec![],
+            keccak_preimages: HashMap::new(),
             error: value.error,
             revert_reason: None, // revert_reason isn't presented in ethers-core crate
         })
     }
 }
 
-impl TryFrom<evm::Block<evm::Transaction>> for Block {
+impl<TX> TryFrom<&evm::Block<TX>> for BlockHeader {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &evm::Block<TX>) -> Result<Self, Self::Error> {
+        Ok(BlockHeader {
+            number: value.number.context("no number")?.as_u64(),
+            hash: format!("{:?}", value.hash.context("no hash")?),
+            parent_hash: format!("{:?}", value.parent_hash),
+            size: value.size.map(|size| format!("{:#x}", size)),
+            sha3_uncles: format!("{:?}", value.uncles_hash),
+            miner: format!("{:?}", value.author.context("no author")?),
+            state_root: format!("{:?}", value.state_root),
+            transactions_root: format!("{:?}", value.transactions_root),
+            receipts_root: format!("{:?}", value.receipts_root),
+            logs_bloom: format!("{:?}", value.logs_bloom.context("no logs bloom")?),
+            difficulty: format!("{:#x}", value.difficulty),
+            total_difficulty: format!(
+                "{:#x}",
+                value.total_difficulty.context("no total difficulty")?
+            ),
+            gas_limit: format!("{:#x}", value.gas_limit),
+            gas_used: format!("{:#x}", value.gas_used),
+            timestamp: value.timestamp.as_u64(),
+            extra_data: value.extra_data.to_hex_prefixed(),
+            mix_hash: format!("{:?}", value.mix_hash.context("no mix hash")?),
+            nonce: format!("{:?}", value.nonce.context("no nonce")?),
+            base_fee_per_gas: value.base_fee_per_gas.map(|val| format!("{:#x}", val)),
+            l2_extra: None,
+            blob_gas_used: quantity(&value.other, "blobGasUsed")?,
+            excess_blob_gas: quantity(&value.other, "excessBlobGas")?,
+            withdrawals_root: value.withdrawals_root.map(|root| format!("{:?}", root)),
+            parent_beacon_root: hash(&value.other, "parentBeaconBlockRoot")?,
+            requests_hash: hash(&value.other, "requestsHash")?,
+        })
+    }
+}
+
+/// Fields added after the ethers-core types were defined, e.g. by EIP-4844, are only present as other fields.
+fn quantity(other: &evm::OtherFields, key: &str) -> anyhow::Result<Option<String>> {
+    let value = other
+        .get_deserialized::<evm::U256>(key)
+        .transpose()
+        .with_context(|| format!("invalid {}", key))?;
+    Ok(value.map(|val| format!("{:#x}", val)))
+}
+
+fn hash(other: &evm::OtherFields, key: &str) -> anyhow::Result<Option<String>> {
+    let value = other
+        .get_deserialized::<evm::H256>(key)
+        .transpose()
+        .with_context(|| format!("invalid {}", key))?;
+    Ok(value.map(|val| format!("{:?}", val)))
+}
+
+/// Arbitrum nodes return the L2 fields next to the regular ones.
+fn l2_extra<TX>(value: &evm::Block<TX>) -> anyhow::Result<L2Extra> {
+    let l1_block_number = value
+        .other
+        .get_deserialized::<evm::U64>("l1BlockNumber")
+        .context("no l1BlockNumber")?
+        .context("invalid l1BlockNumber")?;
+    let send_root = value
+        .other
+        .get_deserialized::<evm::H256>("sendRoot")
+        .context("no sendRoot")?
+        .context("invalid sendRoot")?;
+    Ok(L2Extra {
+        l1_block_number: l1_block_number.as_u64(),
+        send_root: format!("{:?}", send_root),
+    })
+}
+
+/// Converts `value` with the header fields of `header_variant`.
+fn convert_block<TX>(
+    value: evm::Block<TX>,
+    header_variant: HeaderVariant,
+) -> anyhow::Result<Block> {
+    let l2_extra = match header_variant {
+        HeaderVariant::L1 => None,
+        HeaderVariant::Arbitrum => Some(l2_extra(&value)?),
+    };
+    let mut block = Block::try_from(value)?;
+    block.header.l2_extra = l2_extra;
+    Ok(block)
+}
+
+impl<TX> TryFrom<evm::Block<TX>> for Block {
     type Error = anyhow::Error;
 
-    fn try_from(value: evm::Block<evm::Transaction>) -> Result<Self, Self::Error> {
+    fn try_from(value: evm::Block<TX>) -> Result<Self, Self::Error> {
         Ok(Block {
-            header: BlockHeader {
-                number: value.number.context("no number")?.as_u64(),
-                hash: format!("{:?}", value.hash.context("no hash")?),
-                parent_hash: format!("{:?}", value.parent_hash),
-                size: value.size.context("no size")?.as_u64(),
-                sha3_uncles: format!("{:?}", value.uncles_hash),
-                miner: format!("{:?}", value.author.context("no author")?),
-                state_root: format!("{:?}", value.state_root),
-                transactions_root: format!("{:?}", value.transactions_root),
-                receipts_root: format!("{:?}", value.receipts_root),
-                logs_bloom: format!("{:?}", value.logs_bloom.context("no logs bloom")?),
-                difficulty: format!("{:#x}", value.difficulty),
-                total_difficulty: format!(
-                    "{:#x}",
-                    value.total_difficulty.context("no total difficulty")?
-                ),
-                gas_limit: format!("{:#x}", value.gas_limit),
-                gas_used: format!("{:#x}", value.gas_used),
-                timestamp: value.timestamp.as_u64(),
-                extra_data: value.extra_data.to_hex_prefixed(),
-                mix_hash: format!("{:?}", value.mix_hash.context("no mix hash")?),
-                nonce: format!("{:?}", value.nonce.context("no nonce")?),
-                base_fee_per_gas: value
-                    .base_fee_per_gas
-                    .and_then(|val| Some(format!("{:#x}", val))),
-            },
+            header: BlockHeader::try_from(&value)?,
             logs: vec![],
             traces: vec![],
             transactions: vec![],
+            uncles: vec![],
+            balance_changes: vec![],
+            raw: None,
         })
     }
 }
@@ -432,13 +1114,14 @@ impl TryFrom<(evm::Transaction, evm::TransactionReceipt)> for Transaction {
         Ok(Transaction {
             hash: format!("{:?}", tx.hash),
             from: format!("{:?}", tx.from),
-            to: tx.to.and_then(|val| Some(format!("{:?}", val))),
+            to: tx.to.map(|val| format!("{:?}", val)),
             transaction_index: tx
                 .transaction_index
                 .context("no transaction index")?
                 .as_u32(),
             input: tx.input.to_hex_prefixed(),
-            r#type: i32::try_from(tx.transaction_type.context("no transaction type")?)
+            // some rpcs omit the type of legacy transactions
+            r#type: i32::try_from(tx.transaction_type.unwrap_or_default())
                 .map_err(anyhow::Error::msg)?,
             nonce: tx.nonce.as_u64(),
             r: format!("{:#x}", tx.r),
@@ -447,13 +1130,16 @@ impl TryFrom<(evm::Transaction, evm::TransactionReceipt)> for Transaction {
             value: format!("{:#x}", tx.value),
             gas: format!("{:#x}", tx.gas),
             gas_price: format!("{:#x}", tx.gas_price.context("no gas price")?),
-            max_fee_per_gas: tx
-                .max_fee_per_gas
-                .and_then(|val| Some(format!("{:#x}", val))),
-            max_priority_fee_per_gas: tx
-                .max_priority_fee_per_gas
-                .and_then(|val| Some(format!("{:#x}", val))),
-            y_parity: None,
+            max_fee_per_gas: tx.max_fee_per_gas.map(|val| format!("{:#x}", val)),
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas.map(|val| format!("{:#x}", val)),
+            y_parity: tx
+                .other
+                .get_deserialized::<evm::U64>("yParity")
+                .transpose()
+                .context("invalid yParity")?
+                .map(|parity| u8::try_from(parity.as_u64()))
+                .transpose()?,
+            chain_id: tx.chain_id.map(|val| val.as_u64()),
             cumulative_gas_used: format!("{:#x}", receipt.cumulative_gas_used),
             effective_gas_price: format!(
                 "{:#x}",
@@ -464,10 +1150,38 @@ impl TryFrom<(evm::Transaction, evm::TransactionReceipt)> for Transaction {
             gas_used: format!("{:#x}", receipt.gas_used.context("no gas used")?),
             status: i32::try_from(receipt.status.context("no status")?)
                 .map_err(anyhow::Error::msg)?,
+            access_list: tx
+                .access_list
+                .map(|list| list.0.into_iter().map(AccessTuple::from).collect())
+                .unwrap_or_default(),
+            max_fee_per_blob_gas: quantity(&tx.other, "maxFeePerBlobGas")?,
+            blob_versioned_hashes: tx
+                .other
+                .get_deserialized::<Vec<evm::H256>>("blobVersionedHashes")
+                .transpose()
+                .context("invalid blobVersionedHashes")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|hash| format!("{:?}", hash))
+                .collect(),
+            raw_receipt: None,
         })
     }
 }
 
+impl From<evm::transaction::eip2930::AccessListItem> for AccessTuple {
+    fn from(value: evm::transaction::eip2930::AccessListItem) -> Self {
+        AccessTuple {
+            address: format!("{:?}", value.address),
+            storage_keys: value
+                .storage_keys
+                .into_iter()
+                .map(|key| format!("{:?}", key))
+                .collect(),
+        }
+    }
+}
+
 fn split_range(from: u64, to: u64) -> Vec<Range> {
     assert!(from <= to);
     let step = 100;
@@ -483,8 +1197,12 @@ fn split_range(from: u64, to: u64) -> Vec<Range> {
 
 pub struct RpcDataSource {
     client: Provider<Http>,
+    ws_url: Option<String>,
     height_tracker: Arc<HeightTracker>,
     finality_confirmation: u64,
+    /// Cleared once the node turns out not to support `eth_getBlockReceipts`
+    block_receipts: Arc<AtomicBool>,
+    header_variant: HeaderVariant,
 }
 
 #[async_trait::async_trait]
@@ -497,6 +1215,8 @@ impl DataSource for RpcDataSource {
         let client = self.client.clone();
         let finality_confirmation = self.finality_confirmation;
         let height_tracker = self.height_tracker.clone();
+        let block_receipts = self.block_receipts.clone();
+        let header_variant = self.header_variant;
 
         Ok(Box::new(try_stream! {
             let height = get_finalized_height(&height_tracker, finality_confirmation).await?;
@@ -512,7 +1232,12 @@ impl DataSource for RpcDataSource {
 
             let ranges = split_range(request.from, to);
             for chunk in ranges.chunks(5) {
-                let futures: Vec<_> = chunk.into_iter().map(|range| get_stride(&client, range, &request)).collect();
+                let futures: Vec<_> = chunk
+                    .iter()
+                    .map(|range| {
+                        get_stride(&client, range, &request, &block_receipts, header_variant)
+                    })
+                    .collect();
                 let results = join_all(futures).await;
 
                 let mut blocks = vec![];
@@ -539,6 +1264,15 @@ impl DataSource for RpcDataSource {
         let hash = format!("{:?}", block.hash.context("hash is empty")?);
         Ok(hash)
     }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        self.height_tracker.height().await
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        let chain_id = self.client.get_chainid().await?;
+        Ok(Some(chain_id.as_u64()))
+    }
 }
 
 #[async_trait::async_trait]
@@ -551,22 +1285,45 @@ impl HotSource for RpcDataSource {
         let client = self.client.clone();
         let finality_confirmation = self.finality_confirmation;
         let height_tracker = self.height_tracker.clone();
+        let block_receipts = self.block_receipts.clone();
+        let header_variant = self.header_variant;
+        let height_updates: Pin<Box<dyn Stream<Item = anyhow::Result<u64>> + Send>> =
+            match &self.ws_url {
+                Some(url) => Box::pin(subscribe_height_updates(
+                    url.clone(),
+                    height_tracker.clone(),
+                    request.from,
+                )),
+                None => Box::pin(get_height_updates(height_tracker.clone(), request.from)),
+            };
 
         Ok(Box::new(try_stream! {
             let mut nav = ForkNavigator::new(state, |block_id| {
                 let client = client.clone();
                 let request = request.clone();
+                let block_receipts = block_receipts.clone();
                 async move {
-                    let rpc_block = client.get_block_with_txs(block_id).await?.unwrap();
-                    let mut blocks = get_requested_data(&client, vec![rpc_block], &request).await?;
-                    let block = blocks.remove(0);
+                    let (rpc_block, raw) =
+                        get_raw_block(&client, block_id, true, request.raw_blocks)
+                            .await?
+                            .unwrap();
+                    let mut blocks = get_requested_data(
+                        &client,
+                        vec![rpc_block],
+                        &request,
+                        &block_receipts,
+                        header_variant,
+                    )
+                    .await?;
+                    let mut block = blocks.remove(0);
+                    block.raw = raw;
                     Ok(block)
                 }
             });
 
-            for await result in get_height_updates(height_tracker, request.from) {
+            for await result in height_updates {
                 let top = result?;
-                let finalized = top.saturating_sub(finality_confirmation);
+                let finalized = finalized_height(top, finality_confirmation);
                 let height = nav.get_height();
 
                 for number in height + 1..top {
@@ -588,22 +1345,105 @@ impl HotSource for RpcDataSource {
     fn as_ds(&self) -> &(dyn DataSource + Send + Sync) {
         self
     }
+
+    /// Fetches all the hashes concurrently instead of one after another.
+    async fn get_block_hashes(&self, from: u64, to: u64) -> anyhow::Result<Vec<String>> {
+        join_all((from..=to).map(|height| self.get_block_hash(height)))
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    async fn get_tagged_height(&self, tag: BlockTag) -> anyhow::Result<u64> {
+        get_tagged_height(&self.client, tag).await
+    }
 }
 
 impl HotDataSource for RpcDataSource {}
 
+/// How often the head is polled while waiting for new blocks.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 impl RpcDataSource {
-    pub fn new(url: String, finality_confirmation: u64) -> RpcDataSource {
+    pub fn new(url: String, ws_url: Option<String>, finality_confirmation: u64) -> RpcDataSource {
         let client = Provider::<Http>::try_from(url).unwrap();
-        let height_tracker = Arc::new(HeightTracker::new(client.clone(), Duration::from_secs(1)));
+        let height_tracker = Arc::new(HeightTracker::new(
+            client.clone(),
+            DEFAULT_POLL_INTERVAL,
+            DEFAULT_POLL_INTERVAL,
+        ));
         RpcDataSource {
             client,
+            ws_url,
             height_tracker,
             finality_confirmation,
+            block_receipts: Arc::new(AtomicBool::new(true)),
+            header_variant: HeaderVariant::L1,
         }
     }
+
+    /// The L2 header fields are read only for L2 variants, and required then.
+    pub fn with_header_variant(mut self, header_variant: HeaderVariant) -> Self {
+        self.header_variant = header_variant;
+        self
+    }
+
+    /// The head is polled every `interval`, slowing down up to `max_interval`
+    /// while no new block shows up.
+    pub fn with_poll_interval(mut self, interval: Duration, max_interval: Duration) -> Self {
+        self.height_tracker = Arc::new(HeightTracker::new(
+            self.client.clone(),
+            interval,
+            max_interval.max(interval),
+        ));
+        self
+    }
 }
 
+/// Streams heights pushed by a `newHeads` subscription.
+/// While the subscription is down heights are polled until the next reconnection attempt.
+fn subscribe_height_updates(
+    url: String,
+    height_tracker: Arc<HeightTracker>,
+    from: u64,
+) -> impl Stream<Item = anyhow::Result<u64>> {
+    try_stream! {
+        let mut from = from;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match Provider::<Ws>::connect(&url).await {
+                Ok(provider) => match provider.subscribe_blocks().await {
+                    Ok(mut heads) => {
+                        backoff = MIN_BACKOFF;
+                        while let Some(head) = heads.next().await {
+                            if let Some(number) = head.number.map(|number| number.as_u64()) {
+                                if number >= from {
+                                    yield number;
+                                    from = number + 1;
+                                }
+                            }
+                        }
+                        warn!("new heads subscription dropped");
+                    }
+                    Err(e) => warn!("failed to subscribe to new heads: {}", e),
+                },
+                Err(e) => warn!("failed to connect to {}: {}", url, e),
+            }
+
+            let reconnect_at = tokio::time::Instant::now() + backoff;
+            while let Ok(result) = tokio::time::timeout_at(reconnect_at, height_tracker.wait(from)).await {
+                let height = result?;
+                yield height;
+                from = height + 1;
+            }
+            backoff = min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 fn get_height_updates(
     height_tracker: Arc<HeightTracker>,
     from: u64,
@@ -729,10 +1569,11 @@ where
 struct HeightTracker {
     tx: mpsc::UnboundedSender<(u128, oneshot::Sender<anyhow::Result<u64>>)>,
     interval: Duration,
+    max_interval: Duration,
 }
 
 impl HeightTracker {
-    fn new(client: Provider<Http>, interval: Duration) -> HeightTracker {
+    fn new(client: Provider<Http>, interval: Duration, max_interval: Duration) -> HeightTracker {
         let (tx, mut rx) =
             mpsc::unbounded_channel::<(u128, oneshot::Sender<anyhow::Result<u64>>)>();
 
@@ -765,7 +1606,11 @@ impl HeightTracker {
             }
         });
 
-        HeightTracker { tx, interval }
+        HeightTracker {
+            tx,
+            interval,
+            max_interval,
+        }
     }
 
     async fn height(&self) -> anyhow::Result<u64> {
@@ -778,12 +1623,1162 @@ impl HeightTracker {
         Ok(height)
     }
 
+    /// Polls until the head reaches `height`, the delay between polls doubles
+    /// up to the max interval for as long as the head doesn't move.
     async fn wait(&self, height: u64) -> anyhow::Result<u64> {
         let mut current = self.height().await?;
+        let mut delay = self.interval;
         while current < height {
-            tokio::time::sleep(self.interval).await;
+            tokio::time::sleep(delay).await;
+            let previous = current;
             current = self.height().await?;
+            delay = if current > previous {
+                self.interval
+            } else {
+                min(delay * 2, self.max_interval)
+            };
         }
         Ok(current)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::{
+        BalanceChangeReason, DataRequest, LogRequest, StorageChange, TransactionRequest,
+    };
+    use crate::datasource::{
+        Block, BlockHeader, BlockTag, HashAndHeight, HeaderVariant, Trace, TraceType, Transaction,
+    };
+    use crate::ds_rpc::{
+        assign_state_changes, convert_block, finalized_height, get_receipts, get_requested_data,
+        get_stride, get_tagged_height, is_tx_requested, storage_accounts, storage_changes,
+        subscribe_height_updates, traverse_trace, withdrawal_changes, BalanceTracker,
+        ForkNavigator, HeightTracker,
+    };
+    use crate::pbcodec;
+    use ethers_core::types as evm;
+    use ethers_providers::{Http, Provider};
+    use futures_util::{SinkExt, StreamExt};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use serde_json::json;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn transfer_balance_changes() {
+        let sender = evm::Address::from_low_u64_be(1);
+        let recipient = evm::Address::from_low_u64_be(2);
+        let coinbase = evm::Address::from_low_u64_be(3);
+        let forwarded = evm::Address::from_low_u64_be(4);
+
+        let tx = evm::Transaction {
+            from: sender,
+            gas: 30_000.into(),
+            ..Default::default()
+        };
+        let receipt = evm::TransactionReceipt {
+            gas_used: Some(21_000.into()),
+            effective_gas_price: Some(10.into()),
+            ..Default::default()
+        };
+        let forward = |value: u64, error: Option<&str>| evm::CallFrame {
+            typ: "CALL".to_string(),
+            from: recipient,
+            to: Some(forwarded.into()),
+            value: Some(value.into()),
+            error: error.map(str::to_string),
+            ..Default::default()
+        };
+        // the reverted call keeps its position, the transfer after it is the third call
+        let trace = evm::GethTrace::Known(evm::GethTraceFrame::CallTracer(evm::CallFrame {
+            typ: "CALL".to_string(),
+            from: sender,
+            to: Some(recipient.into()),
+            value: Some(1_000.into()),
+            calls: Some(vec![forward(500, Some("reverted")), forward(100, None)]),
+            ..Default::default()
+        }));
+
+        let balances = HashMap::from([(sender, 1_000_000.into())]);
+        let mut tracker = BalanceTracker::new(balances);
+        tracker
+            .apply_transaction(&tx, &receipt, &trace, coinbase, Some(7.into()))
+            .unwrap();
+
+        let changes: Vec<_> = tracker
+            .changes
+            .iter()
+            .map(|(call, change)| {
+                (
+                    *call,
+                    change.address.as_str(),
+                    change.old_value.as_str(),
+                    change.new_value.as_str(),
+                    change.reason,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    0,
+                    "0x0000000000000000000000000000000000000001",
+                    "0xf4240",
+                    "0xaae60",
+                    BalanceChangeReason::GasBuy
+                ),
+                (
+                    0,
+                    "0x0000000000000000000000000000000000000001",
+                    "0xaae60",
+                    "0xaaa78",
+                    BalanceChangeReason::Transfer
+                ),
+                (
+                    0,
+                    "0x0000000000000000000000000000000000000002",
+                    "0x0",
+                    "0x3e8",
+                    BalanceChangeReason::Transfer
+                ),
+                (
+                    2,
+                    "0x0000000000000000000000000000000000000002",
+                    "0x3e8",
+                    "0x384",
+                    BalanceChangeReason::Transfer
+                ),
+                (
+                    2,
+                    "0x0000000000000000000000000000000000000004",
+                    "0x0",
+                    "0x64",
+                    BalanceChangeReason::Transfer
+                ),
+                (
+                    0,
+                    "0x0000000000000000000000000000000000000001",
+                    "0xaaa78",
+                    "0xc0a08",
+                    BalanceChangeReason::GasRefund
+                ),
+                (
+                    0,
+                    "0x0000000000000000000000000000000000000003",
+                    "0x0",
+                    "0xf618",
+                    BalanceChangeReason::RewardTransactionFee
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn storage_changes_of_calls() {
+        let caller = evm::Address::from_low_u64_be(1);
+        let token = evm::Address::from_low_u64_be(0xc0);
+        let library = evm::Address::from_low_u64_be(0xc1);
+        let other = evm::Address::from_low_u64_be(0xc2);
+        let call = |typ: &str, from, to, error: Option<&str>, calls| evm::CallFrame {
+            typ: typ.to_string(),
+            from,
+            to: Some(evm::NameOrAddress::Address(to)),
+            error: error.map(str::to_string),
+            calls: Some(calls),
+            ..Default::default()
+        };
+        // the token writes through its library, the write to the other contract is reverted
+        let root = call(
+            "CALL",
+            caller,
+            token,
+            None,
+            vec![
+                call("DELEGATECALL", token, library, None, vec![]),
+                call("STATICCALL", token, other, None, vec![]),
+                call(
+                    "CALL",
+                    token,
+                    other,
+                    Some("reverted"),
+                    vec![call("CALL", other, token, None, vec![])],
+                ),
+            ],
+        );
+        let mut accounts = vec![];
+        storage_accounts(&root, false, &mut accounts);
+        assert_eq!(accounts, vec![Some(token), Some(token), None, None, None]);
+
+        let change = |address: evm::Address| StorageChange {
+            address: format!("{:?}", address),
+            key: format!("{:?}", evm::H256::zero()),
+            old_value: format!("{:?}", evm::H256::zero()),
+            new_value: format!("{:?}", evm::H256::from_low_u64_be(1)),
+        };
+        let mut traces = traverse_trace(
+            evm::GethTrace::Known(evm::GethTraceFrame::CallTracer(root)),
+            0,
+        )
+        .unwrap();
+        assign_state_changes(
+            &mut traces,
+            &accounts,
+            vec![],
+            vec![change(token), change(other)],
+        );
+        let counts: Vec<_> = traces
+            .iter()
+            .map(|trace| trace.storage_changes.len())
+            .collect();
+        // the delegate call wrote to the token, an unattributed change stays with the root call
+        assert_eq!(counts, vec![1, 1, 0, 0, 0]);
+        assert_eq!(traces[0].storage_changes[0].address, format!("{:?}", other));
+    }
+
+    #[test]
+    fn call_filter_selects_transactions() {
+        let request = DataRequest {
+            from: 0,
+            to: None,
+            logs: vec![],
+            transactions: vec![TransactionRequest {
+                to: vec!["0xdac17f958d2ee523a2206206994597c13d831ec7".to_string()],
+                sighash: vec!["0xa9059cbb".to_string()],
+                from: vec![],
+            }],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        };
+        let tx = |to: &str, input: &str| evm::Transaction {
+            to: Some(to.parse().unwrap()),
+            input: prefix_hex::decode::<Vec<u8>>(input).unwrap().into(),
+            ..Default::default()
+        };
+
+        let transfer = tx(
+            "0xdac17f958d2ee523a2206206994597c13d831ec7",
+            "0xa9059cbb0000",
+        );
+        let approve = tx(
+            "0xdac17f958d2ee523a2206206994597c13d831ec7",
+            "0x095ea7b30000",
+        );
+        let other = tx(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            "0xa9059cbb0000",
+        );
+
+        assert!(is_tx_requested(&transfer, &request));
+        assert!(!is_tx_requested(&approve, &request));
+        assert!(!is_tx_requested(&other, &request));
+    }
+
+    #[test]
+    fn any_transaction_request_matches() {
+        let usdt = "0xdac17f958d2ee523a2206206994597c13d831ec7";
+        let usdc = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+        let request = DataRequest {
+            from: 0,
+            to: None,
+            logs: vec![],
+            transactions: vec![
+                TransactionRequest {
+                    to: vec![usdt.to_string()],
+                    sighash: vec!["0xa9059cbb".to_string()],
+                    from: vec![],
+                },
+                TransactionRequest {
+                    to: vec![usdc.to_string()],
+                    sighash: vec!["0x095ea7b3".to_string()],
+                    from: vec![],
+                },
+            ],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        };
+        let tx = |to: &str, input: &str| evm::Transaction {
+            to: Some(to.parse().unwrap()),
+            input: prefix_hex::decode::<Vec<u8>>(input).unwrap().into(),
+            ..Default::default()
+        };
+
+        assert!(is_tx_requested(&tx(usdt, "0xa9059cbb0000"), &request));
+        assert!(is_tx_requested(&tx(usdc, "0x095ea7b30000"), &request));
+        // criteria are not mixed between requests
+        assert!(!is_tx_requested(&tx(usdt, "0x095ea7b30000"), &request));
+        assert!(!is_tx_requested(&tx(usdc, "0xa9059cbb0000"), &request));
+    }
+
+    #[test]
+    fn sstore_storage_changes() {
+        let contract = evm::Address::from_low_u64_be(0xc0);
+        let slot = evm::H256::from_low_u64_be;
+        let account = |storage: Vec<(u64, u64)>| evm::AccountState {
+            storage: Some(
+                storage
+                    .into_iter()
+                    .map(|(key, value)| (slot(key), slot(value)))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        // slot 0 is written, slot 1 is cleared and slot 2 is overwritten
+        let diff = evm::DiffMode {
+            pre: BTreeMap::from([(contract, account(vec![(1, 5), (2, 7)]))]),
+            post: BTreeMap::from([(contract, account(vec![(0, 1), (2, 8)]))]),
+        };
+
+        let change = |key: u64, old_value: u64, new_value: u64| StorageChange {
+            address: format!("{:?}", contract),
+            key: format!("{:?}", slot(key)),
+            old_value: format!("{:?}", slot(old_value)),
+            new_value: format!("{:?}", slot(new_value)),
+        };
+        assert_eq!(
+            storage_changes(diff),
+            vec![change(0, 0, 1), change(1, 5, 0), change(2, 7, 8)]
+        );
+    }
+
+    #[test]
+    fn create2_frame() {
+        let factory = evm::Address::from_low_u64_be(0xf0);
+        let deployed = evm::Address::from_low_u64_be(0xd0);
+        let frame = |typ: &str| evm::CallFrame {
+            typ: typ.to_string(),
+            from: factory,
+            to: Some(deployed.into()),
+            gas: 100_000.into(),
+            gas_used: 50_000.into(),
+            input: vec![0x60, 0x00].into(),
+            output: Some(vec![0x00].into()),
+            ..Default::default()
+        };
+
+        let trace = Trace::try_from(frame("CREATE2")).unwrap();
+        assert!(matches!(trace.r#type, TraceType::Create));
+        let action = trace.action.unwrap();
+        assert_eq!(action.from, Some(format!("{:?}", factory)));
+        assert_eq!(
+            trace.result.unwrap().address,
+            Some(format!("{:?}", deployed))
+        );
+
+        let trace = Trace::try_from(frame("CREATE")).unwrap();
+        assert!(matches!(trace.r#type, TraceType::Create));
+    }
+
+    fn new_head(number: u64) -> serde_json::Value {
+        let hash = format!("0x{:064x}", number);
+        let zero = format!("0x{:064x}", 0);
+        json!({
+            "hash": hash,
+            "parentHash": format!("0x{:064x}", number - 1),
+            "sha3Uncles": zero,
+            "miner": format!("0x{:040x}", 0),
+            "stateRoot": zero,
+            "transactionsRoot": zero,
+            "receiptsRoot": zero,
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "difficulty": "0x0",
+            "number": format!("{:#x}", number),
+            "gasLimit": "0x1c9c380",
+            "gasUsed": "0x0",
+            "timestamp": "0x64e5a2b0",
+            "extraData": "0x",
+            "mixHash": zero,
+            "nonce": "0x0000000000000000",
+            "baseFeePerGas": "0x7",
+        })
+    }
+
+    #[tokio::test]
+    async fn new_heads_subscription() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+
+            let request = ws.next().await.unwrap().unwrap();
+            let request: serde_json::Value =
+                serde_json::from_str(request.to_text().unwrap()).unwrap();
+            assert_eq!(request["method"], "eth_subscribe");
+            assert_eq!(request["params"][0], "newHeads");
+            let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+            ws.send(Message::Text(response.to_string())).await.unwrap();
+
+            // a stale head followed by two new ones
+            for number in [0xf, 0x10, 0x11] {
+                let notification = json!({
+                    "jsonrpc": "2.0",
+                    "method": "eth_subscription",
+                    "params": {"subscription": "0x1", "result": new_head(number)},
+                });
+                ws.send(Message::Text(notification.to_string()))
+                    .await
+                    .unwrap();
+            }
+            while ws.next().await.is_some() {}
+        });
+
+        // never polled while the subscription is alive
+        let client = Provider::<Http>::try_from("http://127.0.0.1:1").unwrap();
+        let height_tracker = Arc::new(HeightTracker::new(
+            client,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ));
+        let heights: Vec<u64> = subscribe_height_updates(url, height_tracker, 0x10)
+            .take(2)
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(heights, vec![0x10, 0x11]);
+    }
+
+    type Respond = dyn Fn(&str, &serde_json::Value) -> Result<serde_json::Value, i64> + Send + Sync;
+
+    /// Answers json-rpc requests with `respond`, an error is sent as a json-rpc error code.
+    /// Returns the methods that were called.
+    async fn mock_rpc(respond: Arc<Respond>) -> (Provider<Http>, Arc<Mutex<Vec<String>>>) {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let make_service = make_service_fn(move |_| {
+            let respond = respond.clone();
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                    let respond = respond.clone();
+                    let recorded = recorded.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await?;
+                        let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                        let method = request["method"].as_str().unwrap();
+                        recorded.lock().unwrap().push(method.to_string());
+                        let response = match respond(method, &request["params"]) {
+                            Ok(result) => {
+                                json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+                            }
+                            Err(code) => json!({
+                                "jsonrpc": "2.0",
+                                "id": request["id"],
+                                "error": {"code": code, "message": "mock error"},
+                            }),
+                        };
+                        Ok::<_, hyper::Error>(Response::new(Body::from(response.to_string())))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (Provider::<Http>::try_from(url).unwrap(), calls)
+    }
+
+    #[tokio::test]
+    async fn tagged_heights() {
+        let (client, calls) = mock_rpc(Arc::new(|method, params| {
+            assert_eq!(method, "eth_getBlockByNumber");
+            let number = match params[0].as_str().unwrap() {
+                "latest" => 20,
+                "safe" => 16,
+                "finalized" => 12,
+                tag => panic!("unexpected tag {}", tag),
+            };
+            Ok(new_head(number))
+        }))
+        .await;
+
+        assert_eq!(
+            get_tagged_height(&client, BlockTag::Latest).await.unwrap(),
+            20
+        );
+        assert_eq!(
+            get_tagged_height(&client, BlockTag::Safe).await.unwrap(),
+            16
+        );
+        assert_eq!(
+            get_tagged_height(&client, BlockTag::Finalized)
+                .await
+                .unwrap(),
+            12
+        );
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn idle_head_polling_slows_down() {
+        let (client, calls) = mock_rpc(Arc::new(|method, _| {
+            assert_eq!(method, "eth_blockNumber");
+            Ok(json!("0xa"))
+        }))
+        .await;
+        let height_tracker = HeightTracker::new(
+            client,
+            Duration::from_millis(20),
+            Duration::from_millis(160),
+        );
+
+        assert_eq!(height_tracker.wait(10).await.unwrap(), 10);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+
+        // the head never reaches 11, polls are 20, 40, 80, 160, 160.. ms apart
+        let waited =
+            tokio::time::timeout(Duration::from_millis(500), height_tracker.wait(11)).await;
+        assert!(waited.is_err());
+        let polls = calls.lock().unwrap().len() - 1;
+        assert!((2..=5).contains(&polls), "{} polls", polls);
+    }
+
+    fn receipt(hash: u64, block: u64, index: u64) -> evm::TransactionReceipt {
+        evm::TransactionReceipt {
+            transaction_hash: evm::H256::from_low_u64_be(hash),
+            transaction_index: index.into(),
+            block_number: Some(block.into()),
+            cumulative_gas_used: (21_000 * (index + 1)).into(),
+            gas_used: Some(21_000.into()),
+            status: Some(1.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Serves the receipts of blocks 1 and 2, `eth_getBlockReceipts` only when `block_receipts`.
+    fn receipts_rpc(block_receipts: bool) -> Arc<Respond> {
+        let blocks = HashMap::from([
+            (
+                1,
+                vec![receipt(0xa, 1, 0), receipt(0xb, 1, 1), receipt(0xd, 1, 2)],
+            ),
+            (2, vec![receipt(0xc, 2, 0)]),
+        ]);
+        Arc::new(move |method, params| match method {
+            "eth_getBlockReceipts" if block_receipts => {
+                let number = params[0].as_str().unwrap().trim_start_matches("0x");
+                let number = u64::from_str_radix(number, 16).unwrap();
+                Ok(serde_json::to_value(&blocks[&number]).unwrap())
+            }
+            "eth_getBlockReceipts" => Err(-32601),
+            "eth_getTransactionReceipt" => {
+                let hash: evm::H256 = serde_json::from_value(params[0].clone()).unwrap();
+                let receipt = blocks
+                    .values()
+                    .flatten()
+                    .find(|receipt| receipt.transaction_hash == hash);
+                Ok(serde_json::to_value(receipt).unwrap())
+            }
+            _ => panic!("unexpected {}", method),
+        })
+    }
+
+    #[tokio::test]
+    async fn block_receipts_match_transaction_receipts() {
+        let tx = |hash: u64| evm::Transaction {
+            hash: evm::H256::from_low_u64_be(hash),
+            ..Default::default()
+        };
+        // 0xd isn't requested
+        let tx_by_block =
+            HashMap::from([(1, vec![tx(0xa), tx(0xb)]), (2, vec![tx(0xc)]), (3, vec![])]);
+
+        let (client, calls) = mock_rpc(receipts_rpc(true)).await;
+        let supported = AtomicBool::new(true);
+        let batched = get_receipts(&client, &tx_by_block, &supported, false)
+            .await
+            .unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["eth_getBlockReceipts"; 2]);
+        assert!(supported.load(Ordering::SeqCst));
+
+        let (client, calls) = mock_rpc(receipts_rpc(false)).await;
+        let unsupported = AtomicBool::new(true);
+        let separate = get_receipts(&client, &tx_by_block, &unsupported, false)
+            .await
+            .unwrap();
+        assert!(!unsupported.load(Ordering::SeqCst));
+        assert_eq!(separate, batched);
+        let mut hashes: Vec<_> = batched.keys().copied().collect();
+        hashes.sort();
+        assert_eq!(hashes, [0xa, 0xb, 0xc].map(evm::H256::from_low_u64_be));
+
+        // the unsupported method isn't tried again
+        calls.lock().unwrap().clear();
+        get_receipts(&client, &tx_by_block, &unsupported, false)
+            .await
+            .unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["eth_getTransactionReceipt"; 3]);
+    }
+
+    #[tokio::test]
+    async fn raw_receipts_are_kept() {
+        let tx_by_block = HashMap::from([(
+            1,
+            vec![evm::Transaction {
+                hash: evm::H256::from_low_u64_be(0xa),
+                ..Default::default()
+            }],
+        )]);
+        let served = serde_json::to_value(receipt(0xa, 1, 0)).unwrap();
+
+        for block_receipts in [true, false] {
+            let (client, _) = mock_rpc(receipts_rpc(block_receipts)).await;
+            let supported = AtomicBool::new(block_receipts);
+            let receipts = get_receipts(&client, &tx_by_block, &supported, true)
+                .await
+                .unwrap();
+            let (receipt, raw) = &receipts[&evm::H256::from_low_u64_be(0xa)];
+            assert_eq!(receipt.transaction_index, 0.into());
+            let raw: serde_json::Value = serde_json::from_str(raw.as_ref().unwrap()).unwrap();
+            assert_eq!(raw, served);
+
+            let receipts = get_receipts(&client, &tx_by_block, &supported, false)
+                .await
+                .unwrap();
+            assert!(receipts.values().all(|(_, raw)| raw.is_none()));
+        }
+    }
+
+    #[test]
+    fn l1_and_l2_headers() {
+        let mut value = new_head(1);
+        value["size"] = json!("0x220");
+        value["totalDifficulty"] = json!("0x0");
+
+        let block: evm::Block<evm::H256> = serde_json::from_value(value.clone()).unwrap();
+        let header = convert_block(block.clone(), HeaderVariant::L1)
+            .unwrap()
+            .header;
+        assert!(header.l2_extra.is_none());
+        // an arbitrum node always returns the L2 fields
+        assert!(convert_block(block, HeaderVariant::Arbitrum).is_err());
+
+        let send_root = format!("0x{:064x}", 0xabc);
+        value["l1BlockNumber"] = json!("0x11a5c8e");
+        value["sendRoot"] = json!(send_root);
+        let block: evm::Block<evm::H256> = serde_json::from_value(value).unwrap();
+        let header = convert_block(block.clone(), HeaderVariant::L1)
+            .unwrap()
+            .header;
+        assert!(header.l2_extra.is_none());
+        let header = convert_block(block, HeaderVariant::Arbitrum)
+            .unwrap()
+            .header;
+        let l2_extra = header.l2_extra.unwrap();
+        assert_eq!(l2_extra.l1_block_number, 0x11a5c8e);
+        assert_eq!(l2_extra.send_root, send_root);
+    }
+
+    #[test]
+    fn genesis_block() {
+        // eth_getBlockByNumber("0x0", true) on mainnet
+        let zero = format!("0x{:064x}", 0);
+        let value = json!({
+            "number": "0x0",
+            "hash": "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
+            "parentHash": zero,
+            "sha3Uncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+            "miner": format!("0x{:040x}", 0),
+            "stateRoot": "0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544",
+            "transactionsRoot": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+            "receiptsRoot": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "difficulty": "0x400000000",
+            "totalDifficulty": "0x400000000",
+            "gasLimit": "0x1388",
+            "gasUsed": "0x0",
+            "timestamp": "0x0",
+            "extraData": "0x11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa",
+            "mixHash": zero,
+            "nonce": "0x0000000000000042",
+            "size": "0x21c",
+            "transactions": [],
+            "uncles": []
+        });
+        let block: evm::Block<evm::Transaction> = serde_json::from_value(value).unwrap();
+        let block = pbcodec::Block::try_from(Block::try_from(block).unwrap()).unwrap();
+
+        assert_eq!(block.number, 0);
+        assert_eq!(block.size, 540);
+        assert!(block.transaction_traces.is_empty());
+        assert!(block.balance_changes.is_empty());
+        let header = block.header.unwrap();
+        assert_eq!(header.parent_hash, vec![0; 32]);
+        assert_eq!(header.difficulty.unwrap().bytes, vec![4, 0, 0, 0, 0]);
+        assert_eq!(header.total_difficulty.unwrap().bytes, vec![4, 0, 0, 0, 0]);
+        assert_eq!(header.nonce, 0x42);
+        assert_eq!(header.gas_used, 0);
+        assert_eq!(header.timestamp.unwrap().seconds, 0);
+        assert_eq!(header.extra_data.len(), 32);
+        assert_eq!(header.base_fee_per_gas, None);
+    }
+
+    #[test]
+    fn blob_fields() {
+        let mut value = new_head(1);
+        value["size"] = json!("0x220");
+        value["totalDifficulty"] = json!("0x0");
+        let block: evm::Block<evm::H256> = serde_json::from_value(value.clone()).unwrap();
+        let header = BlockHeader::try_from(&block).unwrap();
+        assert_eq!(header.blob_gas_used, None);
+        assert_eq!(header.excess_blob_gas, None);
+
+        value["blobGasUsed"] = json!("0xc0000");
+        value["excessBlobGas"] = json!("0x4b20000");
+        let block: evm::Block<evm::H256> = serde_json::from_value(value).unwrap();
+        let header = BlockHeader::try_from(&block).unwrap();
+        assert_eq!(header.blob_gas_used.as_deref(), Some("0xc0000"));
+        assert_eq!(header.excess_blob_gas.as_deref(), Some("0x4b20000"));
+
+        let blob_hash = format!("0x01{:062x}", 0xb10b);
+        let mut tx = evm::Transaction {
+            transaction_index: Some(0.into()),
+            transaction_type: Some(3.into()),
+            gas_price: Some(0x3b9aca00.into()),
+            ..Default::default()
+        };
+        tx.other
+            .insert("maxFeePerBlobGas".to_string(), json!("0x3b9aca00"));
+        tx.other
+            .insert("blobVersionedHashes".to_string(), json!([blob_hash]));
+        let receipt = evm::TransactionReceipt {
+            effective_gas_price: Some(0x3b9aca00.into()),
+            gas_used: Some(0x5208.into()),
+            status: Some(1.into()),
+            ..Default::default()
+        };
+        let tx = Transaction::try_from((tx, receipt)).unwrap();
+        assert_eq!(tx.r#type, 3);
+        assert_eq!(tx.max_fee_per_blob_gas.as_deref(), Some("0x3b9aca00"));
+        assert_eq!(tx.blob_versioned_hashes, vec![blob_hash]);
+    }
+
+    #[test]
+    fn transaction_types() {
+        let convert = |tx: evm::Transaction| {
+            let receipt = evm::TransactionReceipt {
+                effective_gas_price: Some(0x3b9aca00.into()),
+                gas_used: Some(0x5208.into()),
+                status: Some(1.into()),
+                ..Default::default()
+            };
+            let tx = Transaction::try_from((tx, receipt)).unwrap();
+            let trace = pbcodec::TransactionTrace::try_from(tx.clone()).unwrap();
+            (tx, trace)
+        };
+        let typed = |r#type: u64, v: u64| evm::Transaction {
+            transaction_index: Some(0.into()),
+            transaction_type: Some(r#type.into()),
+            gas_price: Some(0x3b9aca00.into()),
+            max_fee_per_gas: (r#type >= 2).then(|| 0x3b9aca00.into()),
+            max_priority_fee_per_gas: (r#type >= 2).then(|| 0x3b9aca00.into()),
+            v: v.into(),
+            ..Default::default()
+        };
+
+        // legacy transactions without a type field
+        let (tx, trace) = convert(evm::Transaction {
+            transaction_type: None,
+            ..typed(0, 0x25)
+        });
+        assert_eq!(tx.r#type, 0);
+        assert_eq!(trace.r#type, 0);
+        assert_eq!(trace.v, vec![0x25]);
+
+        let (tx, trace) = convert(typed(1, 1));
+        assert_eq!(tx.r#type, 1);
+        assert_eq!(trace.v, vec![1]);
+
+        let mut dynamic_fee = typed(2, 0);
+        dynamic_fee
+            .other
+            .insert("yParity".to_string(), json!("0x0"));
+        let (tx, trace) = convert(dynamic_fee);
+        assert_eq!((tx.r#type, tx.y_parity), (2, Some(0)));
+        assert_eq!(trace.r#type, 2);
+        assert_eq!(trace.v, vec![0]);
+
+        // the parity bit reported in the legacy form
+        let (tx, trace) = convert(typed(3, 28));
+        assert_eq!(tx.r#type, 3);
+        assert_eq!(trace.r#type, 3);
+        assert_eq!(trace.v, vec![1]);
+    }
+
+    #[test]
+    fn withdrawals() {
+        let mut value = new_head(1);
+        value["size"] = json!("0x220");
+        value["totalDifficulty"] = json!("0x0");
+        let block: evm::Block<evm::H256> = serde_json::from_value(value.clone()).unwrap();
+        let block = Block::try_from(block).unwrap();
+        assert_eq!(block.header.withdrawals_root, None);
+
+        let root = format!("0x{:064x}", 0x4895);
+        value["withdrawalsRoot"] = json!(root);
+        value["withdrawals"] = json!([
+            {
+                "index": "0x1a2b",
+                "validatorIndex": "0x5f5e1",
+                "address": "0xb9d7934878b5fb9610b3fe8a5e441e8fad7e293f",
+                "amount": "0xb6d7b1",
+            },
+            {
+                "index": "0x1a2c",
+                "validatorIndex": "0x5f5e2",
+                "address": "0xb9d7934878b5fb9610b3fe8a5e441e8fad7e293f",
+                "amount": "0x3",
+            },
+        ]);
+        let block: evm::Block<evm::H256> = serde_json::from_value(value).unwrap();
+        let withdrawals = block.withdrawals.clone().unwrap();
+        let block = Block::try_from(block).unwrap();
+        assert_eq!(block.header.withdrawals_root, Some(root));
+
+        // replayed from the balance the account ends the block with
+        let address = withdrawals[0].address;
+        let end = evm::U256::from(0xb6d7b4u64 * 1_000_000_000 + 7);
+        let changes = withdrawal_changes(&withdrawals, HashMap::from([(address, end)])).unwrap();
+        let values: Vec<_> = changes
+            .iter()
+            .map(|change| (change.old_value.as_str(), change.new_value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("0x7", "0x2a9243d1abaa07"),
+                ("0x2a9243d1abaa07", "0x2a9244847c0807")
+            ]
+        );
+        assert!(changes
+            .iter()
+            .all(|change| matches!(change.reason, BalanceChangeReason::Withdrawal)));
+        assert!(withdrawal_changes(&withdrawals, HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn finalized_height_boundary() {
+        assert_eq!(finalized_height(100, 10), 90);
+        assert_eq!(finalized_height(100, 0), 100);
+        assert_eq!(finalized_height(5, 10), 0);
+    }
+
+    fn block_hash(number: u64) -> String {
+        format!("{:?}", evm::H256::from_low_u64_be(number + 1))
+    }
+
+    fn linear_block(block_id: evm::BlockId) -> anyhow::Result<Block> {
+        let number = match block_id {
+            evm::BlockId::Number(evm::BlockNumber::Number(number)) => number.as_u64(),
+            evm::BlockId::Hash(hash) => hash.to_low_u64_be() - 1,
+            _ => unreachable!(),
+        };
+        Ok(Block {
+            header: BlockHeader {
+                number,
+                hash: block_hash(number),
+                parent_hash: block_hash(number - 1),
+                size: None,
+                sha3_uncles: String::new(),
+                miner: String::new(),
+                state_root: String::new(),
+                transactions_root: String::new(),
+                receipts_root: String::new(),
+                logs_bloom: String::new(),
+                difficulty: String::new(),
+                total_difficulty: String::new(),
+                gas_limit: String::new(),
+                gas_used: String::new(),
+                timestamp: 0,
+                extra_data: String::new(),
+                mix_hash: String::new(),
+                nonce: String::new(),
+                base_fee_per_gas: None,
+                l2_extra: None,
+                blob_gas_used: None,
+                excess_blob_gas: None,
+                withdrawals_root: None,
+                parent_beacon_root: None,
+                requests_hash: None,
+            },
+            logs: vec![],
+            transactions: vec![],
+            traces: vec![],
+            uncles: vec![],
+            balance_changes: vec![],
+            raw: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn block_becomes_final_at_confirmation_depth() {
+        let confirmations = 3;
+        let state = HashAndHeight {
+            hash: block_hash(0),
+            height: 0,
+        };
+        let mut nav = ForkNavigator::new(state, |block_id| async move { linear_block(block_id) });
+
+        for best in 1..=6 {
+            let finalized = finalized_height(best, confirmations);
+            let update = nav.r#move(best, finalized).await.unwrap();
+            assert_eq!(update.finalized_head.height, finalized);
+
+            // only blocks shallower than the confirmation depth stay reorg-able
+            let hot: Vec<_> = nav.chain[1..].iter().map(|block| block.height).collect();
+            let expected: Vec<_> = (finalized + 1..=best).collect();
+            assert_eq!(hot, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn log_only_request_skips_tracing() {
+        let emitter = evm::Address::from_low_u64_be(0xe);
+        let tx = evm::Transaction {
+            hash: evm::H256::from_low_u64_be(0xa),
+            block_number: Some(1.into()),
+            transaction_index: Some(0.into()),
+            gas_price: Some(1.into()),
+            ..Default::default()
+        };
+        let log = evm::Log {
+            address: emitter,
+            block_number: Some(1.into()),
+            transaction_hash: Some(tx.hash),
+            transaction_index: Some(0.into()),
+            log_index: Some(0.into()),
+            ..Default::default()
+        };
+        let block = evm::Block {
+            number: Some(1.into()),
+            hash: Some(evm::H256::from_low_u64_be(1)),
+            size: Some(0.into()),
+            author: Some(evm::Address::zero()),
+            logs_bloom: Some(Default::default()),
+            total_difficulty: Some(0.into()),
+            mix_hash: Some(Default::default()),
+            nonce: Some(Default::default()),
+            transactions: vec![tx],
+            ..Default::default()
+        };
+        let mut tx_receipt = receipt(0xa, 1, 0);
+        tx_receipt.effective_gas_price = Some(1.into());
+        let (client, calls) = mock_rpc(Arc::new(move |method, _| match method {
+            "eth_getLogs" => Ok(json!([log])),
+            "eth_getBlockReceipts" => Ok(json!([tx_receipt])),
+            _ => panic!("unexpected {}", method),
+        }))
+        .await;
+        let request = DataRequest {
+            from: 1,
+            to: Some(1),
+            logs: vec![LogRequest {
+                address: vec![format!("{:?}", emitter)],
+                topic0: vec![],
+                topic1: vec![],
+                topic2: vec![],
+                topic3: vec![],
+            }],
+            transactions: vec![],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: false,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        };
+
+        let blocks = get_requested_data(
+            &client,
+            vec![block],
+            &request,
+            &AtomicBool::new(true),
+            HeaderVariant::L1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(blocks[0].logs.len(), 1);
+        assert_eq!(blocks[0].transactions.len(), 1);
+        assert!(blocks[0].traces.is_empty());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["eth_getLogs", "eth_getBlockReceipts"]
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_blocks_are_kept() {
+        let block = evm::Block::<evm::Transaction> {
+            number: Some(1.into()),
+            hash: Some(evm::H256::from_low_u64_be(1)),
+            size: Some(0.into()),
+            author: Some(evm::Address::zero()),
+            logs_bloom: Some(Default::default()),
+            total_difficulty: Some(0.into()),
+            mix_hash: Some(Default::default()),
+            nonce: Some(Default::default()),
+            ..Default::default()
+        };
+        let served = serde_json::to_value(block).unwrap();
+        let raw = served.to_string();
+        let (client, calls) = mock_rpc(Arc::new(move |method, params| {
+            assert_eq!(method, "eth_getBlockByNumber");
+            assert_eq!(params[1], true);
+            Ok(served.clone())
+        }))
+        .await;
+        let request = |raw_blocks: bool| DataRequest {
+            from: 1,
+            to: Some(1),
+            logs: vec![],
+            transactions: vec![],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: false,
+            headers_only: false,
+            raw_receipts: false,
+            raw_blocks,
+            all_blocks: false,
+        };
+
+        let supported = AtomicBool::new(true);
+        let blocks = get_stride(
+            &client,
+            &(1, 1),
+            &request(true),
+            &supported,
+            HeaderVariant::L1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(blocks[0].raw.as_ref(), Some(&raw));
+        let converted = pbcodec::Block::try_from(blocks[0].clone()).unwrap();
+        assert_eq!(converted.raw, raw.into_bytes());
+
+        let blocks = get_stride(
+            &client,
+            &(1, 1),
+            &request(false),
+            &supported,
+            HeaderVariant::L1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(blocks[0].raw, None);
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn headers_only_request_fetches_bare_blocks() {
+        let block = evm::Block::<evm::H256> {
+            number: Some(1.into()),
+            hash: Some(evm::H256::from_low_u64_be(1)),
+            size: Some(0.into()),
+            author: Some(evm::Address::zero()),
+            logs_bloom: Some(Default::default()),
+            total_difficulty: Some(0.into()),
+            mix_hash: Some(Default::default()),
+            nonce: Some(Default::default()),
+            transactions: vec![evm::H256::from_low_u64_be(2)],
+            uncles: vec![evm::H256::from_low_u64_be(3)],
+            ..Default::default()
+        };
+        let served = serde_json::to_value(block).unwrap();
+        let (client, calls) = mock_rpc(Arc::new(move |method, params| {
+            // transaction bodies aren't requested
+            assert_eq!(method, "eth_getBlockByNumber");
+            assert_eq!(params[1], false);
+            Ok(served.clone())
+        }))
+        .await;
+        let request = DataRequest {
+            from: 1,
+            to: Some(1),
+            logs: vec![LogRequest {
+                address: vec![],
+                topic0: vec![],
+                topic1: vec![],
+                topic2: vec![],
+                topic3: vec![],
+            }],
+            transactions: vec![TransactionRequest {
+                to: vec![],
+                sighash: vec![],
+                from: vec![],
+            }],
+            balance_changes: false,
+            storage_changes: false,
+            gas_changes: false,
+            traces: true,
+            headers_only: true,
+            raw_receipts: false,
+            raw_blocks: false,
+            all_blocks: false,
+        };
+
+        let blocks = get_stride(
+            &client,
+            &(1, 1),
+            &request,
+            &AtomicBool::new(true),
+            HeaderVariant::L1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].header.number, 1);
+        assert!(blocks[0].transactions.is_empty());
+        assert!(blocks[0].logs.is_empty());
+        assert!(blocks[0].traces.is_empty());
+        assert!(blocks[0].uncles.is_empty());
+        // receipts, logs, traces and uncles are skipped
+        assert_eq!(*calls.lock().unwrap(), vec!["eth_getBlockByNumber"]);
+
+        // hot blocks arrive with their bodies, which are dropped without further calls
+        let block = evm::Block::<evm::Transaction> {
+            number: Some(2.into()),
+            hash: Some(evm::H256::from_low_u64_be(2)),
+            size: Some(0.into()),
+            author: Some(evm::Address::zero()),
+            logs_bloom: Some(Default::default()),
+            total_difficulty: Some(0.into()),
+            mix_hash: Some(Default::default()),
+            nonce: Some(Default::default()),
+            transactions: vec![evm::Transaction::default()],
+            ..Default::default()
+        };
+        let blocks = get_requested_data(
+            &client,
+            vec![block],
+            &request,
+            &AtomicBool::new(true),
+            HeaderVariant::L1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(blocks[0].header.number, 2);
+        assert!(blocks[0].transactions.is_empty());
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+}
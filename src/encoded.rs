@@ -0,0 +1,125 @@
+use crate::datasource::Block;
+use crate::pbcodec;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Request filters and the block hash.
+type Key = (String, String);
+
+type Entry = Arc<OnceCell<Arc<Vec<u8>>>>;
+
+struct State {
+    tick: u64,
+    entries: HashMap<Key, (Entry, u64)>,
+    order: BTreeMap<u64, Key>,
+}
+
+/// Encoded blocks shared between streams requesting the same data,
+/// so concurrent consumers of a block convert and encode it only once.
+pub struct EncodedBlocks {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl EncodedBlocks {
+    /// `capacity` is the number of encoded blocks kept around.
+    pub fn new(capacity: usize) -> EncodedBlocks {
+        EncodedBlocks {
+            capacity,
+            state: Mutex::new(State {
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn entry(&self, key: Key) -> Entry {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        if let Some((entry, used)) = state.entries.get_mut(&key) {
+            let entry = entry.clone();
+            let previous = std::mem::replace(used, tick);
+            state.order.remove(&previous);
+            state.order.insert(tick, key);
+            return entry;
+        }
+
+        while state.entries.len() >= self.capacity.max(1) {
+            let (_, oldest) = state.order.pop_first().expect("entries are ordered");
+            state.entries.remove(&oldest);
+        }
+
+        let entry = Entry::default();
+        state.order.insert(tick, key.clone());
+        state.entries.insert(key, (entry.clone(), tick));
+        entry
+    }
+
+    /// Returns the `sf.ethereum.type.v2.Block` encoding of the block
+    /// requested with `filter_key` filters, tagged with the `version` schema version.
+    pub async fn get_or_encode(
+        &self,
+        filter_key: &str,
+        block: Block,
+        version: i32,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        let entry = self.entry((filter_key.to_string(), block.header.hash.clone()));
+        let bytes = entry
+            .get_or_try_init(|| async move {
+                tokio::task::spawn_blocking(move || {
+                    let mut graph_block = pbcodec::Block::try_from(block)?;
+                    graph_block.ver = version;
+                    Ok::<_, anyhow::Error>(Arc::new(graph_block.encode_to_vec()))
+                })
+                .await?
+            })
+            .await?;
+        Ok(bytes.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoded::EncodedBlocks;
+    use crate::testing::{block, hash};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn shared_encoding() {
+        let encoded = EncodedBlocks::new(2);
+
+        let first = encoded
+            .get_or_encode("all", block(1, hash(1), hash(0)), 2)
+            .await
+            .unwrap();
+        let second = encoded
+            .get_or_encode("all", block(1, hash(1), hash(0)), 2)
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // other filters produce other blocks
+        let filtered = encoded
+            .get_or_encode("filtered", block(1, hash(1), hash(0)), 2)
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &filtered));
+
+        // the least recently used entry goes first
+        encoded
+            .get_or_encode("all", block(2, hash(2), hash(1)), 2)
+            .await
+            .unwrap();
+        let again = encoded
+            .get_or_encode("all", block(1, hash(1), hash(0)), 2)
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &again));
+        assert_eq!(first, again);
+    }
+}
@@ -1,6 +1,10 @@
-use crate::firehose::Firehose;
-use crate::pbfirehose::{fetch_server::Fetch, SingleBlockRequest, SingleBlockResponse};
+use crate::firehose::{FetchError, Firehose};
+use crate::pbfirehose::fetch_server::{Fetch, FetchServer};
+use crate::pbfirehose::{
+    MultiBlockRequest, MultiBlockResponse, SingleBlockRequest, SingleBlockResponse,
+};
 use std::sync::Arc;
+use tonic::codec::CompressionEncoding;
 use tracing::error;
 
 pub struct ArchiveFetch {
@@ -13,6 +17,20 @@ impl ArchiveFetch {
     }
 }
 
+/// Gzip requests are always accepted, responses are compressed with `send`
+/// when the client accepts it.
+pub fn fetch_service(
+    firehose: Arc<Firehose>,
+    send: Option<CompressionEncoding>,
+) -> FetchServer<ArchiveFetch> {
+    let service =
+        FetchServer::new(ArchiveFetch::new(firehose)).accept_compressed(CompressionEncoding::Gzip);
+    match send {
+        Some(encoding) => service.send_compressed(encoding),
+        None => service,
+    }
+}
+
 #[tonic::async_trait]
 impl Fetch for ArchiveFetch {
     async fn block(
@@ -20,14 +38,33 @@ impl Fetch for ArchiveFetch {
         request: tonic::Request<SingleBlockRequest>,
     ) -> Result<tonic::Response<SingleBlockResponse>, tonic::Status> {
         let request = request.into_inner();
-        let response = match self.firehose.block(request).await {
-            Ok(response) => response,
-            Err(e) => {
-                error!("failed to fetch block: {}", e);
-                return Err(tonic::Status::unavailable("operation failed"));
-            }
-        };
+        let response = self.firehose.block(request).await.map_err(to_status)?;
+        Ok(tonic::Response::new(response))
+    }
 
+    async fn blocks(
+        &self,
+        request: tonic::Request<MultiBlockRequest>,
+    ) -> Result<tonic::Response<MultiBlockResponse>, tonic::Status> {
+        let request = request.into_inner();
+        let response = self
+            .firehose
+            .multi_block(request)
+            .await
+            .map_err(to_status)?;
         Ok(tonic::Response::new(response))
     }
 }
+
+fn to_status(e: anyhow::Error) -> tonic::Status {
+    match e.downcast_ref::<FetchError>() {
+        Some(FetchError::InvalidArgument(message)) => tonic::Status::invalid_argument(message),
+        Some(FetchError::NotFound(message)) => tonic::Status::not_found(message),
+        Some(FetchError::Unavailable(message)) => tonic::Status::unavailable(message),
+        Some(FetchError::DataLoss(message)) => tonic::Status::data_loss(message),
+        None => {
+            error!("failed to fetch block: {}", e);
+            tonic::Status::unavailable("operation failed")
+        }
+    }
+}
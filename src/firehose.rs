@@ -1,51 +1,820 @@
 use crate::cursor::Cursor;
 use crate::datasource::{
-    Block, BlockHeader, CallType, DataRequest, DataSource, HashAndHeight, HotDataSource, Log,
-    LogRequest, Trace, TraceResult, TraceType, Transaction, TransactionRequest,
+    AccessTuple, BalanceChange, BalanceChangeReason, Block, BlockHeader, BlockStream, BlockTag,
+    CallType, DataRequest, DataSource, GasChange, GasChangeReason, HashAndHeight, HotDataSource,
+    HotSource, Log, LogRequest, StorageChange, Trace, TraceResult, TraceType, Transaction,
+    TransactionRequest,
 };
+use crate::ds_breaker::{BreakerDataSource, CircuitBreaker};
+use crate::ds_merged::MergedDataSource;
+use crate::encoded::EncodedBlocks;
+use crate::metrics::Metrics;
 use crate::pbcodec;
 use crate::pbfirehose::single_block_request::Reference;
-use crate::pbfirehose::{ForkStep, Request, Response, SingleBlockRequest, SingleBlockResponse};
-use crate::pbtransforms::CombinedFilter;
+use crate::pbfirehose::{
+    ForkStep, HeadResponse, InfoResponse, MultiBlockRequest, MultiBlockResponse, Request, Response,
+    SingleBlockRequest, SingleBlockResponse,
+};
+use crate::pbtransforms::{
+    CombinedFilter, MultiCallToFilter, MultiLogFilter, MultiTransactionFilter,
+};
+use crate::retry::{timeout, Backoff};
+use crate::throttle::RateLimiter;
 use anyhow::{format_err, Context};
 use async_stream::try_stream;
+use ethers_core::abi::{ParamType, Token};
+use ethers_core::k256::ecdsa;
+use ethers_core::types as evm;
+use ethers_core::utils::keccak256;
+use ethers_core::utils::rlp::{self, RlpStream};
 use futures_core::stream::Stream;
 use futures_util::stream::StreamExt;
 use prost::Message;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, info_span};
 
-async fn resolve_negative_start(
+/// Negative starts are relative to the finalized head and never go below the first available block,
+/// explicit starts below it are rejected.
+async fn resolve_start(
     start_block_num: i64,
     ds: &(dyn DataSource + Send + Sync),
 ) -> anyhow::Result<u64> {
+    let first_block = ds.get_first_block().await?;
     if start_block_num < 0 {
         let delta = u64::try_from(start_block_num.abs())?;
         let head = ds.get_finalized_height().await?;
-        return Ok(head.saturating_sub(delta));
+        return Ok(head.saturating_sub(delta).max(first_block));
+    }
+    let start = u64::try_from(start_block_num)?;
+    if start < first_block {
+        return Err(FetchError::InvalidArgument(format!(
+            "start block №{} is below the first available block №{}",
+            start, first_block
+        ))
+        .into());
+    }
+    Ok(start)
+}
+
+fn parse_block_tag(tag: &str) -> anyhow::Result<BlockTag> {
+    match tag {
+        "latest" => Ok(BlockTag::Latest),
+        "safe" => Ok(BlockTag::Safe),
+        "finalized" => Ok(BlockTag::Finalized),
+        _ => Err(FetchError::InvalidArgument(format!(
+            "unknown start block tag {:?}, expected latest, safe or finalized",
+            tag
+        ))
+        .into()),
     }
-    Ok(u64::try_from(start_block_num)?)
 }
 
 fn try_decode_hex(label: &'static str, value: &str) -> anyhow::Result<Vec<u8>> {
-    let buf: Vec<u8> = if value.len() % 2 != 0 {
-        let value = format!("0x0{}", &value[2..]);
-        prefix_hex::decode(&value).map_err(|_| format_err!("invalid {}: {}", label, value))?
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    let padded = if digits.len() % 2 == 1 {
+        format!("0x0{}", digits)
     } else {
-        prefix_hex::decode(value).map_err(|_| format_err!("invalid {}: {}", label, value))?
+        format!("0x{}", digits)
     };
+    let buf: Vec<u8> =
+        prefix_hex::decode(&padded).map_err(|_| format_err!("invalid {}: {}", label, value))?;
 
     Ok(buf)
 }
 
-fn qty2int(value: &String) -> anyhow::Result<u64> {
-    Ok(u64::from_str_radix(value.trim_start_matches("0x"), 16)?)
+/// Byte length bound of 256-bit quantities like values, prices and fees.
+const BIG_INT_MAX_BYTES: usize = 32;
+
+/// Accumulated quantities like the total difficulty get more room.
+const TOTAL_BIG_INT_MAX_BYTES: usize = 64;
+
+/// Quantities longer than `max_bytes` are rejected before they are decoded,
+/// so an upstream can't make us allocate arbitrarily large numbers.
+fn big_int(label: &'static str, value: &str, max_bytes: usize) -> anyhow::Result<pbcodec::BigInt> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    anyhow::ensure!(
+        digits.len() <= max_bytes * 2,
+        "{} is longer than {} bytes: {} hex digits",
+        label,
+        max_bytes,
+        digits.len()
+    );
+    Ok(pbcodec::BigInt {
+        bytes: try_decode_hex(label, value)?,
+    })
+}
+
+fn logs_bloom(logs: &[pbcodec::Log]) -> Vec<u8> {
+    let mut bloom = vec![0u8; 256];
+    for log in logs {
+        for value in std::iter::once(&log.address).chain(log.topics.iter()) {
+            let hash = keccak256(value);
+            for i in [0, 2, 4] {
+                let bit = (usize::from(hash[i]) << 8 | usize::from(hash[i + 1])) & 0x7ff;
+                bloom[255 - bit / 8] |= 1 << (bit % 8);
+            }
+        }
+    }
+    bloom
+}
+
+/// Monotonic counter of execution events within a single block.
+#[derive(Default)]
+struct Ordinal(u64);
+
+impl Ordinal {
+    fn next(&mut self) -> u64 {
+        let ordinal = self.0;
+        self.0 += 1;
+        ordinal
+    }
+}
+
+fn qty2int(label: &'static str, value: &str) -> anyhow::Result<u64> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(digits, 16).with_context(|| format!("invalid {}: {}", label, value))
+}
+
+/// Gas used by the transactions preceding `tx` in its block, known for the first transaction
+/// and when `previous`, the index and cumulative gas used of the previous one, is right before it.
+fn gas_used_before(tx: &Transaction, previous: Option<(u32, u64)>) -> Option<u64> {
+    match previous {
+        Some((index, cumulative)) if index + 1 == tx.transaction_index => Some(cumulative),
+        _ if tx.transaction_index == 0 => Some(0),
+        _ => None,
+    }
+}
+
+/// Checks the gas used by the transactions of the block against its header.
+/// Blocks may carry only the transactions matching the request, so their gas used
+/// must fit in the header's and grow the cumulative gas used of their receipts.
+fn check_gas_used(block: &Block) -> anyhow::Result<()> {
+    let number = block.header.number;
+    let gas_limit = qty2int("gas limit", &block.header.gas_limit)?;
+    let gas_used = qty2int("gas used", &block.header.gas_used)?;
+    anyhow::ensure!(
+        gas_used <= gas_limit,
+        "block №{} uses {} gas over its limit of {}",
+        number,
+        gas_used,
+        gas_limit
+    );
+
+    let mut total: u64 = 0;
+    let mut previous: Option<(u32, u64)> = None;
+    for tx in &block.transactions {
+        let used = qty2int("tx gas used", &tx.gas_used)?;
+        total = total.saturating_add(used);
+        // an unreported cumulative gas used is derived on conversion when it can be
+        if tx.cumulative_gas_used.is_empty() {
+            previous = gas_used_before(tx, previous)
+                .map(|before| (tx.transaction_index, before.saturating_add(used)));
+            continue;
+        }
+        let cumulative = qty2int("cumulative gas used", &tx.cumulative_gas_used)?;
+        match (gas_used_before(tx, previous), previous) {
+            (Some(before), _) => anyhow::ensure!(
+                before.checked_add(used) == Some(cumulative),
+                "transaction {} of block №{} uses {} gas, its cumulative gas used goes from {} to {}",
+                tx.transaction_index,
+                number,
+                used,
+                before,
+                cumulative
+            ),
+            // the transactions in between used some gas too
+            (None, Some((index, before))) => anyhow::ensure!(
+                index < tx.transaction_index && before.saturating_add(used) <= cumulative,
+                "transaction {} of block №{} uses {} gas, its cumulative gas used of {} \
+                doesn't grow past the {} of transaction {}",
+                tx.transaction_index,
+                number,
+                used,
+                cumulative,
+                before,
+                index
+            ),
+            (None, None) => {}
+        }
+        anyhow::ensure!(
+            cumulative <= gas_used,
+            "transaction {} of block №{} has a cumulative gas used of {} over the {} of the block",
+            tx.transaction_index,
+            number,
+            cumulative,
+            gas_used
+        );
+        previous = Some((tx.transaction_index, cumulative));
+    }
+    anyhow::ensure!(
+        total <= gas_used,
+        "transactions of block №{} use {} gas, the header reports {}",
+        number,
+        total,
+        gas_used
+    );
+    Ok(())
+}
+
+/// RLP encoding of the header as it's hashed into the block hash. The fields following
+/// the nonce were added by forks: the base fee by London, the withdrawals root by Shanghai,
+/// the blob gas and the parent beacon root by Cancun and the requests hash by Prague.
+/// Sources not reporting some fork fields leave gaps in that sequence, such headers
+/// can't be encoded back and yield None.
+fn header_rlp(header: &pbcodec::BlockHeader) -> Option<Vec<u8>> {
+    let cancun = match (
+        header.blob_gas_used,
+        header.excess_blob_gas,
+        header.parent_beacon_root.is_empty(),
+    ) {
+        (Some(used), Some(excess), false) => Some(vec![
+            rlp::encode(&used),
+            rlp::encode(&excess),
+            rlp::encode(&header.parent_beacon_root),
+        ]),
+        (None, None, true) => None,
+        _ => return None,
+    };
+    let forks = [
+        header
+            .base_fee_per_gas
+            .as_ref()
+            .map(|fee| vec![rlp::encode(&evm::U256::from_big_endian(&fee.bytes))]),
+        (!header.withdrawals_root.is_empty()).then(|| vec![rlp::encode(&header.withdrawals_root)]),
+        cancun,
+        (!header.requests_hash.is_empty()).then(|| vec![rlp::encode(&header.requests_hash)]),
+    ];
+    let active = forks.iter().take_while(|fork| fork.is_some()).count();
+    if forks[active..].iter().any(Option::is_some) {
+        return None;
+    }
+
+    let difficulty = header.difficulty.as_ref().map_or(&[][..], |d| &d.bytes);
+    let timestamp = header.timestamp.as_ref().map_or(0, |t| t.seconds);
+    let mut stream = RlpStream::new();
+    stream.begin_unbounded_list();
+    stream
+        .append(&header.parent_hash)
+        .append(&header.uncle_hash)
+        .append(&header.coinbase)
+        .append(&header.state_root)
+        .append(&header.transactions_root)
+        .append(&header.receipt_root)
+        .append(&header.logs_bloom)
+        .append(&evm::U256::from_big_endian(difficulty))
+        .append(&header.number)
+        .append(&header.gas_limit)
+        .append(&header.gas_used)
+        .append(&u64::try_from(timestamp).ok()?)
+        .append(&header.extra_data)
+        .append(&header.mix_hash)
+        .append(&header.nonce.to_be_bytes().to_vec());
+    for item in forks.iter().flatten().flatten() {
+        stream.append_raw(item, 1);
+    }
+    stream.finalize_unbounded_list();
+    Some(stream.out().to_vec())
+}
+
+/// Checks that the header, as it's converted, hashes to the hash of the block.
+/// Headers missing fork fields can't be hashed and pass unchecked.
+fn check_header_hash(header: &BlockHeader) -> anyhow::Result<()> {
+    let converted = pbcodec::BlockHeader::try_from(header.clone())?;
+    if let Some(encoded) = header_rlp(&converted) {
+        let hash = keccak256(encoded);
+        anyhow::ensure!(
+            hash[..] == converted.hash[..],
+            "block №{} hashes to {}, not to its hash {}",
+            header.number,
+            prefix_hex::encode(hash),
+            header.hash
+        );
+    }
+    Ok(())
+}
+
+/// Checks run on every block before it's sent, when enabled.
+/// A failed check ends the stream with a data loss error, retrying would fetch the same block.
+#[derive(Debug, Clone, Copy, Default)]
+struct Validation {
+    gas_used: bool,
+    header_hash: bool,
+}
+
+impl Validation {
+    fn check(&self, block: &Block) -> anyhow::Result<()> {
+        if self.gas_used {
+            check_gas_used(block).map_err(|e| FetchError::DataLoss(format!("{:#}", e)))?;
+        }
+        if self.header_hash {
+            check_header_hash(&block.header)
+                .map_err(|e| FetchError::DataLoss(format!("{:#}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct TransformFilter {
+    logs: Vec<LogRequest>,
+    transactions: Vec<TransactionRequest>,
+    /// Some of the transaction requests come from call filters, which need traces
+    calls: bool,
+    headers_only: bool,
+    raw_receipts: bool,
+    balance_changes: bool,
+    storage_changes: bool,
+    send_all_block_headers: bool,
+}
+
+/// Every log, call and transaction filter of every transform is kept as a separate group,
+/// whether it comes in a CombinedFilter or in a MultiLogFilter, MultiCallToFilter
+/// or MultiTransactionFilter of its own.
+/// A group matches when all of its non-empty criteria match, and a transaction
+/// is included when it matches any group, so multiple transforms are OR-ed.
+/// HeaderOnly and LightBlock transforms strip block bodies regardless of filters,
+/// RawReceipts attaches the receipts as returned by the rpc,
+/// BalanceChanges and StorageChanges the state changes of every call.
+/// Any CombinedFilter asking for all block headers keeps the unmatched blocks as headers.
+fn decode_transforms(transforms: &[prost_types::Any]) -> anyhow::Result<TransformFilter> {
+    let mut logs: Vec<LogRequest> = vec![];
+    let mut transactions: Vec<TransactionRequest> = vec![];
+    let mut calls = false;
+    let mut headers_only = false;
+    let mut raw_receipts = false;
+    let mut balance_changes = false;
+    let mut storage_changes = false;
+    let mut send_all_block_headers = false;
+    for transform in transforms {
+        let invalid = |name: &str, err: prost::DecodeError| {
+            FetchError::InvalidArgument(format!("invalid {} transform: {}", name, err))
+        };
+        let (log_filters, call_filters) = match transform.type_url.as_str() {
+            "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter" => {
+                let filter = CombinedFilter::decode(&transform.value[..])
+                    .map_err(|err| invalid("CombinedFilter", err))?;
+                send_all_block_headers |= filter.send_all_block_headers;
+                (filter.log_filters, filter.call_filters)
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.MultiLogFilter" => {
+                let filter = MultiLogFilter::decode(&transform.value[..])
+                    .map_err(|err| invalid("MultiLogFilter", err))?;
+                (filter.log_filters, vec![])
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.MultiCallToFilter" => {
+                let filter = MultiCallToFilter::decode(&transform.value[..])
+                    .map_err(|err| invalid("MultiCallToFilter", err))?;
+                (vec![], filter.call_filters)
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.MultiTransactionFilter" => {
+                let filter = MultiTransactionFilter::decode(&transform.value[..])
+                    .map_err(|err| invalid("MultiTransactionFilter", err))?;
+                for tx_filter in filter.transaction_filters {
+                    if tx_filter.from_addresses.is_empty() && tx_filter.to_addresses.is_empty() {
+                        return Err(FetchError::InvalidArgument(
+                            "transaction filters require a sender or a recipient".to_string(),
+                        )
+                        .into());
+                    }
+                    transactions.push(TransactionRequest {
+                        to: tx_filter
+                            .to_addresses
+                            .into_iter()
+                            .map(prefix_hex::encode)
+                            .collect(),
+                        sighash: vec![],
+                        from: tx_filter
+                            .from_addresses
+                            .into_iter()
+                            .map(prefix_hex::encode)
+                            .collect(),
+                    });
+                }
+                continue;
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.HeaderOnly"
+            | "type.googleapis.com/sf.ethereum.transform.v1.LightBlock" => {
+                headers_only = true;
+                continue;
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.RawReceipts" => {
+                raw_receipts = true;
+                continue;
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.BalanceChanges" => {
+                balance_changes = true;
+                continue;
+            }
+            "type.googleapis.com/sf.ethereum.transform.v1.StorageChanges" => {
+                storage_changes = true;
+                continue;
+            }
+            _ => {
+                return Err(FetchError::InvalidArgument(format!(
+                    "unsupported transform: {}",
+                    transform.type_url
+                ))
+                .into())
+            }
+        };
+
+        for log_filter in log_filters {
+            let log_request = LogRequest {
+                address: log_filter
+                    .addresses
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+                topic0: log_filter
+                    .event_signatures
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+                topic1: log_filter
+                    .topic1
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+                topic2: log_filter
+                    .topic2
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+                topic3: log_filter
+                    .topic3
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+            };
+            logs.push(log_request);
+        }
+
+        calls |= !call_filters.is_empty();
+        for call_filter in call_filters {
+            let tx_request = TransactionRequest {
+                to: call_filter
+                    .addresses
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+                sighash: call_filter
+                    .signatures
+                    .into_iter()
+                    .map(prefix_hex::encode)
+                    .collect(),
+                from: vec![],
+            };
+            transactions.push(tx_request);
+        }
+    }
+
+    Ok(TransformFilter {
+        logs,
+        transactions,
+        calls,
+        headers_only,
+        raw_receipts,
+        balance_changes,
+        storage_changes,
+        send_all_block_headers,
+    })
+}
+
+/// Number of encoded finalized blocks shared between streams.
+const ENCODED_BLOCKS_CAPACITY: usize = 256;
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the archive height is checked while waiting for the archive within the crossover gap.
+const ARCHIVE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of blocks fetched at once by a reverse stream.
+const REVERSE_BATCH_SIZE: u64 = 100;
+
+/// Number of block references resolved by a single request at most.
+const MAX_BLOCK_REFERENCES: usize = 100;
+
+pub const ETH_BLOCK_TYPE_URL: &str = "type.googleapis.com/sf.ethereum.type.v2.Block";
+
+pub const ETH_BLOCK_VERSION: i32 = 2;
+
+/// The schema the emitted blocks are tagged with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockType {
+    pub type_url: String,
+    pub version: i32,
+}
+
+impl Default for BlockType {
+    fn default() -> Self {
+        BlockType {
+            type_url: ETH_BLOCK_TYPE_URL.to_string(),
+            version: ETH_BLOCK_VERSION,
+        }
+    }
+}
+
+impl BlockType {
+    fn encode(&self, mut block: pbcodec::Block) -> prost_types::Any {
+        block.ver = self.version;
+        self.wrap(block.encode_to_vec())
+    }
+
+    fn wrap(&self, value: Vec<u8>) -> prost_types::Any {
+        prost_types::Any {
+            type_url: self.type_url.clone(),
+            value,
+        }
+    }
+}
+
+type ConvertedBlockStream =
+    Pin<Box<dyn Stream<Item = anyhow::Result<(HashAndHeight, prost_types::Any)>> + Send>>;
+
+/// Converts finalized blocks on the blocking pool, `parallelism` blocks at a time.
+/// Blocks are emitted in the order of the source stream.
+fn convert_blocks(
+    stream: BlockStream,
+    parallelism: usize,
+    encoded: Arc<EncodedBlocks>,
+    filter_key: String,
+    filter: Option<Arc<BlockFilter>>,
+    block_type: Arc<BlockType>,
+    validation: Validation,
+) -> ConvertedBlockStream {
+    let filter_key = if filter.as_ref().is_some_and(|filter| filter.strip_unmatched) {
+        format!("{} stripped", filter_key)
+    } else {
+        filter_key
+    };
+    let blocks = Pin::from(stream)
+        .map(|result| match result {
+            Ok(blocks) => futures_util::stream::iter(blocks.into_iter().map(Ok)).left_stream(),
+            Err(e) => futures_util::stream::once(async { Err(e) }).right_stream(),
+        })
+        .flatten();
+    let converted = blocks
+        .map(move |result: anyhow::Result<Block>| {
+            let encoded = encoded.clone();
+            let filter_key = filter_key.clone();
+            let filter = filter.clone();
+            let block_type = block_type.clone();
+            async move {
+                let mut block = result?;
+                validation.check(&block)?;
+                if let Some(filter) = filter {
+                    block = filter.apply(block);
+                }
+                let head = HashAndHeight::from(&block);
+                let bytes = encoded
+                    .get_or_encode(&filter_key, block, block_type.version)
+                    .await?;
+                Ok((head, block_type.wrap(bytes.to_vec())))
+            }
+        })
+        .buffered(parallelism);
+    Box::pin(converted)
+}
+
+/// One streaming phase of a request (archive, rpc, hot or reverse),
+/// its delivered range and duration are reported once it ends.
+struct Phase {
+    span: tracing::Span,
+    name: &'static str,
+    from: u64,
+    to: Option<u64>,
+    blocks: u64,
+    started: Instant,
+}
+
+impl Phase {
+    fn start(name: &'static str, from: u64) -> Phase {
+        let span = info_span!("phase", phase = name, from);
+        info!(parent: &span, phase = name, from, "phase started");
+        Phase {
+            span,
+            name,
+            from,
+            to: None,
+            blocks: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn block_sent(&mut self, height: u64) {
+        self.to = Some(height);
+        self.blocks += 1;
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        info!(
+            parent: &self.span,
+            phase = self.name,
+            from = self.from,
+            to = self.to,
+            blocks = self.blocks,
+            elapsed_ms = self.started.elapsed().as_millis() as u64,
+            "phase finished"
+        );
+    }
+}
+
+pub fn log_matches(log: &Log, requests: &[LogRequest]) -> bool {
+    requests.iter().any(|request| {
+        let address = request.address.is_empty()
+            || request
+                .address
+                .iter()
+                .any(|address| address.eq_ignore_ascii_case(&log.address));
+        let topics = request
+            .topics()
+            .into_iter()
+            .enumerate()
+            .all(|(position, values)| {
+                values.is_empty()
+                    || log.topics.get(position).is_some_and(|topic| {
+                        values.iter().any(|value| value.eq_ignore_ascii_case(topic))
+                    })
+            });
+        address && topics
+    })
+}
+
+pub fn transaction_matches(tx: &Transaction, requests: &[TransactionRequest]) -> bool {
+    let tx_sighash = tx.input.get(..10);
+    requests.iter().any(|request| {
+        let from = request.from.is_empty()
+            || request
+                .from
+                .iter()
+                .any(|from| from.eq_ignore_ascii_case(&tx.from));
+        let to = request.to.is_empty()
+            || tx
+                .to
+                .as_ref()
+                .is_some_and(|tx_to| request.to.iter().any(|to| to.eq_ignore_ascii_case(tx_to)));
+        let sighash = request.sighash.is_empty()
+            || tx_sighash.is_some_and(|tx_sighash| {
+                request
+                    .sighash
+                    .iter()
+                    .any(|sighash| sighash.eq_ignore_ascii_case(tx_sighash))
+            });
+        from && to && sighash
+    })
+}
+
+/// Keeps logs matching a log filter or emitted by a transaction matching a call filter,
+/// and transactions matching a call filter or emitting a kept log, along with their traces.
+/// Block rewards and the header are left untouched.
+fn strip_unmatched(mut block: Block, filter: &TransformFilter) -> Block {
+    if filter.logs.is_empty() && filter.transactions.is_empty() {
+        return block;
+    }
+
+    let called: HashSet<u32> = block
+        .transactions
+        .iter()
+        .filter(|tx| transaction_matches(tx, &filter.transactions))
+        .map(|tx| tx.transaction_index)
+        .collect();
+    block
+        .logs
+        .retain(|log| called.contains(&log.transaction_index) || log_matches(log, &filter.logs));
+
+    let kept: HashSet<u32> = called
+        .into_iter()
+        .chain(block.logs.iter().map(|log| log.transaction_index))
+        .collect();
+    block
+        .transactions
+        .retain(|tx| kept.contains(&tx.transaction_index));
+    block.traces.retain(|trace| {
+        matches!(trace.r#type, TraceType::Reward) || kept.contains(&trace.transaction_index)
+    });
+    block
+}
+
+/// Whether the block has a transaction or a log matched by the filters,
+/// every block matches when there are none.
+fn block_matches(block: &Block, filter: &TransformFilter) -> bool {
+    (filter.logs.is_empty() && filter.transactions.is_empty())
+        || block
+            .transactions
+            .iter()
+            .any(|tx| transaction_matches(tx, &filter.transactions))
+        || block.logs.iter().any(|log| log_matches(log, &filter.logs))
+}
+
+/// Request filters applied to the blocks on their way out.
+struct BlockFilter {
+    filter: TransformFilter,
+    /// Matched blocks only carry the matched data
+    strip_unmatched: bool,
+}
+
+impl BlockFilter {
+    /// None when the blocks are sent as the source delivers them.
+    fn new(filter: TransformFilter, strip_unmatched: bool) -> Option<BlockFilter> {
+        (strip_unmatched || filter.send_all_block_headers).then_some(BlockFilter {
+            filter,
+            strip_unmatched,
+        })
+    }
+
+    /// Blocks matching no filter are kept as light header-only blocks when all headers are requested,
+    /// so the chain stays contiguous.
+    fn apply(&self, block: Block) -> Block {
+        if self.filter.send_all_block_headers && !block_matches(&block, &self.filter) {
+            return header_only(block);
+        }
+        if self.strip_unmatched {
+            strip_unmatched(block, &self.filter)
+        } else {
+            block
+        }
+    }
+}
+
+/// Drops the body of a block that was fetched along with full blocks.
+fn header_only(mut block: Block) -> Block {
+    block.logs.clear();
+    block.transactions.clear();
+    block.traces.clear();
+    block.balance_changes.clear();
+    block
+}
+
+/// Block sent with ForkStep::StepUndo, only number, hash and parent_hash are required.
+fn undone_block(block: &HashAndHeight, parent: &HashAndHeight) -> anyhow::Result<pbcodec::Block> {
+    let hash = try_decode_hex("undone block hash", &block.hash)?;
+    let header = pbcodec::BlockHeader {
+        number: block.height,
+        hash: hash.clone(),
+        parent_hash: try_decode_hex("parent hash", &parent.hash)?,
+        ..Default::default()
+    };
+    Ok(pbcodec::Block {
+        number: block.height,
+        hash,
+        header: Some(header),
+        ..Default::default()
+    })
+}
+
+/// Errors of a request that are caused by the request itself, or by an upstream
+/// that's known to be down.
+#[derive(Debug, PartialEq)]
+pub enum FetchError {
+    InvalidArgument(String),
+    NotFound(String),
+    Unavailable(String),
+    /// The upstream served a block failing validation
+    DataLoss(String),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            FetchError::NotFound(message) => write!(f, "not found: {}", message),
+            FetchError::Unavailable(message) => write!(f, "unavailable: {}", message),
+            FetchError::DataLoss(message) => write!(f, "data loss: {}", message),
+        }
+    }
 }
 
+impl std::error::Error for FetchError {}
+
 pub struct Firehose {
     archive: Arc<dyn DataSource + Sync + Send>,
     rpc: Option<Arc<dyn HotDataSource + Sync + Send>>,
+    /// Blocks of both sources, present along with the rpc
+    merged: Option<Arc<MergedDataSource>>,
+    crossover_gap: u64,
+    metrics: Arc<Metrics>,
+    conversion_parallelism: usize,
+    encoded: Arc<EncodedBlocks>,
+    block_type: Arc<BlockType>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    call_timeout: Duration,
+    chain_id: Option<u64>,
+    max_blocks_per_second: Option<u32>,
+    max_block_range: Option<u64>,
+    validation: Validation,
+    full_traces: bool,
+    raw_blocks: bool,
+    gas_changes: bool,
 }
 
 impl Firehose {
@@ -53,22 +822,287 @@ impl Firehose {
         archive: Arc<dyn DataSource + Sync + Send>,
         rpc: Option<Arc<dyn HotDataSource + Sync + Send>>,
     ) -> Firehose {
-        Firehose { archive, rpc }
+        let merged = rpc
+            .clone()
+            .map(|rpc| Arc::new(MergedDataSource::new(archive.clone(), rpc)));
+        Firehose {
+            archive,
+            rpc,
+            merged,
+            crossover_gap: 0,
+            metrics: Arc::new(Metrics::new()),
+            conversion_parallelism: 1,
+            encoded: Arc::new(EncodedBlocks::new(ENCODED_BLOCKS_CAPACITY)),
+            block_type: Arc::new(BlockType::default()),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            call_timeout: DEFAULT_CALL_TIMEOUT,
+            chain_id: None,
+            max_blocks_per_second: None,
+            max_block_range: None,
+            validation: Validation::default(),
+            full_traces: false,
+            raw_blocks: false,
+            gas_changes: false,
+        }
+    }
+
+    /// Transient upstream failures are retried up to `max_retries` times in a row,
+    /// waiting `backoff` before the first retry and twice as long before every next one.
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Firehose {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Maximum time to wait for a data source call or the next item of a data source stream,
+    /// a timed out stream is retried like any other transient failure.
+    pub fn with_call_timeout(mut self, call_timeout: Duration) -> Firehose {
+        self.call_timeout = call_timeout;
+        self
+    }
+
+    /// The type url and version of the emitted blocks, `sf.ethereum.type.v2.Block` by default.
+    pub fn with_block_type(mut self, block_type: BlockType) -> Firehose {
+        self.block_type = Arc::new(block_type);
+        self
+    }
+
+    /// Number of finalized blocks converted concurrently while streaming.
+    pub fn with_conversion_parallelism(mut self, parallelism: usize) -> Firehose {
+        self.conversion_parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Every block stream sends at most `limit` blocks per second after an initial burst
+    /// of as many blocks, a stream over the limit is paused until it's within it again.
+    pub fn with_rate_limit(mut self, limit: Option<u32>) -> Firehose {
+        self.max_blocks_per_second = limit;
+        self
+    }
+
+    /// A stream may request at most `max_range` blocks, unbounded streams may follow the head
+    /// indefinitely but may start at most `max_range` blocks below the finalized head.
+    pub fn with_max_block_range(mut self, max_range: Option<u64>) -> Firehose {
+        self.max_block_range = max_range;
+        self
+    }
+
+    /// Fails blocks whose transactions don't add up to the gas used of their header
+    /// instead of sending them, the stream ends with a data loss error.
+    pub fn with_strict_validation(mut self, strict: bool) -> Firehose {
+        self.validation.gas_used = strict;
+        self
+    }
+
+    /// Fails blocks whose header doesn't hash to their hash, catching corrupted upstream data.
+    /// A mismatch ends the stream with a data loss error.
+    /// Headers of sources not reporting every field of their fork are sent unchecked.
+    pub fn with_hash_validation(mut self, enabled: bool) -> Firehose {
+        self.validation.header_hash = enabled;
+        self
+    }
+
+    /// Transactions matched by log filters come with their calls too, otherwise they're only
+    /// traced when a call filter asks for calls or balance and storage changes are collected.
+    pub fn with_full_traces(mut self, full_traces: bool) -> Firehose {
+        self.full_traces = full_traces;
+        self
+    }
+
+    /// The archive keeps serving finalized blocks while it's at most `gap` blocks behind the end
+    /// of the range, instead of handing off to the rpc as soon as it's behind.
+    pub fn with_crossover_gap(mut self, gap: u64) -> Firehose {
+        self.crossover_gap = gap;
+        self.merged = self.merged_source();
+        self
+    }
+
+    /// Calls to the archive or the rpc fail fast as unavailable for `cooldown` once that source
+    /// failed `max_failures` times in a row, instead of piling up retries on a struggling upstream.
+    pub fn with_circuit_breaker(
+        mut self,
+        max_failures: Option<u32>,
+        cooldown: Duration,
+    ) -> Firehose {
+        let Some(max_failures) = max_failures else {
+            return self;
+        };
+        let breaker =
+            |source| CircuitBreaker::new(source, max_failures, cooldown, self.metrics.clone());
+        self.archive = Arc::new(BreakerDataSource::new(
+            self.archive.clone(),
+            breaker("archive"),
+        ));
+        self.rpc = self.rpc.clone().map(|rpc| {
+            let rpc: Arc<dyn HotDataSource + Sync + Send> =
+                Arc::new(BreakerDataSource::new(rpc, breaker("rpc")));
+            rpc
+        });
+        self.merged = self.merged_source();
+        self
+    }
+
+    fn merged_source(&self) -> Option<Arc<MergedDataSource>> {
+        self.rpc.clone().map(|rpc| {
+            let merged = MergedDataSource::new(self.archive.clone(), rpc);
+            Arc::new(merged.with_crossover_gap(self.crossover_gap, ARCHIVE_POLL_INTERVAL))
+        })
+    }
+
+    /// Blocks served by the rpc carry the block JSON exactly as it was returned, for auditing
+    /// the conversion. It's a copy of the whole block, so it's off by default.
+    pub fn with_raw_blocks(mut self, raw_blocks: bool) -> Firehose {
+        self.raw_blocks = raw_blocks;
+        self
+    }
+
+    /// Calls carry the gas changes of their execution when the source records them, only
+    /// merged block files of an instrumented node do. Streams asking for them are traced.
+    pub fn with_gas_changes(mut self, gas_changes: bool) -> Firehose {
+        self.gas_changes = gas_changes;
+        self
+    }
+
+    /// Looks up the chain id of the data sources once, failing when the archive and the rpc
+    /// serve different chains.
+    pub async fn with_checked_chain_id(mut self) -> anyhow::Result<Firehose> {
+        let archive_chain_id = self.archive.get_chain_id().await?;
+        let rpc_chain_id = match &self.rpc {
+            Some(rpc) => rpc.get_chain_id().await?,
+            None => None,
+        };
+        if let (Some(archive_chain_id), Some(rpc_chain_id)) = (archive_chain_id, rpc_chain_id) {
+            anyhow::ensure!(
+                archive_chain_id == rpc_chain_id,
+                "archive serves chain {} while the rpc serves chain {}",
+                archive_chain_id,
+                rpc_chain_id
+            );
+        }
+        self.chain_id = rpc_chain_id.or(archive_chain_id);
+        Ok(self)
+    }
+
+    pub fn chain_id(&self) -> Option<u64> {
+        self.chain_id
+    }
+
+    /// The servable range of blocks along with what identifies them.
+    pub async fn info(&self) -> anyhow::Result<InfoResponse> {
+        let ds = match &self.merged {
+            Some(merged) => merged.as_ds(),
+            None => &*self.archive,
+        };
+        Ok(InfoResponse {
+            chain_id: self.chain_id.unwrap_or_default(),
+            first_block_num: timeout(self.call_timeout, "first block", ds.get_first_block())
+                .await??,
+            finalized_block_num: timeout(
+                self.call_timeout,
+                "finalized height",
+                ds.get_finalized_height(),
+            )
+            .await??,
+            head_block_num: timeout(self.call_timeout, "head height", ds.get_head_height())
+                .await??,
+            block_type_url: self.block_type.type_url.clone(),
+        })
+    }
+
+    /// The latest known block, reported by the rpc when there is one.
+    pub async fn head(&self) -> anyhow::Result<HeadResponse> {
+        let ds = match &self.merged {
+            Some(merged) => merged.as_ds(),
+            None => &*self.archive,
+        };
+        let head_block_num =
+            timeout(self.call_timeout, "head height", ds.get_head_height()).await??;
+        Ok(HeadResponse {
+            head_block_num,
+            head_block_hash: timeout(
+                self.call_timeout,
+                "head hash",
+                ds.get_block_hash(head_block_num),
+            )
+            .await??,
+            finalized_block_num: timeout(
+                self.call_timeout,
+                "finalized height",
+                ds.get_finalized_height(),
+            )
+            .await??,
+        })
+    }
+
+    /// Finalized height of the archive and height of the chain head, reported by the rpc
+    /// when there is one, fails if either source is unavailable or doesn't respond in time.
+    pub async fn sync_heights(&self) -> anyhow::Result<(u64, u64)> {
+        let finalized = timeout(
+            self.call_timeout,
+            "archive finalized height",
+            self.archive.get_finalized_height(),
+        )
+        .await??;
+        let head = match &self.rpc {
+            Some(rpc) => {
+                timeout(self.call_timeout, "rpc head height", rpc.get_head_height()).await??
+            }
+            None => {
+                timeout(
+                    self.call_timeout,
+                    "archive head height",
+                    self.archive.get_head_height(),
+                )
+                .await??
+            }
+        };
+        Ok((finalized, head))
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
     }
 
+    /// With `unmatched_stripped` the blocks only carry the data matched by the request filters.
     pub async fn blocks(
         &self,
         request: Request,
+        unmatched_stripped: bool,
     ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Response>>> {
-        let from_block = if request.cursor.is_empty() {
-            if let Some(rpc) = &self.rpc {
-                resolve_negative_start(request.start_block_num, rpc.as_ds()).await?
+        let ds = match &self.merged {
+            Some(merged) => merged.as_ds(),
+            None => &*self.archive,
+        };
+        let (from_block, cursor_head) = if request.cursor.is_empty() || request.reverse {
+            let from_block = if request.start_block_tag.is_empty() {
+                timeout(
+                    self.call_timeout,
+                    "finalized height",
+                    resolve_start(request.start_block_num, ds),
+                )
+                .await??
             } else {
-                resolve_negative_start(request.start_block_num, &*self.archive).await?
-            }
+                // tags name blocks of the chain head, only the rpc knows about them
+                let tag = parse_block_tag(&request.start_block_tag)?;
+                let rpc = self.rpc.as_ref().ok_or_else(|| {
+                    FetchError::InvalidArgument(
+                        "start block tags require an rpc source".to_string(),
+                    )
+                })?;
+                timeout(
+                    self.call_timeout,
+                    "tagged height",
+                    rpc.get_tagged_height(tag),
+                )
+                .await??
+            };
+            (from_block, None)
         } else {
             let cursor = Cursor::try_from(&request.cursor).map_err(|e| anyhow::anyhow!(e))?;
-            cursor.block.height + 1
+            // the block of an undo cursor is gone, streams go on from its parent
+            let head = cursor.head().clone();
+            (head.height + 1, Some(head))
         };
 
         let to_block = if request.stop_block_num == 0 {
@@ -77,231 +1111,662 @@ impl Firehose {
             Some(request.stop_block_num)
         };
 
-        let mut logs: Vec<LogRequest> = vec![];
-        let mut transactions: Vec<TransactionRequest> = vec![];
-        for transform in &request.transforms {
-            let filter = CombinedFilter::decode(&transform.value[..])?;
-
-            for log_filter in filter.log_filters {
-                let log_request = LogRequest {
-                    address: log_filter
-                        .addresses
-                        .into_iter()
-                        .map(|address| prefix_hex::encode(address))
-                        .collect(),
-                    topic0: log_filter
-                        .event_signatures
-                        .into_iter()
-                        .map(|signature| prefix_hex::encode(signature))
-                        .collect(),
-                };
-                logs.push(log_request);
-            }
-
-            for call_filter in filter.call_filters {
-                let tx_request = TransactionRequest {
-                    address: call_filter
-                        .addresses
-                        .into_iter()
-                        .map(|address| prefix_hex::encode(address))
-                        .collect(),
-                    sighash: call_filter
-                        .signatures
-                        .into_iter()
-                        .map(|signature| prefix_hex::encode(signature))
-                        .collect(),
-                };
-                transactions.push(tx_request);
+        // the exclusive upper bound of the blocks left to send in reverse order
+        let reverse_end = if request.reverse {
+            let to_block = to_block.ok_or_else(|| {
+                FetchError::InvalidArgument("reverse order requires a stop block".to_string())
+            })?;
+            let height = timeout(
+                self.call_timeout,
+                "finalized height",
+                ds.get_finalized_height(),
+            )
+            .await??;
+            if to_block > height {
+                return Err(FetchError::InvalidArgument(format!(
+                    "reverse order requires finalized blocks, stop block №{} is beyond the finalized height {}",
+                    to_block, height
+                ))
+                .into());
+            }
+            if request.cursor.is_empty() {
+                Some(to_block + 1)
+            } else {
+                let cursor = Cursor::try_from(&request.cursor).map_err(|e| anyhow::anyhow!(e))?;
+                Some(cursor.head().height)
+            }
+        } else {
+            None
+        };
+
+        if let Some(max_range) = self.max_block_range {
+            let last_block = match to_block {
+                Some(to_block) => to_block,
+                None => {
+                    let height = ds.get_finalized_height();
+                    timeout(self.call_timeout, "finalized height", height).await??
+                }
+            };
+            let range = (last_block + 1).saturating_sub(from_block);
+            if range > max_range {
+                return Err(FetchError::InvalidArgument(format!(
+                    "{} blocks are requested from block №{} to block №{}, at most {} are served per stream",
+                    range, from_block, last_block, max_range
+                ))
+                .into());
             }
         }
 
+        let TransformFilter {
+            logs,
+            transactions,
+            calls,
+            headers_only,
+            raw_receipts,
+            balance_changes,
+            storage_changes,
+            send_all_block_headers,
+        } = decode_transforms(&request.transforms)?;
+        let filter = BlockFilter::new(
+            TransformFilter {
+                logs: logs.clone(),
+                transactions: transactions.clone(),
+                calls,
+                headers_only,
+                raw_receipts,
+                balance_changes,
+                storage_changes,
+                send_all_block_headers,
+            },
+            unmatched_stripped,
+        )
+        .map(Arc::new);
+
         let archive = self.archive.clone();
-        let rpc = self.rpc.clone();
+        let rpc = self.merged.clone();
+        let source: Arc<dyn DataSource + Sync + Send> = match &self.merged {
+            Some(merged) => merged.clone(),
+            None => self.archive.clone(),
+        };
+        let gas_changes = self.gas_changes;
+        let raw_blocks = self.raw_blocks;
+        // tracing is the most expensive part of a block, only call filters need it
+        let traces = calls || self.full_traces || balance_changes || storage_changes || gas_changes;
+        let metrics = self.metrics.clone();
+        let parallelism = self.conversion_parallelism;
+        let encoded = self.encoded.clone();
+        let block_type = self.block_type.clone();
+        let validation = self.validation;
+        let max_retries = self.max_retries;
+        let retry_backoff = self.retry_backoff;
+        let call_timeout = self.call_timeout;
+        let mut limiter = self.max_blocks_per_second.map(RateLimiter::new);
+        let final_blocks_only = request.final_blocks_only;
+        let final_step = if final_blocks_only {
+            ForkStep::StepFinal
+        } else {
+            ForkStep::StepNew
+        };
+        // every event of the stream, retries included, is correlated with the requested range
+        let span = info_span!(
+            "blocks",
+            start = request.start_block_num,
+            stop = request.stop_block_num,
+            cursor = !request.cursor.is_empty(),
+            final_blocks_only,
+            reverse = request.reverse
+        );
 
-        Ok(try_stream! {
-            let mut state = None;
+        let stream = try_stream! {
+            let _active = metrics.stream_started();
+            // resuming from a cursor lets the hot stream detect forks of the last delivered block
+            let mut state = cursor_head;
             let mut from_block = from_block;
 
-            let archive_height = archive.get_finalized_height().await?;
-            if from_block < archive_height || rpc.is_none() {
-                let req = DataRequest {
-                    from: from_block,
-                    to: to_block,
-                    logs: logs.clone(),
-                    transactions: transactions.clone(),
-                };
-                let mut stream = Pin::from(archive.get_finalized_blocks(req, rpc.is_some())?);
-                while let Some(result) = stream.next().await {
-                    let blocks = result?;
-                    for block in blocks {
-                        let cursor = Cursor::new((&block).into(), (&block).into());
-                        state = Some(HashAndHeight {
-                            hash: block.header.hash.clone(),
-                            height: block.header.number,
-                        });
-                        from_block = block.header.number + 1;
+            let mut backoff = Backoff::new(max_retries, retry_backoff);
 
-                        let graph_block = pbcodec::Block::try_from(block)?;
+            let archive_height =
+                timeout(call_timeout, "archive finalized height", archive.get_finalized_height())
+                    .await??;
+            metrics.set_height("archive", archive_height);
+            if let Some(rpc) = &rpc {
+                let rpc_height =
+                    timeout(call_timeout, "rpc finalized height", rpc.get_finalized_height())
+                        .await??;
+                metrics.set_height("rpc", rpc_height);
+            }
+
+            if let Some(mut end) = reverse_end {
+                // batches are fetched in ascending order and sent from the top down,
+                // every block is finalized so there are no forks to follow
+                let mut phase = Phase::start("reverse", end.saturating_sub(1));
+                while end > from_block {
+                    let from = end.saturating_sub(REVERSE_BATCH_SIZE).max(from_block);
+                    let req = DataRequest {
+                        from,
+                        to: Some(end - 1),
+                        logs: logs.clone(),
+                        transactions: transactions.clone(),
+                        balance_changes,
+                        storage_changes,
+                        gas_changes,
+                        traces,
+                        headers_only,
+                        raw_receipts,
+                        raw_blocks,
+                        all_blocks: send_all_block_headers,
+                    };
+                    let filter_key = req.filter_key();
+                    let batch: anyhow::Result<Vec<_>> = async {
+                        let mut stream = source.get_finalized_blocks(req, true)?;
+                        if balance_changes {
+                            stream = with_transaction_fees(stream);
+                        }
+                        let mut stream = convert_blocks(
+                            stream,
+                            parallelism,
+                            encoded.clone(),
+                            filter_key,
+                            filter.clone(),
+                            block_type.clone(),
+                            validation,
+                        );
+                        let mut batch = vec![];
+                        while let Some(converted) =
+                            timeout(call_timeout, "finalized block stream", stream.next()).await?
+                        {
+                            batch.push(converted?);
+                        }
+                        Ok(batch)
+                    }
+                    .await;
+                    let batch = match batch {
+                        Ok(batch) => batch,
+                        Err(e) => {
+                            backoff.retry(e).await?;
+                            continue
+                        }
+                    };
+                    backoff.reset();
 
+                    for (head, block) in batch.into_iter().rev() {
+                        let cursor = Cursor::new(head.clone(), head.clone()).with_step(final_step);
+                        let name = if head.height <= archive_height { "archive" } else { "rpc" };
+                        metrics.block_sent(name, final_step);
+                        phase.block_sent(head.height);
                         yield Response {
-                            block: Some(prost_types::Any {
-                                type_url: "type.googleapis.com/sf.ethereum.type.v2.Block".to_string(),
-                                value: graph_block.encode_to_vec(),
-                            }),
-                            step: ForkStep::StepNew.into(),
+                            block: Some(block),
+                            step: final_step.into(),
                             cursor: cursor.to_string(),
                         };
                     }
+                    end = from;
                 }
-
-                if let Some(to_block) = to_block {
-                    if state.as_ref().unwrap().height == to_block {
-                        return
-                    }
-                }
-            }
-
-            let rpc = if let Some(rpc) = rpc {
-                rpc
-            } else {
                 return
-            };
+            }
 
-            let rpc_height = rpc.get_finalized_height().await?;
-            if from_block < rpc_height {
-                let to = if let Some(to_block) = to_block {
-                    std::cmp::min(to_block, rpc_height)
-                } else {
-                    rpc_height
-                };
+            // the merged source ends at the finalized rpc head, leaving the rest to the hot stream
+            let stop_on_head = rpc.is_some() || final_blocks_only;
+            // the archive phase ends once the first block above its head is delivered
+            let mut phase: Option<Phase> = None;
+            // a failed stream is restarted right after the last delivered block
+            loop {
+                if to_block.is_some_and(|to_block| from_block > to_block) {
+                    break
+                }
                 let req = DataRequest {
                     from: from_block,
-                    to: Some(to),
+                    to: to_block,
                     logs: logs.clone(),
                     transactions: transactions.clone(),
+                    balance_changes,
+                    storage_changes,
+                    gas_changes,
+                    traces,
+                    headers_only,
+                    raw_receipts,
+                    raw_blocks,
+                    all_blocks: send_all_block_headers,
                 };
-                let mut stream = Pin::from(rpc.get_finalized_blocks(req, true)?);
-                while let Some(result) = stream.next().await {
-                    let blocks = result?;
-                    for block in blocks {
-                        let cursor = Cursor::new((&block).into(), (&block).into());
-                        let graph_block = pbcodec::Block::try_from(block)?;
-
-                        yield Response {
-                            block: Some(prost_types::Any {
-                                type_url: "type.googleapis.com/sf.ethereum.type.v2.Block".to_string(),
-                                value: graph_block.encode_to_vec(),
-                            }),
-                            step: ForkStep::StepNew.into(),
-                            cursor: cursor.to_string(),
-                        };
+                let filter_key = req.filter_key();
+                let mut stream = match source.get_finalized_blocks(req, stop_on_head) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        backoff.retry(e).await?;
+                        continue
                     }
+                };
+                if balance_changes {
+                    stream = with_transaction_fees(stream);
                 }
-                state = Some(HashAndHeight {
-                    hash: rpc.get_block_hash(to).await?,
-                    height: to,
-                });
-                from_block = to + 1;
-
-                if let Some(to_block) = to_block {
-                    if state.as_ref().unwrap().height == to_block {
-                        return
+                let mut stream = convert_blocks(
+                    stream,
+                    parallelism,
+                    encoded.clone(),
+                    filter_key,
+                    filter.clone(),
+                    block_type.clone(),
+                    validation,
+                );
+                let mut failure = None;
+                while let Some(result) =
+                    timeout(call_timeout, "finalized block stream", stream.next())
+                        .await
+                        .transpose()
+                {
+                    let (head, block) = match result.and_then(|converted| converted) {
+                        Ok(converted) => converted,
+                        Err(e) => {
+                            failure = Some(e);
+                            break
+                        }
+                    };
+                    if head.height < from_block {
+                        Err(anyhow::anyhow!(
+                            "block №{} was already delivered, expected №{} onwards",
+                            head.height, from_block
+                        ))?;
+                    }
+                    backoff.reset();
+                    let cursor = Cursor::new(head.clone(), head.clone()).with_step(final_step);
+                    let name = if head.height <= archive_height { "archive" } else { "rpc" };
+                    if phase.as_ref().is_none_or(|phase| phase.name != name) {
+                        phase = Some(Phase::start(name, head.height));
                     }
+                    phase.as_mut().unwrap().block_sent(head.height);
+                    from_block = head.height + 1;
+                    state = Some(head);
+
+                    metrics.block_sent(name, final_step);
+                    yield Response {
+                        block: Some(block),
+                        step: final_step.into(),
+                        cursor: cursor.to_string(),
+                    };
+                }
+                match failure {
+                    Some(e) => backoff.retry(e).await?,
+                    None => break,
                 }
             }
+            drop(phase);
 
-            let req = DataRequest {
+            if to_block.is_some_and(|to_block| from_block > to_block) {
+                return
+            }
+
+            let rpc = match rpc {
+                Some(rpc) if !final_blocks_only => rpc,
+                _ => return,
+            };
+
+            let req = DataRequest {
                 from: from_block,
                 to: to_block,
                 logs,
                 transactions,
+                balance_changes,
+                storage_changes,
+                gas_changes,
+                traces,
+                headers_only,
+                raw_receipts,
+                raw_blocks,
+                all_blocks: send_all_block_headers,
+            };
+            let state = match state {
+                Some(state) => state,
+                None => {
+                    // nothing was delivered yet, e.g. the archive had no blocks of the range,
+                    // so the hot stream starts right after the requested block
+                    let height = from_block.checked_sub(1).ok_or_else(|| {
+                        anyhow::format_err!("genesis block isn't final, no block to follow it from")
+                    })?;
+                    let hash = rpc.get_block_hash(height);
+                    match timeout(call_timeout, "rpc block hash", hash).await? {
+                        Ok(hash) => HashAndHeight { hash, height },
+                        Err(e) => {
+                            let rpc_height = rpc.get_finalized_height();
+                            let rpc_height =
+                                timeout(call_timeout, "rpc finalized height", rpc_height).await??;
+                            Err(e.context(format!(
+                                "start block №{} is beyond the chain head, finalized height is {}",
+                                from_block, rpc_height
+                            )))?
+                        }
+                    }
+                }
             };
-            let state = state.context("state isn't expected to be None")?;
             let mut last_head = state.clone();
-            let mut stream = Pin::from(rpc.get_hot_blocks(req, state)?);
-            while let Some(result) = stream.next().await {
-                let upd = result?;
-
-                let new_head = if upd.blocks.is_empty() {
-                    upd.base_head.clone()
-                } else {
-                    let header = &upd.blocks.last().unwrap().header;
-                    HashAndHeight {
-                        hash: header.hash.clone(),
-                        height: header.number,
+            // delivered blocks that are still reversible, they are sent again as final later on
+            // the block the hot stream starts from was delivered before and is only kept for undos
+            let mut reversible: VecDeque<(HashAndHeight, Option<prost_types::Any>)> =
+                VecDeque::from([(state.clone(), None)]);
+            let mut finalized_head = state.clone();
+            let mut phase = Phase::start("hot", last_head.height + 1);
+            let mut restarted = false;
+            // a failed stream is restarted from the last delivered head, keeping the reversible blocks
+            loop {
+                if restarted && reversible.len() > 1 {
+                    // delivered blocks could be reorged away while the stream was down,
+                    // the ones the source doesn't see anymore are undone before restarting
+                    let from = reversible.front().unwrap().0.height;
+                    let hashes = rpc.get_block_hashes(from, last_head.height);
+                    let hashes = match timeout(call_timeout, "rpc block hashes", hashes).await? {
+                        Ok(hashes) => hashes,
+                        Err(e) => {
+                            backoff.retry(e).await?;
+                            continue
+                        }
+                    };
+                    while reversible.len() > 1 {
+                        let (block, _) = reversible.back().unwrap();
+                        if hashes.get((block.height - from) as usize) == Some(&block.hash) {
+                            break
+                        }
+                        let (block, _) = reversible.pop_back().unwrap();
+                        let parent = &reversible.back().unwrap().0;
+                        let cursor = Cursor::undo(block.clone(), parent.clone(), finalized_head.clone());
+                        let graph_block = undone_block(&block, parent)?;
+                        metrics.block_sent("hot", ForkStep::StepUndo);
+                        yield Response {
+                            block: Some(block_type.encode(graph_block)),
+                            step: ForkStep::StepUndo.into(),
+                            cursor: cursor.to_string(),
+                        };
+                    }
+                    last_head = reversible.back().unwrap().0.clone();
+                }
+                let req = DataRequest {
+                    from: last_head.height + 1,
+                    ..req.clone()
+                };
+                let mut stream = match rpc.get_hot_blocks(req, last_head.clone()) {
+                    Ok(stream) => Pin::from(stream),
+                    Err(e) => {
+                        backoff.retry(e).await?;
+                        continue
                     }
                 };
+                let mut failure = None;
+                while let Some(result) =
+                    timeout(call_timeout, "hot block stream", stream.next())
+                        .await
+                        .transpose()
+                {
+                    let upd = match result.and_then(|upd| upd) {
+                        Ok(upd) => upd,
+                        Err(e) => {
+                            failure = Some(e);
+                            break
+                        }
+                    };
+                    backoff.reset();
 
-                if upd.base_head != last_head {
-                    // fork happened
-                    // only number and parent_hash are required for ForkStep::StepUndo
-                    let cursor = Cursor::new(upd.base_head.clone(), upd.finalized_head.clone());
-                    let mut graph_block = pbcodec::Block::default();
-                    let mut header = pbcodec::BlockHeader::default();
-                    header.number = last_head.height;
-                    header.parent_hash = prefix_hex::decode(upd.base_head.hash)?;
-                    graph_block.header = Some(header);
-
-                    yield Response {
-                        block: Some(prost_types::Any {
-                            type_url: "type.googleapis.com/sf.ethereum.type.v2.Block".to_string(),
-                            value: graph_block.encode_to_vec(),
-                        }),
-                        step: ForkStep::StepUndo.into(),
-                        cursor: cursor.to_string(),
+                    let new_head = if upd.blocks.is_empty() {
+                        upd.base_head.clone()
+                    } else {
+                        let header = &upd.blocks.last().unwrap().header;
+                        HashAndHeight {
+                            hash: header.hash.clone(),
+                            height: header.number,
+                        }
                     };
-                }
 
-                for block in upd.blocks {
-                    let cursor = Cursor::new((&block).into(), upd.finalized_head.clone());
-                    let graph_block = pbcodec::Block::try_from(block)?;
-                    yield Response {
-                        block: Some(prost_types::Any {
-                            type_url: "type.googleapis.com/sf.ethereum.type.v2.Block".to_string(),
-                            value: graph_block.encode_to_vec(),
-                        }),
-                        step: ForkStep::StepNew.into(),
-                        cursor: cursor.to_string(),
+                    metrics.set_height("head", new_head.height);
+
+                    if upd.base_head != last_head {
+                        // fork happened, every delivered block above the common ancestor is undone
+                        metrics.reorg();
+                        let mut undone = 0;
+                        while let Some((block, _)) = reversible.back() {
+                            if block.height <= upd.base_head.height {
+                                break
+                            }
+                            let (block, _) = reversible.pop_back().unwrap();
+                            let parent = reversible.back().map_or(&upd.base_head, |(parent, _)| parent);
+                            let cursor = Cursor::undo(block.clone(), parent.clone(), upd.finalized_head.clone());
+                            let graph_block = undone_block(&block, parent)?;
+
+                            metrics.block_sent("hot", ForkStep::StepUndo);
+                            undone += 1;
+                            yield Response {
+                                block: Some(block_type.encode(graph_block)),
+                                step: ForkStep::StepUndo.into(),
+                                cursor: cursor.to_string(),
+                            };
+                        }
+                        info!(
+                            parent: &phase.span,
+                            old_head = last_head.height,
+                            old_hash = %last_head.hash,
+                            new_head = new_head.height,
+                            new_hash = %new_head.hash,
+                            common_ancestor = upd.base_head.height,
+                            undone,
+                            "reorg"
+                        );
+                    }
+
+                    for mut block in upd.blocks {
+                        // the base head was delivered already, by the finalized stream at the handoff
+                        if block.header.number <= upd.base_head.height {
+                            continue
+                        }
+                        validation.check(&block)?;
+                        if balance_changes {
+                            credit_transaction_fees(&mut block)?;
+                        }
+                        if let Some(filter) = &filter {
+                            block = filter.apply(block);
+                        }
+                        let head = HashAndHeight::from(&block);
+                        let head_height = head.height;
+                        let cursor = Cursor::new(head.clone(), upd.finalized_head.clone());
+                        let graph_block = pbcodec::Block::try_from(block)?;
+                        let any = block_type.encode(graph_block);
+                        reversible.push_back((head, Some(any.clone())));
+                        metrics.block_sent("hot", ForkStep::StepNew);
+                        phase.block_sent(head_height);
+                        yield Response {
+                            block: Some(any),
+                            step: ForkStep::StepNew.into(),
+                            cursor: cursor.to_string(),
+                        }
                     }
-                }
 
-                last_head = new_head;
+                    last_head = new_head;
+                    finalized_head = upd.finalized_head.clone();
+
+                    while let Some((block, _)) = reversible.front() {
+                        if block.height > upd.finalized_head.height {
+                            break
+                        }
+                        let (block, any) = reversible.pop_front().unwrap();
+                        if let Some(any) = any {
+                            let cursor = Cursor::new(last_head.clone(), block)
+                                .with_step(ForkStep::StepFinal);
+                            metrics.block_sent("hot", ForkStep::StepFinal);
+                            yield Response {
+                                block: Some(any),
+                                step: ForkStep::StepFinal.into(),
+                                cursor: cursor.to_string(),
+                            }
+                        }
+                    }
+                }
+                match failure {
+                    Some(e) => {
+                        backoff.retry(e).await?;
+                        restarted = true;
+                    }
+                    None => break,
+                }
             }
-        })
+        };
+        let metrics = self.metrics.clone();
+        let stream = async_stream::stream! {
+            tokio::pin!(stream);
+            while let Some(result) = stream.next().await {
+                if let (Ok(_), Some(limiter)) = (&result, &mut limiter) {
+                    if limiter.acquire().await {
+                        metrics.throttled();
+                    }
+                }
+                yield result;
+            }
+        };
+        let mut stream = Box::pin(stream);
+        Ok(futures_util::stream::poll_fn(move |cx| {
+            let _entered = span.enter();
+            stream.as_mut().poll_next(cx)
+        }))
     }
 
     pub async fn block(&self, request: SingleBlockRequest) -> anyhow::Result<SingleBlockResponse> {
-        let block_num = match request.reference.as_ref().unwrap() {
-            Reference::BlockNumber(block_number) => block_number.num,
-            Reference::BlockHashAndNumber(block_hash_and_number) => block_hash_and_number.num,
-            Reference::Cursor(cursor) => {
-                let cursor = Cursor::try_from(&cursor.cursor).unwrap();
-                cursor.block.height
-            }
-        };
+        let block = self.fetch_blocks(&[request]).await?.pop();
+        Ok(SingleBlockResponse { block })
+    }
+
+    pub async fn multi_block(
+        &self,
+        request: MultiBlockRequest,
+    ) -> anyhow::Result<MultiBlockResponse> {
+        let blocks = self.fetch_blocks(&request.requests).await?;
+        Ok(MultiBlockResponse { blocks })
+    }
+
+    /// Resolves every reference with a single data source call.
+    /// Blocks come with every transaction and its calls unless a HeaderOnly or LightBlock
+    /// transform asks for the header only, other transforms don't apply to single blocks.
+    async fn fetch_blocks(
+        &self,
+        requests: &[SingleBlockRequest],
+    ) -> anyhow::Result<Vec<prost_types::Any>> {
+        if requests.len() > MAX_BLOCK_REFERENCES {
+            return Err(FetchError::InvalidArgument(format!(
+                "{} blocks are requested, at most {} are served per request",
+                requests.len(),
+                MAX_BLOCK_REFERENCES
+            ))
+            .into());
+        }
+        let references = requests
+            .iter()
+            .map(resolve_reference)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let heights: Vec<u64> = references.iter().map(|(height, _)| *height).collect();
+        let filters = requests
+            .iter()
+            .map(|request| decode_transforms(&request.transforms))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let headers_only: Vec<bool> = filters.iter().map(|filter| filter.headers_only).collect();
+        // bodies are fetched once any of the blocks needs one, and so are state changes
+        let bodies = headers_only.iter().any(|headers_only| !headers_only);
+        let with_bodies = || filters.iter().filter(|filter| !filter.headers_only);
+        let balance_changes = with_bodies().any(|filter| filter.balance_changes);
+        let storage_changes = with_bodies().any(|filter| filter.storage_changes);
 
         let req = DataRequest {
-            from: block_num,
-            to: Some(block_num),
+            from: 0,
+            to: None,
             logs: vec![],
-            transactions: vec![],
+            transactions: if bodies {
+                vec![TransactionRequest {
+                    to: vec![],
+                    sighash: vec![],
+                    from: vec![],
+                }]
+            } else {
+                vec![]
+            },
+            balance_changes,
+            storage_changes,
+            gas_changes: self.gas_changes && bodies,
+            traces: bodies,
+            headers_only: !bodies,
+            // single blocks come from the archive, which has no raw receipts
+            raw_receipts: false,
+            raw_blocks: self.raw_blocks,
+            all_blocks: true,
         };
+        let blocks = timeout(
+            self.call_timeout,
+            "archive blocks",
+            self.archive.get_blocks_by_refs(&heights, req),
+        )
+        .await??;
+        if blocks.len() != references.len() {
+            anyhow::bail!(
+                "{} blocks were returned for {} references",
+                blocks.len(),
+                references.len()
+            );
+        }
 
-        let mut stream = Pin::from(self.archive.get_finalized_blocks(req, true)?);
-        let blocks = stream.next().await.unwrap()?;
-        let block = blocks.into_iter().nth(0).unwrap();
+        let mut result = Vec::with_capacity(blocks.len());
+        for ((((block_num, expected_hash), headers_only), block), request) in references
+            .into_iter()
+            .zip(headers_only)
+            .zip(blocks)
+            .zip(requests)
+        {
+            let mut block =
+                block.ok_or_else(|| FetchError::NotFound(format!("block №{}", block_num)))?;
 
-        let graph_block = pbcodec::Block::try_from(block)?;
+            if let Some(expected_hash) = expected_hash {
+                if try_decode_hex("hash", &block.header.hash)? != expected_hash {
+                    let mut message = format!(
+                        "block {} at height {}",
+                        prefix_hex::encode(expected_hash),
+                        block_num
+                    );
+                    if let Some(Reference::Cursor(_)) = request.reference {
+                        message.push_str(", the cursor points to a block that was reorged away");
+                    }
+                    return Err(FetchError::NotFound(message).into());
+                }
+            }
 
-        Ok(SingleBlockResponse {
-            block: Some(prost_types::Any {
-                type_url: "type.googleapis.com/sf.ethereum.type.v2.Block".to_string(),
-                value: graph_block.encode_to_vec(),
-            }),
-        })
+            self.validation.check(&block)?;
+            if balance_changes {
+                credit_transaction_fees(&mut block)?;
+            }
+            if headers_only {
+                block = header_only(block);
+            }
+            let graph_block = pbcodec::Block::try_from(block)?;
+            result.push(self.block_type.encode(graph_block));
+        }
+        Ok(result)
     }
 }
 
+/// The height of the referenced block and its hash when the reference pins one,
+/// cursors pin the hash of the block they were issued for.
+fn resolve_reference(request: &SingleBlockRequest) -> anyhow::Result<(u64, Option<Vec<u8>>)> {
+    let reference = request
+        .reference
+        .as_ref()
+        .ok_or_else(|| FetchError::InvalidArgument("block reference is required".to_string()))?;
+    let resolved = match reference {
+        Reference::BlockNumber(block_number) => (block_number.num, None),
+        Reference::BlockHashAndNumber(block_hash_and_number) => {
+            let hash = try_decode_hex("block hash", &block_hash_and_number.hash)
+                .map_err(|e| FetchError::InvalidArgument(e.to_string()))?;
+            (block_hash_and_number.num, Some(hash))
+        }
+        Reference::Cursor(cursor) => {
+            let cursor = Cursor::try_from(&cursor.cursor)
+                .map_err(|e| FetchError::InvalidArgument(format!("{}: {}", e, cursor.cursor)))?;
+            let hash = try_decode_hex("cursor block hash", &cursor.block.hash)
+                .map_err(|e| FetchError::InvalidArgument(e.to_string()))?;
+            (cursor.block.height, Some(hash))
+        }
+    };
+    Ok(resolved)
+}
+
 impl TryFrom<BlockHeader> for pbcodec::BlockHeader {
     type Error = anyhow::Error;
 
@@ -314,32 +1779,284 @@ impl TryFrom<BlockHeader> for pbcodec::BlockHeader {
             transactions_root: try_decode_hex("transactions root", &value.transactions_root)?,
             receipt_root: try_decode_hex("receipts root", &value.receipts_root)?,
             logs_bloom: try_decode_hex("logs bloom", &value.logs_bloom)?,
-            difficulty: Some(pbcodec::BigInt {
-                bytes: try_decode_hex("difficulty", &value.difficulty)?,
-            }),
-            total_difficulty: Some(pbcodec::BigInt {
-                bytes: try_decode_hex("total difficulty", &value.total_difficulty)?,
-            }),
+            difficulty: Some(big_int("difficulty", &value.difficulty, BIG_INT_MAX_BYTES)?),
+            total_difficulty: Some(big_int(
+                "total difficulty",
+                &value.total_difficulty,
+                TOTAL_BIG_INT_MAX_BYTES,
+            )?),
             number: value.number,
-            gas_limit: qty2int(&value.gas_limit)?,
-            gas_used: qty2int(&value.gas_used)?,
+            gas_limit: qty2int("gas limit", &value.gas_limit)?,
+            gas_used: qty2int("gas used", &value.gas_used)?,
             timestamp: Some(prost_types::Timestamp {
                 seconds: i64::try_from(value.timestamp)?,
                 nanos: 0,
             }),
             extra_data: try_decode_hex("extra data", &value.extra_data)?,
             mix_hash: try_decode_hex("mix hash", &value.mix_hash)?,
-            nonce: qty2int(&value.nonce)?,
+            nonce: qty2int("nonce", &value.nonce)?,
             hash: try_decode_hex("hash", &value.hash)?,
-            base_fee_per_gas: value.base_fee_per_gas.map_or::<anyhow::Result<_>, _>(
-                Ok(None),
-                |val| {
-                    Ok(Some(pbcodec::BigInt {
-                        bytes: try_decode_hex("base fee per gas", &val)?,
+            base_fee_per_gas: value
+                .base_fee_per_gas
+                .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
+                    Ok(Some(big_int("base fee per gas", &val, BIG_INT_MAX_BYTES)?))
+                })?,
+            l2_extra: value
+                .l2_extra
+                .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
+                    Ok(Some(pbcodec::L2Extra {
+                        l1_block_number: val.l1_block_number,
+                        send_root: try_decode_hex("send root", &val.send_root)?,
                     }))
-                },
-            )?,
+                })?,
+            blob_gas_used: value
+                .blob_gas_used
+                .map(|val| qty2int("blob gas used", &val))
+                .transpose()?,
+            excess_blob_gas: value
+                .excess_blob_gas
+                .map(|val| qty2int("excess blob gas", &val))
+                .transpose()?,
+            withdrawals_root: value
+                .withdrawals_root
+                .map_or(Ok(vec![]), |root| try_decode_hex("withdrawals root", &root))?,
+            parent_beacon_root: value.parent_beacon_root.map_or(Ok(vec![]), |root| {
+                try_decode_hex("parent beacon root", &root)
+            })?,
+            requests_hash: value
+                .requests_hash
+                .map_or(Ok(vec![]), |hash| try_decode_hex("requests hash", &hash))?,
+        })
+    }
+}
+
+impl TryFrom<AccessTuple> for pbcodec::AccessTuple {
+    type Error = anyhow::Error;
+
+    fn try_from(value: AccessTuple) -> Result<Self, Self::Error> {
+        Ok(pbcodec::AccessTuple {
+            address: try_decode_hex("access list address", &value.address)?,
+            storage_keys: value
+                .storage_keys
+                .iter()
+                .map(|key| try_decode_hex("access list storage key", key))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        })
+    }
+}
+
+fn try_decode_u256(label: &'static str, value: &str) -> anyhow::Result<evm::U256> {
+    let buf = try_decode_hex(label, value)?;
+    anyhow::ensure!(buf.len() <= 32, "invalid {}: {}", label, value);
+    Ok(evm::U256::from_big_endian(&buf))
+}
+
+/// Typed transactions are signed with a parity bit, reported either as `yParity`
+/// or as `v`, which some sources still give in the legacy 27/28 form.
+fn signature_parity(tx: &Transaction) -> anyhow::Result<u8> {
+    if let Some(parity) = tx.y_parity {
+        anyhow::ensure!(parity <= 1, "invalid tx y parity: {}", parity);
+        return Ok(parity);
+    }
+    match qty2int("tx v", &tx.v)? {
+        v @ (0 | 1) => Ok(v as u8),
+        v @ (27 | 28) => Ok((v - 27) as u8),
+        v => anyhow::bail!("invalid tx v of a typed transaction: {}", v),
+    }
+}
+
+/// Recovers the uncompressed secp256k1 key (without the 0x04 prefix) that signed the transaction.
+fn recover_public_key(tx: &Transaction) -> anyhow::Result<Vec<u8>> {
+    // ethers-core can't compute the signing hash of blob transactions
+    anyhow::ensure!(tx.r#type <= 2, "unsupported transaction type {}", tx.r#type);
+    let v = if tx.r#type == 0 {
+        qty2int("tx v", &tx.v)?
+    } else {
+        signature_parity(tx)?.into()
+    };
+    let chain_id = if tx.r#type == 0 {
+        // pre EIP-155 signatures don't commit to a chain id
+        (v >= 35).then(|| (v - 35) / 2)
+    } else {
+        tx.chain_id
+    };
+    let access_list = tx
+        .access_list
+        .iter()
+        .map(|item| {
+            Ok(evm::transaction::eip2930::AccessListItem {
+                address: item.address.parse()?,
+                storage_keys: item
+                    .storage_keys
+                    .iter()
+                    .map(|key| key.parse())
+                    .collect::<Result<_, _>>()?,
+            })
         })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let unsigned = evm::Transaction {
+        nonce: tx.nonce.into(),
+        to: tx.to.as_deref().map(str::parse).transpose()?,
+        value: try_decode_u256("tx value", &tx.value)?,
+        gas: try_decode_u256("tx gas", &tx.gas)?,
+        gas_price: Some(try_decode_u256("tx gas price", &tx.gas_price)?),
+        input: try_decode_hex("tx input", &tx.input)?.into(),
+        max_fee_per_gas: tx
+            .max_fee_per_gas
+            .as_deref()
+            .map(|val| try_decode_u256("tx max fee", val))
+            .transpose()?,
+        max_priority_fee_per_gas: tx
+            .max_priority_fee_per_gas
+            .as_deref()
+            .map(|val| try_decode_u256("tx max priority fee", val))
+            .transpose()?,
+        access_list: Some(evm::transaction::eip2930::AccessList(access_list)),
+        transaction_type: Some(u64::try_from(tx.r#type)?.into()),
+        chain_id: chain_id.map(evm::U256::from),
+        ..Default::default()
+    };
+    let typed: evm::transaction::eip2718::TypedTransaction = (&unsigned).into();
+    let sighash = typed.sighash();
+
+    let r = try_decode_u256("tx r", &tx.r)?;
+    let s = try_decode_u256("tx s", &tx.s)?;
+    let recovery_id = evm::Signature { r, s, v }.recovery_id()?;
+    let mut r_bytes = [0u8; 32];
+    let mut s_bytes = [0u8; 32];
+    r.to_big_endian(&mut r_bytes);
+    s.to_big_endian(&mut s_bytes);
+    let signature = ecdsa::Signature::from_scalars(r_bytes, s_bytes)?;
+    let key =
+        ecdsa::VerifyingKey::recover_from_prehash(sighash.as_bytes(), &signature, recovery_id)?;
+    let public_key = key.to_encoded_point(false).as_bytes()[1..].to_vec();
+
+    // a wrong signing hash still recovers some key, so make sure it belongs to the sender
+    let address = evm::Address::from_slice(&keccak256(&public_key)[12..]);
+    anyhow::ensure!(
+        address == tx.from.parse::<evm::Address>()?,
+        "recovered signer {:?} doesn't match sender {}",
+        address,
+        tx.from
+    );
+
+    Ok(public_key)
+}
+
+/// Dynamic fee transactions pay the base fee plus their tip, capped by their max fee.
+/// Used when the source didn't report the effective gas price itself.
+fn effective_gas_price(tx: &Transaction, base_fee: &str) -> anyhow::Result<String> {
+    let max_fee = tx.max_fee_per_gas.as_deref().context("no max fee")?;
+    let max_fee = try_decode_u256("tx max fee", max_fee)?;
+    let tip = tx
+        .max_priority_fee_per_gas
+        .as_deref()
+        .context("no max priority fee")?;
+    let tip = try_decode_u256("tx max priority fee", tip)?;
+    let base_fee = try_decode_u256("base fee", base_fee)?;
+    Ok(format!("{:#x}", max_fee.min(base_fee.saturating_add(tip))))
+}
+
+/// What the fee recipient earns from a transaction, the part of its fee above the burned base fee.
+fn priority_fee(tx: &Transaction, base_fee: Option<&str>) -> anyhow::Result<evm::U256> {
+    let price = match base_fee {
+        Some(base_fee) if tx.r#type >= 2 && tx.effective_gas_price.is_empty() => {
+            effective_gas_price(tx, base_fee)?
+        }
+        _ => paid_gas_price(tx).to_string(),
+    };
+    let price = try_decode_u256("tx gas price", &price)?;
+    let base_fee = match base_fee {
+        Some(base_fee) => try_decode_u256("base fee", base_fee)?,
+        None => evm::U256::zero(),
+    };
+    let gas_used = qty2int("tx gas used", &tx.gas_used)?;
+    Ok(price
+        .saturating_sub(base_fee)
+        .saturating_mul(evm::U256::from(gas_used)))
+}
+
+/// Credits the fee recipient with the priority fees of the block when its source didn't record them.
+/// The balance after the fees is the one the next block-level change of the recipient,
+/// like a withdrawal, starts from. Without such a change, or without every transaction
+/// of the block, the balances aren't known and nothing is added.
+fn credit_transaction_fees(block: &mut Block) -> anyhow::Result<()> {
+    let recorded = block
+        .traces
+        .iter()
+        .flat_map(|trace| &trace.balance_changes)
+        .chain(&block.balance_changes)
+        .any(|change| matches!(change.reason, BalanceChangeReason::RewardTransactionFee));
+    if recorded {
+        return Ok(());
+    }
+    let miner = &block.header.miner;
+    let Some(next) = block.balance_changes.iter().position(|change| {
+        !change.reason.is_pre_transaction() && change.address.eq_ignore_ascii_case(miner)
+    }) else {
+        return Ok(());
+    };
+
+    let mut gas_used = 0u64;
+    let mut fees = evm::U256::zero();
+    for tx in &block.transactions {
+        gas_used = gas_used.saturating_add(qty2int("tx gas used", &tx.gas_used)?);
+        let base_fee = block.header.base_fee_per_gas.as_deref();
+        fees = fees.saturating_add(priority_fee(tx, base_fee)?);
+    }
+    if gas_used != qty2int("block gas used", &block.header.gas_used)? || fees.is_zero() {
+        return Ok(());
+    }
+
+    let new_value = try_decode_u256("balance", &block.balance_changes[next].old_value)?;
+    let Some(old_value) = new_value.checked_sub(fees) else {
+        return Ok(());
+    };
+    let change = BalanceChange {
+        address: miner.clone(),
+        old_value: format!("{:#x}", old_value),
+        new_value: format!("{:#x}", new_value),
+        reason: BalanceChangeReason::RewardTransactionFee,
+    };
+    block.balance_changes.insert(next, change);
+    Ok(())
+}
+
+/// Adds the fee credits of [`credit_transaction_fees`] to the blocks of `stream`.
+fn with_transaction_fees(stream: BlockStream) -> BlockStream {
+    Box::new(Pin::from(stream).map(|result| {
+        let mut blocks = result?;
+        for block in &mut blocks {
+            credit_transaction_fees(block)?;
+        }
+        Ok(blocks)
+    }))
+}
+
+/// Legacy and access list transactions pay their gas price,
+/// the others the effective gas price of their receipt.
+fn paid_gas_price(tx: &Transaction) -> &str {
+    if tx.r#type >= 2 && !tx.effective_gas_price.is_empty() {
+        &tx.effective_gas_price
+    } else {
+        &tx.gas_price
+    }
+}
+
+/// Contract creations have no recipient, they're sent with an empty `to`
+/// and the created address is the one of their root call.
+fn recipient(tx: &Transaction) -> anyhow::Result<Vec<u8>> {
+    match tx.to.as_deref() {
+        None | Some("") | Some("0x") => Ok(vec![]),
+        Some(to) => {
+            let to = try_decode_hex("tx to", to)?;
+            anyhow::ensure!(
+                to.len() == 20,
+                "tx to of {} bytes is not an address",
+                to.len()
+            );
+            Ok(to)
+        }
     }
 }
 
@@ -347,48 +2064,57 @@ impl TryFrom<Transaction> for pbcodec::TransactionTrace {
     type Error = anyhow::Error;
 
     fn try_from(value: Transaction) -> Result<Self, Self::Error> {
+        let public_key = recover_public_key(&value).unwrap_or_default();
+        let gas_price = big_int("tx gas price", paid_gas_price(&value), BIG_INT_MAX_BYTES)?;
+        // an unexpected v of a typed transaction is passed on as is
+        let parity = (value.r#type != 0)
+            .then(|| signature_parity(&value).ok())
+            .flatten();
+        let v = match parity {
+            Some(parity) => vec![parity],
+            None => try_decode_hex("tx v", &value.v)?,
+        };
         Ok(pbcodec::TransactionTrace {
-            to: try_decode_hex(
-                "tx to",
-                &value
-                    .to
-                    .unwrap_or("0x0000000000000000000000000000000000000000".to_string()),
-            )?,
+            to: recipient(&value)?,
             nonce: value.nonce,
-            gas_price: Some(pbcodec::BigInt {
-                bytes: try_decode_hex("tx gas price", &value.gas_price)?,
-            }),
-            gas_limit: qty2int(&value.gas)?,
-            gas_used: qty2int(&value.gas_used)?,
-            value: Some(pbcodec::BigInt {
-                bytes: try_decode_hex("tx value", &value.value)?,
-            }),
+            gas_price: Some(gas_price),
+            gas_limit: qty2int("tx gas", &value.gas)?,
+            gas_used: qty2int("tx gas used", &value.gas_used)?,
+            value: Some(big_int("tx value", &value.value, BIG_INT_MAX_BYTES)?),
             input: try_decode_hex("tx input", &value.input)?,
-            v: try_decode_hex("tx v", &value.v)?,
+            v,
             r: try_decode_hex("tx r", &value.r)?,
             s: try_decode_hex("tx s", &value.s)?,
             r#type: value.r#type,
-            access_list: vec![],
-            max_fee_per_gas: value.max_fee_per_gas.map_or::<anyhow::Result<_>, _>(
-                Ok(None),
-                |val| {
-                    Ok(Some(pbcodec::BigInt {
-                        bytes: try_decode_hex("tx max fee", &val)?,
-                    }))
-                },
-            )?,
+            access_list: value
+                .access_list
+                .into_iter()
+                .map(pbcodec::AccessTuple::try_from)
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            max_fee_per_gas: value
+                .max_fee_per_gas
+                .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
+                    Ok(Some(big_int("tx max fee", &val, BIG_INT_MAX_BYTES)?))
+                })?,
             max_priority_fee_per_gas: value
                 .max_priority_fee_per_gas
                 .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
-                    Ok(Some(pbcodec::BigInt {
-                        bytes: try_decode_hex("tx max priority", &val)?,
-                    }))
+                    Ok(Some(big_int("tx max priority", &val, BIG_INT_MAX_BYTES)?))
                 })?,
+            blob_gas_fee_cap: value
+                .max_fee_per_blob_gas
+                .map(|val| big_int("tx max fee per blob gas", &val, BIG_INT_MAX_BYTES))
+                .transpose()?,
+            blob_hashes: value
+                .blob_versioned_hashes
+                .iter()
+                .map(|hash| try_decode_hex("tx blob hash", hash))
+                .collect::<anyhow::Result<Vec<_>>>()?,
             index: value.transaction_index,
             hash: try_decode_hex("tx hash", &value.hash)?,
             from: try_decode_hex("tx from", &value.from)?,
             return_data: vec![],
-            public_key: vec![],
+            public_key,
             begin_ordinal: 0,
             end_ordinal: 0,
             status: value.status,
@@ -398,19 +2124,101 @@ impl TryFrom<Transaction> for pbcodec::TransactionTrace {
     }
 }
 
+/// Parity style traces report reverts as errors rather than with a revert reason.
+fn is_revert_error(error: &str) -> bool {
+    error.eq_ignore_ascii_case("reverted") || error.eq_ignore_ascii_case("execution reverted")
+}
+
+/// Reason string of a Solidity `Error(string)` revert, `None` for any other revert data.
+fn decode_revert_reason(output: &[u8]) -> Option<String> {
+    let data = output.strip_prefix(&[0x08, 0xc3, 0x79, 0xa0])?;
+    match ethers_core::abi::decode(&[ParamType::String], data)
+        .ok()?
+        .pop()?
+    {
+        Token::String(reason) => Some(reason),
+        _ => None,
+    }
+}
+
+/// Names the errors of parity style traces the way geth does.
+fn error_category(error: &str) -> String {
+    match error {
+        "Out of gas" => "out of gas",
+        "Bad instruction" => "invalid opcode",
+        "Bad jump destination" => "invalid jump destination",
+        "Stack underflow" => "stack underflow",
+        "Mutable Call In Static Context" => "write protection",
+        _ => error,
+    }
+    .to_string()
+}
+
+/// How a call ended according to its trace error, revert reason and revert data.
+struct CallStatus {
+    failed: bool,
+    reverted: bool,
+    failure_reason: String,
+}
+
+impl CallStatus {
+    /// Reverts are reported as "execution reverted" followed by the reason when there is one,
+    /// calls traced without a result have failed even when no error is given.
+    fn new(
+        error: Option<&str>,
+        revert_reason: Option<&str>,
+        has_result: bool,
+        output: &[u8],
+    ) -> CallStatus {
+        let reverted = revert_reason.is_some() || error.is_some_and(is_revert_error);
+        let failure_reason = if reverted {
+            let reason = revert_reason
+                .filter(|reason| !reason.is_empty() && !is_revert_error(reason))
+                .map(str::to_string)
+                .or_else(|| decode_revert_reason(output));
+            match reason {
+                Some(reason) => format!("execution reverted: {}", reason),
+                None => "execution reverted".to_string(),
+            }
+        } else {
+            error.map(error_category).unwrap_or_default()
+        };
+        CallStatus {
+            failed: reverted || error.is_some() || !has_result,
+            reverted,
+            failure_reason,
+        }
+    }
+}
+
 impl TryFrom<Trace> for pbcodec::Call {
     type Error = anyhow::Error;
 
     fn try_from(value: Trace) -> Result<Self, Self::Error> {
+        let unprefixed = |value: String| value.strip_prefix("0x").unwrap_or(&value).to_string();
+        let keccak_preimages = value
+            .keccak_preimages
+            .into_iter()
+            .map(|(hash, preimage)| (unprefixed(hash), unprefixed(preimage)))
+            .collect();
+
         match value.r#type {
             TraceType::Create => {
                 let action = value.action.context("no action")?;
                 let result = value.result.context("no result")?;
                 let gas = action.gas.context("no gas")?;
                 let gas_used = result.gas_used.context("no gas_used")?;
+                let output = result.output.or(result.code).unwrap_or("0x".to_string());
+                let return_data = try_decode_hex("trace output", &output)?;
+                let status = CallStatus::new(
+                    value.error.as_deref(),
+                    value.revert_reason.as_deref(),
+                    true,
+                    &return_data,
+                );
 
                 Ok(pbcodec::Call {
-                    call_type: 5,
+                    call_type: pbcodec::CallType::Create.into(),
                     caller: try_decode_hex("trace from", &action.from.context("no from")?)?,
                     address: try_decode_hex(
                         "trace address",
@@ -419,28 +2227,27 @@ impl TryFrom<Trace> for pbcodec::Call {
                     value: action
                         .value
                         .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
-                            Ok(Some(pbcodec::BigInt {
-                                bytes: try_decode_hex("trace value", &val)?,
-                            }))
+                            Ok(Some(big_int("trace value", &val, BIG_INT_MAX_BYTES)?))
                         })?,
-                    gas_limit: u64::from_str_radix(&gas.trim_start_matches("0x"), 16)?,
-                    gas_consumed: u64::from_str_radix(&gas_used.trim_start_matches("0x"), 16)?,
-                    return_data: prefix_hex::decode("0x")?,
+                    gas_limit: qty2int("trace gas", &gas)?,
+                    gas_consumed: qty2int("trace gas used", &gas_used)?,
+                    return_data,
                     input: prefix_hex::decode("0x")?,
-                    status_failed: value.error.is_some() || value.revert_reason.is_some(),
-                    status_reverted: value.revert_reason.is_some(),
-                    failure_reason: value
-                        .error
-                        .unwrap_or_else(|| value.revert_reason.unwrap_or_default()),
+                    status_failed: status.failed,
+                    status_reverted: status.reverted,
+                    failure_reason: status.failure_reason,
+                    keccak_preimages,
                     ..Default::default()
                 })
             }
             TraceType::Call => {
                 let action = value.action.context("no action")?;
+                let has_result = value.result.is_some();
                 let result = value.result.unwrap_or(TraceResult {
                     gas_used: None,
                     address: None,
                     output: None,
+                    code: None,
                 });
                 let call_type = match action.r#type.context("no type")? {
                     CallType::Call => 1,
@@ -449,8 +2256,18 @@ impl TryFrom<Trace> for pbcodec::Call {
                     CallType::Staticcall => 4,
                 };
                 let gas = action.gas.context("no gas")?;
-                let gas_used = result.gas_used.unwrap_or("0x0".to_string());
                 let output = result.output.unwrap_or("0x".to_string());
+                let return_data = try_decode_hex("trace output", &output)?;
+                let status = CallStatus::new(
+                    value.error.as_deref(),
+                    value.revert_reason.as_deref(),
+                    has_result,
+                    &return_data,
+                );
+                // calls are traced without a result only when they failed, the gas they burnt
+                // before failing is unknown then, so the whole gas limit is counted as consumed,
+                // zero would make a failed call look free
+                let gas_used = result.gas_used.unwrap_or_else(|| gas.clone());
 
                 Ok(pbcodec::Call {
                     call_type,
@@ -459,23 +2276,289 @@ impl TryFrom<Trace> for pbcodec::Call {
                     value: action
                         .value
                         .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
-                            Ok(Some(pbcodec::BigInt {
-                                bytes: try_decode_hex("trace value", &val)?,
-                            }))
+                            Ok(Some(big_int("trace value", &val, BIG_INT_MAX_BYTES)?))
                         })?,
-                    gas_limit: u64::from_str_radix(&gas.trim_start_matches("0x"), 16)?,
-                    gas_consumed: u64::from_str_radix(&gas_used.trim_start_matches("0x"), 16)?,
-                    return_data: try_decode_hex("trace output", &output)?,
+                    gas_limit: qty2int("trace gas", &gas)?,
+                    gas_consumed: qty2int("trace gas used", &gas_used)?,
+                    return_data,
                     input: try_decode_hex("trace input", &action.input.context("no input")?)?,
-                    status_failed: value.error.is_some() || value.revert_reason.is_some(),
-                    status_reverted: value.revert_reason.is_some(),
-                    failure_reason: value
-                        .error
-                        .unwrap_or_else(|| value.revert_reason.unwrap_or_default()),
+                    status_failed: status.failed,
+                    status_reverted: status.reverted,
+                    failure_reason: status.failure_reason,
+                    keccak_preimages,
+                    ..Default::default()
+                })
+            }
+            TraceType::Suicide => {
+                let action = value.action.context("no action")?;
+
+                Ok(pbcodec::Call {
+                    caller: try_decode_hex("suicide from", &action.from.context("no from")?)?,
+                    address: try_decode_hex("suicide refund", &action.to.context("no to")?)?,
+                    value: action
+                        .value
+                        .map_or::<anyhow::Result<_>, _>(Ok(None), |val| {
+                            Ok(Some(big_int("suicide value", &val, BIG_INT_MAX_BYTES)?))
+                        })?,
+                    suicide: true,
+                    keccak_preimages,
                     ..Default::default()
                 })
             }
-            TraceType::Suicide | TraceType::Reward => anyhow::bail!("unsupported trace type"),
+            TraceType::Reward => anyhow::bail!("unsupported trace type"),
+        }
+    }
+}
+
+impl From<BalanceChangeReason> for pbcodec::balance_change::Reason {
+    fn from(value: BalanceChangeReason) -> Self {
+        use pbcodec::balance_change::Reason;
+        match value {
+            BalanceChangeReason::RewardMineUncle => Reason::RewardMineUncle,
+            BalanceChangeReason::RewardMineBlock => Reason::RewardMineBlock,
+            BalanceChangeReason::DaoRefundContract => Reason::DaoRefundContract,
+            BalanceChangeReason::DaoAdjustBalance => Reason::DaoAdjustBalance,
+            BalanceChangeReason::Transfer => Reason::Transfer,
+            BalanceChangeReason::GasBuy => Reason::GasBuy,
+            BalanceChangeReason::RewardTransactionFee => Reason::RewardTransactionFee,
+            BalanceChangeReason::GasRefund => Reason::GasRefund,
+            BalanceChangeReason::SuicideRefund => Reason::SuicideRefund,
+            BalanceChangeReason::SuicideWithdraw => Reason::SuicideWithdraw,
+            BalanceChangeReason::Withdrawal => Reason::Withdrawal,
+        }
+    }
+}
+
+impl TryFrom<BalanceChange> for pbcodec::BalanceChange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: BalanceChange) -> Result<Self, Self::Error> {
+        Ok(pbcodec::BalanceChange {
+            address: try_decode_hex("balance change address", &value.address)?,
+            old_value: Some(big_int(
+                "balance change old value",
+                &value.old_value,
+                BIG_INT_MAX_BYTES,
+            )?),
+            new_value: Some(big_int(
+                "balance change new value",
+                &value.new_value,
+                BIG_INT_MAX_BYTES,
+            )?),
+            reason: pbcodec::balance_change::Reason::from(value.reason).into(),
+            ordinal: 0,
+        })
+    }
+}
+
+impl From<GasChangeReason> for pbcodec::gas_change::Reason {
+    fn from(value: GasChangeReason) -> Self {
+        use pbcodec::gas_change::Reason;
+        match value {
+            GasChangeReason::Call => Reason::Call,
+            GasChangeReason::CallCode => Reason::CallCode,
+            GasChangeReason::CallDataCopy => Reason::CallDataCopy,
+            GasChangeReason::CodeCopy => Reason::CodeCopy,
+            GasChangeReason::CodeStorage => Reason::CodeStorage,
+            GasChangeReason::ContractCreation => Reason::ContractCreation,
+            GasChangeReason::ContractCreation2 => Reason::ContractCreation2,
+            GasChangeReason::DelegateCall => Reason::DelegateCall,
+            GasChangeReason::EventLog => Reason::EventLog,
+            GasChangeReason::ExtCodeCopy => Reason::ExtCodeCopy,
+            GasChangeReason::FailedExecution => Reason::FailedExecution,
+            GasChangeReason::IntrinsicGas => Reason::IntrinsicGas,
+            GasChangeReason::PrecompiledContract => Reason::PrecompiledContract,
+            GasChangeReason::RefundAfterExecution => Reason::RefundAfterExecution,
+            GasChangeReason::Return => Reason::Return,
+            GasChangeReason::ReturnDataCopy => Reason::ReturnDataCopy,
+            GasChangeReason::Revert => Reason::Revert,
+            GasChangeReason::SelfDestruct => Reason::SelfDestruct,
+            GasChangeReason::StaticCall => Reason::StaticCall,
+            GasChangeReason::StateColdAccess => Reason::StateColdAccess,
+        }
+    }
+}
+
+impl From<GasChange> for pbcodec::GasChange {
+    fn from(value: GasChange) -> Self {
+        pbcodec::GasChange {
+            old_value: value.old_value,
+            new_value: value.new_value,
+            reason: pbcodec::gas_change::Reason::from(value.reason).into(),
+            ordinal: 0,
+        }
+    }
+}
+
+impl TryFrom<StorageChange> for pbcodec::StorageChange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: StorageChange) -> Result<Self, Self::Error> {
+        Ok(pbcodec::StorageChange {
+            address: try_decode_hex("storage change address", &value.address)?,
+            key: try_decode_hex("storage change key", &value.key)?,
+            old_value: try_decode_hex("storage change old value", &value.old_value)?,
+            new_value: try_decode_hex("storage change new value", &value.new_value)?,
+            ordinal: 0,
+        })
+    }
+}
+
+fn build_storage_changes(
+    changes: Vec<StorageChange>,
+    ordinal: &mut Ordinal,
+) -> anyhow::Result<Vec<pbcodec::StorageChange>> {
+    changes
+        .into_iter()
+        .map(|change| {
+            let mut change = pbcodec::StorageChange::try_from(change)?;
+            change.ordinal = ordinal.next();
+            Ok(change)
+        })
+        .collect()
+}
+
+fn build_gas_changes(changes: Vec<GasChange>, ordinal: &mut Ordinal) -> Vec<pbcodec::GasChange> {
+    changes
+        .into_iter()
+        .map(|change| {
+            let mut change = pbcodec::GasChange::from(change);
+            change.ordinal = ordinal.next();
+            change
+        })
+        .collect()
+}
+
+fn build_balance_changes(
+    changes: Vec<BalanceChange>,
+    ordinal: &mut Ordinal,
+) -> anyhow::Result<Vec<pbcodec::BalanceChange>> {
+    changes
+        .into_iter()
+        .map(|change| {
+            let mut change = pbcodec::BalanceChange::try_from(change)?;
+            change.ordinal = ordinal.next();
+            Ok(change)
+        })
+        .collect()
+}
+
+/// Converts the traces of a single transaction into a call tree ordered depth-first,
+/// so every parent precedes its children.
+fn build_calls(
+    mut traces: Vec<Trace>,
+    ordinal: &mut Ordinal,
+) -> anyhow::Result<Vec<pbcodec::Call>> {
+    traces.sort_by(|a, b| a.trace_address.cmp(&b.trace_address));
+
+    let mut calls: Vec<pbcodec::Call> = vec![];
+    let mut index_by_address: HashMap<Vec<u32>, u32> = HashMap::new();
+    // calls whose execution hasn't finished yet
+    let mut stack: Vec<(Vec<u32>, usize)> = vec![];
+    // code deployed within the transaction by contract address
+    let mut code_by_address: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    for mut trace in traces {
+        if let TraceType::Reward = trace.r#type {
+            continue;
+        }
+
+        let trace_address = trace.trace_address.clone();
+        let balance_changes = std::mem::take(&mut trace.balance_changes);
+        let storage_changes = std::mem::take(&mut trace.storage_changes);
+        let gas_changes = std::mem::take(&mut trace.gas_changes);
+        while let Some((address, pos)) = stack.pop() {
+            if trace_address.starts_with(&address) {
+                stack.push((address, pos));
+                break;
+            }
+            calls[pos].end_ordinal = ordinal.next();
+        }
+
+        // only creations carry code in their result
+        let code = trace.result.as_ref().and_then(|result| result.code.clone());
+
+        let mut call = pbcodec::Call::try_from(trace)?;
+        call.index = u32::try_from(calls.len() + 1)?;
+        call.depth = u32::try_from(trace_address.len())?;
+        call.parent_index = trace_address
+            .split_last()
+            .and_then(|(_, parent)| index_by_address.get(parent))
+            .copied()
+            .unwrap_or(0);
+        call.begin_ordinal = ordinal.next();
+        // the account exists from the start of the creation, before any value is transferred to it
+        if call.call_type() == pbcodec::CallType::Create {
+            call.account_creations.push(pbcodec::AccountCreation {
+                account: call.address.clone(),
+                ordinal: ordinal.next(),
+            });
+        }
+        call.balance_changes = build_balance_changes(balance_changes, ordinal)?;
+        call.storage_changes = build_storage_changes(storage_changes, ordinal)?;
+        call.gas_changes = build_gas_changes(gas_changes, ordinal);
+        if call.suicide {
+            // the old code is only known if it was deployed by the same transaction
+            let old_code = code_by_address.remove(&call.caller);
+            call.code_changes.push(pbcodec::CodeChange {
+                address: call.caller.clone(),
+                old_hash: old_code
+                    .as_ref()
+                    .map(|code| keccak256(code).to_vec())
+                    .unwrap_or_default(),
+                old_code: old_code.unwrap_or_default(),
+                new_hash: keccak256([]).to_vec(),
+                new_code: vec![],
+                ordinal: ordinal.next(),
+            });
+        } else if let Some(code) = code.filter(|_| !call.status_failed) {
+            let code = try_decode_hex("trace code", &code)?;
+            call.code_changes.push(pbcodec::CodeChange {
+                address: call.address.clone(),
+                old_hash: keccak256([]).to_vec(),
+                old_code: vec![],
+                new_hash: keccak256(&code).to_vec(),
+                new_code: code.clone(),
+                ordinal: ordinal.next(),
+            });
+            code_by_address.insert(call.address.clone(), code);
+        }
+
+        index_by_address.insert(trace_address.clone(), call.index);
+        stack.push((trace_address, calls.len()));
+        calls.push(call);
+    }
+    while let Some((_, pos)) = stack.pop() {
+        calls[pos].end_ordinal = ordinal.next();
+    }
+
+    Ok(calls)
+}
+
+/// Attributes receipt logs to the calls that emitted them.
+///
+/// Traces don't record where a log was emitted, so a log goes to the innermost
+/// successful call running in the context of the log address, skipping calls
+/// that had already finished when the previous log was emitted.
+fn assign_call_logs(calls: &mut [pbcodec::Call], logs: &[pbcodec::Log]) {
+    let mut position = 0;
+    for log in logs {
+        let emitter = calls
+            .iter()
+            .enumerate()
+            .filter(|(_, call)| {
+                // delegated code runs in the context of its caller
+                let context = match call.call_type {
+                    2 | 3 => &call.caller,
+                    _ => &call.address,
+                };
+                !call.status_failed && context == &log.address && call.end_ordinal > position
+            })
+            .max_by_key(|(_, call)| (call.depth, std::cmp::Reverse(call.begin_ordinal)))
+            .map(|(pos, _)| pos);
+
+        if let Some(pos) = emitter {
+            position = position.max(calls[pos].begin_ordinal);
+            calls[pos].logs.push(log.clone());
         }
     }
 }
@@ -486,66 +2569,3343 @@ impl TryFrom<Block> for pbcodec::Block {
     fn try_from(value: Block) -> Result<Self, Self::Error> {
         let mut logs_by_tx: HashMap<u32, Vec<Log>> = HashMap::new();
         for log in value.logs {
-            if logs_by_tx.contains_key(&log.transaction_index) {
-                logs_by_tx
-                    .get_mut(&log.transaction_index)
-                    .unwrap()
-                    .push(log);
-            } else {
-                logs_by_tx.insert(log.transaction_index, vec![log]);
-            }
+            logs_by_tx
+                .entry(log.transaction_index)
+                .or_default()
+                .push(log);
         }
 
         let mut traces_by_tx: HashMap<u32, Vec<Trace>> = HashMap::new();
         for trace in value.traces {
-            if traces_by_tx.contains_key(&trace.transaction_index) {
-                traces_by_tx
-                    .get_mut(&trace.transaction_index)
-                    .unwrap()
-                    .push(trace);
-            } else {
-                traces_by_tx.insert(trace.transaction_index, vec![trace]);
+            // reward traces carry the credited amount without the balances of the recipient,
+            // sources knowing them report rewards as balance changes instead
+            if let TraceType::Reward = trace.r#type {
+                continue;
             }
+            traces_by_tx
+                .entry(trace.transaction_index)
+                .or_default()
+                .push(trace);
         }
 
-        let transaction_traces = value.transactions.into_iter().map(|tx| {
-            let logs = logs_by_tx.remove(&tx.transaction_index).unwrap_or_default().into_iter().map(|log| pbcodec::Log {
-                address: try_decode_hex("log address", &log.address).unwrap(),
-                data: try_decode_hex("log data", &log.data).unwrap(),
-                block_index: log.log_index,
-                topics: log.topics.into_iter().map(|topic| try_decode_hex("log topic", &topic).unwrap()).collect(),
-                index: log.transaction_index,
-                ordinal: 0,
-            }).collect();
-            let calls = traces_by_tx.remove(&tx.transaction_index).unwrap_or_default().into_iter().filter_map(|trace| {
-                match trace.r#type {
-                    TraceType::Call | TraceType::Create => Some(pbcodec::Call::try_from(trace)),
-                    TraceType::Reward | TraceType::Suicide => None,
+        // receipts without a cumulative gas used get it from the transactions before them
+        let mut transactions = value.transactions;
+        let mut previous = None;
+        for tx in &mut transactions {
+            if tx.cumulative_gas_used.is_empty() {
+                if let Some(before) = gas_used_before(tx, previous) {
+                    let used = qty2int("tx gas used", &tx.gas_used)?;
+                    tx.cumulative_gas_used = format!("{:#x}", before.saturating_add(used));
                 }
-            }).collect::<anyhow::Result<Vec<pbcodec::Call>>>()?;
-            let receipt = pbcodec::TransactionReceipt {
-                state_root: vec![],
-                cumulative_gas_used: qty2int(&tx.cumulative_gas_used)?,
-                logs_bloom: prefix_hex::decode("0x00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000")?,
-                logs,
-            };
-            let mut tx_trace = pbcodec::TransactionTrace::try_from(tx)?;
-            tx_trace.receipt = Some(receipt);
-            tx_trace.calls = calls;
-            Ok(tx_trace)
-        })
-        .collect::<anyhow::Result<Vec<_>>>()?;
+            }
+            previous = qty2int("tx cumulative gas used", &tx.cumulative_gas_used)
+                .ok()
+                .map(|cumulative| (tx.transaction_index, cumulative));
+        }
+
+        // block-level changes preceding the transactions come first, so every transaction
+        // spans a window of ordinals after them and before the ones of the next transaction
+        let (pre_transaction, post_transaction): (Vec<_>, Vec<_>) = value
+            .balance_changes
+            .into_iter()
+            .partition(|change| change.reason.is_pre_transaction());
+        let mut ordinal = Ordinal::default();
+        let mut balance_changes = build_balance_changes(pre_transaction, &mut ordinal)?;
+        let base_fee = value.header.base_fee_per_gas.clone();
+        let transaction_traces = transactions
+            .into_iter()
+            .map(|mut tx| {
+                if tx.r#type >= 2 && tx.effective_gas_price.is_empty() {
+                    if let Some(base_fee) = &base_fee {
+                        tx.effective_gas_price = effective_gas_price(&tx, base_fee)?;
+                    }
+                }
+                let begin_ordinal = ordinal.next();
+                let mut calls = build_calls(
+                    traces_by_tx
+                        .remove(&tx.transaction_index)
+                        .unwrap_or_default(),
+                    &mut ordinal,
+                )?;
+                let mut tx_logs = logs_by_tx.remove(&tx.transaction_index).unwrap_or_default();
+                tx_logs.sort_by_key(|log| log.log_index);
+                let logs = tx_logs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, log)| {
+                        Ok(pbcodec::Log {
+                            address: try_decode_hex("log address", &log.address)?,
+                            data: try_decode_hex("log data", &log.data)?,
+                            block_index: log.log_index,
+                            topics: log
+                                .topics
+                                .into_iter()
+                                .map(|topic| try_decode_hex("log topic", &topic))
+                                .collect::<anyhow::Result<Vec<_>>>()?,
+                            index: u32::try_from(index)?,
+                            ordinal: ordinal.next(),
+                        })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                assign_call_logs(&mut calls, &logs);
+                let receipt = pbcodec::TransactionReceipt {
+                    state_root: vec![],
+                    cumulative_gas_used: qty2int(
+                        "tx cumulative gas used",
+                        &tx.cumulative_gas_used,
+                    )?,
+                    logs_bloom: logs_bloom(&logs),
+                    logs,
+                    raw: tx
+                        .raw_receipt
+                        .take()
+                        .map(String::into_bytes)
+                        .unwrap_or_default(),
+                };
+                let mut tx_trace = pbcodec::TransactionTrace::try_from(tx)?;
+                if let Some(root) = calls.first() {
+                    tx_trace.return_data = root.return_data.clone();
+                }
+                tx_trace.receipt = Some(receipt);
+                tx_trace.calls = calls;
+                tx_trace.begin_ordinal = begin_ordinal;
+                tx_trace.end_ordinal = ordinal.next();
+                Ok(tx_trace)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        balance_changes.extend(build_balance_changes(post_transaction, &mut ordinal)?);
 
-        Ok(pbcodec::Block {
-            ver: 2,
+        let size = value
+            .header
+            .size
+            .as_deref()
+            .map(|size| qty2int("block size", size))
+            .transpose()?;
+        let mut block = pbcodec::Block {
+            ver: ETH_BLOCK_VERSION,
             hash: try_decode_hex("hash", &value.header.hash.clone())?,
             number: value.header.number,
-            size: value.header.size,
+            size: size.unwrap_or_default(),
             header: Some(pbcodec::BlockHeader::try_from(value.header)?),
-            uncles: vec![],
+            uncles: value
+                .uncles
+                .into_iter()
+                .map(pbcodec::BlockHeader::try_from)
+                .collect::<anyhow::Result<Vec<_>>>()?,
             transaction_traces,
-            balance_changes: vec![],
+            balance_changes,
             code_changes: vec![],
-        })
+            raw: value.raw.map(String::into_bytes).unwrap_or_default(),
+        };
+        // sources not reporting the size get the size of the encoded block instead
+        if size.is_none() {
+            block.size = block.encoded_len() as u64;
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive;
+    use crate::cursor::Cursor;
+    use crate::datasource::{
+        BalanceChange, BalanceChangeReason, Block, BlockHeader, BlockStream, CallType, DataRequest,
+        DataSource, GasChange, GasChangeReason, HashAndHeight, HotUpdate, L2Extra, Log, LogRequest,
+        StorageChange, Trace, TraceAction, TraceResult, TraceType, Transaction,
+    };
+    use crate::ds_archive::ArchiveDataSource;
+    use crate::firehose::{
+        big_int, block_matches, build_calls, check_gas_used, check_header_hash,
+        credit_transaction_fees, decode_transforms, header_rlp, logs_bloom, qty2int,
+        recover_public_key, strip_unmatched, try_decode_hex, BlockType, FetchError, Firehose,
+        Ordinal, TransformFilter, BIG_INT_MAX_BYTES, ETH_BLOCK_TYPE_URL,
+    };
+    use crate::pbcodec;
+    use crate::pbfirehose::single_block_request::{self, Reference};
+    use crate::pbfirehose::{
+        ForkStep, HeadResponse, InfoResponse, MultiBlockRequest, Request, Response,
+        SingleBlockRequest,
+    };
+    use crate::pbtransforms::{
+        CallToFilter, CombinedFilter, LogFilter, MultiCallToFilter, MultiLogFilter,
+        MultiTransactionFilter, TransactionFilter,
+    };
+    use crate::testing::{
+        block, chain, eip155_transaction, fork_hash, hash, head, hot_update, signed_transaction,
+        MockArchive, MockDataSource,
+    };
+    use ethers_core::abi::Token;
+    use ethers_core::utils::rlp;
+    use futures_util::stream::StreamExt;
+    use prost::Message;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Knows its height, but never delivers a block.
+    struct HangingDataSource;
+
+    #[async_trait::async_trait]
+    impl DataSource for HangingDataSource {
+        fn get_finalized_blocks(
+            &self,
+            _request: DataRequest,
+            _stop_on_head: bool,
+        ) -> anyhow::Result<BlockStream> {
+            Ok(Box::new(futures_util::stream::pending()))
+        }
+
+        async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+            Ok(10)
+        }
+
+        async fn get_block_hash(&self, _height: u64) -> anyhow::Result<String> {
+            std::future::pending().await
+        }
+    }
+
+    /// Knows its height, but its streams end without a block.
+    struct EmptyDataSource;
+
+    #[async_trait::async_trait]
+    impl DataSource for EmptyDataSource {
+        fn get_finalized_blocks(
+            &self,
+            _request: DataRequest,
+            _stop_on_head: bool,
+        ) -> anyhow::Result<BlockStream> {
+            Ok(Box::new(futures_util::stream::empty()))
+        }
+
+        async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+            Ok(5)
+        }
+
+        async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+            Ok(hash(height))
+        }
+    }
+
+    async fn collect(firehose: &Firehose, request: Request) -> Vec<(ForkStep, u64)> {
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        responses
+            .into_iter()
+            .map(|response| {
+                let block = pbcodec::Block::decode(&response.block.unwrap().value[..]).unwrap();
+                let number = block.header.map_or(block.number, |header| header.number);
+                (ForkStep::from_i32(response.step).unwrap(), number)
+            })
+            .collect()
+    }
+
+    fn call_trace(trace_address: Vec<u32>, to: &str) -> Trace {
+        Trace {
+            transaction_index: 0,
+            trace_address,
+            r#type: TraceType::Call,
+            error: None,
+            revert_reason: None,
+            action: Some(TraceAction {
+                from: Some("0x0000000000000000000000000000000000000001".to_string()),
+                to: Some(to.to_string()),
+                value: Some("0x0".to_string()),
+                gas: Some("0x5208".to_string()),
+                input: Some("0x".to_string()),
+                r#type: Some(CallType::Call),
+            }),
+            result: Some(TraceResult {
+                gas_used: Some("0x5208".to_string()),
+                address: None,
+                output: Some("0x".to_string()),
+                code: None,
+            }),
+            balance_changes: vec![],
+            storage_changes: vec![],
+            gas_changes: vec![],
+            keccak_preimages: HashMap::new(),
+        }
+    }
+
+    fn create_trace(trace_address: Vec<u32>, address: &str) -> Trace {
+        Trace {
+            transaction_index: 0,
+            trace_address,
+            r#type: TraceType::Create,
+            error: None,
+            revert_reason: None,
+            action: Some(TraceAction {
+                from: Some("0x0000000000000000000000000000000000000002".to_string()),
+                to: None,
+                value: Some("0x0".to_string()),
+                gas: Some("0x5208".to_string()),
+                input: Some("0x".to_string()),
+                r#type: None,
+            }),
+            result: Some(TraceResult {
+                gas_used: Some("0x5208".to_string()),
+                address: Some(address.to_string()),
+                output: None,
+                code: None,
+            }),
+            balance_changes: vec![],
+            storage_changes: vec![],
+            gas_changes: vec![],
+            keccak_preimages: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn nested_call_tree() {
+        // CALL -> CREATE -> CALL, followed by a sibling CALL of the CREATE
+        let traces = vec![
+            call_trace(vec![1], "0x0000000000000000000000000000000000000004"),
+            call_trace(vec![0, 0], "0x0000000000000000000000000000000000000003"),
+            create_trace(vec![0], "0x0000000000000000000000000000000000000002"),
+            call_trace(vec![], "0x0000000000000000000000000000000000000001"),
+        ];
+        let calls = build_calls(traces, &mut Ordinal::default()).unwrap();
+
+        let tree: Vec<_> = calls
+            .iter()
+            .map(|call| (call.index, call.parent_index, call.depth, call.call_type))
+            .collect();
+        assert_eq!(
+            tree,
+            vec![(1, 0, 0, 1), (2, 1, 1, 5), (3, 2, 2, 1), (4, 1, 1, 1)]
+        );
+
+        let ordinals: Vec<_> = calls
+            .iter()
+            .map(|call| (call.begin_ordinal, call.end_ordinal))
+            .collect();
+        assert_eq!(ordinals, vec![(0, 8), (1, 5), (3, 4), (6, 7)]);
+    }
+
+    #[test]
+    fn call_storage_change_ordinals() {
+        let mut root = call_trace(vec![], "0x0000000000000000000000000000000000000001");
+        root.storage_changes = vec![StorageChange {
+            address: "0x0000000000000000000000000000000000000001".to_string(),
+            key: format!("0x{:064x}", 0),
+            old_value: format!("0x{:064x}", 0),
+            new_value: format!("0x{:064x}", 1),
+        }];
+        let traces = vec![
+            root,
+            call_trace(vec![0], "0x0000000000000000000000000000000000000002"),
+        ];
+        let calls = build_calls(traces, &mut Ordinal::default()).unwrap();
+
+        let changes = &calls[0].storage_changes;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, vec![0u8; 32]);
+        assert_eq!(changes[0].new_value[31], 1);
+        assert_eq!(changes[0].ordinal, 1);
+        assert_eq!(calls[1].begin_ordinal, 2);
+        assert!(calls[1].storage_changes.is_empty());
+    }
+
+    #[test]
+    fn call_gas_refund() {
+        let gas_change = |old_value: u64, new_value: u64, reason: GasChangeReason| GasChange {
+            old_value,
+            new_value,
+            reason,
+        };
+        // clearing a storage slot is refunded once the transaction is executed
+        let mut root = call_trace(vec![], "0x0000000000000000000000000000000000000001");
+        root.gas_changes = vec![
+            gas_change(30000, 9000, GasChangeReason::IntrinsicGas),
+            gas_change(3900, 8700, GasChangeReason::RefundAfterExecution),
+        ];
+        let traces = vec![
+            root,
+            call_trace(vec![0], "0x0000000000000000000000000000000000000002"),
+        ];
+        let calls = build_calls(traces, &mut Ordinal::default()).unwrap();
+
+        let changes: Vec<_> = calls[0]
+            .gas_changes
+            .iter()
+            .map(|change| {
+                (
+                    change.old_value,
+                    change.new_value,
+                    change.reason(),
+                    change.ordinal,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (30000, 9000, pbcodec::gas_change::Reason::IntrinsicGas, 1),
+                (
+                    3900,
+                    8700,
+                    pbcodec::gas_change::Reason::RefundAfterExecution,
+                    2
+                ),
+            ]
+        );
+        assert_eq!(calls[1].begin_ordinal, 3);
+        assert!(calls[1].gas_changes.is_empty());
+    }
+
+    #[test]
+    fn create_and_selfdestruct_code_changes() {
+        let contract = "0x0000000000000000000000000000000000000002";
+        let mut create = create_trace(vec![0], contract);
+        create.result.as_mut().unwrap().code = Some("0x6000".to_string());
+        let mut suicide = call_trace(vec![1, 0], "0x0000000000000000000000000000000000000003");
+        suicide.r#type = TraceType::Suicide;
+        suicide.action.as_mut().unwrap().from = Some(contract.to_string());
+        suicide.result = None;
+        let traces = vec![
+            call_trace(vec![], "0x0000000000000000000000000000000000000001"),
+            create,
+            call_trace(vec![1], contract),
+            suicide,
+        ];
+        let calls = build_calls(traces, &mut Ordinal::default()).unwrap();
+
+        let empty_hash = ethers_core::utils::keccak256([]).to_vec();
+        let code_hash = ethers_core::utils::keccak256([0x60, 0x00]).to_vec();
+
+        assert_eq!(calls.len(), 4);
+        assert!(calls[0].code_changes.is_empty());
+        assert!(calls[0].account_creations.is_empty());
+        assert_eq!(
+            calls[1].account_creations,
+            vec![pbcodec::AccountCreation {
+                account: prefix_hex::decode(contract).unwrap(),
+                ordinal: 2,
+            }]
+        );
+        assert_eq!(
+            calls[1].code_changes,
+            vec![pbcodec::CodeChange {
+                address: prefix_hex::decode(contract).unwrap(),
+                old_hash: empty_hash.clone(),
+                old_code: vec![],
+                new_hash: code_hash.clone(),
+                new_code: vec![0x60, 0x00],
+                ordinal: 3,
+            }]
+        );
+        assert!(!calls[2].suicide);
+        assert!(calls[3].suicide);
+        assert_eq!(
+            calls[3].code_changes,
+            vec![pbcodec::CodeChange {
+                address: prefix_hex::decode(contract).unwrap(),
+                old_hash: code_hash,
+                old_code: vec![0x60, 0x00],
+                new_hash: empty_hash,
+                new_code: vec![],
+                ordinal: 7,
+            }]
+        );
+        assert!(calls[3].account_creations.is_empty());
+        assert_eq!((calls[3].begin_ordinal, calls[3].end_ordinal), (6, 8));
+    }
+
+    #[test]
+    fn selfdestruct_call() {
+        let mut suicide = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        suicide.r#type = TraceType::Suicide;
+        let action = suicide.action.as_mut().unwrap();
+        action.from = Some("0x0000000000000000000000000000000000000002".to_string());
+        action.value = Some("0xde0b6b3a7640000".to_string());
+        suicide.result = None;
+        let traces = vec![
+            call_trace(vec![], "0x0000000000000000000000000000000000000002"),
+            suicide,
+        ];
+        let calls = build_calls(traces, &mut Ordinal::default()).unwrap();
+
+        assert_eq!(calls.len(), 2);
+        let call = &calls[1];
+        assert!(call.suicide);
+        assert_eq!((call.index, call.parent_index, call.depth), (2, 1, 1));
+        assert_eq!(
+            call.caller,
+            prefix_hex::decode::<Vec<u8>>("0x0000000000000000000000000000000000000002").unwrap()
+        );
+        assert_eq!(
+            call.address,
+            prefix_hex::decode::<Vec<u8>>("0x0000000000000000000000000000000000000003").unwrap()
+        );
+        assert_eq!(
+            call.value.as_ref().unwrap().bytes,
+            vec![0x0d, 0xe0, 0xb6, 0xb3, 0xa7, 0x64, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn call_without_result() {
+        let mut reverted = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        reverted.error = Some("Reverted".to_string());
+        reverted.result = None;
+        let reverted = pbcodec::Call::try_from(reverted).unwrap();
+        assert!(reverted.status_failed);
+        assert!(reverted.status_reverted);
+        assert_eq!(reverted.failure_reason, "execution reverted");
+        assert_eq!(reverted.gas_limit, 21000);
+        assert_eq!(reverted.gas_consumed, 21000);
+        assert!(reverted.return_data.is_empty());
+
+        let mut out_of_gas = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        out_of_gas.error = Some("Out of gas".to_string());
+        out_of_gas.result = None;
+        let out_of_gas = pbcodec::Call::try_from(out_of_gas).unwrap();
+        assert!(out_of_gas.status_failed);
+        assert!(!out_of_gas.status_reverted);
+        assert_eq!(out_of_gas.failure_reason, "out of gas");
+        assert_eq!(out_of_gas.gas_consumed, 21000);
+
+        let mut unexplained = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        unexplained.result = None;
+        let unexplained = pbcodec::Call::try_from(unexplained).unwrap();
+        assert!(unexplained.status_failed);
+        assert!(unexplained.failure_reason.is_empty());
+        assert_eq!(unexplained.gas_consumed, 21000);
+
+        // the gas a reverted call reports is kept as it is
+        let mut partially_used = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        partially_used.error = Some("Reverted".to_string());
+        partially_used.result.as_mut().unwrap().gas_used = Some("0x1388".to_string());
+        let partially_used = pbcodec::Call::try_from(partially_used).unwrap();
+        assert!(partially_used.status_reverted);
+        assert_eq!(partially_used.gas_consumed, 5000);
+
+        let succeeded = pbcodec::Call::try_from(call_trace(vec![], "0x01")).unwrap();
+        assert!(!succeeded.status_failed);
+    }
+
+    #[test]
+    fn revert_reasons() {
+        // Error("insufficient balance")
+        let mut output = vec![0x08, 0xc3, 0x79, 0xa0];
+        output.extend(ethers_core::abi::encode(&[Token::String(
+            "insufficient balance".to_string(),
+        )]));
+        let mut trace = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        trace.error = Some("Reverted".to_string());
+        trace.result.as_mut().unwrap().output = Some(prefix_hex::encode(output.clone()));
+        let call = pbcodec::Call::try_from(trace).unwrap();
+        assert!(call.status_failed);
+        assert!(call.status_reverted);
+        assert_eq!(
+            call.failure_reason,
+            "execution reverted: insufficient balance"
+        );
+        assert_eq!(call.return_data, output);
+
+        // the archive decodes the reason itself
+        let mut trace = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        trace.error = Some("execution reverted".to_string());
+        trace.revert_reason = Some("not the owner".to_string());
+        let call = pbcodec::Call::try_from(trace).unwrap();
+        assert_eq!(call.failure_reason, "execution reverted: not the owner");
+
+        // custom errors carry no reason string
+        let mut trace = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        trace.error = Some("Reverted".to_string());
+        trace.result.as_mut().unwrap().output = Some("0x82b42900".to_string());
+        let call = pbcodec::Call::try_from(trace).unwrap();
+        assert!(call.status_reverted);
+        assert_eq!(call.failure_reason, "execution reverted");
+
+        let mut trace = call_trace(vec![0], "0x0000000000000000000000000000000000000003");
+        trace.error = Some("Bad jump destination".to_string());
+        let call = pbcodec::Call::try_from(trace).unwrap();
+        assert!(call.status_failed);
+        assert!(!call.status_reverted);
+        assert_eq!(call.failure_reason, "invalid jump destination");
+    }
+
+    #[test]
+    fn empty_logs_bloom() {
+        assert_eq!(logs_bloom(&[]), vec![0u8; 256]);
+    }
+
+    #[test]
+    fn erc20_transfer_logs_bloom() {
+        // USDT Transfer(from, to, value) log
+        let log = pbcodec::Log {
+            address: prefix_hex::decode("0xdac17f958d2ee523a2206206994597c13d831ec7").unwrap(),
+            topics: vec![
+                prefix_hex::decode(
+                    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+                )
+                .unwrap(),
+                prefix_hex::decode(
+                    "0x00000000000000000000000028c6c06298d514db089934071355e5743bf21d60",
+                )
+                .unwrap(),
+                prefix_hex::decode(
+                    "0x000000000000000000000000a9d1e08c7793af67e9d92fe308d5697fb81d3e43",
+                )
+                .unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        let mut expected = vec![0u8; 256];
+        for (i, byte) in [
+            (19, 0x01),
+            (42, 0x01),
+            (46, 0x01),
+            (51, 0x08),
+            (75, 0x08),
+            (110, 0x08),
+            (120, 0x02),
+            (123, 0x10),
+            (157, 0x10),
+            (171, 0x80),
+            (187, 0x02),
+            (195, 0x02),
+        ] {
+            expected[i] = byte;
+        }
+
+        assert_eq!(logs_bloom(&[log]), expected);
+    }
+
+    #[test]
+    fn call_filters_only_transform() {
+        let filter = CombinedFilter {
+            log_filters: vec![],
+            call_filters: vec![CallToFilter {
+                addresses: vec![vec![0xaa; 20]],
+                signatures: vec![vec![0xa9, 0x05, 0x9c, 0xbb]],
+            }],
+            send_all_block_headers: false,
+        };
+        let transform = prost_types::Any {
+            type_url: "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter".to_string(),
+            value: filter.encode_to_vec(),
+        };
+
+        let filter = decode_transforms(&[transform]).unwrap();
+
+        assert!(filter.logs.is_empty());
+        assert_eq!(filter.transactions.len(), 1);
+        assert_eq!(
+            filter.transactions[0].to,
+            vec!["0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"]
+        );
+        assert_eq!(filter.transactions[0].sighash, vec!["0xa9059cbb"]);
+    }
+
+    #[test]
+    fn unsupported_transform() {
+        let transform = prost_types::Any {
+            type_url: "type.googleapis.com/sf.near.transform.v1.BasicReceiptFilter".to_string(),
+            value: vec![],
+        };
+
+        let err = decode_transforms(&[transform]).unwrap_err();
+
+        assert_eq!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument(
+                "unsupported transform: type.googleapis.com/sf.near.transform.v1.BasicReceiptFilter"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn log_and_call_filters_in_separate_transforms() {
+        let emitter = "0x1111111111111111111111111111111111111111";
+        let callee = "0x3535353535353535353535353535353535353535";
+        let logs = MultiLogFilter {
+            log_filters: vec![LogFilter {
+                addresses: vec![vec![0x11; 20]],
+                ..Default::default()
+            }],
+        };
+        let calls = MultiCallToFilter {
+            call_filters: vec![CallToFilter {
+                addresses: vec![vec![0x35; 20]],
+                signatures: vec![],
+            }],
+        };
+        let transform = |type_url: &str, value: Vec<u8>| prost_types::Any {
+            type_url: format!("type.googleapis.com/sf.ethereum.transform.v1.{}", type_url),
+            value,
+        };
+        let combined = CombinedFilter {
+            log_filters: logs.log_filters.clone(),
+            call_filters: vec![],
+            send_all_block_headers: false,
+        };
+
+        let mut block = block(1, hash(1), hash(0));
+        let transaction = |index: u32, to: &str| Transaction {
+            transaction_index: index,
+            hash: hash(index.into()),
+            to: Some(to.to_string()),
+            ..eip155_transaction()
+        };
+        block.transactions = vec![
+            transaction(0, callee),
+            transaction(1, "0x2222222222222222222222222222222222222222"),
+            transaction(2, "0x2222222222222222222222222222222222222222"),
+        ];
+        block.logs = vec![Log {
+            address: emitter.to_string(),
+            data: "0x".to_string(),
+            topics: vec![],
+            log_index: 0,
+            transaction_index: 2,
+        }];
+
+        for transforms in [
+            vec![
+                transform("MultiLogFilter", logs.encode_to_vec()),
+                transform("MultiCallToFilter", calls.encode_to_vec()),
+            ],
+            vec![
+                transform("CombinedFilter", combined.encode_to_vec()),
+                transform("MultiCallToFilter", calls.encode_to_vec()),
+            ],
+        ] {
+            let filter = decode_transforms(&transforms).unwrap();
+            assert_eq!(filter.logs.len(), 1);
+            assert_eq!(filter.logs[0].address, vec![emitter]);
+            assert_eq!(filter.transactions.len(), 1);
+            assert_eq!(filter.transactions[0].to, vec![callee]);
+            assert!(filter.calls);
+
+            // the called transaction and the one emitting the log are kept
+            let stripped = strip_unmatched(block.clone(), &filter);
+            let kept: Vec<u32> = stripped
+                .transactions
+                .iter()
+                .map(|tx| tx.transaction_index)
+                .collect();
+            assert_eq!(kept, vec![0, 2]);
+            assert_eq!(stripped.logs.len(), 1);
+        }
+    }
+
+    #[test]
+    fn multiple_transforms_keep_separate_groups() {
+        let usdt_transfers = CombinedFilter {
+            log_filters: vec![],
+            call_filters: vec![CallToFilter {
+                addresses: vec![vec![0xaa; 20]],
+                signatures: vec![vec![0xa9, 0x05, 0x9c, 0xbb]],
+            }],
+            send_all_block_headers: false,
+        };
+        let weth_calls = CombinedFilter {
+            log_filters: vec![LogFilter {
+                addresses: vec![vec![0xbb; 20]],
+                ..Default::default()
+            }],
+            call_filters: vec![CallToFilter {
+                addresses: vec![vec![0xbb; 20]],
+                signatures: vec![],
+            }],
+            send_all_block_headers: false,
+        };
+        let transforms: Vec<_> = [usdt_transfers, weth_calls]
+            .into_iter()
+            .map(|filter| prost_types::Any {
+                type_url: "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter".to_string(),
+                value: filter.encode_to_vec(),
+            })
+            .collect();
+
+        let filter = decode_transforms(&transforms).unwrap();
+
+        assert_eq!(filter.transactions.len(), 2);
+        assert_eq!(
+            filter.transactions[0].to,
+            vec!["0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"]
+        );
+        assert_eq!(filter.transactions[0].sighash, vec!["0xa9059cbb"]);
+        assert_eq!(
+            filter.transactions[1].to,
+            vec!["0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"]
+        );
+        assert!(filter.transactions[1].sighash.is_empty());
+        assert_eq!(filter.logs.len(), 1);
+        assert_eq!(
+            filter.logs[0].address,
+            vec!["0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"]
+        );
+    }
+
+    #[tokio::test]
+    async fn final_blocks_only_skips_hot_blocks() {
+        let reorg = HotUpdate {
+            blocks: vec![block(8, fork_hash(8), hash(7))],
+            base_head: HashAndHeight {
+                hash: hash(7),
+                height: 7,
+            },
+            finalized_head: HashAndHeight {
+                hash: hash(7),
+                height: 7,
+            },
+        };
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(vec![reorg]));
+        let firehose = Firehose::new(archive, Some(rpc.clone()));
+
+        let request = Request {
+            start_block_num: 0,
+            final_blocks_only: true,
+            ..Default::default()
+        };
+        let responses = collect(&firehose, request).await;
+
+        let expected: Vec<_> = (0..=8)
+            .map(|number| (ForkStep::StepFinal, number))
+            .collect();
+        assert_eq!(responses, expected);
+        assert_eq!(rpc.hot_calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn start_at_block_tag() {
+        // the hot stream follows the block before the latest one
+        let update = hot_update(vec![block(10, hash(10), hash(9))], head(hash(8), 8));
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_head(10).with_hot(vec![update]));
+        let firehose = Firehose::new(archive.clone(), Some(rpc));
+        let request = |tag: &str, stop_block_num: u64| Request {
+            start_block_tag: tag.to_string(),
+            stop_block_num,
+            ..Default::default()
+        };
+
+        for tag in ["finalized", "safe"] {
+            let responses = collect(&firehose, request(tag, 8)).await;
+            assert_eq!(responses, vec![(ForkStep::StepNew, 8)]);
+        }
+        let responses = collect(&firehose, request("latest", 10)).await;
+        assert_eq!(responses, vec![(ForkStep::StepNew, 10)]);
+
+        let err = firehose
+            .blocks(request("pending", 10), false)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument(_)
+        ));
+        let archive_only = Firehose::new(archive, None);
+        let err = archive_only
+            .blocks(request("finalized", 5), false)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument("start block tags require an rpc source".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn handoff_block_is_not_repeated() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let update = HotUpdate {
+            blocks: vec![block(8, hash(8), hash(7)), block(9, hash(9), hash(8))],
+            base_head: head(hash(8), 8),
+            finalized_head: head(hash(8), 8),
+        };
+        let archive = Arc::new(MockDataSource::new(8));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(vec![update]));
+        let firehose = Firehose::new(archive, Some(rpc));
+
+        let request = Request {
+            start_block_num: 5,
+            ..Default::default()
+        };
+        let responses = collect(&firehose, request).await;
+
+        let expected: Vec<_> = (5..=9).map(|number| (ForkStep::StepNew, number)).collect();
+        assert_eq!(responses, expected);
+    }
+
+    #[tokio::test]
+    async fn reverse_bounded_range() {
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8));
+        let firehose = Firehose::new(archive, Some(rpc.clone()));
+
+        let request = Request {
+            start_block_num: 2,
+            stop_block_num: 7,
+            reverse: true,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request.clone(), false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        let numbers: Vec<_> = responses
+            .iter()
+            .map(|response| {
+                let block =
+                    pbcodec::Block::decode(&response.block.as_ref().unwrap().value[..]).unwrap();
+                assert_eq!(response.step, ForkStep::StepNew as i32);
+                block.number
+            })
+            .collect();
+        assert_eq!(numbers, vec![7, 6, 5, 4, 3, 2]);
+        assert_eq!(rpc.hot_calls(), 0);
+
+        // resuming continues below the last delivered block
+        let resumed = Request {
+            cursor: responses[2].cursor.clone(),
+            ..request
+        };
+        let responses = collect(&firehose, resumed).await;
+        assert_eq!(
+            responses,
+            vec![
+                (ForkStep::StepNew, 4),
+                (ForkStep::StepNew, 3),
+                (ForkStep::StepNew, 2)
+            ]
+        );
+
+        let beyond_finalized = Request {
+            stop_block_num: 9,
+            reverse: true,
+            ..Default::default()
+        };
+        assert!(firehose.blocks(beyond_finalized, false).await.is_err());
+        let unbounded = Request {
+            reverse: true,
+            ..Default::default()
+        };
+        assert!(firehose.blocks(unbounded, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reverse_spans_several_batches() {
+        let firehose = Firehose::new(Arc::new(MockDataSource::new(250)), None);
+
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 250,
+            reverse: true,
+            final_blocks_only: true,
+            ..Default::default()
+        };
+        let responses = collect(&firehose, request).await;
+
+        let expected: Vec<_> = (0..=250)
+            .rev()
+            .map(|number| (ForkStep::StepFinal, number))
+            .collect();
+        assert_eq!(responses, expected);
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let archive = Arc::new(MockDataSource::new(10).with_failure(4, reset.into()));
+        let firehose =
+            Firehose::new(archive.clone(), None).with_retries(3, Duration::from_millis(1));
+
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 10,
+            ..Default::default()
+        };
+        let responses = collect(&firehose, request).await;
+
+        let expected: Vec<_> = (0..=10).map(|number| (ForkStep::StepNew, number)).collect();
+        assert_eq!(responses, expected);
+        assert!(archive.failure_delivered());
+    }
+
+    #[tokio::test]
+    async fn fatal_failure_ends_stream() {
+        let archive =
+            Arc::new(MockDataSource::new(10).with_failure(4, anyhow::anyhow!("invalid block")));
+        let firehose = Firehose::new(archive, None).with_retries(3, Duration::from_millis(1));
+
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 10,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let results: Vec<_> = stream.collect().await;
+
+        assert_eq!(results.len(), 5);
+        assert!(results[..4].iter().all(|result| result.is_ok()));
+        assert_eq!(
+            results[4].as_ref().unwrap_err().to_string(),
+            "invalid block"
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_ids() {
+        let archive = Arc::new(MockDataSource::new(5).with_chain_id(1));
+        let rpc = Arc::new(MockDataSource::new(8).with_chain_id(5));
+        let e = Firehose::new(archive, Some(rpc))
+            .with_checked_chain_id()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "archive serves chain 1 while the rpc serves chain 5"
+        );
+
+        let archive = Arc::new(MockDataSource::new(5).with_chain_id(1));
+        let rpc = Arc::new(MockDataSource::new(8).with_chain_id(1));
+        let firehose = Firehose::new(archive, Some(rpc))
+            .with_checked_chain_id()
+            .await
+            .unwrap();
+        assert_eq!(firehose.chain_id(), Some(1));
+
+        // an archive without a configured chain id takes the one of the rpc
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_chain_id(5));
+        let firehose = Firehose::new(archive, Some(rpc))
+            .with_checked_chain_id()
+            .await
+            .unwrap();
+        assert_eq!(firehose.chain_id(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn servable_range() {
+        let archive = Arc::new(MockDataSource::new(5).with_first_block(3));
+        let rpc = Arc::new(MockDataSource::new(8).with_chain_id(1));
+        let firehose = Firehose::new(archive.clone(), Some(rpc))
+            .with_checked_chain_id()
+            .await
+            .unwrap();
+        assert_eq!(
+            firehose.info().await.unwrap(),
+            InfoResponse {
+                chain_id: 1,
+                first_block_num: 3,
+                finalized_block_num: 8,
+                head_block_num: 8,
+                block_type_url: ETH_BLOCK_TYPE_URL.to_string(),
+            }
+        );
+
+        let firehose = Firehose::new(archive, None);
+        let info = firehose.info().await.unwrap();
+        assert_eq!(info.chain_id, 0);
+        assert_eq!(info.first_block_num, 3);
+        assert_eq!(info.finalized_block_num, 5);
+        assert_eq!(info.head_block_num, 5);
+    }
+
+    #[tokio::test]
+    async fn chain_head() {
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_head(10));
+        let firehose = Firehose::new(archive.clone(), Some(rpc));
+        assert_eq!(
+            firehose.head().await.unwrap(),
+            HeadResponse {
+                head_block_num: 10,
+                head_block_hash: hash(10),
+                finalized_block_num: 8,
+            }
+        );
+
+        let firehose = Firehose::new(archive, None);
+        let head = firehose.head().await.unwrap();
+        assert_eq!(head.head_block_num, 5);
+        assert_eq!(head.head_block_hash, hash(5));
+        assert_eq!(head.finalized_block_num, 5);
+    }
+
+    #[tokio::test]
+    async fn archive_only_head() {
+        let server = MockArchive::start(5);
+        let ds = ArchiveDataSource::new(Arc::new(archive::Archive::new(server.url())));
+        let firehose = Firehose::new(Arc::new(ds), None);
+        assert_eq!(
+            firehose.head().await.unwrap(),
+            HeadResponse {
+                head_block_num: 5,
+                head_block_hash: hash(5),
+                finalized_block_num: 5,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn hung_upstream_times_out() {
+        let firehose = Firehose::new(Arc::new(HangingDataSource), None)
+            .with_retries(1, Duration::from_millis(1))
+            .with_call_timeout(Duration::from_millis(10));
+
+        let request = Request {
+            start_block_num: 0,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let results: Vec<_> = stream.collect().await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "finalized block stream timed out after 10ms"
+        );
+
+        let request = SingleBlockRequest {
+            reference: Some(Reference::BlockNumber(single_block_request::BlockNumber {
+                num: 5,
+            })),
+            ..Default::default()
+        };
+        let e = firehose.block(request).await.unwrap_err();
+        assert_eq!(e.to_string(), "archive blocks timed out after 10ms");
+    }
+
+    #[tokio::test]
+    async fn custom_block_type() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let updates = vec![
+            HotUpdate {
+                blocks: vec![block(7, hash(7), hash(6)), block(8, hash(8), hash(7))],
+                base_head: head(hash(6), 6),
+                finalized_head: head(hash(6), 6),
+            },
+            HotUpdate {
+                blocks: vec![block(8, fork_hash(8), hash(7))],
+                base_head: head(hash(7), 7),
+                finalized_head: head(hash(6), 6),
+            },
+        ];
+        let archive = Arc::new(MockDataSource::new(3));
+        let rpc = Arc::new(MockDataSource::new(6).with_hot(updates));
+        let block_type = BlockType {
+            type_url: "type.googleapis.com/sf.ethereum.type.v3.Block".to_string(),
+            version: 3,
+        };
+        let firehose = Firehose::new(archive, Some(rpc)).with_block_type(block_type.clone());
+
+        let stream = firehose.blocks(Request::default(), false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        // archive, finalized rpc, new hot and undone blocks
+        assert_eq!(responses.len(), 11);
+        for response in responses {
+            let any = response.block.unwrap();
+            assert_eq!(any.type_url, block_type.type_url);
+            assert_eq!(pbcodec::Block::decode(&any.value[..]).unwrap().ver, 3);
+        }
+
+        let request = SingleBlockRequest {
+            reference: Some(Reference::BlockNumber(single_block_request::BlockNumber {
+                num: 2,
+            })),
+            ..Default::default()
+        };
+        let any = firehose.block(request).await.unwrap().block.unwrap();
+        assert_eq!(any.type_url, block_type.type_url);
+        assert_eq!(pbcodec::Block::decode(&any.value[..]).unwrap().ver, 3);
+    }
+
+    #[tokio::test]
+    async fn hot_blocks_become_final() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let updates = vec![
+            HotUpdate {
+                blocks: vec![block(9, hash(9), hash(8)), block(10, hash(10), hash(9))],
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+            HotUpdate {
+                blocks: vec![
+                    block(10, fork_hash(10), hash(9)),
+                    block(11, fork_hash(11), fork_hash(10)),
+                ],
+                base_head: head(hash(9), 9),
+                finalized_head: head(hash(8), 8),
+            },
+            HotUpdate {
+                blocks: vec![block(12, fork_hash(12), fork_hash(11))],
+                base_head: head(fork_hash(11), 11),
+                finalized_head: head(fork_hash(10), 10),
+            },
+        ];
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(updates));
+        let firehose = Firehose::new(archive, Some(rpc));
+
+        let request = Request {
+            start_block_num: 9,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+
+        let steps: Vec<_> = responses
+            .iter()
+            .map(|response| {
+                let block =
+                    pbcodec::Block::decode(&response.block.as_ref().unwrap().value[..]).unwrap();
+                let header = block.header.unwrap();
+                (ForkStep::from_i32(response.step).unwrap(), header.number)
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (ForkStep::StepNew, 9),
+                (ForkStep::StepNew, 10),
+                (ForkStep::StepUndo, 10),
+                (ForkStep::StepNew, 10),
+                (ForkStep::StepNew, 11),
+                (ForkStep::StepNew, 12),
+                (ForkStep::StepFinal, 9),
+                (ForkStep::StepFinal, 10),
+            ]
+        );
+
+        // the reorged block 10 is never finalized
+        let final_10 =
+            pbcodec::Block::decode(&responses[7].block.as_ref().unwrap().value[..]).unwrap();
+        assert_eq!(prefix_hex::encode(final_10.hash), fork_hash(10));
+
+        let cursor = Cursor::try_from(&responses[7].cursor).unwrap();
+        assert_eq!(cursor.block, head(fork_hash(12), 12));
+        assert_eq!(cursor.finalized, head(fork_hash(10), 10));
+    }
+
+    #[tokio::test]
+    async fn undo_carries_undone_block_hash() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let updates = vec![
+            HotUpdate {
+                blocks: vec![block(9, hash(9), hash(8))],
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+            HotUpdate {
+                blocks: vec![block(9, fork_hash(9), hash(8))],
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+        ];
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(updates));
+        let firehose = Firehose::new(archive, Some(rpc));
+
+        let request = Request {
+            start_block_num: 9,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        assert_eq!(responses.len(), 3);
+
+        let undo = &responses[1];
+        assert_eq!(ForkStep::from_i32(undo.step), Some(ForkStep::StepUndo));
+        let block = pbcodec::Block::decode(&undo.block.as_ref().unwrap().value[..]).unwrap();
+        let header = block.header.unwrap();
+        assert_eq!(block.number, 9);
+        assert_eq!(prefix_hex::encode(block.hash), hash(9));
+        assert_eq!(prefix_hex::encode(header.hash), hash(9));
+        assert_eq!(prefix_hex::encode(header.parent_hash), hash(8));
+
+        let cursor = Cursor::try_from(&undo.cursor).unwrap();
+        assert_eq!(cursor.step, ForkStep::StepUndo);
+        assert_eq!(cursor.block, head(hash(9), 9));
+        assert_eq!(cursor.head(), &head(hash(8), 8));
+    }
+
+    #[tokio::test]
+    async fn resume_from_undo_cursor() {
+        let updates = vec![
+            hot_update(vec![block(9, hash(9), hash(8))], head(hash(8), 8)),
+            hot_update(vec![block(9, fork_hash(9), hash(8))], head(hash(8), 8)),
+        ];
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(updates));
+        let firehose = Firehose::new(archive, Some(rpc.clone()));
+
+        let request = Request {
+            start_block_num: 9,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        let undo = &responses[1];
+        assert_eq!(undo.step, ForkStep::StepUndo as i32);
+
+        // the client dropped block 9 already, it gets the block of the new fork only
+        rpc.set_hot(vec![hot_update(
+            vec![block(9, fork_hash(9), hash(8))],
+            head(hash(8), 8),
+        )]);
+        let request = Request {
+            cursor: undo.cursor.clone(),
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].step, ForkStep::StepNew as i32);
+        let block =
+            pbcodec::Block::decode(&responses[0].block.as_ref().unwrap().value[..]).unwrap();
+        assert_eq!(prefix_hex::encode(block.hash), fork_hash(9));
+    }
+
+    #[tokio::test]
+    async fn empty_archive_range_falls_through_to_rpc() {
+        let request = Request {
+            start_block_num: 3,
+            ..Default::default()
+        };
+
+        // the rpc backfills the blocks the archive didn't deliver
+        let rpc = MockDataSource::new(4).with_hot(vec![hot_update(
+            chain(5..=6, hash, hash(4)),
+            head(hash(4), 4),
+        )]);
+        let firehose = Firehose::new(Arc::new(EmptyDataSource), Some(Arc::new(rpc)));
+        let new = |number| (ForkStep::StepNew, number);
+        assert_eq!(
+            collect(&firehose, request.clone()).await,
+            vec![new(3), new(4), new(5), new(6)]
+        );
+
+        // nothing final is delivered, the hot stream starts right below the requested block
+        let rpc = MockDataSource::new(2).with_hot(vec![hot_update(
+            chain(3..=4, hash, hash(2)),
+            head(hash(2), 2),
+        )]);
+        let firehose = Firehose::new(Arc::new(EmptyDataSource), Some(Arc::new(rpc)));
+        assert_eq!(collect(&firehose, request).await, vec![new(3), new(4)]);
+    }
+
+    #[tokio::test]
+    async fn restart_undoes_blocks_reorged_while_down() {
+        let updates = vec![hot_update(chain(9..=11, hash, hash(8)), head(hash(8), 8))];
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(
+            MockDataSource::new(8)
+                .with_head(12)
+                .with_block(block(11, fork_hash(11), hash(10)))
+                .with_hot(updates)
+                .with_hot_failure(reset.into()),
+        );
+        let firehose = Firehose::new(archive, Some(rpc.clone())).with_retries(1, Duration::ZERO);
+
+        let request = Request {
+            start_block_num: 9,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        let steps: Vec<_> = responses
+            .iter()
+            .map(|response| {
+                let block =
+                    pbcodec::Block::decode(&response.block.as_ref().unwrap().value[..]).unwrap();
+                (ForkStep::from_i32(response.step).unwrap(), block.number)
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (ForkStep::StepNew, 9),
+                (ForkStep::StepNew, 10),
+                (ForkStep::StepNew, 11),
+                (ForkStep::StepUndo, 11),
+            ]
+        );
+        let undo = pbcodec::Block::decode(&responses[3].block.as_ref().unwrap().value[..]).unwrap();
+        assert_eq!(prefix_hex::encode(undo.hash), hash(11));
+        assert_eq!(
+            prefix_hex::encode(undo.header.unwrap().parent_hash),
+            hash(10)
+        );
+        assert_eq!(rpc.hot_calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn deep_reorg_undoes_every_block() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let updates = vec![
+            HotUpdate {
+                blocks: (9..=12)
+                    .map(|number| block(number, hash(number), hash(number - 1)))
+                    .collect(),
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+            HotUpdate {
+                blocks: vec![
+                    block(10, fork_hash(10), hash(9)),
+                    block(11, fork_hash(11), fork_hash(10)),
+                    block(12, fork_hash(12), fork_hash(11)),
+                    block(13, fork_hash(13), fork_hash(12)),
+                ],
+                base_head: head(hash(9), 9),
+                finalized_head: head(hash(8), 8),
+            },
+        ];
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(updates));
+        let firehose = Firehose::new(archive, Some(rpc));
+
+        let request = Request {
+            start_block_num: 9,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+
+        let undos: Vec<_> = responses
+            .iter()
+            .filter(|response| response.step == ForkStep::StepUndo as i32)
+            .map(|response| {
+                let block =
+                    pbcodec::Block::decode(&response.block.as_ref().unwrap().value[..]).unwrap();
+                let parent_hash = block.header.unwrap().parent_hash;
+                (
+                    block.number,
+                    prefix_hex::encode(block.hash),
+                    prefix_hex::encode(parent_hash),
+                )
+            })
+            .collect();
+        assert_eq!(
+            undos,
+            vec![
+                (12, hash(12), hash(11)),
+                (11, hash(11), hash(10)),
+                (10, hash(10), hash(9)),
+            ]
+        );
+        assert_eq!(responses.len(), 4 + 3 + 4);
+    }
+
+    #[tokio::test]
+    async fn every_phase_with_reorg() {
+        let to = "0x3535353535353535353535353535353535353535";
+        let log = Log {
+            address: to.to_string(),
+            data: "0x".to_string(),
+            topics: vec![],
+            log_index: 0,
+            transaction_index: 0,
+        };
+        let archive = MockDataSource::new(3)
+            .with_transactions(2, vec![eip155_transaction()])
+            .with_logs(2, vec![log])
+            .with_traces(2, vec![call_trace(vec![], to)]);
+        let mut rpc_block = block(5, hash(5), hash(4));
+        rpc_block.header.timestamp = 1_700_000_000;
+        let hot = vec![
+            hot_update(chain(7..=9, hash, hash(6)), head(hash(6), 6)),
+            // a longer fork replaces blocks 8 and 9
+            hot_update(chain(8..=10, fork_hash, hash(7)), head(hash(7), 7)),
+            hot_update(
+                chain(11..=11, fork_hash, fork_hash(10)),
+                head(fork_hash(9), 9),
+            ),
+        ];
+        let rpc = MockDataSource::new(6).with_block(rpc_block).with_hot(hot);
+        let firehose = Firehose::new(Arc::new(archive), Some(Arc::new(rpc))).with_full_traces(true);
+
+        let request = Request {
+            start_block_num: 0,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let blocks: Vec<(ForkStep, pbcodec::Block)> = stream
+            .map(|result| {
+                let response = result.unwrap();
+                let block = pbcodec::Block::decode(&response.block.unwrap().value[..]).unwrap();
+                (ForkStep::from_i32(response.step).unwrap(), block)
+            })
+            .collect()
+            .await;
+
+        let steps: Vec<_> = blocks
+            .iter()
+            .map(|(step, block)| (*step, block.number, prefix_hex::encode(&block.hash)))
+            .collect();
+        let new = |number: u64, hash: fn(u64) -> String| (ForkStep::StepNew, number, hash(number));
+        let mut expected: Vec<_> = (0..=9).map(|number| new(number, hash)).collect();
+        expected.extend([
+            (ForkStep::StepUndo, 9, hash(9)),
+            (ForkStep::StepUndo, 8, hash(8)),
+            new(8, fork_hash),
+            new(9, fork_hash),
+            new(10, fork_hash),
+            (ForkStep::StepFinal, 7, hash(7)),
+            new(11, fork_hash),
+            (ForkStep::StepFinal, 8, fork_hash(8)),
+            (ForkStep::StepFinal, 9, fork_hash(9)),
+        ]);
+        assert_eq!(steps, expected);
+
+        let traces = &blocks[2].1.transaction_traces;
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].calls.len(), 1);
+        assert_eq!(traces[0].receipt.as_ref().unwrap().logs.len(), 1);
+        assert_eq!(
+            blocks[5]
+                .1
+                .header
+                .as_ref()
+                .unwrap()
+                .timestamp
+                .as_ref()
+                .unwrap()
+                .seconds,
+            1_700_000_000
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn events(&self, message: &str) -> Vec<serde_json::Value> {
+            let logs = self.0.lock().unwrap();
+            serde_json::Deserializer::from_slice(&logs)
+                .into_iter::<serde_json::Value>()
+                .map(|event| event.unwrap())
+                .filter(|event| event["message"] == message)
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_phases_are_traced() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let updates = vec![
+            HotUpdate {
+                blocks: (9..=12)
+                    .map(|number| block(number, hash(number), hash(number - 1)))
+                    .collect(),
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+            HotUpdate {
+                blocks: vec![
+                    block(10, fork_hash(10), hash(9)),
+                    block(11, fork_hash(11), fork_hash(10)),
+                ],
+                base_head: head(hash(9), 9),
+                finalized_head: head(hash(8), 8),
+            },
+        ];
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(updates));
+        let firehose = Firehose::new(archive, Some(rpc));
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_span_list(true)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let request = Request {
+            start_block_num: 3,
+            stop_block_num: 20,
+            ..Default::default()
+        };
+        collect(&firehose, request).await;
+
+        let phases: Vec<_> = logs
+            .events("phase finished")
+            .into_iter()
+            .map(|event| {
+                assert_eq!(event["spans"][0]["name"], "blocks");
+                assert_eq!(event["spans"][0]["start"], 3);
+                assert_eq!(event["spans"][0]["stop"], 20);
+                (
+                    event["phase"].as_str().unwrap().to_string(),
+                    event["from"].as_u64().unwrap(),
+                    event["to"].as_u64().unwrap(),
+                    event["blocks"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            phases,
+            vec![
+                ("archive".to_string(), 3, 5, 3),
+                ("rpc".to_string(), 6, 8, 3),
+                ("hot".to_string(), 9, 11, 6),
+            ]
+        );
+
+        let reorgs = logs.events("reorg");
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0]["old_head"], 12);
+        assert_eq!(reorgs[0]["new_head"], 11);
+        assert_eq!(reorgs[0]["new_hash"], fork_hash(11));
+        assert_eq!(reorgs[0]["common_ancestor"], 9);
+        assert_eq!(reorgs[0]["undone"], 3);
+        assert_eq!(reorgs[0]["span"]["phase"], "hot");
+    }
+
+    #[tokio::test]
+    async fn all_block_headers_of_sparse_filter() {
+        let watched = "0x1111111111111111111111111111111111111111";
+        let tx = signed_transaction(
+            0,
+            "0x0000000000000000000000000000000000000001",
+            watched,
+            "0x0",
+            "0x",
+            ("0x1b", "0x1", "0x1"),
+        );
+        let archive = MockDataSource::new(5)
+            .with_transactions(2, vec![tx.clone()])
+            .with_transactions(4, vec![tx]);
+        let firehose = Firehose::new(Arc::new(archive), None);
+        let transform = |send_all_block_headers: bool| {
+            let filter = CombinedFilter {
+                log_filters: vec![],
+                call_filters: vec![CallToFilter {
+                    addresses: vec![prefix_hex::decode(watched).unwrap()],
+                    signatures: vec![],
+                }],
+                send_all_block_headers,
+            };
+            prost_types::Any {
+                type_url: "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter".to_string(),
+                value: filter.encode_to_vec(),
+            }
+        };
+
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 5,
+            transforms: vec![transform(false), transform(true)],
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let traces: Vec<_> = stream
+            .map(|result| {
+                let block = pbcodec::Block::decode(&result.unwrap().block.unwrap().value[..]);
+                let block = block.unwrap();
+                assert!(block.header.is_some());
+                (block.number, block.transaction_traces.len())
+            })
+            .collect()
+            .await;
+        assert_eq!(traces, vec![(0, 0), (1, 0), (2, 1), (3, 0), (4, 1), (5, 0)]);
+    }
+
+    #[tokio::test]
+    async fn header_only_transform() {
+        let archive = Arc::new(MockDataSource::new(5));
+        let firehose = Firehose::new(archive, None);
+        let calls = CombinedFilter {
+            log_filters: vec![],
+            call_filters: vec![CallToFilter {
+                addresses: vec![vec![0x35; 20]],
+                signatures: vec![],
+            }],
+            send_all_block_headers: false,
+        };
+        let combined_filter = prost_types::Any {
+            type_url: "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter".to_string(),
+            value: calls.encode_to_vec(),
+        };
+        let header_only = prost_types::Any {
+            type_url: "type.googleapis.com/sf.ethereum.transform.v1.HeaderOnly".to_string(),
+            value: vec![],
+        };
+
+        for (transforms, expected_traces) in [
+            (vec![combined_filter.clone()], 1),
+            (vec![combined_filter, header_only], 0),
+        ] {
+            let request = Request {
+                start_block_num: 0,
+                stop_block_num: 5,
+                transforms,
+                ..Default::default()
+            };
+            let stream = firehose.blocks(request, false).await.unwrap();
+            let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+            assert_eq!(responses.len(), 6);
+
+            for response in responses {
+                let block = pbcodec::Block::decode(&response.block.unwrap().value[..]).unwrap();
+                assert!(block.header.is_some());
+                assert_eq!(block.transaction_traces.len(), expected_traces);
+            }
+        }
+    }
+
+    #[test]
+    fn strip_unmatched_logs() {
+        let matched = "0x1111111111111111111111111111111111111111";
+        let unmatched = "0x2222222222222222222222222222222222222222";
+        let log = |address: &str, transaction_index: u32| Log {
+            address: address.to_string(),
+            data: "0x".to_string(),
+            topics: vec![hash(0)],
+            log_index: transaction_index,
+            transaction_index,
+        };
+
+        let mut block = block(1, hash(1), hash(0));
+        let mut other = eip155_transaction();
+        other.transaction_index = 1;
+        other.hash = hash(0xbeef);
+        block.transactions = vec![eip155_transaction(), other];
+        block.logs = vec![log(matched, 0), log(unmatched, 1)];
+
+        let filter = TransformFilter {
+            logs: vec![LogRequest {
+                address: vec![matched.to_uppercase().replace("0X", "0x")],
+                topic0: vec![],
+                topic1: vec![],
+                topic2: vec![],
+                topic3: vec![],
+            }],
+            ..Default::default()
+        };
+        let block = strip_unmatched(block, &filter);
+
+        assert_eq!(block.logs.len(), 1);
+        assert_eq!(block.logs[0].address, matched);
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].transaction_index, 0);
+
+        // nothing is stripped without filters
+        let mut unfiltered = block.clone();
+        unfiltered.logs.push(log(unmatched, 1));
+        let unfiltered = strip_unmatched(unfiltered, &TransformFilter::default());
+        assert_eq!(unfiltered.logs.len(), 2);
+    }
+
+    #[test]
+    fn erc20_transfer_recipient_filter() {
+        let transfer = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+        let sender = "0x00000000000000000000000028c6c06298d514db089934071355e5743bf21d60";
+        let recipient = "0x000000000000000000000000a9d1e08c7793af67e9d92fe308d5697fb81d3e43";
+        let log = |topics: Vec<&str>, transaction_index: u32| Log {
+            address: "0xdac17f958d2ee523a2206206994597c13d831ec7".to_string(),
+            data: "0x".to_string(),
+            topics: topics.into_iter().map(str::to_string).collect(),
+            log_index: transaction_index,
+            transaction_index,
+        };
+
+        let mut block = block(1, hash(1), hash(0));
+        block.logs = vec![
+            log(vec![transfer, sender, recipient], 0),
+            log(vec![transfer, recipient, sender], 1),
+            log(vec![transfer], 2),
+        ];
+
+        let filter = CombinedFilter {
+            log_filters: vec![LogFilter {
+                event_signatures: vec![prefix_hex::decode(transfer).unwrap()],
+                topic2: vec![prefix_hex::decode(recipient).unwrap()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let transforms = vec![prost_types::Any {
+            type_url: "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter".to_string(),
+            value: filter.encode_to_vec(),
+        }];
+        let filter = decode_transforms(&transforms).unwrap();
+        assert_eq!(filter.logs[0].topic2, vec![recipient]);
+        assert!(filter.logs[0].topic1.is_empty());
+
+        let block = strip_unmatched(block, &filter);
+        assert_eq!(block.logs.len(), 1);
+        assert_eq!(block.logs[0].topics[2], recipient);
+    }
+
+    #[test]
+    fn transaction_sender_filter() {
+        let sender = "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f";
+        let filter = MultiTransactionFilter {
+            transaction_filters: vec![TransactionFilter {
+                from_addresses: vec![prefix_hex::decode(sender).unwrap()],
+                to_addresses: vec![],
+            }],
+        };
+        let transform = |filter: &MultiTransactionFilter| prost_types::Any {
+            type_url: "type.googleapis.com/sf.ethereum.transform.v1.MultiTransactionFilter"
+                .to_string(),
+            value: filter.encode_to_vec(),
+        };
+        let filter = decode_transforms(&[transform(&filter)]).unwrap();
+        assert_eq!(filter.transactions[0].from, vec![sender]);
+        assert!(filter.transactions[0].to.is_empty());
+        // plain transaction filters are matched without tracing
+        assert!(!filter.calls);
+
+        let transaction = |index: u32, from: &str| Transaction {
+            transaction_index: index,
+            hash: hash(index.into()),
+            from: from.to_string(),
+            ..eip155_transaction()
+        };
+        let mut block = block(1, hash(1), hash(0));
+        block.transactions = vec![
+            transaction(0, "0x1111111111111111111111111111111111111111"),
+            transaction(1, &sender.to_uppercase().replace("0X", "0x")),
+            transaction(2, "0x2222222222222222222222222222222222222222"),
+        ];
+        assert!(block_matches(&block, &filter));
+        let stripped = strip_unmatched(block.clone(), &filter);
+        let kept: Vec<u32> = stripped
+            .transactions
+            .iter()
+            .map(|tx| tx.transaction_index)
+            .collect();
+        assert_eq!(kept, vec![1]);
+
+        // blocks without a transaction of the sender don't match
+        block.transactions.remove(1);
+        assert!(!block_matches(&block, &filter));
+
+        let empty = MultiTransactionFilter {
+            transaction_filters: vec![TransactionFilter::default()],
+        };
+        let err = decode_transforms(&[transform(&empty)]).unwrap_err();
+        assert!(matches!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument(_)
+        ));
+    }
+
+    #[test]
+    fn state_change_transforms() {
+        let transform = |name: &str| prost_types::Any {
+            type_url: format!("type.googleapis.com/sf.ethereum.transform.v1.{}", name),
+            value: vec![],
+        };
+        let filter = decode_transforms(&[]).unwrap();
+        assert!(!filter.balance_changes && !filter.storage_changes);
+
+        let filter = decode_transforms(&[transform("BalanceChanges")]).unwrap();
+        assert!(filter.balance_changes && !filter.storage_changes);
+
+        let transforms = [transform("StorageChanges"), transform("HeaderOnly")];
+        let filter = decode_transforms(&transforms).unwrap();
+        assert!(!filter.balance_changes && filter.storage_changes);
+    }
+
+    #[tokio::test]
+    async fn stream_metrics() {
+        let head = |hash: String, height: u64| HashAndHeight { hash, height };
+        let updates = vec![
+            HotUpdate {
+                blocks: vec![block(9, hash(9), hash(8))],
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+            HotUpdate {
+                blocks: vec![block(9, fork_hash(9), hash(8))],
+                base_head: head(hash(8), 8),
+                finalized_head: head(hash(8), 8),
+            },
+        ];
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8).with_hot(updates));
+        let firehose = Firehose::new(archive, Some(rpc));
+
+        let request = Request {
+            start_block_num: 0,
+            ..Default::default()
+        };
+        collect(&firehose, request).await;
+
+        let text = firehose.metrics().encode().unwrap();
+        let expected = [
+            r#"firehose_blocks_total{phase="archive",step="STEP_NEW"} 6"#,
+            r#"firehose_blocks_total{phase="rpc",step="STEP_NEW"} 3"#,
+            r#"firehose_blocks_total{phase="hot",step="STEP_NEW"} 2"#,
+            r#"firehose_blocks_total{phase="hot",step="STEP_UNDO"} 1"#,
+            r#"firehose_height{source="archive"} 5"#,
+            r#"firehose_height{source="rpc"} 8"#,
+            r#"firehose_height{source="head"} 9"#,
+            "firehose_reorgs_total 1",
+            "firehose_active_streams 0",
+        ];
+        for line in expected {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} is missing in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_rate_is_capped() {
+        let archive = Arc::new(MockDataSource::new(59));
+        let firehose = Firehose::new(archive, None).with_rate_limit(Some(20));
+
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 59,
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let mut stream = Box::pin(firehose.blocks(request, false).await.unwrap());
+        let mut sent = 0;
+        while let Some(result) = stream.next().await {
+            result.unwrap();
+            sent += 1;
+            // a second worth of blocks right away, then 20 blocks per second
+            let elapsed = started.elapsed().as_secs_f64();
+            assert!(
+                f64::from(sent) <= 20.0 + 20.0 * elapsed + 1.0,
+                "block {} was sent after {}s",
+                sent,
+                elapsed
+            );
+        }
+        assert_eq!(sent, 60);
+        assert!(started.elapsed() >= Duration::from_secs(2));
+
+        let text = firehose.metrics().encode().unwrap();
+        assert!(text
+            .lines()
+            .any(|l| l == "firehose_throttled_blocks_total 40"));
+    }
+
+    fn call_filter_transform() -> prost_types::Any {
+        let calls = CombinedFilter {
+            log_filters: vec![],
+            call_filters: vec![CallToFilter {
+                addresses: vec![vec![0x35; 20]],
+                signatures: vec![],
+            }],
+            send_all_block_headers: false,
+        };
+        prost_types::Any {
+            type_url: "type.googleapis.com/sf.ethereum.transform.v1.CombinedFilter".to_string(),
+            value: calls.encode_to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_conversion_preserves_order() {
+        let archive = Arc::new(MockDataSource::new(199));
+        let firehose = Firehose::new(archive, None).with_conversion_parallelism(8);
+
+        let request = Request {
+            start_block_num: 0,
+            transforms: vec![call_filter_transform()],
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+
+        assert_eq!(responses.len(), 200);
+        for (number, response) in (0..).zip(responses) {
+            let block = pbcodec::Block::decode(&response.block.unwrap().value[..]).unwrap();
+            assert_eq!(block.number, number);
+            assert_eq!(block.transaction_traces.len(), 1);
+            let cursor = Cursor::try_from(&response.cursor).unwrap();
+            assert_eq!(cursor.block.height, number);
+            assert_eq!(cursor.block.hash, hash(number));
+        }
+    }
+
+    // cargo test conversion_speedup -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn conversion_speedup() {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        for parallelism in [1, cpus] {
+            let archive = Arc::new(MockDataSource::new(9_999));
+            let firehose = Firehose::new(archive, None).with_conversion_parallelism(parallelism);
+            let request = Request {
+                start_block_num: 0,
+                transforms: vec![call_filter_transform()],
+                ..Default::default()
+            };
+
+            let started = std::time::Instant::now();
+            let stream = firehose.blocks(request, false).await.unwrap();
+            let count = stream.map(|result| result.unwrap()).count().await;
+            assert_eq!(count, 10_000);
+            println!("parallelism {}: {:?}", parallelism, started.elapsed());
+        }
+    }
+
+    // cargo test concurrent_identical_streams -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn concurrent_identical_streams() {
+        let archive = Arc::new(MockDataSource::new(999));
+        let firehose = Arc::new(Firehose::new(archive, None));
+
+        let started = std::time::Instant::now();
+        let streams: Vec<_> = (0..50)
+            .map(|_| {
+                let firehose = firehose.clone();
+                tokio::spawn(async move {
+                    let request = Request {
+                        start_block_num: 0,
+                        transforms: vec![call_filter_transform()],
+                        ..Default::default()
+                    };
+                    let stream = firehose.blocks(request, false).await.unwrap();
+                    stream
+                        .map(|result| result.unwrap().block.unwrap().value)
+                        .collect::<Vec<_>>()
+                        .await
+                })
+            })
+            .collect();
+        let results = futures_util::future::try_join_all(streams).await.unwrap();
+        println!("50 streams of 1000 blocks: {:?}", started.elapsed());
+
+        assert!(results.iter().all(|blocks| blocks == &results[0]));
+    }
+
+    #[tokio::test]
+    async fn resume_from_cursor() {
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8));
+        let firehose = Firehose::new(archive, Some(rpc.clone()));
+
+        let request = Request {
+            start_block_num: 0,
+            ..Default::default()
+        };
+        let stream = firehose.blocks(request, false).await.unwrap();
+        let responses: Vec<Response> = stream.map(|result| result.unwrap()).collect().await;
+        let cursor = responses.last().unwrap().cursor.clone();
+        assert_eq!(Cursor::try_from(&cursor).unwrap().block.height, 8);
+
+        rpc.set_hot(vec![HotUpdate {
+            blocks: vec![block(9, hash(9), hash(8))],
+            base_head: HashAndHeight {
+                hash: hash(8),
+                height: 8,
+            },
+            finalized_head: HashAndHeight {
+                hash: hash(8),
+                height: 8,
+            },
+        }]);
+
+        // the cursor takes precedence over start_block_num
+        let request = Request {
+            start_block_num: 0,
+            cursor,
+            ..Default::default()
+        };
+        let responses = collect(&firehose, request).await;
+
+        assert_eq!(responses, vec![(ForkStep::StepNew, 9)]);
+    }
+
+    #[tokio::test]
+    async fn start_beyond_chain_head() {
+        let archive = Arc::new(MockDataSource::new(5));
+        let rpc = Arc::new(MockDataSource::new(8));
+        let firehose = Firehose::new(archive, Some(rpc.clone()));
+
+        let request = Request {
+            start_block_num: 1000,
+            ..Default::default()
+        };
+        let mut stream = Box::pin(firehose.blocks(request, false).await.unwrap());
+        let err = stream.next().await.unwrap().unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "start block №1000 is beyond the chain head, finalized height is 8"
+        );
+        assert_eq!(rpc.hot_calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn start_below_first_block() {
+        let archive = Arc::new(MockDataSource::new(1_000_010).with_first_block(1_000_000));
+        let firehose = Firehose::new(archive, None);
+
+        let request = Request {
+            start_block_num: 0,
+            ..Default::default()
+        };
+        let err = firehose.blocks(request, false).await.err().unwrap();
+        assert_eq!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument(
+                "start block №0 is below the first available block №1000000".to_string()
+            )
+        );
+
+        // relative starts are clamped to the first block instead
+        let request = Request {
+            start_block_num: -100,
+            stop_block_num: 1_000_002,
+            ..Default::default()
+        };
+        let responses = collect(&firehose, request).await;
+        let expected: Vec<_> = (1_000_000..=1_000_002)
+            .map(|number| (ForkStep::StepNew, number))
+            .collect();
+        assert_eq!(responses, expected);
+    }
+
+    async fn fetch_error(firehose: &Firehose, reference: Option<Reference>) -> FetchError {
+        let request = SingleBlockRequest {
+            reference,
+            ..Default::default()
+        };
+        let err = firehose.block(request).await.unwrap_err();
+        err.downcast::<FetchError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn block_range_is_capped() {
+        let archive = Arc::new(MockDataSource::new(100));
+        let firehose = Firehose::new(archive, None).with_max_block_range(Some(50));
+        let request = |start_block_num: i64, stop_block_num: u64| Request {
+            start_block_num,
+            stop_block_num,
+            ..Default::default()
+        };
+
+        let err = firehose.blocks(request(0, 50), false).await.err().unwrap();
+        assert_eq!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument(
+                "51 blocks are requested from block №0 to block №50, at most 50 are served per stream"
+                    .to_string()
+            )
+        );
+        assert_eq!(collect(&firehose, request(1, 50)).await.len(), 50);
+
+        // unbounded streams are capped by their finalized range
+        let err = firehose.blocks(request(0, 0), false).await.err().unwrap();
+        assert!(matches!(
+            err.downcast::<FetchError>().unwrap(),
+            FetchError::InvalidArgument(_)
+        ));
+        assert!(firehose.blocks(request(51, 0), false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn strict_gas_validation() {
+        let transaction = |index: u32, cumulative_gas_used: &str| Transaction {
+            transaction_index: index,
+            cumulative_gas_used: cumulative_gas_used.to_string(),
+            ..eip155_transaction()
+        };
+        let mut consistent = block(4, hash(4), hash(3));
+        consistent.header.gas_limit = "0x1c9c380".to_string();
+        consistent.header.gas_used = "0xa410".to_string();
+        consistent.transactions = vec![transaction(0, "0x5208"), transaction(1, "0xa410")];
+        // the second transaction is missing from the gas used of the header
+        let mut inconsistent = consistent.clone();
+        inconsistent.header.number = 5;
+        inconsistent.header.hash = hash(5);
+        inconsistent.header.parent_hash = hash(4);
+        inconsistent.header.gas_used = "0x5208".to_string();
+
+        let archive = Arc::new(
+            MockDataSource::new(9)
+                .with_block(consistent)
+                .with_block(inconsistent),
+        );
+        let firehose = |strict: bool| {
+            Firehose::new(archive.clone(), None)
+                .with_retries(0, Duration::from_millis(1))
+                .with_strict_validation(strict)
+        };
+        let fetch = |firehose: Firehose, num: u64| async move {
+            let request = SingleBlockRequest {
+                reference: Some(Reference::BlockNumber(single_block_request::BlockNumber {
+                    num,
+                })),
+                ..Default::default()
+            };
+            firehose.block(request).await
+        };
+
+        assert!(fetch(firehose(true), 4).await.is_ok());
+        let err = fetch(firehose(true), 5).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(FetchError::DataLoss(message)) if message == "transaction 1 of block №5 has a cumulative gas used of 42000 over the 21000 of the block"
+        ));
+        assert!(fetch(firehose(false), 5).await.is_ok());
+
+        let request = Request {
+            start_block_num: 3,
+            stop_block_num: 6,
+            ..Default::default()
+        };
+        let stream = firehose(true).blocks(request.clone(), false).await.unwrap();
+        let results: Vec<_> = stream.collect().await;
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(|result| result.is_ok()));
+        assert!(results[2].is_err());
+
+        let stream = firehose(false).blocks(request, false).await.unwrap();
+        assert!(stream.all(|result| async move { result.is_ok() }).await);
+    }
+
+    #[tokio::test]
+    async fn single_block_errors() {
+        let firehose = Firehose::new(Arc::new(MockDataSource::new(5)), None);
+
+        assert_eq!(
+            fetch_error(&firehose, None).await,
+            FetchError::InvalidArgument("block reference is required".to_string())
+        );
+
+        let cursor = Reference::Cursor(single_block_request::Cursor {
+            cursor: "garbage".to_string(),
+        });
+        assert_eq!(
+            fetch_error(&firehose, Some(cursor)).await,
+            FetchError::InvalidArgument("invalid cursor: garbage".to_string())
+        );
+
+        let number = Reference::BlockNumber(single_block_request::BlockNumber { num: 10 });
+        assert_eq!(
+            fetch_error(&firehose, Some(number)).await,
+            FetchError::NotFound("block №10".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn single_block_hash_mismatch() {
+        let firehose = Firehose::new(Arc::new(MockDataSource::new(5)), None);
+
+        let reference = Reference::BlockHashAndNumber(single_block_request::BlockHashAndNumber {
+            num: 3,
+            hash: fork_hash(3),
+        });
+        assert_eq!(
+            fetch_error(&firehose, Some(reference)).await,
+            FetchError::NotFound(format!("block {} at height 3", fork_hash(3)))
+        );
+
+        let request = SingleBlockRequest {
+            reference: Some(Reference::BlockHashAndNumber(
+                single_block_request::BlockHashAndNumber {
+                    num: 3,
+                    hash: hash(3),
+                },
+            )),
+            ..Default::default()
+        };
+        let response = firehose.block(request).await.unwrap();
+        let block = pbcodec::Block::decode(&response.block.unwrap().value[..]).unwrap();
+        assert_eq!(block.number, 3);
+    }
+
+    #[tokio::test]
+    async fn stale_single_block_cursor() {
+        let firehose = Firehose::new(Arc::new(MockDataSource::new(5)), None);
+        let cursor = |hash: String| {
+            let head = HashAndHeight { hash, height: 3 };
+            Reference::Cursor(single_block_request::Cursor {
+                cursor: Cursor::new(head.clone(), head).to_string(),
+            })
+        };
+
+        assert_eq!(
+            fetch_error(&firehose, Some(cursor(fork_hash(3)))).await,
+            FetchError::NotFound(format!(
+                "block {} at height 3, the cursor points to a block that was reorged away",
+                fork_hash(3)
+            ))
+        );
+
+        let request = SingleBlockRequest {
+            reference: Some(cursor(hash(3))),
+            ..Default::default()
+        };
+        let response = firehose.block(request).await.unwrap();
+        let block = pbcodec::Block::decode(&response.block.unwrap().value[..]).unwrap();
+        assert_eq!(block.number, 3);
+    }
+
+    #[tokio::test]
+    async fn multi_block() {
+        let firehose = Firehose::new(Arc::new(MockDataSource::new(10)), None);
+
+        let cursor = Cursor::new(
+            HashAndHeight {
+                hash: hash(2),
+                height: 2,
+            },
+            HashAndHeight {
+                hash: hash(2),
+                height: 2,
+            },
+        );
+        let references = vec![
+            Reference::BlockNumber(single_block_request::BlockNumber { num: 7 }),
+            Reference::BlockHashAndNumber(single_block_request::BlockHashAndNumber {
+                num: 4,
+                hash: hash(4),
+            }),
+            Reference::Cursor(single_block_request::Cursor {
+                cursor: cursor.to_string(),
+            }),
+            Reference::BlockNumber(single_block_request::BlockNumber { num: 10 }),
+            Reference::BlockNumber(single_block_request::BlockNumber { num: 0 }),
+        ];
+        let request = MultiBlockRequest {
+            requests: references
+                .into_iter()
+                .map(|reference| SingleBlockRequest {
+                    reference: Some(reference),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let response = firehose.multi_block(request).await.unwrap();
+
+        let numbers: Vec<_> = response
+            .blocks
+            .iter()
+            .map(|any| pbcodec::Block::decode(&any.value[..]).unwrap().number)
+            .collect();
+        assert_eq!(numbers, vec![7, 4, 2, 10, 0]);
+
+        let request = MultiBlockRequest {
+            requests: vec![SingleBlockRequest::default(); 101],
+        };
+        let err = firehose.multi_block(request).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FetchError>(),
+            Some(FetchError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn header_only_single_block() {
+        let firehose = Firehose::new(Arc::new(MockDataSource::new(10)), None);
+        let request = |headers_only: bool| SingleBlockRequest {
+            reference: Some(Reference::BlockNumber(single_block_request::BlockNumber {
+                num: 7,
+            })),
+            transforms: if headers_only {
+                vec![prost_types::Any {
+                    type_url: "type.googleapis.com/sf.ethereum.transform.v1.HeaderOnly".to_string(),
+                    value: vec![],
+                }]
+            } else {
+                vec![]
+            },
+        };
+        let decode = |any: &prost_types::Any| pbcodec::Block::decode(&any.value[..]).unwrap();
+
+        let full = firehose.block(request(false)).await.unwrap();
+        let full = decode(&full.block.unwrap());
+        assert_eq!(full.transaction_traces.len(), 1);
+
+        let header = firehose.block(request(true)).await.unwrap();
+        let header = decode(&header.block.unwrap());
+        assert_eq!(header.number, 7);
+        assert!(header.header.is_some());
+        assert!(header.transaction_traces.is_empty());
+
+        // header-only blocks are stripped when fetched along with full ones
+        let request = MultiBlockRequest {
+            requests: vec![request(true), request(false)],
+        };
+        let blocks = firehose.multi_block(request).await.unwrap().blocks;
+        let traces: Vec<_> = blocks
+            .iter()
+            .map(|any| decode(any).transaction_traces.len())
+            .collect();
+        assert_eq!(traces, vec![0, 1]);
+    }
+
+    #[test]
+    fn l1_and_l2_header_conversion() {
+        let header = block(1, hash(1), hash(0)).header;
+        let l1 = pbcodec::BlockHeader::try_from(header.clone()).unwrap();
+        assert_eq!(l1.l2_extra, None);
+
+        let l2_header = BlockHeader {
+            l2_extra: Some(L2Extra {
+                l1_block_number: 18500000,
+                send_root: "0xabcd".to_string(),
+            }),
+            ..header
+        };
+        let l2 = pbcodec::BlockHeader::try_from(l2_header).unwrap();
+        assert_eq!(
+            l2.l2_extra,
+            Some(pbcodec::L2Extra {
+                l1_block_number: 18500000,
+                send_root: vec![0xab, 0xcd],
+            })
+        );
+        assert_eq!(l2.number, l1.number);
+    }
+
+    #[test]
+    fn blob_fields_conversion() {
+        let header = BlockHeader {
+            blob_gas_used: Some("0xc0000".to_string()),
+            excess_blob_gas: Some("0x4b20000".to_string()),
+            ..block(1, hash(1), hash(0)).header
+        };
+        let header = pbcodec::BlockHeader::try_from(header).unwrap();
+        assert_eq!(header.blob_gas_used, Some(0xc0000));
+        assert_eq!(header.excess_blob_gas, Some(0x4b20000));
+
+        let legacy = pbcodec::TransactionTrace::try_from(eip155_transaction()).unwrap();
+        assert_eq!(legacy.blob_gas_fee_cap, None);
+        assert!(legacy.blob_hashes.is_empty());
+
+        let blob_hash = format!("0x01{:062x}", 0xb10b);
+        let tx = Transaction {
+            r#type: 3,
+            max_fee_per_gas: Some("0x2540be400".to_string()),
+            max_priority_fee_per_gas: Some("0x3b9aca00".to_string()),
+            max_fee_per_blob_gas: Some("0x3b9aca00".to_string()),
+            blob_versioned_hashes: vec![blob_hash.clone()],
+            chain_id: Some(1),
+            ..eip155_transaction()
+        };
+        let trace = pbcodec::TransactionTrace::try_from(tx).unwrap();
+        assert_eq!(
+            trace.r#type,
+            pbcodec::transaction_trace::Type::TrxTypeBlob as i32
+        );
+        assert_eq!(
+            trace.blob_gas_fee_cap,
+            Some(pbcodec::BigInt {
+                bytes: vec![0x3b, 0x9a, 0xca, 0x00]
+            })
+        );
+        assert_eq!(
+            trace.blob_hashes,
+            vec![prefix_hex::decode::<Vec<u8>>(&blob_hash).unwrap()]
+        );
+        // the signing hash of blob transactions isn't supported
+        assert!(trace.public_key.is_empty());
+    }
+
+    #[test]
+    fn raw_receipt_conversion() {
+        let raw = r#"{"status":"0x1","type":"0x0","effectiveGasPrice":"0x4a817c800"}"#;
+        let mut block = block(1, hash(1), hash(0));
+        block.transactions = vec![
+            Transaction {
+                raw_receipt: Some(raw.to_string()),
+                ..eip155_transaction()
+            },
+            Transaction {
+                transaction_index: 1,
+                hash: hash(1),
+                ..eip155_transaction()
+            },
+        ];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+        let receipts: Vec<_> = block
+            .transaction_traces
+            .into_iter()
+            .map(|trace| trace.receipt.unwrap().raw)
+            .collect();
+        assert_eq!(receipts, vec![raw.as_bytes().to_vec(), vec![]]);
+    }
+
+    #[test]
+    fn cumulative_gas_used() {
+        let transaction = |index: u32, gas_used: &str, cumulative_gas_used: &str| Transaction {
+            transaction_index: index,
+            hash: hash(index.into()),
+            gas_used: gas_used.to_string(),
+            cumulative_gas_used: cumulative_gas_used.to_string(),
+            ..eip155_transaction()
+        };
+        let mut block = block(1, hash(1), hash(0));
+        block.header.gas_limit = "0x1c9c380".to_string();
+        block.header.gas_used = "0x16378".to_string();
+        block.transactions = vec![
+            transaction(0, "0x5208", "0x5208"),
+            // not reported by the source
+            transaction(1, "0x7530", ""),
+            transaction(2, "0x9c40", "0x16378"),
+        ];
+        // the call doesn't pay for the intrinsic gas of its transaction
+        let mut call = call_trace(vec![], "0x3535353535353535353535353535353535353535");
+        call.transaction_index = 2;
+        call.result.as_mut().unwrap().gas_used = Some("0x4e20".to_string());
+        block.traces = vec![call];
+        check_gas_used(&block).unwrap();
+
+        let converted = pbcodec::Block::try_from(block.clone()).unwrap();
+        let gas: Vec<_> = converted
+            .transaction_traces
+            .iter()
+            .map(|trace| {
+                (
+                    trace.gas_used,
+                    trace.receipt.as_ref().unwrap().cumulative_gas_used,
+                )
+            })
+            .collect();
+        assert_eq!(gas, vec![(21000, 21000), (30000, 51000), (40000, 91000)]);
+        assert_eq!(converted.transaction_traces[2].calls[0].gas_consumed, 20000);
+
+        block.transactions[1].cumulative_gas_used = "0xc738".to_string();
+        check_gas_used(&block).unwrap();
+        // a cumulative gas used that doesn't follow the gas used of the transaction
+        block.transactions[2].cumulative_gas_used = "0x15f90".to_string();
+        assert_eq!(
+            check_gas_used(&block).unwrap_err().to_string(),
+            "transaction 2 of block №1 uses 40000 gas, its cumulative gas used goes from 51000 to 90000"
+        );
+        // with a transaction left out in between it can only grow past the previous one
+        block.transactions.remove(1);
+        check_gas_used(&block).unwrap();
+        block.transactions[1].cumulative_gas_used = "0xc738".to_string();
+        assert!(check_gas_used(&block).is_err());
+    }
+
+    #[test]
+    fn dynamic_fee_gas_price() {
+        let wei = |n: u64| pbcodec::BigInt {
+            bytes: n
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|b| *b == 0)
+                .collect(),
+        };
+        let dynamic_fee = |index: u32, max_fee: &str, effective_gas_price: &str| Transaction {
+            transaction_index: index,
+            hash: hash(index.into()),
+            r#type: 2,
+            max_fee_per_gas: Some(max_fee.to_string()),
+            max_priority_fee_per_gas: Some("0x3b9aca00".to_string()),
+            effective_gas_price: effective_gas_price.to_string(),
+            ..eip155_transaction()
+        };
+        let mut block = block(1, hash(1), hash(0));
+        block.header.base_fee_per_gas = Some("0x12a05f200".to_string());
+        block.transactions = vec![
+            // reported by the receipt
+            dynamic_fee(0, "0x2540be400", "0x165a0bc00"),
+            // derived from the base fee
+            dynamic_fee(1, "0x2540be400", ""),
+            // capped by the max fee
+            dynamic_fee(2, "0x14dc93800", ""),
+            Transaction {
+                transaction_index: 3,
+                hash: hash(3),
+                ..eip155_transaction()
+            },
+        ];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+        let prices: Vec<_> = block
+            .transaction_traces
+            .iter()
+            .map(|trace| {
+                (
+                    trace.gas_price.clone().unwrap(),
+                    trace.max_fee_per_gas.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            prices,
+            vec![
+                (wei(6_000_000_000), Some(wei(10_000_000_000))),
+                (wei(6_000_000_000), Some(wei(10_000_000_000))),
+                (wei(5_600_000_000), Some(wei(5_600_000_000))),
+                (wei(20_000_000_000), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn withdrawals_conversion() {
+        let mut block = block(1, hash(1), hash(0));
+        block.header.withdrawals_root = Some(hash(0x4895));
+        block.transactions = vec![eip155_transaction()];
+        block.balance_changes = vec![BalanceChange {
+            address: "0xb9d7934878b5fb9610b3fe8a5e441e8fad7e293f".to_string(),
+            old_value: "0x1".to_string(),
+            new_value: "0x2a9147f5b94e01".to_string(),
+            reason: BalanceChangeReason::Withdrawal,
+        }];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+        assert_eq!(
+            block.header.unwrap().withdrawals_root,
+            prefix_hex::decode::<Vec<u8>>(hash(0x4895)).unwrap()
+        );
+        // withdrawals are processed after the transactions of the block
+        let tx = &block.transaction_traces[0];
+        let change = &block.balance_changes[0];
+        assert!(change.ordinal > tx.end_ordinal);
+        assert_eq!(
+            change.reason,
+            pbcodec::balance_change::Reason::Withdrawal as i32
+        );
+        assert_eq!(
+            change.address,
+            prefix_hex::decode::<Vec<u8>>("0xb9d7934878b5fb9610b3fe8a5e441e8fad7e293f").unwrap()
+        );
+        assert_eq!(change.old_value.as_ref().unwrap().bytes, vec![1]);
+    }
+
+    #[test]
+    fn decode_hex() {
+        assert_eq!(try_decode_hex("value", "0x").unwrap(), Vec::<u8>::new());
+        assert_eq!(try_decode_hex("value", "").unwrap(), Vec::<u8>::new());
+        assert_eq!(try_decode_hex("value", "0x0").unwrap(), vec![0x00]);
+        assert_eq!(try_decode_hex("value", "0x00").unwrap(), vec![0x00]);
+        assert_eq!(try_decode_hex("value", "0x1").unwrap(), vec![0x01]);
+        assert_eq!(try_decode_hex("value", "0xabc").unwrap(), vec![0x0a, 0xbc]);
+        assert_eq!(try_decode_hex("value", "abc").unwrap(), vec![0x0a, 0xbc]);
+        assert_eq!(try_decode_hex("value", "dead").unwrap(), vec![0xde, 0xad]);
+        assert_eq!(
+            try_decode_hex("value", "0xzz").unwrap_err().to_string(),
+            "invalid value: 0xzz"
+        );
+    }
+
+    #[test]
+    fn overlong_quantities_are_rejected() {
+        let max = format!("0x{}", "ff".repeat(32));
+        assert_eq!(
+            big_int("value", &max, BIG_INT_MAX_BYTES).unwrap().bytes,
+            vec![0xff; 32]
+        );
+        assert_eq!(
+            big_int("value", &format!("{}f", max), BIG_INT_MAX_BYTES)
+                .unwrap_err()
+                .to_string(),
+            "value is longer than 32 bytes: 65 hex digits"
+        );
+
+        let mut header = block(1, hash(1), hash(0)).header;
+        header.difficulty = format!("0x{}", "1".repeat(1 << 20));
+        assert_eq!(
+            pbcodec::BlockHeader::try_from(header.clone())
+                .unwrap_err()
+                .to_string(),
+            "difficulty is longer than 32 bytes: 1048576 hex digits"
+        );
+        // the total difficulty may outgrow 32 bytes
+        header.difficulty = "0x1".to_string();
+        header.total_difficulty = format!("0x1{}", "0".repeat(64));
+        assert!(pbcodec::BlockHeader::try_from(header).is_ok());
+    }
+
+    #[test]
+    fn decode_quantity() {
+        assert_eq!(qty2int("gas", "0x5208").unwrap(), 21000);
+        assert_eq!(qty2int("gas", "5208").unwrap(), 21000);
+        assert_eq!(qty2int("gas", "0xffffffffffffffff").unwrap(), u64::MAX);
+        assert_eq!(
+            qty2int("gas", "0x10000000000000000")
+                .unwrap_err()
+                .to_string(),
+            "invalid gas: 0x10000000000000000"
+        );
+        assert_eq!(
+            qty2int("nonce", "0xnope").unwrap_err().to_string(),
+            "invalid nonce: 0xnope"
+        );
+        assert!(qty2int("nonce", "0x").is_err());
+    }
+
+    fn signer(public_key: &[u8]) -> String {
+        prefix_hex::encode(&ethers_core::utils::keccak256(public_key)[12..])
+    }
+
+    #[test]
+    fn recover_legacy_public_key() {
+        let tx = eip155_transaction();
+
+        let public_key = recover_public_key(&tx).unwrap();
+
+        assert_eq!(public_key.len(), 64);
+        assert_eq!(signer(&public_key), tx.from);
+    }
+
+    #[test]
+    fn recover_dynamic_fee_public_key() {
+        // goerli transaction 0x5e2fc091e15119c97722e9b63d5d32b043d077d834f377b91f80d32872c78109
+        let mut tx = signed_transaction(
+            65,
+            "0xe66b278fa9fbb181522f6916ec2f6d66ab846e04",
+            "0x11d7c2ab0d4aa26b7d8502f6a7ef6844908495c2",
+            "0x0",
+            "0xe5225381",
+            (
+                "0x1",
+                "0x1a8d7bef47f6155cbdf13d57107fc577fd52880fa2862b1a50d47641f8839419",
+                "0x3279bbf73fde76de83440d04b9d97f3809fec8617d3557ee40ac3e0edc391514",
+            ),
+        );
+        tx.r#type = 2;
+        tx.chain_id = Some(5);
+        tx.gas = "0x1a0cf".to_string();
+        tx.gas_price = "0x59682f07".to_string();
+        tx.max_fee_per_gas = Some("0x59682f09".to_string());
+        tx.max_priority_fee_per_gas = Some("0x59682f00".to_string());
+
+        let public_key = recover_public_key(&tx).unwrap();
+        assert_eq!(signer(&public_key), tx.from);
+
+        // the parity in the legacy form, or only as y parity
+        tx.v = "0x1c".to_string();
+        assert_eq!(signer(&recover_public_key(&tx).unwrap()), tx.from);
+        tx.v = String::new();
+        tx.y_parity = Some(1);
+        assert_eq!(signer(&recover_public_key(&tx).unwrap()), tx.from);
+
+        // without the chain id the signing hash is different
+        tx.chain_id = None;
+        assert!(recover_public_key(&tx).is_err());
+    }
+
+    #[test]
+    fn malformed_signature_leaves_public_key_empty() {
+        let tx = signed_transaction(
+            9,
+            "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f",
+            "0x3535353535353535353535353535353535353535",
+            "0xde0b6b3a7640000",
+            "0x",
+            ("0x25", "0x0", "0x0"),
+        );
+
+        let trace = pbcodec::TransactionTrace::try_from(tx).unwrap();
+
+        assert!(trace.public_key.is_empty());
+    }
+
+    #[test]
+    fn call_keccak_preimages() {
+        let mut trace = call_trace(vec![], "0x0000000000000000000000000000000000000001");
+        trace.keccak_preimages.insert(
+            format!("0x{:064x}", 0xbb),
+            format!("0x{:064x}{:064x}", 0xaa, 0),
+        );
+
+        let call = pbcodec::Call::try_from(trace).unwrap();
+
+        assert_eq!(
+            call.keccak_preimages,
+            HashMap::from([(format!("{:064x}", 0xbb), format!("{:064x}{:064x}", 0xaa, 0))])
+        );
+
+        let trace = call_trace(vec![], "0x0000000000000000000000000000000000000001");
+        let call = pbcodec::Call::try_from(trace).unwrap();
+        assert!(call.keccak_preimages.is_empty());
+    }
+
+    #[test]
+    fn block_reward_balance_change() {
+        // block 1 of mainnet paid the 5 ether block reward to its miner
+        let miner = "0x05a56e2d52c817161883f50c441c3228cfe54d9f";
+        let mut block = block(1, hash(1), hash(0));
+        block.traces = vec![Trace {
+            transaction_index: 0,
+            trace_address: vec![],
+            r#type: TraceType::Reward,
+            error: None,
+            revert_reason: None,
+            action: Some(TraceAction {
+                from: None,
+                to: Some(miner.to_string()),
+                value: Some("0x4563918244f40000".to_string()),
+                gas: None,
+                input: None,
+                r#type: None,
+            }),
+            result: None,
+            balance_changes: vec![],
+            storage_changes: vec![],
+            gas_changes: vec![],
+            keccak_preimages: HashMap::new(),
+        }];
+        // the trace lacks the balances of the miner, the balance change has them
+        block.balance_changes = vec![BalanceChange {
+            address: miner.to_string(),
+            old_value: "0x0".to_string(),
+            new_value: "0x4563918244f40000".to_string(),
+            reason: BalanceChangeReason::RewardMineBlock,
+        }];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+
+        assert_eq!(
+            block.balance_changes,
+            vec![pbcodec::BalanceChange {
+                address: prefix_hex::decode(miner).unwrap(),
+                old_value: Some(pbcodec::BigInt { bytes: vec![0] }),
+                new_value: Some(pbcodec::BigInt {
+                    bytes: prefix_hex::decode("0x4563918244f40000").unwrap(),
+                }),
+                reason: pbcodec::balance_change::Reason::RewardMineBlock.into(),
+                ordinal: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn transaction_return_data() {
+        let token = "0xdac17f958d2ee523a2206206994597c13d831ec7";
+        let balance = format!("0x{:064x}", 1_000_000);
+        let mut block = block(1, hash(1), hash(0));
+        block.transactions = vec![signed_transaction(
+            0,
+            "0x0000000000000000000000000000000000000001",
+            token,
+            "0x0",
+            "0x70a08231",
+            ("0x1b", "0x1", "0x1"),
+        )];
+        let mut balance_of = call_trace(vec![], token);
+        balance_of.result.as_mut().unwrap().output = Some(balance.clone());
+        block.traces = vec![balance_of];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+
+        let tx = &block.transaction_traces[0];
+        assert_eq!(tx.return_data, try_decode_hex("output", &balance).unwrap());
+        assert_eq!(tx.return_data, tx.calls[0].return_data);
+    }
+
+    #[test]
+    fn create_transaction_return_data() {
+        let mut block = block(1, hash(1), hash(0));
+        let mut tx = signed_transaction(
+            0,
+            "0x0000000000000000000000000000000000000001",
+            "0x0000000000000000000000000000000000000000",
+            "0x0",
+            "0x600260005260206000f3",
+            ("0x1b", "0x1", "0x1"),
+        );
+        tx.to = None;
+        block.transactions = vec![tx];
+        let mut create = create_trace(vec![], "0x0000000000000000000000000000000000000002");
+        create.result.as_mut().unwrap().code = Some("0x6000".to_string());
+        block.traces = vec![create];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+
+        assert_eq!(block.transaction_traces[0].return_data, vec![0x60, 0x00]);
+    }
+
+    #[test]
+    fn block_size_quantity() {
+        let size = |size: Option<serde_json::Value>| {
+            let zero = format!("0x{:064x}", 0);
+            let mut header = serde_json::json!({
+                "number": 1,
+                "hash": hash(1),
+                "parentHash": hash(0),
+                "sha3Uncles": zero,
+                "miner": format!("0x{:040x}", 0),
+                "stateRoot": zero,
+                "transactionsRoot": zero,
+                "receiptsRoot": zero,
+                "logsBloom": format!("0x{}", "00".repeat(256)),
+                "difficulty": "0x0",
+                "totalDifficulty": "0x0",
+                "gasLimit": "0x0",
+                "gasUsed": "0x0",
+                "timestamp": 0,
+                "extraData": "0x",
+                "mixHash": zero,
+                "nonce": "0x0",
+            });
+            if let Some(size) = size {
+                header["size"] = size;
+            }
+            let header: archive::BlockHeader = serde_json::from_value(header).unwrap();
+            let mut block = block(1, hash(1), hash(0));
+            block.header = BlockHeader::from(header);
+            pbcodec::Block::try_from(block).unwrap()
+        };
+
+        assert_eq!(size(Some("0x21c".into())).size, 540);
+        assert_eq!(size(Some(540.into())).size, 540);
+        let block = size(None);
+        assert_eq!(block.size, {
+            let mut unsized_block = block.clone();
+            unsized_block.size = 0;
+            unsized_block.encoded_len() as u64
+        });
+    }
+
+    #[test]
+    fn contract_creation_has_empty_to() {
+        let mut block = block(1, hash(1), hash(0));
+        let mut deployment = signed_transaction(
+            0,
+            "0x0000000000000000000000000000000000000001",
+            "0x0000000000000000000000000000000000000000",
+            "0x0",
+            "0x600260005260206000f3",
+            ("0x1b", "0x1", "0x1"),
+        );
+        deployment.to = None;
+        let mut transfer = deployment.clone();
+        transfer.transaction_index = 1;
+        transfer.to = Some("0x0000000000000000000000000000000000000000".to_string());
+        block.transactions = vec![deployment, transfer];
+        block.traces = vec![create_trace(
+            vec![],
+            "0x0000000000000000000000000000000000000002",
+        )];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+        let deployment = &block.transaction_traces[0];
+        assert!(deployment.to.is_empty());
+        assert_eq!(
+            deployment.calls[0].call_type,
+            i32::from(pbcodec::CallType::Create)
+        );
+        assert_eq!(
+            prefix_hex::encode(deployment.calls[0].address.clone()),
+            "0x0000000000000000000000000000000000000002"
+        );
+        // a transfer to the zero address isn't a creation
+        assert_eq!(block.transaction_traces[1].to, vec![0; 20]);
+
+        let mut tx = eip155_transaction();
+        tx.to = Some("0x0102".to_string());
+        assert!(pbcodec::TransactionTrace::try_from(tx).is_err());
+    }
+
+    #[test]
+    fn log_indexes() {
+        let mut block = block(1, hash(1), hash(0));
+        let tx = |transaction_index: u32| {
+            let mut tx = signed_transaction(
+                u64::from(transaction_index),
+                "0x0000000000000000000000000000000000000001",
+                "0x0000000000000000000000000000000000000002",
+                "0x0",
+                "0x",
+                ("0x1b", "0x1", "0x1"),
+            );
+            tx.transaction_index = transaction_index;
+            tx
+        };
+        let log = |transaction_index: u32, log_index: u32| Log {
+            address: "0x0000000000000000000000000000000000000002".to_string(),
+            data: "0x".to_string(),
+            topics: vec![],
+            log_index,
+            transaction_index,
+        };
+        block.transactions = vec![tx(0), tx(1)];
+        block.logs = vec![log(1, 3), log(0, 1), log(1, 2), log(0, 0)];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+
+        let indexes: Vec<_> = block
+            .transaction_traces
+            .iter()
+            .flat_map(|tx| tx.receipt.as_ref().unwrap().logs.iter())
+            .map(|log| (log.index, log.block_index))
+            .collect();
+        assert_eq!(indexes, vec![(0, 0), (1, 1), (0, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn delegatecall_logs() {
+        // proxy delegating to an implementation that emits a log in the proxy context
+        let proxy = "0x0000000000000000000000000000000000000002";
+        let implementation = "0x0000000000000000000000000000000000000003";
+        let mut block = block(1, hash(1), hash(0));
+        block.transactions = vec![signed_transaction(
+            0,
+            "0x0000000000000000000000000000000000000001",
+            proxy,
+            "0x0",
+            "0x",
+            ("0x1b", "0x1", "0x1"),
+        )];
+        let mut delegate = call_trace(vec![0], implementation);
+        let action = delegate.action.as_mut().unwrap();
+        action.from = Some(proxy.to_string());
+        action.r#type = Some(CallType::Delegatecall);
+        block.traces = vec![call_trace(vec![], proxy), delegate];
+        block.logs = vec![Log {
+            address: proxy.to_string(),
+            data: "0x".to_string(),
+            topics: vec![],
+            log_index: 0,
+            transaction_index: 0,
+        }];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+
+        let tx = &block.transaction_traces[0];
+        assert!(tx.calls[0].logs.is_empty());
+        assert_eq!(tx.calls[1].logs, tx.receipt.as_ref().unwrap().logs);
+        assert_eq!(tx.calls[1].logs.len(), 1);
+    }
+
+    /// Mainnet genesis, the headers of other eras are derived from it.
+    fn genesis() -> Block {
+        let mut genesis = block(
+            0,
+            "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3".to_string(),
+            format!("0x{:064x}", 0),
+        );
+        let header = &mut genesis.header;
+        header.sha3_uncles =
+            "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347".to_string();
+        header.state_root =
+            "0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544".to_string();
+        header.transactions_root =
+            "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421".to_string();
+        header.receipts_root = header.transactions_root.clone();
+        header.difficulty = "0x400000000".to_string();
+        header.gas_limit = "0x1388".to_string();
+        header.extra_data =
+            "0x11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa".to_string();
+        header.nonce = "0x42".to_string();
+        genesis
+    }
+
+    #[test]
+    fn header_hash_of_eras() {
+        let genesis = genesis();
+        check_header_hash(&genesis.header).unwrap();
+
+        // mainnet block 436, mined with uncles
+        let mut frontier = genesis.clone();
+        let header = &mut frontier.header;
+        header.number = 436;
+        header.hash =
+            "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae".to_string();
+        header.parent_hash =
+            "0xe99e022112df268087ea7eafaf4790497fd21dbeeb6bd7a1721df161a6657a54".to_string();
+        header.state_root =
+            "0xddc8b0234c2e0cad087c8b389aa7ef01f7d79b2570bccb77ce48648aa61c904d".to_string();
+        header.miner = "0xbb7b8287f3f0a933474a79eae42cbca977791171".to_string();
+        header.difficulty = "0x4ea3f27bc".to_string();
+        header.nonce = "0x689056015818adbe".to_string();
+        header.mix_hash =
+            "0x4fffe9ae21f1c9e15207b1f472d5bbdd68c9595d461666602f2be20daf5e7843".to_string();
+        header.extra_data =
+            "0x476574682f4c5649562f76312e302e302f6c696e75782f676f312e342e32".to_string();
+        header.timestamp = 0x55ba467c;
+        check_header_hash(&frontier.header).unwrap();
+
+        // sepolia genesis, london is active from it on
+        let mut london = genesis.clone();
+        let header = &mut london.header;
+        header.hash =
+            "0x25a5cc106eea7138acab33231d7160d69cb777ee0c2c553fcddf5138993e6dd9".to_string();
+        header.state_root =
+            "0x5eb6e371a698b8d68f665192350ffcecbbbf322916f4b51bd79bb6887da3f494".to_string();
+        header.difficulty = "0x20000".to_string();
+        header.gas_limit = "0x1c9c380".to_string();
+        header.timestamp = 0x6159af19;
+        header.extra_data =
+            "0x5365706f6c69612c20417468656e732c204174746963612c2047726565636521".to_string();
+        header.nonce = "0x0".to_string();
+        header.base_fee_per_gas = Some("0x3b9aca00".to_string());
+        check_header_hash(&london.header).unwrap();
+
+        // holesky genesis, london too
+        let mut holesky = london.clone();
+        let header = &mut holesky.header;
+        header.hash =
+            "0xb5f7f912443c940f21fd611f12828d75b534364ed9e95ca4e307729a4661bde4".to_string();
+        header.state_root =
+            "0x69d8c9d72f6fa4ad42d4702b433707212f90db395eb54dc20bc85de253788783".to_string();
+        header.difficulty = "0x1".to_string();
+        header.gas_limit = "0x17d7840".to_string();
+        header.timestamp = 0x65156994;
+        header.extra_data = "0x".to_string();
+        header.nonce = "0x1234".to_string();
+        check_header_hash(&holesky.header).unwrap();
+    }
+
+    #[test]
+    fn header_fields_of_later_forks() {
+        let items = |header: &BlockHeader| {
+            let header = pbcodec::BlockHeader::try_from(header.clone()).unwrap();
+            header_rlp(&header).map(|encoded| {
+                let list = rlp::Rlp::new(&encoded);
+                (0..list.item_count().unwrap())
+                    .map(|i| list.at(i).unwrap().data().unwrap().to_vec())
+                    .collect::<Vec<_>>()
+            })
+        };
+        let mut header = genesis().header;
+        assert_eq!(items(&header).unwrap().len(), 15);
+
+        // the withdrawals root can't follow a missing base fee
+        header.withdrawals_root = Some(hash(0x4895));
+        assert_eq!(items(&header), None);
+        header.base_fee_per_gas = Some("0x7".to_string());
+        let shanghai = items(&header).unwrap();
+        assert_eq!(shanghai.len(), 17);
+        assert_eq!(shanghai[15], vec![7]);
+        assert_eq!(prefix_hex::encode(&shanghai[16]), hash(0x4895));
+
+        // cancun fields come together, a source without the parent beacon root can't hash them
+        header.blob_gas_used = Some("0x20000".to_string());
+        header.excess_blob_gas = Some("0x0".to_string());
+        assert_eq!(items(&header), None);
+        assert!(check_header_hash(&header).is_ok());
+        header.parent_beacon_root = Some(hash(0x4788));
+        let cancun = items(&header).unwrap();
+        assert_eq!(cancun.len(), 20);
+        assert_eq!(cancun[17], vec![2, 0, 0]);
+        assert!(cancun[18].is_empty());
+        assert_eq!(prefix_hex::encode(&cancun[19]), hash(0x4788));
+
+        header.requests_hash = Some(hash(0x7685));
+        let prague = items(&header).unwrap();
+        assert_eq!(prague.len(), 21);
+        assert_eq!(prefix_hex::encode(&prague[20]), hash(0x7685));
+    }
+
+    #[tokio::test]
+    async fn hash_validation() {
+        let mut tampered = genesis();
+        tampered.header.gas_used = "0x1".to_string();
+        let firehose = |archive: MockDataSource| {
+            Firehose::new(Arc::new(archive), None)
+                .with_retries(0, Duration::from_millis(1))
+                .with_hash_validation(true)
+        };
+        let fetch = |firehose: Firehose| async move {
+            let request = SingleBlockRequest {
+                reference: Some(Reference::BlockNumber(single_block_request::BlockNumber {
+                    num: 0,
+                })),
+                ..Default::default()
+            };
+            firehose.block(request).await
+        };
+
+        assert!(
+            fetch(firehose(MockDataSource::new(5).with_block(genesis())))
+                .await
+                .is_ok()
+        );
+        let err = fetch(firehose(
+            MockDataSource::new(5).with_block(tampered.clone()),
+        ))
+        .await
+        .unwrap_err();
+        let Some(FetchError::DataLoss(message)) = err.downcast_ref() else {
+            panic!("unexpected error: {}", err);
+        };
+        assert!(message.starts_with("block №0 hashes to 0x"));
+        assert!(message.ends_with(
+            "not to its hash 0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+        ));
+
+        // streams end with the error rather than finishing early
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 5,
+            ..Default::default()
+        };
+        let stream = firehose(MockDataSource::new(5).with_block(tampered))
+            .blocks(request, false)
+            .await
+            .unwrap();
+        let results: Vec<_> = stream.collect().await;
+        assert_eq!(results.len(), 1);
+        let err = results[0].as_ref().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(FetchError::DataLoss(_))));
+    }
+
+    #[test]
+    fn transaction_ordinal_windows() {
+        let change = |reason: BalanceChangeReason| BalanceChange {
+            address: "0xbf4ed7b27f1d666546e30d74d50d173d20bca754".to_string(),
+            old_value: "0x0".to_string(),
+            new_value: "0x1".to_string(),
+            reason,
+        };
+        let log = |log_index: u32, transaction_index: u32| Log {
+            address: "0x3333333333333333333333333333333333333333".to_string(),
+            data: "0x".to_string(),
+            topics: vec![],
+            log_index,
+            transaction_index,
+        };
+        let mut block = block(1920000, hash(1920000), hash(1919999));
+        block.transactions = (0..2)
+            .map(|index| Transaction {
+                transaction_index: index,
+                ..eip155_transaction()
+            })
+            .collect();
+        block.traces = vec![
+            call_trace(vec![], "0x3333333333333333333333333333333333333333"),
+            call_trace(vec![0], "0x4444444444444444444444444444444444444444"),
+            Trace {
+                transaction_index: 1,
+                ..call_trace(vec![], "0x3333333333333333333333333333333333333333")
+            },
+        ];
+        block.logs = vec![log(0, 0), log(1, 1), log(2, 1)];
+        // a post-transaction change listed first still comes after the transactions
+        block.balance_changes = vec![
+            change(BalanceChangeReason::Transfer),
+            change(BalanceChangeReason::DaoAdjustBalance),
+        ];
+
+        let block = pbcodec::Block::try_from(block).unwrap();
+        let changes = &block.balance_changes;
+        assert_eq!(
+            changes.iter().map(|c| c.ordinal).collect::<Vec<_>>(),
+            vec![0, 14]
+        );
+        assert_eq!(
+            changes[0].reason,
+            pbcodec::balance_change::Reason::DaoAdjustBalance as i32
+        );
+
+        let windows: Vec<_> = block
+            .transaction_traces
+            .iter()
+            .map(|tx| (tx.begin_ordinal, tx.end_ordinal))
+            .collect();
+        assert_eq!(windows, vec![(1, 7), (8, 13)]);
+        for tx in &block.transaction_traces {
+            let window = tx.begin_ordinal..tx.end_ordinal;
+            for call in &tx.calls {
+                assert!(window.contains(&call.begin_ordinal) && window.contains(&call.end_ordinal));
+            }
+            for log in &tx.receipt.as_ref().unwrap().logs {
+                assert!(window.contains(&log.ordinal));
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_fees_of_miner() {
+        let gwei = |n: u64| n * 1_000_000_000;
+        let miner = "0x95222290dd7278aa3ddd389cc1e1d165cc4bafe5";
+        let balance = gwei(1_000_000);
+        let withdrawal = BalanceChange {
+            address: miner.to_string(),
+            old_value: format!("{:#x}", balance),
+            new_value: format!("{:#x}", balance + gwei(32)),
+            reason: BalanceChangeReason::Withdrawal,
+        };
+        let fees = |mut block: Block| {
+            credit_transaction_fees(&mut block).unwrap();
+            let block = pbcodec::Block::try_from(block).unwrap();
+            block
+                .balance_changes
+                .into_iter()
+                .filter(|change| {
+                    change.reason() == pbcodec::balance_change::Reason::RewardTransactionFee
+                })
+                .map(|change| {
+                    assert_eq!(prefix_hex::encode(change.address), miner);
+                    let value = |value: Option<pbcodec::BigInt>| {
+                        let bytes = value.unwrap().bytes;
+                        bytes.iter().fold(0, |n, byte| n << 8 | u64::from(*byte))
+                    };
+                    let (old_value, new_value) = (value(change.old_value), value(change.new_value));
+                    // the recipient ends up with the balance its withdrawal starts from
+                    assert_eq!(new_value, balance);
+                    new_value - old_value
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut block = block(1, hash(1), hash(0));
+        block.header.miner = miner.to_string();
+        block.header.gas_used = format!("{:#x}", 71_000);
+        block.transactions = vec![
+            eip155_transaction(),
+            Transaction {
+                transaction_index: 1,
+                r#type: 2,
+                max_fee_per_gas: Some(format!("{:#x}", gwei(3))),
+                max_priority_fee_per_gas: Some(format!("{:#x}", gwei(1))),
+                effective_gas_price: String::new(),
+                gas_used: format!("{:#x}", 50_000),
+                ..eip155_transaction()
+            },
+        ];
+        block.balance_changes = vec![withdrawal.clone()];
+
+        // the miner earns what's paid above the base fee, the base fee is burned
+        let mut london = block.clone();
+        london.header.base_fee_per_gas = Some(format!("{:#x}", gwei(1)));
+        assert_eq!(fees(london), vec![gwei(19) * 21_000 + gwei(1) * 50_000]);
+
+        // before london the whole gas price goes to the miner
+        let mut frontier = block.clone();
+        frontier.transactions.truncate(1);
+        frontier.header.gas_used = format!("{:#x}", 21_000);
+        assert_eq!(fees(frontier), vec![gwei(20) * 21_000]);
+
+        // without a later balance of the miner, or with only some transactions,
+        // the balances aren't known
+        let mut unknown = block.clone();
+        unknown.balance_changes.clear();
+        assert!(fees(unknown).is_empty());
+        let mut filtered = block.clone();
+        filtered.transactions.truncate(1);
+        assert!(fees(filtered).is_empty());
+
+        // fees recorded by the source aren't summed up again
+        let mut recorded = block;
+        recorded.balance_changes.insert(
+            0,
+            BalanceChange {
+                old_value: format!("{:#x}", balance - gwei(5)),
+                new_value: format!("{:#x}", balance),
+                reason: BalanceChangeReason::RewardTransactionFee,
+                ..withdrawal
+            },
+        );
+        assert_eq!(fees(recorded), vec![gwei(5)]);
     }
 }
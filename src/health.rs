@@ -0,0 +1,204 @@
+use crate::firehose::Firehose;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic_health::pb::health_server::{Health, HealthServer};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{info, warn};
+
+/// The overall server status followed by the block services.
+const SERVICES: [&str; 3] = ["", "sf.firehose.v2.Stream", "sf.firehose.v2.Fetch"];
+
+/// Blocks are served once the data sources respond
+/// and the finalized archive height is at most `max_lag` blocks behind the chain head.
+async fn status(firehose: &Firehose, max_lag: u64) -> (ServingStatus, String) {
+    match firehose.sync_heights().await {
+        Ok((finalized, head)) if head > finalized + max_lag => (
+            ServingStatus::NotServing,
+            format!(
+                "archive is {} blocks behind the chain head",
+                head - finalized
+            ),
+        ),
+        Ok((finalized, _)) => (
+            ServingStatus::Serving,
+            format!("finalized height is {}", finalized),
+        ),
+        Err(e) => (
+            ServingStatus::NotServing,
+            format!("data source is unavailable: {}", e),
+        ),
+    }
+}
+
+async fn report(reporter: &mut HealthReporter, status: ServingStatus) {
+    for service in SERVICES {
+        reporter.set_service_status(service, status).await;
+    }
+}
+
+/// grpc.health.v1 service reflecting the sync state of the firehose data sources,
+/// checked every `interval`.
+pub fn health_service(
+    firehose: Arc<Firehose>,
+    max_lag: u64,
+    interval: Duration,
+) -> HealthServer<impl Health> {
+    let (mut reporter, service) = tonic_health::server::health_reporter();
+    tokio::spawn(async move {
+        report(&mut reporter, ServingStatus::NotServing).await;
+        let mut current = ServingStatus::NotServing;
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let (status, reason) = status(&firehose, max_lag).await;
+            if status != current {
+                match status {
+                    ServingStatus::Serving => info!("serving blocks, {}", reason),
+                    _ => warn!("not serving blocks, {}", reason),
+                }
+                report(&mut reporter, status).await;
+                current = status;
+            }
+        }
+    });
+    service
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datasource::{
+        BlockStream, DataRequest, DataSource, HashAndHeight, HotBlockStream, HotDataSource,
+        HotSource,
+    };
+    use crate::firehose::Firehose;
+    use crate::health::health_service;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Channel;
+    use tonic_health::pb::health_check_response::ServingStatus;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    struct FlakyDataSource {
+        height: AtomicU64,
+        failing: AtomicBool,
+        hanging: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl DataSource for FlakyDataSource {
+        fn get_finalized_blocks(
+            &self,
+            _request: DataRequest,
+            _stop_on_head: bool,
+        ) -> anyhow::Result<BlockStream> {
+            Ok(Box::new(futures_util::stream::empty()))
+        }
+
+        async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+            anyhow::ensure!(!self.failing.load(Ordering::SeqCst), "connection refused");
+            if self.hanging.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            Ok(self.height.load(Ordering::SeqCst))
+        }
+
+        async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+            Ok(format!("0x{:064x}", height))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl HotSource for FlakyDataSource {
+        fn get_hot_blocks(
+            &self,
+            _request: DataRequest,
+            _state: HashAndHeight,
+        ) -> anyhow::Result<HotBlockStream> {
+            Ok(Box::new(futures_util::stream::empty()))
+        }
+
+        fn as_ds(&self) -> &(dyn DataSource + Send + Sync) {
+            self
+        }
+    }
+
+    impl HotDataSource for FlakyDataSource {}
+
+    fn flaky(height: u64) -> Arc<FlakyDataSource> {
+        Arc::new(FlakyDataSource {
+            height: AtomicU64::new(height),
+            failing: AtomicBool::new(false),
+            hanging: AtomicBool::new(false),
+        })
+    }
+
+    async fn serve(firehose: Arc<Firehose>) -> HealthClient<Channel> {
+        let service = health_service(firehose, 10, Duration::from_millis(10));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let channel = Channel::from_shared(url).unwrap().connect().await.unwrap();
+        HealthClient::new(channel)
+    }
+
+    async fn wait_for(client: &mut HealthClient<Channel>, status: ServingStatus) {
+        let request = HealthCheckRequest {
+            service: "sf.firehose.v2.Stream".to_string(),
+        };
+        for _ in 0..100 {
+            let response = client.check(request.clone()).await.unwrap().into_inner();
+            if response.status == status as i32 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("health status never became {:?}", status);
+    }
+
+    #[tokio::test]
+    async fn not_serving_when_rpc_fails() {
+        let archive = flaky(100);
+        let rpc = flaky(105);
+        let firehose = Arc::new(Firehose::new(archive, Some(rpc.clone())));
+        let mut client = serve(firehose).await;
+
+        wait_for(&mut client, ServingStatus::Serving).await;
+        rpc.failing.store(true, Ordering::SeqCst);
+        wait_for(&mut client, ServingStatus::NotServing).await;
+        rpc.failing.store(false, Ordering::SeqCst);
+        wait_for(&mut client, ServingStatus::Serving).await;
+    }
+
+    #[tokio::test]
+    async fn not_serving_while_archive_lags() {
+        let archive = flaky(100);
+        let rpc = flaky(110);
+        let firehose = Arc::new(Firehose::new(archive, Some(rpc.clone())));
+        let mut client = serve(firehose).await;
+
+        wait_for(&mut client, ServingStatus::Serving).await;
+        rpc.height.store(111, Ordering::SeqCst);
+        wait_for(&mut client, ServingStatus::NotServing).await;
+    }
+
+    #[tokio::test]
+    async fn not_serving_when_rpc_hangs() {
+        let archive = flaky(100);
+        let rpc = flaky(105);
+        let firehose =
+            Firehose::new(archive, Some(rpc.clone())).with_call_timeout(Duration::from_millis(20));
+        let mut client = serve(Arc::new(firehose)).await;
+
+        wait_for(&mut client, ServingStatus::Serving).await;
+        rpc.hanging.store(true, Ordering::SeqCst);
+        wait_for(&mut client, ServingStatus::NotServing).await;
+    }
+}
@@ -0,0 +1,38 @@
+use crate::firehose::Firehose;
+use crate::pbfirehose::endpoint_info_server::{EndpointInfo, EndpointInfoServer};
+use crate::pbfirehose::{HeadRequest, HeadResponse, InfoRequest, InfoResponse};
+use std::sync::Arc;
+use tracing::error;
+
+pub struct ServerInfo {
+    firehose: Arc<Firehose>,
+}
+
+pub fn info_service(firehose: Arc<Firehose>) -> EndpointInfoServer<ServerInfo> {
+    EndpointInfoServer::new(ServerInfo { firehose })
+}
+
+#[tonic::async_trait]
+impl EndpointInfo for ServerInfo {
+    async fn info(
+        &self,
+        _request: tonic::Request<InfoRequest>,
+    ) -> Result<tonic::Response<InfoResponse>, tonic::Status> {
+        let response = self.firehose.info().await.map_err(|e| {
+            error!("failed to get the endpoint info: {}", e);
+            tonic::Status::unavailable("operation failed")
+        })?;
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn head(
+        &self,
+        _request: tonic::Request<HeadRequest>,
+    ) -> Result<tonic::Response<HeadResponse>, tonic::Status> {
+        let response = self.firehose.head().await.map_err(|e| {
+            error!("failed to get the head block: {}", e);
+            tonic::Status::unavailable("operation failed")
+        })?;
+        Ok(tonic::Response::new(response))
+    }
+}
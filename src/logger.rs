@@ -14,7 +14,7 @@ pub fn init() {
             .with_env_filter(EnvFilter::from_default_env())
             .json()
             .flatten_event(true)
-            .with_span_list(false)
+            .with_span_list(true)
             .with_current_span(false)
             .init();
     }
@@ -1,37 +1,65 @@
 use archive::Archive;
+use auth::Auth;
 use clap::Parser;
 use cli::Cli;
-use datasource::HotDataSource;
+use datasource::{DataSource, HotDataSource};
 use ds_archive::ArchiveDataSource;
+use ds_cache::{CachingDataSource, HeightCachingDataSource};
+use ds_failover::FailoverDataSource;
+use ds_file::FileDataSource;
 use ds_rpc::RpcDataSource;
-use fetch::ArchiveFetch;
-use firehose::Firehose;
-use pbfirehose::{fetch_server::FetchServer, stream_server::StreamServer};
+use firehose::{BlockType, Firehose};
+use shutdown::Shutdown;
 use std::sync::Arc;
-use stream::ArchiveStream;
+use std::time::Duration;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Server;
-use tracing::info;
+use tracing::{error, info, warn};
 
 mod archive;
+mod auth;
 mod cli;
 mod cursor;
 mod datasource;
+#[cfg(feature = "debug-json")]
+mod debug_json;
 mod ds_archive;
+mod ds_breaker;
+mod ds_cache;
+mod ds_failover;
+mod ds_file;
+mod ds_merged;
 mod ds_rpc;
+mod encoded;
 mod fetch;
 mod firehose;
+mod health;
+mod info;
 mod logger;
+mod metrics;
+mod retry;
+mod shutdown;
 mod stream;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod tls;
 
+#[allow(clippy::enum_variant_names)]
 #[path = "protobuf/sf.firehose.v2.rs"]
 mod pbfirehose;
 
+#[allow(dead_code)]
 #[path = "protobuf/sf.ethereum.transform.v1.rs"]
 mod pbtransforms;
 
+#[allow(dead_code, clippy::enum_variant_names)]
 #[path = "protobuf/sf.ethereum.r#type.v2.rs"]
 mod pbcodec;
 
+/// Time given to the server to finish once the streams are closed on shutdown.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 const FIREHOSE_DESCRIPTOR: &[u8] = tonic::include_file_descriptor_set!("firehose_descriptor");
 
 #[tokio::main]
@@ -44,29 +72,170 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let finality_confirmation = args
             .finality_confirmation
             .expect("finality_confirmation is required if rpc is specified");
-        Some(Arc::new(RpcDataSource::new(rpc, finality_confirmation)))
+        let poll_interval = Duration::from_millis(args.rpc_poll_interval_ms);
+        let max_poll_interval = args
+            .rpc_max_poll_interval_ms
+            .map_or(poll_interval, Duration::from_millis);
+        Some(Arc::new(
+            RpcDataSource::new(rpc, args.rpc_ws, finality_confirmation)
+                .with_poll_interval(poll_interval, max_poll_interval)
+                .with_header_variant(args.header_variant),
+        ))
     } else {
         None
     };
 
-    let archive = Arc::new(Archive::new(args.archive));
-    let archive_ds = Arc::new(ArchiveDataSource::new(archive));
-    let firehose = Arc::new(Firehose::new(archive_ds, rpc_ds));
+    let archives: Vec<Arc<dyn DataSource + Sync + Send>> = args
+        .archive
+        .into_iter()
+        .map(|archive| -> Arc<dyn DataSource + Sync + Send> {
+            Arc::new(
+                ArchiveDataSource::new(Arc::new(Archive::new(archive)))
+                    .with_header_variant(args.header_variant)
+                    .with_chain_id(args.archive_chain_id),
+            )
+        })
+        .collect();
+    let archive_ds: Arc<dyn DataSource + Sync + Send> = match (archives.len(), args.merged_blocks) {
+        (0, Some(dir)) => Arc::new(FileDataSource::new(dir).with_chain_id(args.archive_chain_id)),
+        (0, None) => unreachable!("either the archive or the merged blocks are required"),
+        (1, _) => archives.into_iter().next().unwrap(),
+        _ => Arc::new(FailoverDataSource::new(archives).with_racing(args.race_archives)),
+    };
+    let archive_ds = if let Some(dir) = args.block_cache_dir {
+        Arc::new(
+            CachingDataSource::new(archive_ds, dir, args.block_cache_size)?
+                .with_memory_capacity(args.block_cache_memory_size),
+        )
+    } else {
+        archive_ds
+    };
+    let archive_ds: Arc<dyn DataSource + Sync + Send> = if args.height_cache_ttl_ms > 0 {
+        Arc::new(HeightCachingDataSource::new(
+            archive_ds,
+            Duration::from_millis(args.height_cache_ttl_ms),
+        ))
+    } else {
+        archive_ds
+    };
+    let conversion_parallelism = args
+        .conversion_parallelism
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let firehose = Arc::new(
+        Firehose::new(archive_ds, rpc_ds)
+            .with_conversion_parallelism(conversion_parallelism)
+            .with_retries(
+                args.max_retries,
+                Duration::from_millis(args.retry_backoff_ms),
+            )
+            .with_call_timeout(Duration::from_millis(args.call_timeout_ms))
+            .with_rate_limit(args.max_blocks_per_second)
+            .with_max_block_range(args.max_block_range)
+            .with_strict_validation(args.strict)
+            .with_hash_validation(args.verify_block_hashes)
+            .with_crossover_gap(args.archive_crossover_gap)
+            .with_circuit_breaker(
+                args.circuit_breaker_failures,
+                Duration::from_millis(args.circuit_breaker_cooldown_ms),
+            )
+            .with_full_traces(args.full_traces)
+            .with_raw_blocks(args.raw_blocks)
+            .with_gas_changes(args.gas_changes)
+            .with_block_type(BlockType {
+                type_url: args.block_type_url,
+                version: args.block_version,
+            })
+            .with_checked_chain_id()
+            .await?,
+    );
+    if let Some(chain_id) = firehose.chain_id() {
+        info!("serving blocks of chain {}", chain_id);
+    }
+
+    if let Some(port) = args.metrics_port {
+        let metrics = firehose.metrics();
+        let addr = ([0, 0, 0, 0], port).into();
+        info!("serving metrics at 0.0.0.0:{}/metrics", port);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics, addr).await {
+                error!("metrics server failed: {}", e);
+            }
+        });
+    }
 
-    let stream_service = StreamServer::new(ArchiveStream::new(firehose.clone()));
-    let fetch_service = FetchServer::new(ArchiveFetch::new(firehose));
+    #[cfg(feature = "debug-json")]
+    if let Some(port) = args.debug_json_port {
+        let firehose = firehose.clone();
+        let addr = ([0, 0, 0, 0], port).into();
+        info!("serving blocks as json at 0.0.0.0:{}/blocks/<number>", port);
+        tokio::spawn(async move {
+            if let Err(e) = debug_json::serve(firehose, addr).await {
+                error!("json server failed: {}", e);
+            }
+        });
+    }
+
+    let shutdown = Arc::new(Shutdown::new());
+    let stream_service = stream::stream_service(
+        firehose.clone(),
+        args.stream_compression.encoding(),
+        args.max_streams,
+        shutdown.clone(),
+    );
+    let fetch_service = fetch::fetch_service(firehose.clone(), args.fetch_compression.encoding());
+    let mut api_keys = args.api_key;
+    if let Some(path) = &args.api_keys_file {
+        api_keys.extend(Auth::read_tokens(path)?);
+    }
+    let auth = Auth::new(api_keys);
+    if auth.is_enabled() {
+        info!("requiring api keys for block requests");
+    }
+    let stream_service = InterceptedService::new(stream_service, auth.clone());
+    let fetch_service = InterceptedService::new(fetch_service, auth);
+    let info_service = info::info_service(firehose.clone());
+    let health_service =
+        health::health_service(firehose, args.health_max_lag, Duration::from_secs(5));
     let reflection_service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(FIREHOSE_DESCRIPTOR)
         .build()?;
 
     info!("starting firehose-grpc at 0.0.0.0:13042");
     let addr = "0.0.0.0:13042".parse()?;
-    Server::builder()
+    let mut server = Server::builder();
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        let tls = tls::server_tls_config(cert, key, args.tls_client_ca.as_deref())?;
+        server = server.tls_config(tls)?;
+        if args.tls_client_ca.is_some() {
+            info!("requiring client certificates");
+        }
+    }
+    let (stop_accepting, stopped_accepting) = tokio::sync::oneshot::channel::<()>();
+    let server = server
         .add_service(stream_service)
         .add_service(fetch_service)
+        .add_service(info_service)
+        .add_service(health_service)
         .add_service(reflection_service)
-        .serve(addr)
-        .await?;
+        .serve_with_shutdown(addr, async {
+            let _ = stopped_accepting.await;
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result?,
+        _ = shutdown::signal() => {
+            info!("shutting down");
+            let _ = stop_accepting.send(());
+            shutdown
+                .drain(Duration::from_millis(args.shutdown_grace_period_ms))
+                .await;
+            // closed streams end right away, the remaining requests get a moment to complete
+            if tokio::time::timeout(CLOSE_TIMEOUT, server).await.is_err() {
+                warn!("connections are still open {:?} after closing the streams", CLOSE_TIMEOUT);
+            }
+        }
+    }
 
     Ok(())
 }
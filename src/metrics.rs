@@ -0,0 +1,169 @@
+use crate::pbfirehose::ForkStep;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub struct Metrics {
+    registry: Registry,
+    blocks: IntCounterVec,
+    heights: IntGaugeVec,
+    reorgs: IntCounter,
+    active_streams: IntGauge,
+    throttled: IntCounter,
+    breaker_states: IntGaugeVec,
+    short_circuited: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let blocks = IntCounterVec::new(
+            Opts::new("firehose_blocks_total", "Blocks sent to clients"),
+            &["phase", "step"],
+        )
+        .unwrap();
+        let heights = IntGaugeVec::new(
+            Opts::new("firehose_height", "Last seen height of a source"),
+            &["source"],
+        )
+        .unwrap();
+        let reorgs =
+            IntCounter::new("firehose_reorgs_total", "Forks detected in hot blocks").unwrap();
+        let active_streams =
+            IntGauge::new("firehose_active_streams", "Block streams being served").unwrap();
+
+        registry.register(Box::new(blocks.clone())).unwrap();
+        registry.register(Box::new(heights.clone())).unwrap();
+        registry.register(Box::new(reorgs.clone())).unwrap();
+        let throttled = IntCounter::new(
+            "firehose_throttled_blocks_total",
+            "Blocks held back by the per-stream rate limit",
+        )
+        .unwrap();
+
+        registry.register(Box::new(active_streams.clone())).unwrap();
+        registry.register(Box::new(throttled.clone())).unwrap();
+        let breaker_states = IntGaugeVec::new(
+            Opts::new(
+                "firehose_circuit_breaker_state",
+                "Circuit breaker of a source, 0 closed, 1 half-open and 2 open",
+            ),
+            &["source"],
+        )
+        .unwrap();
+        let short_circuited = IntCounterVec::new(
+            Opts::new(
+                "firehose_short_circuited_calls_total",
+                "Calls to a source failed by its open circuit breaker",
+            ),
+            &["source"],
+        )
+        .unwrap();
+        registry.register(Box::new(breaker_states.clone())).unwrap();
+        registry
+            .register(Box::new(short_circuited.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            blocks,
+            heights,
+            reorgs,
+            active_streams,
+            throttled,
+            breaker_states,
+            short_circuited,
+        }
+    }
+
+    /// `phase` is one of archive, rpc or hot.
+    pub fn block_sent(&self, phase: &str, step: ForkStep) {
+        self.blocks
+            .with_label_values(&[phase, step.as_str_name()])
+            .inc();
+    }
+
+    /// `source` is one of archive, rpc or head.
+    pub fn set_height(&self, source: &str, height: u64) {
+        self.heights
+            .with_label_values(&[source])
+            .set(i64::try_from(height).unwrap_or(i64::MAX));
+    }
+
+    pub fn reorg(&self) {
+        self.reorgs.inc();
+    }
+
+    pub fn throttled(&self) {
+        self.throttled.inc();
+    }
+
+    pub fn set_breaker_state(&self, source: &str, state: i64) {
+        self.breaker_states.with_label_values(&[source]).set(state);
+    }
+
+    pub fn short_circuited(&self, source: &str) {
+        self.short_circuited.with_label_values(&[source]).inc();
+    }
+
+    /// Counts the stream as active until the returned guard is dropped.
+    pub fn stream_started(&self) -> StreamGuard {
+        self.active_streams.inc();
+        StreamGuard(self.active_streams.clone())
+    }
+
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = vec![];
+        prometheus::TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StreamGuard(IntGauge);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+fn handle(metrics: &Metrics, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    match metrics.encode() {
+        Ok(text) => Response::new(Body::from(text)),
+        Err(e) => {
+            let mut response = Response::new(Body::from(e.to_string()));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+/// Serves the metrics in the prometheus text format at `/metrics`.
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = handle(&metrics, request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    hyper::Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Block {
@@ -24,6 +25,10 @@ pub struct Block {
     pub balance_changes: ::prost::alloc::vec::Vec<BalanceChange>,
     #[prost(message, repeated, tag = "20")]
     pub code_changes: ::prost::alloc::vec::Vec<CodeChange>,
+    /// Raw is the block JSON exactly as the rpc node returned it, only set when the server runs
+    /// with --raw-blocks and only for blocks served by the rpc.
+    #[prost(bytes = "vec", tag = "1000")]
+    pub raw: ::prost::alloc::vec::Vec<u8>,
 }
 /// HeaderOnlyBlock is used to optimally unpack the \[Block\] structure (note the
 /// corresponding message number for the `header` field) while consuming less
@@ -31,6 +36,7 @@ pub struct Block {
 ///
 /// WARN: this is a client-side optimization pattern and should be moved in the
 /// consuming code.
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct HeaderOnlyBlock {
@@ -40,6 +46,7 @@ pub struct HeaderOnlyBlock {
 /// BlockWithRefs is a lightweight block, with traces and transactions
 /// purged from the `block` within, and only.  It is used in transports
 /// to pass block data around.
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BlockWithRefs {
@@ -52,18 +59,21 @@ pub struct BlockWithRefs {
     #[prost(bool, tag = "4")]
     pub irreversible: bool,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TransactionRefs {
     #[prost(bytes = "vec", repeated, tag = "1")]
     pub hashes: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct UnclesHeaders {
     #[prost(message, repeated, tag = "1")]
     pub uncles: ::prost::alloc::vec::Vec<BlockHeader>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BlockRef {
@@ -72,6 +82,7 @@ pub struct BlockRef {
     #[prost(uint64, tag = "2")]
     pub number: u64,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BlockHeader {
@@ -115,6 +126,10 @@ pub struct BlockHeader {
     #[prost(uint64, tag = "11")]
     pub gas_used: u64,
     #[prost(message, optional, tag = "12")]
+    #[cfg_attr(
+        feature = "debug-json",
+        serde(serialize_with = "crate::debug_json::timestamp")
+    )]
     pub timestamp: ::core::option::Option<::prost_types::Timestamp>,
     /// ExtraData is free-form bytes included in the block by the "miner". While on Yellow paper of
     /// Ethereum this value is maxed to 32 bytes, other consensus algorithm like Clique and some other
@@ -161,13 +176,49 @@ pub struct BlockHeader {
     /// Base fee per gas according to EIP-1559 (e.g. London Fork) rules, only set if London is present/active on the chain.
     #[prost(message, optional, tag = "18")]
     pub base_fee_per_gas: ::core::option::Option<BigInt>,
+    /// Withdrawals root hash according to EIP-4895 (e.g. Shanghai Fork) rules, only set if Shanghai is present/active on the chain.
+    #[prost(bytes = "vec", tag = "19")]
+    pub withdrawals_root: ::prost::alloc::vec::Vec<u8>,
+    /// Total amount of blob gas consumed by the transactions of the block according to EIP-4844 (e.g. Cancun Fork) rules,
+    /// only set if Cancun is present/active on the chain.
+    #[prost(uint64, optional, tag = "22")]
+    pub blob_gas_used: ::core::option::Option<u64>,
+    /// Running total of blob gas consumed in excess of the target prior to the block according to EIP-4844 rules,
+    /// only set if Cancun is present/active on the chain.
+    #[prost(uint64, optional, tag = "23")]
+    pub excess_blob_gas: ::core::option::Option<u64>,
+    /// Root of the parent beacon block according to EIP-4788 (e.g. Cancun Fork) rules,
+    /// only set if Cancun is present/active on the chain.
+    #[prost(bytes = "vec", tag = "24")]
+    pub parent_beacon_root: ::prost::alloc::vec::Vec<u8>,
+    /// Hash of the execution layer requests according to EIP-7685 (e.g. Prague Fork) rules,
+    /// only set if Prague is present/active on the chain.
+    #[prost(bytes = "vec", tag = "25")]
+    pub requests_hash: ::prost::alloc::vec::Vec<u8>,
+    /// Arbitrum-style L2 fields, only set for L2 chains. On these chains `base_fee_per_gas`
+    /// is the L2 gas price floor rather than the EIP-1559 base fee.
+    #[prost(message, optional, tag = "100")]
+    pub l2_extra: ::core::option::Option<L2Extra>,
+}
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct L2Extra {
+    /// Number of the L1 block the L2 block was derived from
+    #[prost(uint64, tag = "1")]
+    pub l1_block_number: u64,
+    /// Merkle root of the outgoing L2 to L1 message accumulator
+    #[prost(bytes = "vec", tag = "2")]
+    pub send_root: ::prost::alloc::vec::Vec<u8>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BigInt {
     #[prost(bytes = "vec", tag = "1")]
     pub bytes: ::prost::alloc::vec::Vec<u8>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TransactionTrace {
@@ -233,6 +284,18 @@ pub struct TransactionTrace {
     /// if London fork is active on the chain.
     #[prost(message, optional, tag = "13")]
     pub max_priority_fee_per_gas: ::core::option::Option<BigInt>,
+    /// BlobGasFeeCap is the maximum fee per blob gas the user is willing to pay for the blobs of the transaction.
+    ///
+    /// This will is populated only if `TransactionTrace.Type == TRX_TYPE_BLOB` which is possible only
+    /// if Cancun fork is active on the chain.
+    #[prost(message, optional, tag = "34")]
+    pub blob_gas_fee_cap: ::core::option::Option<BigInt>,
+    /// BlobHashes are the versioned hashes of the blobs carried by the transaction.
+    ///
+    /// This will is populated only if `TransactionTrace.Type == TRX_TYPE_BLOB` which is possible only
+    /// if Cancun fork is active on the chain.
+    #[prost(bytes = "vec", repeated, tag = "35")]
+    pub blob_hashes: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
     /// meta
     #[prost(uint32, tag = "20")]
     pub index: u32,
@@ -257,6 +320,7 @@ pub struct TransactionTrace {
 }
 /// Nested message and enum types in `TransactionTrace`.
 pub mod transaction_trace {
+    #[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
     #[derive(
         Clone,
         Copy,
@@ -281,6 +345,11 @@ pub mod transaction_trace {
         /// max base gas gee and max priority gas fee to pay for this transaction. Transaction's of those type are
         /// executed against EIP-1559 rules which dictates a dynamic gas cost based on the congestion of the network.
         TrxTypeDynamicFee = 2,
+        /// Transaction that carries blobs of data next to the dynamic fee fields, the blobs are committed to
+        /// by their versioned hashes and paid for with a separate blob gas market.
+        ///
+        /// Added in Cancun fork (EIP-4844).
+        TrxTypeBlob = 3,
     }
     impl Type {
         /// String value of the enum field names used in the ProtoBuf definition.
@@ -292,6 +361,7 @@ pub mod transaction_trace {
                 Type::TrxTypeLegacy => "TRX_TYPE_LEGACY",
                 Type::TrxTypeAccessList => "TRX_TYPE_ACCESS_LIST",
                 Type::TrxTypeDynamicFee => "TRX_TYPE_DYNAMIC_FEE",
+                Type::TrxTypeBlob => "TRX_TYPE_BLOB",
             }
         }
         /// Creates an enum from field names used in the ProtoBuf definition.
@@ -300,6 +370,7 @@ pub mod transaction_trace {
                 "TRX_TYPE_LEGACY" => Some(Self::TrxTypeLegacy),
                 "TRX_TYPE_ACCESS_LIST" => Some(Self::TrxTypeAccessList),
                 "TRX_TYPE_DYNAMIC_FEE" => Some(Self::TrxTypeDynamicFee),
+                "TRX_TYPE_BLOB" => Some(Self::TrxTypeBlob),
                 _ => None,
             }
         }
@@ -307,6 +378,7 @@ pub mod transaction_trace {
 }
 /// AccessTuple represents a list of storage keys for a given contract's address and is used
 /// for AccessList construction.
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct AccessTuple {
@@ -316,6 +388,7 @@ pub struct AccessTuple {
     pub storage_keys: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
 }
 /// TransactionTraceWithBlockRef
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TransactionTraceWithBlockRef {
@@ -324,6 +397,7 @@ pub struct TransactionTraceWithBlockRef {
     #[prost(message, optional, tag = "2")]
     pub block_ref: ::core::option::Option<BlockRef>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TransactionReceipt {
@@ -348,7 +422,12 @@ pub struct TransactionReceipt {
     pub logs_bloom: ::prost::alloc::vec::Vec<u8>,
     #[prost(message, repeated, tag = "4")]
     pub logs: ::prost::alloc::vec::Vec<Log>,
+    /// Raw is the receipt JSON exactly as the rpc node returned it, only set when requested
+    /// with the sf.ethereum.transform.v1.RawReceipts transform and only for blocks served by the rpc.
+    #[prost(bytes = "vec", tag = "1000")]
+    pub raw: ::prost::alloc::vec::Vec<u8>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Log {
@@ -383,6 +462,7 @@ pub struct Log {
     #[prost(uint64, tag = "7")]
     pub ordinal: u64,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Call {
@@ -476,6 +556,7 @@ pub struct Call {
     #[prost(message, repeated, tag = "33")]
     pub account_creations: ::prost::alloc::vec::Vec<AccountCreation>,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct StorageChange {
@@ -490,6 +571,7 @@ pub struct StorageChange {
     #[prost(uint64, tag = "5")]
     pub ordinal: u64,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BalanceChange {
@@ -511,6 +593,7 @@ pub mod balance_change {
     /// ```shell
     /// ack -ho 'BalanceChangeReason\(".*"\)' | grep -Eo '".*"' | sort | uniq
     /// ```
+    #[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
     #[derive(
         Clone,
         Copy,
@@ -541,6 +624,8 @@ pub mod balance_change {
         CallBalanceOverride = 12,
         /// Used on chain(s) where some Ether burning happens
         Burn = 15,
+        /// Validator withdrawals credited at the end of the block according to EIP-4895
+        Withdrawal = 16,
     }
     impl Reason {
         /// String value of the enum field names used in the ProtoBuf definition.
@@ -565,6 +650,7 @@ pub mod balance_change {
                 Reason::SuicideWithdraw => "REASON_SUICIDE_WITHDRAW",
                 Reason::CallBalanceOverride => "REASON_CALL_BALANCE_OVERRIDE",
                 Reason::Burn => "REASON_BURN",
+                Reason::Withdrawal => "REASON_WITHDRAWAL",
             }
         }
         /// Creates an enum from field names used in the ProtoBuf definition.
@@ -586,11 +672,13 @@ pub mod balance_change {
                 "REASON_SUICIDE_WITHDRAW" => Some(Self::SuicideWithdraw),
                 "REASON_CALL_BALANCE_OVERRIDE" => Some(Self::CallBalanceOverride),
                 "REASON_BURN" => Some(Self::Burn),
+                "REASON_WITHDRAWAL" => Some(Self::Withdrawal),
                 _ => None,
             }
         }
     }
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct NonceChange {
@@ -603,6 +691,7 @@ pub struct NonceChange {
     #[prost(uint64, tag = "4")]
     pub ordinal: u64,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct AccountCreation {
@@ -611,6 +700,7 @@ pub struct AccountCreation {
     #[prost(uint64, tag = "2")]
     pub ordinal: u64,
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CodeChange {
@@ -633,6 +723,7 @@ pub struct CodeChange {
 ///
 /// Hence, we only index some of them, those that are costy like all the calls
 /// one, log events, return data, etc.
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GasChange {
@@ -652,6 +743,7 @@ pub mod gas_change {
     /// ```shell
     /// ack -ho 'GasChangeReason\(".*"\)' | grep -Eo '".*"' | sort | uniq
     /// ```
+    #[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
     #[derive(
         Clone,
         Copy,
@@ -747,6 +839,7 @@ pub mod gas_change {
         }
     }
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
 pub enum TransactionTraceStatus {
@@ -779,6 +872,7 @@ impl TransactionTraceStatus {
         }
     }
 }
+#[cfg_attr(feature = "debug-json", derive(serde::Serialize))]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
 pub enum CallType {
@@ -38,6 +38,7 @@ pub struct MultiLogFilter {
 /// LogFilter will match calls where *BOTH*
 /// * the contract address that emits the log is one in the provided addresses -- OR addresses list is empty --
 /// * the event signature (topic.0) is one of the provided event_signatures -- OR event_signatures is empty --
+/// * each indexed parameter (topic.1 to topic.3) is one of the provided values -- OR its list is empty --
 ///
 /// a LogFilter with both empty addresses and event_signatures lists is invalid and will fail.
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -48,6 +49,12 @@ pub struct LogFilter {
     /// corresponds to the keccak of the event signature which is stores in topic.0
     #[prost(bytes = "vec", repeated, tag = "2")]
     pub event_signatures: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    pub topic1: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    pub topic2: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "5")]
+    pub topic3: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
 }
 /// MultiCallToFilter concatenates the results of each CallToFilter (inclusive OR)
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -69,6 +76,26 @@ pub struct CallToFilter {
     #[prost(bytes = "vec", repeated, tag = "2")]
     pub signatures: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
 }
+/// MultiTransactionFilter concatenates the results of each TransactionFilter (inclusive OR)
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MultiTransactionFilter {
+    #[prost(message, repeated, tag = "1")]
+    pub transaction_filters: ::prost::alloc::vec::Vec<TransactionFilter>,
+}
+/// TransactionFilter will match transactions where *BOTH*
+/// * the sender (FROM) is one in the provided from_addresses -- OR from_addresses is empty --
+/// * the recipient (TO) is one in the provided to_addresses -- OR to_addresses is empty --
+///
+/// a TransactionFilter with both empty from_addresses and to_addresses lists is invalid and will fail.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransactionFilter {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub from_addresses: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub to_addresses: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+}
 /// Deprecated: LightBlock is deprecated, replaced by HeaderOnly, note however that the new transform
 /// does not have any transactions traces returned, so it's not a direct replacement.
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -93,3 +120,20 @@ pub struct LightBlock {}
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct HeaderOnly {}
+/// RawReceipts attaches the receipt JSON of every transaction exactly as the rpc node returned it
+/// to TransactionReceipt.raw, so consumers can verify the reconstructed receipt against it.
+/// Blocks served by the archive come without raw receipts.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RawReceipts {}
+/// BalanceChanges attaches the balance changes of every call, which requires tracing the state
+/// of every transaction. Only the rpc and merged blocks files can supply them,
+/// streams reaching blocks served by the archive fail with an invalid argument.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BalanceChanges {}
+/// StorageChanges attaches the storage changes of every call, with the same cost and sources
+/// as BalanceChanges.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StorageChanges {}
@@ -0,0 +1,210 @@
+use crate::archive::ResponseError;
+use anyhow::Context;
+use ethers_providers::ProviderError;
+use reqwest::StatusCode;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// JSON-RPC error code of a request exceeding the limits of the node (EIP-1474)
+const LIMIT_EXCEEDED: i64 = -32005;
+
+/// Network failures, timeouts and overloaded upstreams are worth retrying,
+/// anything else (invalid data, rejected requests) would fail the same way again.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<ResponseError>() {
+            return is_transient_status(e.status);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return is_transient_request(e);
+        }
+        if let Some(e) = cause.downcast_ref::<ProviderError>() {
+            return is_transient_rpc(e);
+        }
+        cause.is::<std::io::Error>() || cause.is::<tokio::time::error::Elapsed>()
+    })
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_transient_request(e: &reqwest::Error) -> bool {
+    e.is_timeout()
+        || e.is_connect()
+        || e.is_request()
+        || e.status().is_some_and(is_transient_status)
+}
+
+/// Error responses are answers of the node, unless it's rate limiting.
+/// Proxies in front of the node answer 5xx and 429 with bodies that aren't JSON at all.
+fn is_transient_rpc(e: &ProviderError) -> bool {
+    match e {
+        ProviderError::HTTPError(e) => is_transient_request(e),
+        ProviderError::JsonRpcClientError(e) => match e.as_error_response() {
+            Some(response) => response.code == 429 || response.code == LIMIT_EXCEEDED,
+            None => e
+                .as_serde_error()
+                .is_some_and(|e| e.is_syntax() || e.is_eof()),
+        },
+        _ => false,
+    }
+}
+
+/// Fails with a timeout error naming the `call` if the upstream doesn't respond within `duration`,
+/// the timeout is transient so the call is retried like any other network failure.
+pub async fn timeout<F: Future>(
+    duration: Duration,
+    call: &str,
+    future: F,
+) -> anyhow::Result<F::Output> {
+    tokio::time::timeout(duration, future)
+        .await
+        .with_context(|| format!("{} timed out after {:?}", call, duration))
+}
+
+/// Exponential backoff between attempts of an upstream call.
+pub struct Backoff {
+    max_retries: u32,
+    initial: Duration,
+    retries: u32,
+}
+
+impl Backoff {
+    pub fn new(max_retries: u32, initial: Duration) -> Backoff {
+        Backoff {
+            max_retries,
+            initial,
+            retries: 0,
+        }
+    }
+
+    /// Waits before the next attempt, the error is given back
+    /// when it's fatal or the retries are exhausted.
+    pub async fn retry(&mut self, e: anyhow::Error) -> anyhow::Result<()> {
+        if self.retries >= self.max_retries || !is_transient(&e) {
+            return Err(e);
+        }
+        let delay = self.initial * 2u32.saturating_pow(self.retries);
+        self.retries += 1;
+        warn!(
+            "transient upstream failure, retry {} of {} in {:?}: {:#}",
+            self.retries, self.max_retries, delay, e
+        );
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+
+    /// Progress was made, so the next failure starts over from the initial delay.
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::ResponseError;
+    use crate::retry::{is_transient, timeout, Backoff};
+    use anyhow::Context;
+    use ethers_providers::{
+        Http, HttpClientError, JsonRpcError, Middleware, Provider, ProviderError,
+    };
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    fn response_error(status: StatusCode) -> anyhow::Error {
+        ResponseError {
+            status,
+            text: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn transient_errors() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_transient(&anyhow::Error::from(reset)));
+        assert!(is_transient(
+            &Err::<(), _>(response_error(StatusCode::BAD_GATEWAY))
+                .context("archive query")
+                .unwrap_err()
+        ));
+        assert!(is_transient(&response_error(StatusCode::TOO_MANY_REQUESTS)));
+
+        assert!(!is_transient(&response_error(StatusCode::BAD_REQUEST)));
+        assert!(!is_transient(&anyhow::anyhow!("no hash")));
+    }
+
+    fn rpc_error(error: HttpClientError) -> anyhow::Error {
+        ProviderError::from(error).into()
+    }
+
+    #[tokio::test]
+    async fn transient_rpc_errors() {
+        let client = Provider::<Http>::try_from("http://127.0.0.1:1").unwrap();
+        let refused = client.get_block_number().await.unwrap_err();
+        assert!(is_transient(&refused.into()));
+
+        let response = |code: i64| {
+            rpc_error(HttpClientError::JsonRpcError(JsonRpcError {
+                code,
+                message: "rejected".to_string(),
+                data: None,
+            }))
+        };
+        assert!(is_transient(&response(429)));
+        assert!(is_transient(&response(-32005)));
+        assert!(!is_transient(&response(-32000)));
+
+        // a gateway page instead of a JSON-RPC response
+        let text = "<html>502 Bad Gateway</html>".to_string();
+        let err = serde_json::from_str::<serde_json::Value>(&text).unwrap_err();
+        assert!(is_transient(&rpc_error(HttpClientError::SerdeJson {
+            err,
+            text
+        })));
+        // a response that doesn't decode fails the same way again
+        let text = "\"0xzz\"".to_string();
+        let err = serde_json::from_str::<u64>(&text).unwrap_err();
+        assert!(!is_transient(&rpc_error(HttpClientError::SerdeJson {
+            err,
+            text
+        })));
+        assert!(!is_transient(&ProviderError::UnsupportedRPC.into()));
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let transient = || anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        let mut backoff = Backoff::new(2, Duration::from_millis(1));
+
+        backoff.retry(transient()).await.unwrap();
+        backoff.retry(transient()).await.unwrap();
+        assert!(backoff.retry(transient()).await.is_err());
+
+        backoff.reset();
+        backoff.retry(transient()).await.unwrap();
+        assert!(backoff.retry(anyhow::anyhow!("fatal")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn timeout_is_transient() {
+        let e = timeout(
+            Duration::from_millis(1),
+            "archive height",
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.to_string(), "archive height timed out after 1ms");
+        assert!(is_transient(&e));
+
+        assert_eq!(
+            timeout(Duration::from_secs(1), "archive height", async { 5 })
+                .await
+                .unwrap(),
+            5
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Running,
+    /// New streams are rejected while the running ones may still finish
+    Draining,
+    /// Running streams are closed
+    Closing,
+}
+
+/// Coordinates a graceful shutdown of the block streams.
+pub struct Shutdown {
+    state: watch::Sender<State>,
+    active: Arc<watch::Sender<usize>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            state: watch::channel(State::Running).0,
+            active: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<State> {
+        self.state.subscribe()
+    }
+
+    /// Counts the stream as running until the returned guard is dropped.
+    pub fn stream_started(&self) -> ActiveStream {
+        self.active.send_modify(|active| *active += 1);
+        ActiveStream(self.active.clone())
+    }
+
+    /// Rejects new streams, gives the running ones up to `grace` to finish and closes the rest.
+    pub async fn drain(&self, grace: Duration) {
+        self.state.send_replace(State::Draining);
+        let active = *self.active.borrow();
+        if active > 0 {
+            info!("waiting up to {:?} for {} streams to finish", grace, active);
+        }
+        let mut active = self.active.subscribe();
+        let finished = tokio::time::timeout(grace, active.wait_for(|active| *active == 0)).await;
+        if finished.is_err() {
+            warn!(
+                "closing {} streams still running after the grace period",
+                *self.active.borrow()
+            );
+        }
+        self.state.send_replace(State::Closing);
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ActiveStream(Arc<watch::Sender<usize>>);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.send_modify(|active| *active -= 1);
+    }
+}
+
+/// Resolves once the process is asked to stop with SIGTERM or ctrl-c.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
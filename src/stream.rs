@@ -1,17 +1,64 @@
-use crate::firehose::Firehose;
-use crate::pbfirehose::{stream_server::Stream, Request, Response};
+use crate::firehose::{FetchError, Firehose};
+use crate::pbfirehose::stream_server::{Stream, StreamServer};
+use crate::pbfirehose::{Request, Response};
+use crate::shutdown::{Shutdown, State};
 use futures_util::stream::StreamExt;
 use std::sync::Arc;
+use tokio::sync::{mpsc, watch, Semaphore};
 use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::CompressionEncoding;
 use tracing::{debug, error};
 
+/// Number of responses buffered for a slow client before pulling more from the sources.
+const BUFFER_SIZE: usize = 1;
+
 pub struct ArchiveStream {
     firehose: Arc<Firehose>,
+    max_streams: Option<usize>,
+    streams: Option<Arc<Semaphore>>,
+    shutdown: Arc<Shutdown>,
 }
 
 impl ArchiveStream {
     pub fn new(firehose: Arc<Firehose>) -> ArchiveStream {
-        ArchiveStream { firehose }
+        ArchiveStream {
+            firehose,
+            max_streams: None,
+            streams: None,
+            shutdown: Arc::new(Shutdown::new()),
+        }
+    }
+
+    /// Streams beyond `limit` are rejected until one of the running streams ends.
+    pub fn with_max_streams(mut self, limit: Option<usize>) -> ArchiveStream {
+        self.max_streams = limit;
+        self.streams = limit.map(|limit| Arc::new(Semaphore::new(limit)));
+        self
+    }
+
+    /// New streams are rejected once `shutdown` drains, running ones are closed
+    /// with a status naming the cursor to resume from.
+    pub fn with_shutdown(mut self, shutdown: Arc<Shutdown>) -> ArchiveStream {
+        self.shutdown = shutdown;
+        self
+    }
+}
+
+/// Gzip requests are always accepted, responses are compressed with `send`
+/// when the client accepts it.
+pub fn stream_service(
+    firehose: Arc<Firehose>,
+    send: Option<CompressionEncoding>,
+    max_streams: Option<usize>,
+    shutdown: Arc<Shutdown>,
+) -> StreamServer<ArchiveStream> {
+    let stream = ArchiveStream::new(firehose)
+        .with_max_streams(max_streams)
+        .with_shutdown(shutdown);
+    let service = StreamServer::new(stream).accept_compressed(CompressionEncoding::Gzip);
+    match send {
+        Some(encoding) => service.send_compressed(encoding),
+        None => service,
     }
 }
 
@@ -23,15 +70,51 @@ impl Stream for ArchiveStream {
         &self,
         request: tonic::Request<Request>,
     ) -> Result<tonic::Response<Self::BlocksStream>, tonic::Status> {
-        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        if self.shutdown.state() != State::Running {
+            return Err(tonic::Status::unavailable("server is shutting down"));
+        }
+        // held by the forwarding task, so the slot frees up once the stream ends
+        let permit = match &self.streams {
+            Some(streams) => match streams.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    return Err(tonic::Status::resource_exhausted(format!(
+                        "too many concurrent streams, at most {} are served",
+                        self.max_streams.unwrap_or_default()
+                    )))
+                }
+            },
+            None => None,
+        };
 
+        let (tx, rx) = mpsc::channel(BUFFER_SIZE);
+
+        let strip_unmatched = request
+            .metadata()
+            .get("x-strip-unmatched")
+            .is_some_and(|value| value == "true");
         let request = request.into_inner();
         let firehose = self.firehose.clone();
+        let active = self.shutdown.stream_started();
+        let shutdown = self.shutdown.subscribe();
 
         tokio::spawn(async move {
-            let stream = match firehose.blocks(request).await {
+            let _permit = permit;
+            let _active = active;
+            let stream = match firehose.blocks(request, strip_unmatched).await {
                 Ok(stream) => stream,
                 Err(e) => {
+                    match e.downcast_ref() {
+                        Some(FetchError::InvalidArgument(message)) => {
+                            let _ = tx.send(Err(tonic::Status::invalid_argument(message))).await;
+                            return;
+                        }
+                        Some(FetchError::Unavailable(message)) => {
+                            let _ = tx.send(Err(tonic::Status::unavailable(message))).await;
+                            return;
+                        }
+                        _ => {}
+                    }
                     error!("failed to establish block stream: {}", e);
                     return;
                 }
@@ -39,26 +122,289 @@ impl Stream for ArchiveStream {
 
             debug!("block stream established successfully");
 
-            tokio::pin!(stream);
+            forward(stream, tx, shutdown).await;
+        });
 
-            while let Some(result) = stream.next().await {
-                match result {
-                    Ok(response) => {
-                        if let Err(e) = tx.send(Ok(response)).await {
-                            debug!("block stream has been closed: {}", e);
-                            return;
-                        }
-                    }
-                    Err(e) => {
-                        error!("error while streaming data: {}", e);
-                        return;
-                    }
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Sends responses to the client until either side is done or the server shuts down.
+/// The source stream is dropped as soon as the client goes away,
+/// even if it is waiting for new blocks at that moment.
+async fn forward<S>(
+    stream: S,
+    tx: mpsc::Sender<Result<Response, tonic::Status>>,
+    mut shutdown: watch::Receiver<State>,
+) where
+    S: futures_core::Stream<Item = anyhow::Result<Response>>,
+{
+    tokio::pin!(stream);
+    let closing = async move {
+        if shutdown
+            .wait_for(|state| *state == State::Closing)
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::pin!(closing);
+    let mut cursor = None;
+
+    loop {
+        let result = tokio::select! {
+            result = stream.next() => result,
+            _ = tx.closed() => {
+                debug!("block stream has been closed");
+                return;
+            }
+            _ = &mut closing => {
+                // unavailable tells clients to reconnect, from the last cursor they received
+                let message = match cursor {
+                    Some(cursor) => format!("server is shutting down, resume from cursor {}", cursor),
+                    None => "server is shutting down".to_string(),
+                };
+                let _ = tx.send(Err(tonic::Status::unavailable(message))).await;
+                debug!("block stream has been closed on shutdown");
+                return;
+            }
+        };
+
+        match result {
+            Some(Ok(response)) => {
+                cursor = Some(response.cursor.clone());
+                if let Err(e) = tx.send(Ok(response)).await {
+                    debug!("block stream has been closed: {}", e);
+                    return;
                 }
             }
+            Some(Err(e)) => {
+                error!("error while streaming data: {}", e);
+                let status = match e.downcast_ref() {
+                    Some(FetchError::Unavailable(message)) => tonic::Status::unavailable(message),
+                    Some(FetchError::DataLoss(message)) => tonic::Status::data_loss(message),
+                    _ => return,
+                };
+                let _ = tx.send(Err(status)).await;
+                return;
+            }
+            None => break,
+        }
+    }
 
-            debug!("block stream finished");
+    debug!("block stream finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::firehose::Firehose;
+    use crate::pbfirehose::stream_client::StreamClient;
+    use crate::pbfirehose::stream_server::StreamServer;
+    use crate::pbfirehose::{Request, Response};
+    use crate::shutdown::{Shutdown, State};
+    use crate::stream::{forward, stream_service, ArchiveStream};
+    use crate::testing::{block, hash, MockDataSource};
+    use futures_util::StreamExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::codec::CompressionEncoding;
+
+    struct DropGuard(Arc<AtomicBool>);
+
+    impl Drop for DropGuard {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn client_disconnect_drops_source() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = DropGuard(dropped.clone());
+        let source = async_stream::stream! {
+            let _guard = guard;
+            yield Ok(Response::default());
+            // a source waiting for a new head
+            std::future::pending::<()>().await;
+        };
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let shutdown = Shutdown::new();
+        let task = tokio::spawn(forward(source, tx, shutdown.subscribe()));
+
+        assert!(rx.recv().await.unwrap().is_ok());
+        drop(rx);
+
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("forwarding should stop once the client is gone")
+            .unwrap();
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    async fn serve(service: StreamServer<ArchiveStream>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        url
+    }
+
+    #[tokio::test]
+    async fn gzip_compressed_blocks() {
+        let archive = Arc::new(MockDataSource::new(9));
+        let firehose = Arc::new(Firehose::new(archive, None));
+        let url = serve(stream_service(
+            firehose,
+            Some(CompressionEncoding::Gzip),
+            None,
+            Default::default(),
+        ))
+        .await;
+
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 9,
+            ..Default::default()
+        };
+
+        let mut plain = StreamClient::connect(url.clone()).await.unwrap();
+        let response = plain.blocks(request.clone()).await.unwrap();
+        assert!(response.metadata().get("grpc-encoding").is_none());
+        let plain_blocks: Vec<_> = response
+            .into_inner()
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+
+        let mut gzip = StreamClient::connect(url)
+            .await
+            .unwrap()
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip);
+        let response = gzip.blocks(request).await.unwrap();
+        assert_eq!(response.metadata().get("grpc-encoding").unwrap(), "gzip");
+        let blocks: Vec<_> = response
+            .into_inner()
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(blocks.len(), 10);
+        assert_eq!(blocks, plain_blocks);
+    }
+
+    #[tokio::test]
+    async fn streams_beyond_limit_are_rejected() {
+        let archive = Arc::new(MockDataSource::new(9).with_open_streams());
+        let firehose = Arc::new(Firehose::new(archive, None));
+        let url = serve(stream_service(firehose, None, Some(2), Default::default())).await;
+        let mut client = StreamClient::connect(url).await.unwrap();
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 0,
+            ..Default::default()
+        };
+
+        let mut first = client.blocks(request.clone()).await.unwrap().into_inner();
+        let mut second = client.blocks(request.clone()).await.unwrap().into_inner();
+        let status = client.blocks(request.clone()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(
+            status.message(),
+            "too many concurrent streams, at most 2 are served"
+        );
+
+        // running streams are unaffected
+        assert!(first.message().await.unwrap().is_some());
+        assert!(second.message().await.unwrap().is_some());
+
+        drop(first);
+        for _ in 0..100 {
+            match client.blocks(request.clone()).await {
+                Ok(_) => return,
+                Err(status) => assert_eq!(status.code(), tonic::Code::ResourceExhausted),
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the slot of a closed stream was never freed");
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_running_streams() {
+        let archive = Arc::new(MockDataSource::new(9).with_open_streams());
+        let firehose = Arc::new(Firehose::new(archive, None));
+        let shutdown = Arc::new(Shutdown::new());
+        let url = serve(stream_service(firehose, None, None, shutdown.clone())).await;
+        let mut client = StreamClient::connect(url).await.unwrap();
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 0,
+            ..Default::default()
+        };
+
+        let mut stream = client.blocks(request.clone()).await.unwrap().into_inner();
+        let mut last_cursor = String::new();
+        for _ in 0..10 {
+            last_cursor = stream.message().await.unwrap().unwrap().cursor;
+        }
+
+        let drained = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.drain(Duration::from_millis(50)).await }
         });
+        while shutdown.state() == State::Running {
+            tokio::task::yield_now().await;
+        }
+        let status = client.blocks(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.message(), "server is shutting down");
 
-        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+        // the running stream waits for a new head until the grace period is over
+        let status = stream.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(
+            status.message(),
+            format!(
+                "server is shutting down, resume from cursor {}",
+                last_cursor
+            )
+        );
+        tokio::time::timeout(Duration::from_secs(1), drained)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(shutdown.state(), State::Closing);
+    }
+
+    #[tokio::test]
+    async fn invalid_block_ends_stream_with_data_loss() {
+        let mut invalid = block(2, hash(2), hash(1));
+        invalid.header.gas_used = "0x1".to_string();
+        let archive = Arc::new(MockDataSource::new(9).with_block(invalid));
+        let firehose = Arc::new(Firehose::new(archive, None).with_strict_validation(true));
+        let url = serve(stream_service(firehose, None, None, Default::default())).await;
+        let mut client = StreamClient::connect(url).await.unwrap();
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 9,
+            ..Default::default()
+        };
+
+        let mut stream = client.blocks(request).await.unwrap().into_inner();
+        for _ in 0..2 {
+            assert!(stream.message().await.unwrap().is_some());
+        }
+        // the client can tell the failure from the end of the range
+        let status = stream.message().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        assert_eq!(status.message(), "block №2 uses 1 gas over its limit of 0");
     }
 }
@@ -0,0 +1,508 @@
+//! Data sources and chain builders shared by the tests.
+
+use crate::datasource::{
+    Block, BlockHeader, BlockStream, DataRequest, DataSource, HashAndHeight, HotBlockStream,
+    HotDataSource, HotSource, HotUpdate, Log, Trace, Transaction,
+};
+use futures_util::StreamExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub fn hash(number: u64) -> String {
+    format!("0x{:064x}", number)
+}
+
+pub fn fork_hash(number: u64) -> String {
+    format!("0x{:064x}", 0xf000 + number)
+}
+
+pub fn head(hash: String, height: u64) -> HashAndHeight {
+    HashAndHeight { hash, height }
+}
+
+pub fn block(number: u64, hash: String, parent_hash: String) -> Block {
+    let zero = format!("0x{:064x}", 0);
+    Block {
+        header: BlockHeader {
+            number,
+            hash,
+            parent_hash,
+            size: None,
+            sha3_uncles: zero.clone(),
+            miner: format!("0x{:040x}", 0),
+            state_root: zero.clone(),
+            transactions_root: zero.clone(),
+            receipts_root: zero.clone(),
+            logs_bloom: format!("0x{}", "00".repeat(256)),
+            difficulty: "0x0".to_string(),
+            total_difficulty: "0x0".to_string(),
+            gas_limit: "0x0".to_string(),
+            gas_used: "0x0".to_string(),
+            timestamp: 0,
+            extra_data: "0x".to_string(),
+            mix_hash: zero,
+            nonce: "0x0".to_string(),
+            base_fee_per_gas: None,
+            l2_extra: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            withdrawals_root: None,
+            parent_beacon_root: None,
+            requests_hash: None,
+        },
+        logs: vec![],
+        transactions: vec![],
+        traces: vec![],
+        uncles: vec![],
+        balance_changes: vec![],
+        raw: None,
+    }
+}
+
+/// Linked blocks hashed with `hash`, the first one pointing to `parent_hash`.
+pub fn chain(
+    numbers: RangeInclusive<u64>,
+    hash: fn(u64) -> String,
+    parent_hash: String,
+) -> Vec<Block> {
+    let mut parent_hash = parent_hash;
+    numbers
+        .map(|number| {
+            let block = block(number, hash(number), parent_hash.clone());
+            parent_hash = block.header.hash.clone();
+            block
+        })
+        .collect()
+}
+
+/// Hot update delivering `blocks` on top of their parent, with `finalized` as the new finalized head.
+pub fn hot_update(blocks: Vec<Block>, finalized: HashAndHeight) -> HotUpdate {
+    let first = &blocks[0].header;
+    HotUpdate {
+        base_head: head(first.parent_hash.clone(), first.number - 1),
+        finalized_head: finalized,
+        blocks,
+    }
+}
+
+pub fn signed_transaction(
+    nonce: u64,
+    from: &str,
+    to: &str,
+    value: &str,
+    input: &str,
+    (v, r, s): (&str, &str, &str),
+) -> Transaction {
+    Transaction {
+        transaction_index: 0,
+        hash: hash(0),
+        nonce,
+        from: from.to_string(),
+        to: Some(to.to_string()),
+        input: input.to_string(),
+        value: value.to_string(),
+        gas: "0x5208".to_string(),
+        gas_price: "0x4a817c800".to_string(),
+        max_fee_per_gas: None,
+        max_priority_fee_per_gas: None,
+        v: v.to_string(),
+        r: r.to_string(),
+        s: s.to_string(),
+        y_parity: None,
+        chain_id: None,
+        gas_used: "0x5208".to_string(),
+        cumulative_gas_used: "0x5208".to_string(),
+        effective_gas_price: "0x4a817c800".to_string(),
+        r#type: 0,
+        status: 1,
+        access_list: vec![],
+        max_fee_per_blob_gas: None,
+        blob_versioned_hashes: vec![],
+        raw_receipt: None,
+    }
+}
+
+/// Signed mainnet example from the EIP-155 specification.
+pub fn eip155_transaction() -> Transaction {
+    signed_transaction(
+        9,
+        "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f",
+        "0x3535353535353535353535353535353535353535",
+        "0xde0b6b3a7640000",
+        "0x",
+        (
+            "0x25",
+            "0x28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+            "0x67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+        ),
+    )
+}
+
+/// Serves a canonical chain where block `n` has hash `hash(n)`,
+/// followed by the scripted hot updates.
+///
+/// Blocks carry the EIP-155 example transaction when transactions are requested,
+/// unless their content was injected with one of the `with_*` methods.
+pub struct MockDataSource {
+    heights: Mutex<Vec<u64>>,
+    height_calls: AtomicUsize,
+    served: Option<u64>,
+    batch_size: Option<usize>,
+    open: bool,
+    requests: Mutex<Vec<(u64, Option<u64>)>>,
+    head: Option<u64>,
+    hot: Mutex<Vec<HotUpdate>>,
+    hot_failure: Mutex<Option<anyhow::Error>>,
+    hot_calls: AtomicUsize,
+    failure: Mutex<Option<(u64, anyhow::Error)>>,
+    chain_id: Option<u64>,
+    first_block: u64,
+    blocks: HashMap<u64, Block>,
+}
+
+impl MockDataSource {
+    pub fn new(height: u64) -> MockDataSource {
+        MockDataSource {
+            heights: Mutex::new(vec![height]),
+            height_calls: AtomicUsize::new(0),
+            served: None,
+            batch_size: None,
+            open: false,
+            requests: Mutex::new(vec![]),
+            head: None,
+            hot: Mutex::new(vec![]),
+            hot_failure: Mutex::new(None),
+            hot_calls: AtomicUsize::new(0),
+            failure: Mutex::new(None),
+            chain_id: None,
+            first_block: 0,
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn with_first_block(mut self, first_block: u64) -> MockDataSource {
+        self.first_block = first_block;
+        self
+    }
+
+    /// Reports the `heights` as finalized one call after another, repeating the last one.
+    pub fn with_heights(self, heights: Vec<u64>) -> MockDataSource {
+        *self.heights.lock().unwrap() = heights;
+        self
+    }
+
+    /// Serves finalized blocks only up to `served`, short of the reported height.
+    pub fn with_served(mut self, served: u64) -> MockDataSource {
+        self.served = Some(served);
+        self
+    }
+
+    /// Splits finalized streams into batches of `batch_size` blocks.
+    pub fn with_batch_size(mut self, batch_size: usize) -> MockDataSource {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// Keeps finalized streams open after the last block like a source waiting for a new head.
+    pub fn with_open_streams(mut self) -> MockDataSource {
+        self.open = true;
+        self
+    }
+
+    /// Reports `head` as the latest block, above the finalized height.
+    pub fn with_head(mut self, head: u64) -> MockDataSource {
+        self.head = Some(head);
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> MockDataSource {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    /// The first finalized stream reaching block `number`, or ending right before it,
+    /// fails with `error` instead of serving it.
+    pub fn with_failure(self, number: u64, error: anyhow::Error) -> MockDataSource {
+        *self.failure.lock().unwrap() = Some((number, error));
+        self
+    }
+
+    /// Updates delivered in order by the first hot stream, see [`hot_update`].
+    pub fn with_hot(self, updates: Vec<HotUpdate>) -> MockDataSource {
+        self.set_hot(updates);
+        self
+    }
+
+    /// The next hot stream fails with `error` once its scripted updates are delivered.
+    pub fn with_hot_failure(self, error: anyhow::Error) -> MockDataSource {
+        *self.hot_failure.lock().unwrap() = Some(error);
+        self
+    }
+
+    /// Serves `block` as is in place of the canonical block of its height.
+    pub fn with_block(mut self, block: Block) -> MockDataSource {
+        self.blocks.insert(block.header.number, block);
+        self
+    }
+
+    pub fn with_transactions(self, number: u64, transactions: Vec<Transaction>) -> MockDataSource {
+        self.with_content(number, |block| block.transactions = transactions)
+    }
+
+    pub fn with_logs(self, number: u64, logs: Vec<Log>) -> MockDataSource {
+        self.with_content(number, |block| block.logs = logs)
+    }
+
+    pub fn with_traces(self, number: u64, traces: Vec<Trace>) -> MockDataSource {
+        self.with_content(number, |block| block.traces = traces)
+    }
+
+    fn with_content(mut self, number: u64, set: impl FnOnce(&mut Block)) -> MockDataSource {
+        let block = self
+            .blocks
+            .entry(number)
+            .or_insert_with(|| block(number, hash(number), hash(number.saturating_sub(1))));
+        set(block);
+        self
+    }
+
+    pub fn set_height(&self, height: u64) {
+        *self.heights.lock().unwrap() = vec![height];
+    }
+
+    /// Scripts the updates of the next hot stream.
+    pub fn set_hot(&self, updates: Vec<HotUpdate>) {
+        *self.hot.lock().unwrap() = updates;
+    }
+
+    /// Whether the failure scripted with [`MockDataSource::with_failure`] was delivered.
+    pub fn failure_delivered(&self) -> bool {
+        self.failure.lock().unwrap().is_none()
+    }
+
+    /// Ranges of the finalized streams requested so far.
+    pub fn requests(&self) -> Vec<(u64, Option<u64>)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Number of finalized heights requested so far.
+    pub fn height_calls(&self) -> usize {
+        self.height_calls.load(Ordering::SeqCst)
+    }
+
+    /// Number of hot streams requested so far.
+    pub fn hot_calls(&self) -> usize {
+        self.hot_calls.load(Ordering::SeqCst)
+    }
+
+    fn height(&self) -> u64 {
+        self.heights.lock().unwrap()[0]
+    }
+
+    fn block(&self, number: u64, request: &DataRequest) -> Block {
+        if let Some(block) = self.blocks.get(&number) {
+            let mut block = block.clone();
+            if !request.traces {
+                block.traces.clear();
+            }
+            if !request.raw_blocks {
+                block.raw = None;
+            }
+            return block;
+        }
+        let mut block = block(number, hash(number), hash(number.saturating_sub(1)));
+        if !request.transactions.is_empty() && !request.headers_only {
+            block.transactions.push(eip155_transaction());
+        }
+        block
+    }
+}
+
+#[async_trait::async_trait]
+impl DataSource for MockDataSource {
+    fn get_finalized_blocks(
+        &self,
+        request: DataRequest,
+        _stop_on_head: bool,
+    ) -> anyhow::Result<BlockStream> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push((request.from, request.to));
+        let served = self.served.unwrap_or_else(|| self.height());
+        let to = request.to.map_or(served, |to| to.min(served));
+        let blocks = |from: u64, to: u64| -> Vec<Block> {
+            (from..=to)
+                .map(|number| self.block(number, &request))
+                .collect()
+        };
+        let mut failure = self.failure.lock().unwrap();
+        let batches = if failure
+            .as_ref()
+            .is_some_and(|(number, _)| (request.from..=to + 1).contains(number))
+        {
+            let (number, error) = failure.take().unwrap();
+            vec![Ok(blocks(request.from, number - 1)), Err(error)]
+        } else if let Some(size) = self.batch_size {
+            let blocks = blocks(request.from, to);
+            blocks
+                .chunks(size)
+                .map(|chunk| Ok(chunk.to_vec()))
+                .collect()
+        } else {
+            vec![Ok(blocks(request.from, to))]
+        };
+        let stream = futures_util::stream::iter(batches);
+        if self.open {
+            Ok(Box::new(stream.chain(futures_util::stream::pending())))
+        } else {
+            Ok(Box::new(stream))
+        }
+    }
+
+    async fn get_finalized_height(&self) -> anyhow::Result<u64> {
+        self.height_calls.fetch_add(1, Ordering::SeqCst);
+        let mut heights = self.heights.lock().unwrap();
+        let height = heights[0];
+        if heights.len() > 1 {
+            heights.remove(0);
+        }
+        Ok(height)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> anyhow::Result<String> {
+        let head = self.head.unwrap_or_else(|| self.height());
+        anyhow::ensure!(height <= head, "block №{} not found", height);
+        Ok(self
+            .blocks
+            .get(&height)
+            .map_or_else(|| hash(height), |block| block.header.hash.clone()))
+    }
+
+    async fn get_first_block(&self) -> anyhow::Result<u64> {
+        Ok(self.first_block)
+    }
+
+    async fn get_head_height(&self) -> anyhow::Result<u64> {
+        Ok(self.head.unwrap_or_else(|| self.height()))
+    }
+
+    async fn get_chain_id(&self) -> anyhow::Result<Option<u64>> {
+        Ok(self.chain_id)
+    }
+}
+
+#[async_trait::async_trait]
+impl HotSource for MockDataSource {
+    fn get_hot_blocks(
+        &self,
+        _request: DataRequest,
+        _state: HashAndHeight,
+    ) -> anyhow::Result<HotBlockStream> {
+        self.hot_calls.fetch_add(1, Ordering::SeqCst);
+        let updates = std::mem::take(&mut *self.hot.lock().unwrap());
+        let failure = self.hot_failure.lock().unwrap().take();
+        Ok(Box::new(futures_util::stream::iter(
+            updates.into_iter().map(Ok).chain(failure.map(Err)),
+        )))
+    }
+
+    fn as_ds(&self) -> &(dyn DataSource + Send + Sync) {
+        self
+    }
+}
+
+impl HotDataSource for MockDataSource {}
+
+/// Archive http api serving the headers of the canonical chain of [`MockDataSource`]
+/// up to `height`, recording every query it receives.
+pub struct MockArchive {
+    url: String,
+    queries: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockArchive {
+    /// Binds to a free local port, must be called within a tokio runtime.
+    pub fn start(height: u64) -> MockArchive {
+        let queries = Arc::new(Mutex::new(vec![]));
+        let recorded = queries.clone();
+        let make_service = make_service_fn(move |_| {
+            let queries = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let queries = queries.clone();
+                    async move { Ok::<_, Infallible>(archive_response(height, &queries, request).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        MockArchive { url, queries }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Bodies of the queries received so far.
+    pub fn queries(&self) -> Vec<Value> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+async fn archive_response(
+    height: u64,
+    queries: &Mutex<Vec<Value>>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let path = request.uri().path().to_string();
+    if request.method() == Method::GET && path == "/height" {
+        return Response::new(Body::from(height.to_string()));
+    }
+    if request.method() == Method::GET && path.ends_with("/worker") {
+        let host = request.headers()["host"].to_str().unwrap().to_string();
+        return Response::new(Body::from(format!("http://{}/query", host)));
+    }
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+    let query: Value = serde_json::from_slice(&body).unwrap();
+    queries.lock().unwrap().push(query.clone());
+    let from = query["fromBlock"].as_u64().unwrap();
+    let to = query["toBlock"]
+        .as_u64()
+        .map_or(height, |to| to.min(height));
+    let blocks: Vec<Value> = (from..=to)
+        .map(|number| {
+            let header = block(number, hash(number), hash(number.saturating_sub(1))).header;
+            json!({
+                "header": {
+                    "number": header.number,
+                    "hash": header.hash,
+                    "parentHash": header.parent_hash,
+                    "sha3Uncles": header.sha3_uncles,
+                    "miner": header.miner,
+                    "stateRoot": header.state_root,
+                    "transactionsRoot": header.transactions_root,
+                    "receiptsRoot": header.receipts_root,
+                    "logsBloom": header.logs_bloom,
+                    "difficulty": header.difficulty,
+                    "totalDifficulty": header.total_difficulty,
+                    "gasLimit": header.gas_limit,
+                    "gasUsed": header.gas_used,
+                    "timestamp": header.timestamp,
+                    "extraData": header.extra_data,
+                    "mixHash": header.mix_hash,
+                    "nonce": header.nonce,
+                    "baseFeePerGas": null,
+                }
+            })
+        })
+        .collect();
+    Response::new(Body::from(Value::Array(blocks).to_string()))
+}
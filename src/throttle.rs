@@ -0,0 +1,63 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket refilled with `rate` tokens per second,
+/// holding up to a second worth of tokens for bursts.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> RateLimiter {
+        let rate = f64::from(rate.max(1));
+        RateLimiter {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token, waiting for one to be refilled if the bucket is empty.
+    /// Returns whether the caller had to wait.
+    pub async fn acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            return false;
+        }
+        // the missing part of the token is refilled by the time the sleep ends
+        let wait = Duration::from_secs_f64(-self.tokens / self.rate);
+        tokio::time::sleep(wait).await;
+        self.tokens = 0.0;
+        self.refilled += wait;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::throttle::RateLimiter;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn bursts_then_waits() {
+        let mut limiter = RateLimiter::new(100);
+        let started = Instant::now();
+        for _ in 0..100 {
+            assert!(!limiter.acquire().await);
+        }
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        for _ in 0..10 {
+            assert!(limiter.acquire().await);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
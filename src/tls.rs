@@ -0,0 +1,118 @@
+use anyhow::Context;
+use std::path::Path;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+/// Serves with the PEM encoded certificate chain `cert` and its private `key`,
+/// clients must present a certificate signed by `client_ca` when it's given.
+pub fn server_tls_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> anyhow::Result<ServerTlsConfig> {
+    let identity = Identity::from_pem(read_pem(cert)?, read_pem(key)?);
+    let config = ServerTlsConfig::new().identity(identity);
+    match client_ca {
+        Some(path) => Ok(config.client_ca_root(Certificate::from_pem(read_pem(path)?))),
+        None => Ok(config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::firehose::Firehose;
+    use crate::pbcodec;
+    use crate::pbfirehose::stream_client::StreamClient;
+    use crate::pbfirehose::Request;
+    use crate::stream::stream_service;
+    use crate::testing::MockDataSource;
+    use crate::tls::server_tls_config;
+    use futures_util::StreamExt;
+    use prost::Message;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity, Server};
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn pem(name: &str) -> Vec<u8> {
+        std::fs::read(testdata(name)).unwrap()
+    }
+
+    async fn serve(client_ca: Option<&Path>) -> u16 {
+        let archive = Arc::new(MockDataSource::new(9));
+        let firehose = Arc::new(Firehose::new(archive, None));
+        let tls =
+            server_tls_config(&testdata("server.pem"), &testdata("server.key"), client_ca).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(
+            Server::builder()
+                .tls_config(tls)
+                .unwrap()
+                .add_service(stream_service(firehose, None, None, Default::default()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        port
+    }
+
+    async fn heights(port: u16, tls: ClientTlsConfig) -> anyhow::Result<Vec<u64>> {
+        let channel = Channel::from_shared(format!("https://localhost:{}", port))?
+            .tls_config(tls)?
+            .connect()
+            .await?;
+        let request = Request {
+            start_block_num: 0,
+            stop_block_num: 9,
+            ..Default::default()
+        };
+        let mut stream = StreamClient::new(channel)
+            .blocks(request)
+            .await?
+            .into_inner();
+        let mut heights = vec![];
+        while let Some(response) = stream.next().await {
+            let block = response?.block.unwrap();
+            heights.push(pbcodec::Block::decode(&block.value[..])?.number);
+        }
+        Ok(heights)
+    }
+
+    fn client_tls() -> ClientTlsConfig {
+        ClientTlsConfig::new()
+            .domain_name("localhost")
+            .ca_certificate(Certificate::from_pem(pem("ca.pem")))
+    }
+
+    #[tokio::test]
+    async fn blocks_over_tls() {
+        let port = serve(None).await;
+        assert_eq!(
+            heights(port, client_tls()).await.unwrap(),
+            (0..=9).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_requires_client_certificate() {
+        let port = serve(Some(&testdata("ca.pem"))).await;
+        assert!(heights(port, client_tls()).await.is_err());
+
+        let identity = Identity::from_pem(pem("client.pem"), pem("client.key"));
+        assert_eq!(
+            heights(port, client_tls().identity(identity))
+                .await
+                .unwrap(),
+            (0..=9).collect::<Vec<_>>()
+        );
+    }
+}